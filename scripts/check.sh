@@ -11,6 +11,7 @@ echo "Checking: `git show --oneline -s HEAD`"
 
 cargo fmt --check
 cargo clippy -- -D warnings -A clippy::empty-loop
+cargo clippy --all-features -- -D warnings -A clippy::empty-loop
 
 if [ "${SKIP_TEST}" = "1" ] ; then
     echo "## SKIP_TEST env var is set to 1. Skip running tests."
@@ -6,6 +6,14 @@ PATH_TO_EFI="$1"
 rm -rf mnt
 mkdir -p mnt/EFI/BOOT/
 cp ${PATH_TO_EFI} mnt/EFI/BOOT/BOOTX64.EFI
+# Set QEMU_IOMMU=1 to emulate an Intel VT-d IOMMU. It should come before the
+# other PCI devices.
+IOMMU_ARGS=""
+if [ -n "${QEMU_IOMMU}" ]; then
+  IOMMU_ARGS="-device intel-iommu"
+fi
+# COM2 serves the remote control protocol (see src/remote.rs) on this port.
+REMOTE_PORT=${REMOTE_PORT:-2346}
 set +e
 mkdir -p log
 qemu-system-x86_64 \
@@ -16,6 +24,9 @@ qemu-system-x86_64 \
   -monitor telnet:0.0.0.0:2345,server,nowait,logfile=log/qemu_monitor.txt \
   -chardev stdio,id=char_com1,mux=on,logfile=log/com1.txt \
   -serial chardev:char_com1 \
+  -chardev socket,id=char_com2,host=127.0.0.1,port=${REMOTE_PORT},server=on,wait=off \
+  -serial chardev:char_com2 \
+  ${IOMMU_ARGS} \
   -device qemu-xhci \
   -device usb-kbd \
   -device usb-tablet \
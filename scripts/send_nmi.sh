@@ -0,0 +1,6 @@
+#!/bin/bash -e
+# Sends an NMI to the WasabiOS running in QEMU via the telnet monitor
+# exposed by scripts/launch_qemu.sh. The CPU state will be dumped to the
+# serial console.
+MONITOR_PORT=${MONITOR_PORT:-2345}
+{ echo "nmi"; sleep 1; } | nc localhost ${MONITOR_PORT} > /dev/null
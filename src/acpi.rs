@@ -1,7 +1,56 @@
+extern crate alloc;
+
+use crate::frame::FrameOwner;
+use crate::frame::Frames;
+use crate::frame::FRAME_SIZE_4K;
 use crate::hpet::HpetRegisters;
+use crate::mutex::RwLock;
 use crate::result::Result;
+use crate::slice::Sliceable;
+use crate::warn;
+use crate::x86::read_io_port_u16;
+use crate::x86::read_io_port_u32;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u16;
+use crate::x86::write_io_port_u32;
+use crate::x86::write_io_port_u8;
+use alloc::vec::Vec;
 use core::fmt;
+use core::mem::offset_of;
 use core::mem::size_of;
+use core::ptr::copy_nonoverlapping;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::slice;
+
+// Copies of the tables are placed below 4 GiB so that the 32-bit pointers
+// to them (e.g. FADT.DSDT) can hold their addresses.
+const ACPI_COPY_ADDR_LIMIT: u64 = 1 << 32;
+const HEADER_CHECKSUM_OFFSET: usize = 9;
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_CHECKSUM_OFFSET: usize = 8;
+const RSDP_V1_LENGTH: usize = 20;
+const RSDP_EXTENDED_CHECKSUM_OFFSET: usize = 32;
+const FADT_DSDT_OFFSET: usize = 40;
+const FADT_X_DSDT_OFFSET: usize = 140;
+
+/// The sum of `bytes`, which is 0 for a valid table
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, e| sum.wrapping_add(*e))
+}
+
+/// Updates the byte at `offset` so that the sum of `bytes` becomes 0.
+fn update_checksum(bytes: &mut [u8], offset: usize) {
+    bytes[offset] = 0;
+    bytes[offset] = checksum(bytes).wrapping_neg();
+}
+
+/// Makes a fixed-length ASCII field of a table printable.
+fn ascii_field(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes)
+        .unwrap_or("?")
+        .trim_end_matches(['\0', ' '])
+}
 
 #[repr(packed)]
 #[derive(Clone, Copy, Debug)]
@@ -10,7 +59,13 @@ struct SystemDescriptionTableHeader {
     // Table 5.4: DESCRIPTION_HEADER Fields
     signature: [u8; 4],
     length: u32,
-    _unused: [u8; 28],
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    _oem_revision: u32,
+    _creator_id: u32,
+    _creator_revision: u32,
 }
 const _: () = assert!(size_of::<SystemDescriptionTableHeader>() == 36);
 
@@ -21,22 +76,80 @@ impl SystemDescriptionTableHeader {
     fn signature(&self) -> &[u8; 4] {
         &self.signature
     }
+    fn length(&self) -> usize {
+        self.length as usize
+    }
+    fn as_ptr(&self) -> *const u8 {
+        self as *const Self as *const u8
+    }
+    fn bytes(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.length()) }
+    }
+    /// Checks the length and the checksum of the whole table.
+    fn validate(&self) -> Result<()> {
+        if self.length() < size_of::<Self>() {
+            Err("ACPI table is too short")
+        } else if checksum(self.bytes()) != 0 {
+            Err("ACPI table checksum mismatch")
+        } else {
+            Ok(())
+        }
+    }
+    /// The bytes after the header
+    fn body(&self) -> &'static [u8] {
+        let header_size = size_of::<Self>();
+        unsafe {
+            slice::from_raw_parts(
+                self.as_ptr().add(header_size),
+                self.length().saturating_sub(header_size),
+            )
+        }
+    }
+    /// Returns the DSDT if this is a FADT.
+    fn dsdt(&self) -> Option<&'static SystemDescriptionTableHeader> {
+        if self.signature != *b"FACP" {
+            return None;
+        }
+        let read_field = |offset: usize, size: usize| -> u64 {
+            if offset + size > self.length() {
+                return 0;
+            }
+            unsafe {
+                let p = self.as_ptr().add(offset);
+                match size {
+                    4 => (p as *const u32).read_unaligned() as u64,
+                    _ => (p as *const u64).read_unaligned(),
+                }
+            }
+        };
+        let addr = match read_field(FADT_X_DSDT_OFFSET, 8) {
+            0 => read_field(FADT_DSDT_OFFSET, 4),
+            addr => addr,
+        };
+        if addr == 0 {
+            None
+        } else {
+            Some(unsafe { &*(addr as *const SystemDescriptionTableHeader) })
+        }
+    }
 }
 
-struct XsdtIterator<'a> {
-    table: &'a Xsdt,
+struct RootTableIterator<'a> {
+    table: &'a RootTable,
     index: usize,
 }
 
-impl<'a> XsdtIterator<'a> {
-    pub fn new(table: &'a Xsdt) -> Self {
-        XsdtIterator { table, index: 0 }
+impl<'a> RootTableIterator<'a> {
+    pub fn new(table: &'a RootTable) -> Self {
+        RootTableIterator { table, index: 0 }
     }
 }
-impl<'a> Iterator for XsdtIterator<'a> {
+impl<'a> Iterator for RootTableIterator<'a> {
     // The item will have a static lifetime
     // since it will be allocated on
-    // ACPI_RECLAIM_MEMORY region.
+    // ACPI_RECLAIM_MEMORY region, or on the
+    // frames made by copy_tables() that are
+    // never freed.
     type Item = &'static SystemDescriptionTableHeader;
     fn next(&mut self) -> Option<Self::Item> {
         if self.index >= self.table.num_of_entries() {
@@ -51,13 +164,14 @@ impl<'a> Iterator for XsdtIterator<'a> {
     }
 }
 
+/// The XSDT, or the RSDT of ACPI 1.0 which has 32-bit entries
 #[repr(packed)]
-struct Xsdt {
+struct RootTable {
     header: SystemDescriptionTableHeader,
 }
-const _: () = assert!(size_of::<Xsdt>() == 36);
+const _: () = assert!(size_of::<RootTable>() == 36);
 
-impl Xsdt {
+impl RootTable {
     fn find_table(
         &self,
         sig: &'static [u8; 4],
@@ -67,18 +181,27 @@ impl Xsdt {
     fn header_size(&self) -> usize {
         size_of::<Self>()
     }
+    fn entry_size(&self) -> usize {
+        if self.header.signature == *b"RSDT" {
+            size_of::<u32>()
+        } else {
+            size_of::<u64>()
+        }
+    }
     fn num_of_entries(&self) -> usize {
-        (self.header.length as usize - self.header_size())
-            / size_of::<*const u8>()
+        (self.header.length() - self.header_size()) / self.entry_size()
     }
     unsafe fn entry(&self, index: usize) -> *const u8 {
-        ((self as *const Self as *const u8).add(self.header_size())
-            as *const *const u8)
-            .add(index)
-            .read_unaligned()
+        let p = (self as *const Self as *const u8)
+            .add(self.header_size() + index * self.entry_size());
+        if self.entry_size() == size_of::<u32>() {
+            (p as *const u32).read_unaligned() as usize as *const u8
+        } else {
+            (p as *const u64).read_unaligned() as usize as *const u8
+        }
     }
-    fn iter(&self) -> XsdtIterator {
-        XsdtIterator::new(self)
+    fn iter(&self) -> RootTableIterator {
+        RootTableIterator::new(self)
     }
 }
 
@@ -98,20 +221,179 @@ trait AcpiTable {
 }
 
 #[repr(packed)]
+#[derive(Clone, Copy, Debug)]
 pub struct GenericAddress {
     address_space_id: u8,
-    _unused: [u8; 3],
+    bit_width: u8,
+    _bit_offset: u8,
+    access_size: u8,
     address: u64,
 }
 const _: () = assert!(size_of::<GenericAddress>() == 12);
+unsafe impl Sliceable for GenericAddress {}
 impl GenericAddress {
+    const SPACE_SYSTEM_MEMORY: u8 = 0;
+    const SPACE_SYSTEM_IO: u8 = 1;
+    pub fn new(address_space_id: u8, bit_width: u8, address: u64) -> Self {
+        Self {
+            address_space_id,
+            bit_width,
+            _bit_offset: 0,
+            access_size: 0,
+            address,
+        }
+    }
+    /// A register in the I/O space, for the legacy FADT fields
+    fn io_port(port: u32, bit_width: u8) -> Self {
+        Self::new(Self::SPACE_SYSTEM_IO, bit_width, port as u64)
+    }
     pub fn address_in_memory_space(&self) -> Result<usize> {
-        if self.address_space_id == 0 {
+        if self.address_space_id == Self::SPACE_SYSTEM_MEMORY {
             Ok(self.address as usize)
         } else {
             Err("ACPI Generic Address is not in system memory space")
         }
     }
+    pub fn is_null(&self) -> bool {
+        self.address == 0
+    }
+    pub fn is_in_io_space(&self) -> bool {
+        self.address_space_id == Self::SPACE_SYSTEM_IO
+    }
+    pub fn is_in_memory_space(&self) -> bool {
+        self.address_space_id == Self::SPACE_SYSTEM_MEMORY
+    }
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+    /// The size of the register in bits
+    pub fn bit_width(&self) -> u8 {
+        self.bit_width
+    }
+    /// The width of the accesses in bits
+    pub fn access_width(&self) -> u8 {
+        match self.access_size {
+            1..=4 => 8 << (self.access_size - 1),
+            _ => self.bit_width,
+        }
+    }
+    pub fn read(&self) -> Result<u64> {
+        let addr = self.address;
+        match (self.address_space_id, self.access_width()) {
+            (Self::SPACE_SYSTEM_IO, 8) => {
+                Ok(read_io_port_u8(addr as u16) as u64)
+            }
+            (Self::SPACE_SYSTEM_IO, 16) => {
+                Ok(read_io_port_u16(addr as u16) as u64)
+            }
+            (Self::SPACE_SYSTEM_IO, 32) => {
+                Ok(read_io_port_u32(addr as u16) as u64)
+            }
+            (Self::SPACE_SYSTEM_MEMORY, 8) => unsafe {
+                Ok(read_volatile(addr as *const u8) as u64)
+            },
+            (Self::SPACE_SYSTEM_MEMORY, 16) => unsafe {
+                Ok(read_volatile(addr as *const u16) as u64)
+            },
+            (Self::SPACE_SYSTEM_MEMORY, 32) => unsafe {
+                Ok(read_volatile(addr as *const u32) as u64)
+            },
+            (Self::SPACE_SYSTEM_MEMORY, 64) => unsafe {
+                Ok(read_volatile(addr as *const u64))
+            },
+            _ => Err("Unsupported ACPI Generic Address"),
+        }
+    }
+    pub fn write(&self, value: u64) -> Result<()> {
+        let addr = self.address;
+        match (self.address_space_id, self.access_width()) {
+            (Self::SPACE_SYSTEM_IO, 8) => {
+                write_io_port_u8(addr as u16, value as u8)
+            }
+            (Self::SPACE_SYSTEM_IO, 16) => {
+                write_io_port_u16(addr as u16, value as u16)
+            }
+            (Self::SPACE_SYSTEM_IO, 32) => {
+                write_io_port_u32(addr as u16, value as u32)
+            }
+            (Self::SPACE_SYSTEM_MEMORY, 8) => unsafe {
+                write_volatile(addr as *mut u8, value as u8)
+            },
+            (Self::SPACE_SYSTEM_MEMORY, 16) => unsafe {
+                write_volatile(addr as *mut u16, value as u16)
+            },
+            (Self::SPACE_SYSTEM_MEMORY, 32) => unsafe {
+                write_volatile(addr as *mut u32, value as u32)
+            },
+            (Self::SPACE_SYSTEM_MEMORY, 64) => unsafe {
+                write_volatile(addr as *mut u64, value)
+            },
+            _ => return Err("Unsupported ACPI Generic Address"),
+        }
+        Ok(())
+    }
+}
+
+pub struct DefinitionBlock {
+    header: &'static SystemDescriptionTableHeader,
+}
+impl DefinitionBlock {
+    pub fn signature(&self) -> &[u8; 4] {
+        self.header.signature()
+    }
+    pub fn revision(&self) -> u8 {
+        self.header.revision
+    }
+    /// The AML byte code
+    pub fn aml(&self) -> &'static [u8] {
+        self.header.body()
+    }
+}
+
+/// A table listed in the XSDT, the XSDT itself or the DSDT
+#[derive(Clone, Copy)]
+pub struct SystemDescriptionTable {
+    header: &'static SystemDescriptionTableHeader,
+}
+impl SystemDescriptionTable {
+    pub fn signature(&self) -> &str {
+        ascii_field(&self.header.signature)
+    }
+    pub fn oem_id(&self) -> &str {
+        ascii_field(&self.header.oem_id)
+    }
+    pub fn oem_table_id(&self) -> &str {
+        ascii_field(&self.header.oem_table_id)
+    }
+    pub fn revision(&self) -> u8 {
+        self.header.revision
+    }
+    pub fn length(&self) -> usize {
+        self.header.length()
+    }
+    pub fn is_valid(&self) -> bool {
+        self.header.validate().is_ok()
+    }
+    /// The whole table including the header
+    pub fn bytes(&self) -> &'static [u8] {
+        self.header.bytes()
+    }
+}
+impl fmt::Display for SystemDescriptionTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let checksum = self.header.checksum;
+        write!(
+            f,
+            "{:4} {:6} {:8} rev {:<3} len {:#07X} checksum {:#04X}{}",
+            self.signature(),
+            self.oem_id(),
+            self.oem_table_id(),
+            self.revision(),
+            self.length(),
+            checksum,
+            if self.is_valid() { "" } else { " (invalid)" }
+        )
+    }
 }
 
 #[repr(packed)]
@@ -148,16 +430,223 @@ pub struct AcpiRsdpStruct {
     xsdt: u64,
 }
 impl AcpiRsdpStruct {
-    fn xsdt(&self) -> &Xsdt {
-        unsafe { &*(self.xsdt as *const Xsdt) }
+    /// True if the XSDT should be used instead of the RSDT
+    fn has_xsdt(&self) -> bool {
+        self.revision >= 2 && self.xsdt != 0
+    }
+    fn length(&self) -> usize {
+        // ACPI 1.0 RSDP does not have the length field and the rest.
+        if self.revision >= 2 {
+            self.length as usize
+        } else {
+            RSDP_V1_LENGTH
+        }
+    }
+    /// Checks the signature and the checksums of the RSDP.
+    fn validate(&self) -> Result<()> {
+        if self.signature != *RSDP_SIGNATURE {
+            return Err("RSDP signature mismatch");
+        }
+        if self.length() < RSDP_V1_LENGTH {
+            return Err("RSDP is too short");
+        }
+        let bytes = unsafe {
+            slice::from_raw_parts(
+                self as *const Self as *const u8,
+                self.length(),
+            )
+        };
+        if checksum(&bytes[..RSDP_V1_LENGTH]) != 0 {
+            return Err("RSDP checksum mismatch");
+        }
+        if self.revision >= 2 && checksum(bytes) != 0 {
+            return Err("RSDP extended checksum mismatch");
+        }
+        Ok(())
+    }
+    /// Returns the XSDT, or the RSDT if the XSDT is not available, after
+    /// checking it.
+    fn root_table(&self) -> Result<&'static RootTable> {
+        let (addr, signature) = if self.has_xsdt() {
+            (self.xsdt, b"XSDT")
+        } else {
+            (self.rsdt_address as u64, b"RSDT")
+        };
+        if addr == 0 {
+            return Err("Neither XSDT nor RSDT is available");
+        }
+        let table = unsafe { &*(addr as *const RootTable) };
+        if table.header.signature != *signature {
+            return Err("XSDT/RSDT signature mismatch");
+        }
+        table.header.validate()?;
+        Ok(table)
+    }
+    fn find_table(
+        &self,
+        sig: &'static [u8; 4],
+    ) -> Option<&'static SystemDescriptionTableHeader> {
+        self.root_table().ok()?.find_table(sig)
     }
     pub fn hpet(&self) -> Option<&AcpiHpetDescriptor> {
-        let xsdt = self.xsdt();
-        xsdt.find_table(b"HPET").map(AcpiHpetDescriptor::new)
+        self.find_table(b"HPET").map(AcpiHpetDescriptor::new)
     }
     pub fn mcfg(&self) -> Option<&AcpiMcfgDescriptor> {
-        let xsdt = self.xsdt();
-        xsdt.find_table(b"MCFG").map(AcpiMcfgDescriptor::new)
+        self.find_table(b"MCFG").map(AcpiMcfgDescriptor::new)
+    }
+    pub fn fadt(&self) -> Option<&AcpiFadt> {
+        self.find_table(b"FACP").map(AcpiFadt::new)
+    }
+    pub fn madt(&self) -> Option<&AcpiMadtDescriptor> {
+        self.find_table(b"APIC").map(AcpiMadtDescriptor::new)
+    }
+    pub fn dmar(&self) -> Option<&AcpiDmarDescriptor> {
+        self.find_table(b"DMAR").map(AcpiDmarDescriptor::new)
+    }
+    pub fn spcr(&self) -> Option<&AcpiSpcr> {
+        self.find_table(b"SPCR").map(AcpiSpcr::new)
+    }
+    pub fn dbg2(&self) -> Option<&AcpiDbg2> {
+        self.find_table(b"DBG2").map(AcpiDbg2::new)
+    }
+    /// The DSDT followed by the SSDTs, i.e. the tables that have AML
+    pub fn definition_blocks(
+        &self,
+    ) -> impl Iterator<Item = DefinitionBlock> + '_ {
+        let root = self.root_table().ok();
+        let dsdt = self.find_table(b"FACP").and_then(|e| e.dsdt());
+        dsdt.into_iter()
+            .chain(
+                root.into_iter()
+                    .flat_map(|e| e.iter())
+                    .filter(|e| e.signature() == b"SSDT"),
+            )
+            .map(|header| DefinitionBlock { header })
+    }
+    /// The XSDT (or the RSDT), the tables listed in it, and the DSDT
+    pub fn tables(&self) -> impl Iterator<Item = SystemDescriptionTable> + '_ {
+        let root = self.root_table().ok();
+        let dsdt = self.find_table(b"FACP").and_then(|e| e.dsdt());
+        root.map(|e| &e.header)
+            .into_iter()
+            .chain(root.into_iter().flat_map(|e| e.iter()))
+            .chain(dsdt)
+            .map(|header| SystemDescriptionTable { header })
+    }
+    /// Copies the RSDP, the XSDT (or the RSDT), the tables listed in it and
+    /// the DSDT into newly allocated frames, and returns the copy of the
+    /// RSDP. Tables with a bad checksum are dropped with a warning. The copy
+    /// always has an ACPI 2.0 RSDP and an XSDT, even if the original only has
+    /// an RSDT, and the pointers and checksums in it are updated.
+    /// The copies stay valid after ACPI_RECLAIM_MEMORY is reused.
+    pub fn copy_tables(&self) -> Result<&'static AcpiRsdpStruct> {
+        self.validate()?;
+        let root = self.root_table()?;
+        let mut tables = Vec::new();
+        for e in root.iter() {
+            match e.validate() {
+                Ok(()) => tables.push(e),
+                Err(err) => {
+                    warn!(
+                        "acpi: Ignoring {}: {err}",
+                        ascii_field(e.signature())
+                    )
+                }
+            }
+        }
+        let num_entries = tables.len();
+        if let Some(dsdt) = tables.iter().find_map(|e| e.dsdt()) {
+            match dsdt.validate() {
+                Ok(()) => tables.push(dsdt),
+                Err(err) => warn!("acpi: Ignoring DSDT: {err}"),
+            }
+        }
+        let rsdp_length = size_of::<Self>();
+        let xsdt_offset = rsdp_length.next_multiple_of(8);
+        let xsdt_length = root.header_size() + num_entries * size_of::<u64>();
+        let size = tables.iter().fold(xsdt_offset + xsdt_length, |size, e| {
+            size.next_multiple_of(8) + e.length()
+        });
+        let mut frames = Frames::alloc_constrained(
+            size,
+            FRAME_SIZE_4K,
+            0,
+            ACPI_COPY_ADDR_LIMIT,
+            FrameOwner::Acpi,
+        )?;
+        frames.fill_zero();
+        let base = frames.leak();
+        let copy = unsafe { slice::from_raw_parts_mut(base as *mut u8, size) };
+        let copy_table = |copy: &mut [u8], offset: usize, src, len| unsafe {
+            copy_nonoverlapping(
+                src,
+                copy[offset..offset + len].as_mut_ptr(),
+                len,
+            )
+        };
+        copy_table(
+            copy,
+            0,
+            self as *const Self as *const u8,
+            self.length().min(rsdp_length),
+        );
+        copy_table(copy, xsdt_offset, root.header.as_ptr(), root.header_size());
+        copy[xsdt_offset..xsdt_offset + 4].copy_from_slice(b"XSDT");
+        copy[xsdt_offset + 4..xsdt_offset + 8]
+            .copy_from_slice(&(xsdt_length as u32).to_le_bytes());
+        let mut offset = xsdt_offset + xsdt_length;
+        let mut fadt_range = None;
+        let mut dsdt_addr = 0;
+        for (i, e) in tables.iter().enumerate() {
+            offset = offset.next_multiple_of(8);
+            copy_table(copy, offset, e.as_ptr(), e.length());
+            let addr = base + offset as u64;
+            if i < num_entries {
+                let entry = xsdt_offset + root.header_size() + i * 8;
+                copy[entry..entry + 8].copy_from_slice(&addr.to_le_bytes());
+                if e.signature == *b"FACP" {
+                    fadt_range = Some(offset..offset + e.length());
+                }
+            } else {
+                dsdt_addr = addr;
+            }
+            offset += e.length();
+        }
+        if let Some(fadt_range) = fadt_range {
+            let len = fadt_range.len();
+            let fadt = &mut copy[fadt_range];
+            if dsdt_addr != 0 {
+                if FADT_DSDT_OFFSET + 4 <= len {
+                    fadt[FADT_DSDT_OFFSET..FADT_DSDT_OFFSET + 4]
+                        .copy_from_slice(&(dsdt_addr as u32).to_le_bytes());
+                }
+                if FADT_X_DSDT_OFFSET + 8 <= len {
+                    fadt[FADT_X_DSDT_OFFSET..FADT_X_DSDT_OFFSET + 8]
+                        .copy_from_slice(&dsdt_addr.to_le_bytes());
+                }
+            }
+            update_checksum(fadt, HEADER_CHECKSUM_OFFSET);
+        }
+        update_checksum(
+            &mut copy[xsdt_offset..xsdt_offset + xsdt_length],
+            HEADER_CHECKSUM_OFFSET,
+        );
+        copy[offset_of!(AcpiRsdpStruct, revision)] = 2;
+        let length_field = offset_of!(AcpiRsdpStruct, length);
+        copy[length_field..length_field + 4]
+            .copy_from_slice(&(rsdp_length as u32).to_le_bytes());
+        let rsdt_field = offset_of!(AcpiRsdpStruct, rsdt_address);
+        copy[rsdt_field..rsdt_field + 4].fill(0);
+        let xsdt_field = offset_of!(AcpiRsdpStruct, xsdt);
+        copy[xsdt_field..xsdt_field + 8]
+            .copy_from_slice(&(base + xsdt_offset as u64).to_le_bytes());
+        let rsdp_bytes = &mut copy[..rsdp_length];
+        update_checksum(
+            &mut rsdp_bytes[..RSDP_V1_LENGTH],
+            RSDP_CHECKSUM_OFFSET,
+        );
+        update_checksum(rsdp_bytes, RSDP_EXTENDED_CHECKSUM_OFFSET);
+        Ok(unsafe { &*(base as *const AcpiRsdpStruct) })
     }
 }
 
@@ -223,3 +712,1123 @@ impl fmt::Display for EcamEntry {
         )
     }
 }
+
+#[repr(C, packed)]
+#[allow(dead_code)]
+pub struct AcpiFadt {
+    // 5.2.9 Fixed ACPI Description Table (FADT)
+    // Fields up to X_PM1b_CNT_BLK. Older revisions are shorter, so the
+    // fields should be accessed via has_field().
+    header: SystemDescriptionTableHeader,
+    firmware_ctrl: u32,
+    dsdt: u32,
+    _reserved0: u8,
+    preferred_pm_profile: u8,
+    sci_int: u16,
+    smi_cmd: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_req: u8,
+    pstate_cnt: u8,
+    pm1a_evt_blk: u32,
+    pm1b_evt_blk: u32,
+    pm1a_cnt_blk: u32,
+    pm1b_cnt_blk: u32,
+    pm2_cnt_blk: u32,
+    pm_tmr_blk: u32,
+    gpe0_blk: u32,
+    gpe1_blk: u32,
+    pm1_evt_len: u8,
+    pm1_cnt_len: u8,
+    pm2_cnt_len: u8,
+    pm_tmr_len: u8,
+    gpe0_blk_len: u8,
+    gpe1_blk_len: u8,
+    gpe1_base: u8,
+    cst_cnt: u8,
+    _unused0: [u8; 16],
+    flags: u32,
+    reset_reg: GenericAddress,
+    reset_value: u8,
+    _unused1: [u8; 3],
+    x_firmware_ctrl: u64,
+    x_dsdt: u64,
+    x_pm1a_evt_blk: GenericAddress,
+    x_pm1b_evt_blk: GenericAddress,
+    x_pm1a_cnt_blk: GenericAddress,
+    x_pm1b_cnt_blk: GenericAddress,
+}
+impl AcpiTable for AcpiFadt {
+    const SIGNATURE: &'static [u8; 4] = b"FACP";
+    type Table = Self;
+}
+const _: () = assert!(size_of::<AcpiFadt>() == 196);
+impl AcpiFadt {
+    const FLAG_PWR_BUTTON: u32 = 1 << 4;
+    const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+    const FLAG_HW_REDUCED_ACPI: u32 = 1 << 20;
+    fn has_field(&self, offset: usize, size: usize) -> bool {
+        offset + size <= self.header.length()
+    }
+    /// Returns the reset register and the value to write to it, if the
+    /// platform supports the reset register.
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        if !self.has_field(offset_of!(Self, reset_value), 1)
+            || self.flags & Self::FLAG_RESET_REG_SUP == 0
+            || self.reset_reg.is_null()
+        {
+            return None;
+        }
+        Some((self.reset_reg, self.reset_value))
+    }
+    fn pm1_cnt_blk(
+        &self,
+        x_blk: GenericAddress,
+        blk: u32,
+    ) -> Option<GenericAddress> {
+        if self.has_field(offset_of!(Self, x_pm1b_cnt_blk), 12)
+            && !x_blk.is_null()
+        {
+            Some(x_blk)
+        } else if blk != 0 {
+            Some(GenericAddress::io_port(blk, self.pm1_cnt_len * 8))
+        } else {
+            None
+        }
+    }
+    /// PM1a Control Block, which has SLP_TYP and SLP_EN
+    pub fn pm1a_cnt_blk(&self) -> Option<GenericAddress> {
+        self.pm1_cnt_blk(self.x_pm1a_cnt_blk, self.pm1a_cnt_blk)
+    }
+    pub fn pm1b_cnt_blk(&self) -> Option<GenericAddress> {
+        self.pm1_cnt_blk(self.x_pm1b_cnt_blk, self.pm1b_cnt_blk)
+    }
+    fn pm1_evt_blk(
+        &self,
+        x_blk: GenericAddress,
+        blk: u32,
+    ) -> Option<(GenericAddress, GenericAddress)> {
+        // The status register is in the first half of the block, and the
+        // enable register is in the second half.
+        let half = (self.pm1_evt_len / 2) as u64;
+        let (space, address) = if self
+            .has_field(offset_of!(Self, x_pm1b_evt_blk), 12)
+            && !x_blk.is_null()
+        {
+            (x_blk.address_space_id, x_blk.address)
+        } else if blk != 0 {
+            (GenericAddress::SPACE_SYSTEM_IO, blk as u64)
+        } else {
+            return None;
+        };
+        if half == 0 {
+            return None;
+        }
+        let width = (half * 8) as u8;
+        Some((
+            GenericAddress::new(space, width, address),
+            GenericAddress::new(space, width, address + half),
+        ))
+    }
+    /// PM1a Event Block as (PM1a_STS, PM1a_EN)
+    pub fn pm1a_evt_blk(&self) -> Option<(GenericAddress, GenericAddress)> {
+        self.pm1_evt_blk(self.x_pm1a_evt_blk, self.pm1a_evt_blk)
+    }
+    /// PM1b Event Block as (PM1b_STS, PM1b_EN)
+    pub fn pm1b_evt_blk(&self) -> Option<(GenericAddress, GenericAddress)> {
+        self.pm1_evt_blk(self.x_pm1b_evt_blk, self.pm1b_evt_blk)
+    }
+    fn gpe_blk(&self, blk: u32, len: u8) -> Option<(u16, usize)> {
+        if blk == 0 || len == 0 {
+            None
+        } else {
+            Some((blk as u16, len as usize))
+        }
+    }
+    /// GPE0 Block as (I/O port, length in bytes). The first half is the
+    /// status registers, and the second half is the enable registers.
+    pub fn gpe0_blk(&self) -> Option<(u16, usize)> {
+        self.gpe_blk(self.gpe0_blk, self.gpe0_blk_len)
+    }
+    pub fn gpe1_blk(&self) -> Option<(u16, usize)> {
+        self.gpe_blk(self.gpe1_blk, self.gpe1_blk_len)
+    }
+    /// The ISA IRQ (or the GSI if the 8259 PICs are not present) of the SCI
+    pub fn sci_int(&self) -> u16 {
+        self.sci_int
+    }
+    /// Returns the SMI command port and the value to write to it to switch
+    /// the platform into ACPI mode, if it needs the switch.
+    pub fn acpi_enable_command(&self) -> Option<(u16, u8)> {
+        if self.smi_cmd == 0 || self.acpi_enable == 0 {
+            None
+        } else {
+            Some((self.smi_cmd as u16, self.acpi_enable))
+        }
+    }
+    /// True if the power button is the fixed hardware one, which is
+    /// reported via PWRBTN_STS in PM1 Event Blocks.
+    pub fn has_fixed_power_button(&self) -> bool {
+        self.flags & Self::FLAG_PWR_BUTTON == 0
+    }
+    /// True on HW-reduced ACPI platforms, which do not have the fixed
+    /// hardware registers.
+    pub fn is_hw_reduced(&self) -> bool {
+        self.flags & Self::FLAG_HW_REDUCED_ACPI != 0
+    }
+    /// The content of the DSDT, i.e. the AML byte code
+    pub fn dsdt_aml(&self) -> Option<&'static [u8]> {
+        self.header.dsdt().map(|e| e.body())
+    }
+}
+impl fmt::Debug for AcpiFadt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sci_int = self.sci_int;
+        let smi_cmd = self.smi_cmd;
+        let flags = self.flags;
+        f.debug_struct("AcpiFadt")
+            .field("length", &self.header.length())
+            .field("sci_int", &sci_int)
+            .field("smi_cmd", &format_args!("{smi_cmd:#X}"))
+            .field("flags", &format_args!("{flags:#X}"))
+            .field("pm1a_evt_blk", &self.pm1a_evt_blk())
+            .field("pm1b_evt_blk", &self.pm1b_evt_blk())
+            .field("pm1a_cnt_blk", &self.pm1a_cnt_blk())
+            .field("pm1b_cnt_blk", &self.pm1b_cnt_blk())
+            .field("reset_register", &self.reset_register())
+            .finish()
+    }
+}
+
+static ACPI: RwLock<Option<&'static AcpiRsdpStruct>> = RwLock::new(None);
+/// Makes the tables available via global_acpi(). `acpi` should be the copy
+/// made by AcpiRsdpStruct::copy_tables().
+pub fn set_global_acpi(acpi: &'static AcpiRsdpStruct) {
+    *ACPI.write() = Some(acpi);
+}
+pub fn global_acpi() -> Option<&'static AcpiRsdpStruct> {
+    *ACPI.read()
+}
+
+#[repr(C, packed)]
+#[allow(dead_code)]
+pub struct AcpiMadtDescriptor {
+    // 5.2.12 Multiple APIC Description Table (MADT)
+    header: SystemDescriptionTableHeader,
+    local_apic_address: u32,
+    flags: u32,
+    // 44 + (variable length entries)
+}
+impl AcpiTable for AcpiMadtDescriptor {
+    const SIGNATURE: &'static [u8; 4] = b"APIC";
+    type Table = Self;
+}
+const _: () = assert!(size_of::<AcpiMadtDescriptor>() == 44);
+impl AcpiMadtDescriptor {
+    const FLAG_PCAT_COMPAT: u32 = 1;
+    fn entries(&self) -> &[u8] {
+        // A broken table may be shorter than the fixed part.
+        unsafe {
+            slice::from_raw_parts(
+                (self as *const Self as *const u8).add(size_of::<Self>()),
+                self.header.length().saturating_sub(size_of::<Self>()),
+            )
+        }
+    }
+    pub fn iter(&self) -> MadtIterator {
+        MadtIterator::new(self.entries())
+    }
+    /// The physical address of the Local APIC, taking the Local APIC Address
+    /// Override entry into account.
+    pub fn local_apic_address(&self) -> u64 {
+        self.iter()
+            .find_map(|e| match e {
+                MadtEntry::LocalApicAddressOverride(e) => Some(e.address()),
+                _ => None,
+            })
+            .unwrap_or(self.local_apic_address as u64)
+    }
+    /// True if the system also has dual 8259 PICs, which should be
+    /// disabled before using the I/O APICs.
+    pub fn has_8259_pics(&self) -> bool {
+        self.flags & Self::FLAG_PCAT_COMPAT != 0
+    }
+}
+
+#[repr(u8)]
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MadtEntryType {
+    LocalApic = 0,
+    IoApic = 1,
+    InterruptSourceOverride = 2,
+    NmiSource = 3,
+    LocalApicNmi = 4,
+    LocalApicAddressOverride = 5,
+    LocalX2Apic = 9,
+    LocalX2ApicNmi = 10,
+}
+
+const MADT_LAPIC_FLAG_ENABLED: u32 = 1;
+const MADT_LAPIC_FLAG_ONLINE_CAPABLE: u32 = 2;
+
+#[derive(Debug, Copy, Clone)]
+#[allow(unused)]
+#[repr(packed)]
+pub struct MadtLocalApic {
+    entry_type: u8,
+    length: u8,
+    processor_uid: u8,
+    apic_id: u8,
+    flags: u32,
+}
+const _: () = assert!(size_of::<MadtLocalApic>() == 8);
+unsafe impl Sliceable for MadtLocalApic {}
+impl MadtLocalApic {
+    pub fn processor_uid(&self) -> u32 {
+        self.processor_uid as u32
+    }
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id as u32
+    }
+    /// True if the processor is usable, i.e. it is enabled or can be
+    /// enabled later.
+    pub fn is_usable(&self) -> bool {
+        self.flags & (MADT_LAPIC_FLAG_ENABLED | MADT_LAPIC_FLAG_ONLINE_CAPABLE)
+            != 0
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[allow(unused)]
+#[repr(packed)]
+pub struct MadtIoApic {
+    entry_type: u8,
+    length: u8,
+    id: u8,
+    _reserved: u8,
+    address: u32,
+    gsi_base: u32,
+}
+const _: () = assert!(size_of::<MadtIoApic>() == 12);
+unsafe impl Sliceable for MadtIoApic {}
+impl MadtIoApic {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+    pub fn address(&self) -> u64 {
+        self.address as u64
+    }
+    /// The first Global System Interrupt number that this I/O APIC handles
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[allow(unused)]
+#[repr(packed)]
+pub struct MadtInterruptSourceOverride {
+    entry_type: u8,
+    length: u8,
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    flags: u16,
+}
+const _: () = assert!(size_of::<MadtInterruptSourceOverride>() == 10);
+unsafe impl Sliceable for MadtInterruptSourceOverride {}
+impl MadtInterruptSourceOverride {
+    /// The ISA IRQ number
+    pub fn source(&self) -> u8 {
+        self.source
+    }
+    pub fn gsi(&self) -> u32 {
+        self.gsi
+    }
+    /// MPS INTI flags (polarity in bits 0-1, trigger mode in bits 2-3)
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[allow(unused)]
+#[repr(packed)]
+pub struct MadtNmiSource {
+    entry_type: u8,
+    length: u8,
+    flags: u16,
+    gsi: u32,
+}
+const _: () = assert!(size_of::<MadtNmiSource>() == 8);
+unsafe impl Sliceable for MadtNmiSource {}
+impl MadtNmiSource {
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+    pub fn gsi(&self) -> u32 {
+        self.gsi
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[allow(unused)]
+#[repr(packed)]
+pub struct MadtLocalApicNmi {
+    entry_type: u8,
+    length: u8,
+    /// 0xFF means all processors
+    processor_uid: u8,
+    flags: u16,
+    lint: u8,
+}
+const _: () = assert!(size_of::<MadtLocalApicNmi>() == 6);
+unsafe impl Sliceable for MadtLocalApicNmi {}
+impl MadtLocalApicNmi {
+    pub fn processor_uid(&self) -> u8 {
+        self.processor_uid
+    }
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+    /// The LINTn pin of the Local APIC that the NMI is connected to
+    pub fn lint(&self) -> u8 {
+        self.lint
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[allow(unused)]
+#[repr(packed)]
+pub struct MadtLocalApicAddressOverride {
+    entry_type: u8,
+    length: u8,
+    _reserved: u16,
+    address: u64,
+}
+const _: () = assert!(size_of::<MadtLocalApicAddressOverride>() == 12);
+unsafe impl Sliceable for MadtLocalApicAddressOverride {}
+impl MadtLocalApicAddressOverride {
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[allow(unused)]
+#[repr(packed)]
+pub struct MadtLocalX2Apic {
+    entry_type: u8,
+    length: u8,
+    _reserved: u16,
+    x2apic_id: u32,
+    flags: u32,
+    processor_uid: u32,
+}
+const _: () = assert!(size_of::<MadtLocalX2Apic>() == 16);
+unsafe impl Sliceable for MadtLocalX2Apic {}
+impl MadtLocalX2Apic {
+    pub fn x2apic_id(&self) -> u32 {
+        self.x2apic_id
+    }
+    pub fn processor_uid(&self) -> u32 {
+        self.processor_uid
+    }
+    pub fn is_usable(&self) -> bool {
+        self.flags & (MADT_LAPIC_FLAG_ENABLED | MADT_LAPIC_FLAG_ONLINE_CAPABLE)
+            != 0
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[allow(unused)]
+#[repr(packed)]
+pub struct MadtLocalX2ApicNmi {
+    entry_type: u8,
+    length: u8,
+    flags: u16,
+    /// 0xFFFFFFFF means all processors
+    processor_uid: u32,
+    lint: u8,
+    _reserved: [u8; 3],
+}
+const _: () = assert!(size_of::<MadtLocalX2ApicNmi>() == 12);
+unsafe impl Sliceable for MadtLocalX2ApicNmi {}
+impl MadtLocalX2ApicNmi {
+    pub fn processor_uid(&self) -> u32 {
+        self.processor_uid
+    }
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+    pub fn lint(&self) -> u8 {
+        self.lint
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum MadtEntry {
+    LocalApic(MadtLocalApic),
+    IoApic(MadtIoApic),
+    InterruptSourceOverride(MadtInterruptSourceOverride),
+    NmiSource(MadtNmiSource),
+    LocalApicNmi(MadtLocalApicNmi),
+    LocalApicAddressOverride(MadtLocalApicAddressOverride),
+    LocalX2Apic(MadtLocalX2Apic),
+    LocalX2ApicNmi(MadtLocalX2ApicNmi),
+    Unknown { entry_type: u8, length: u8 },
+}
+
+pub struct MadtIterator<'a> {
+    buf: &'a [u8],
+    index: usize,
+}
+impl<'a> MadtIterator<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, index: 0 }
+    }
+}
+impl<'a> Iterator for MadtIterator<'a> {
+    type Item = MadtEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        let buf = self.buf.get(self.index..)?;
+        let (entry_type, length) = (*buf.first()?, *buf.get(1)?);
+        if length < 2 || length as usize > buf.len() {
+            // Broken entry. Stop here since the next one can not be found.
+            return None;
+        }
+        let buf = &buf[..length as usize];
+        let entry = Self::parse_entry(entry_type, buf)
+            .unwrap_or(MadtEntry::Unknown { entry_type, length });
+        self.index += length as usize;
+        Some(entry)
+    }
+}
+impl MadtIterator<'_> {
+    /// Returns None for an unknown type, or an entry that is too short for
+    /// its type.
+    fn parse_entry(entry_type: u8, buf: &[u8]) -> Option<MadtEntry> {
+        Some(match entry_type {
+            e if e == MadtEntryType::LocalApic as u8 => {
+                MadtEntry::LocalApic(MadtLocalApic::copy_from_slice(buf).ok()?)
+            }
+            e if e == MadtEntryType::IoApic as u8 => {
+                MadtEntry::IoApic(MadtIoApic::copy_from_slice(buf).ok()?)
+            }
+            e if e == MadtEntryType::InterruptSourceOverride as u8 => {
+                MadtEntry::InterruptSourceOverride(
+                    MadtInterruptSourceOverride::copy_from_slice(buf).ok()?,
+                )
+            }
+            e if e == MadtEntryType::NmiSource as u8 => {
+                MadtEntry::NmiSource(MadtNmiSource::copy_from_slice(buf).ok()?)
+            }
+            e if e == MadtEntryType::LocalApicNmi as u8 => {
+                MadtEntry::LocalApicNmi(
+                    MadtLocalApicNmi::copy_from_slice(buf).ok()?,
+                )
+            }
+            e if e == MadtEntryType::LocalApicAddressOverride as u8 => {
+                MadtEntry::LocalApicAddressOverride(
+                    MadtLocalApicAddressOverride::copy_from_slice(buf).ok()?,
+                )
+            }
+            e if e == MadtEntryType::LocalX2Apic as u8 => {
+                MadtEntry::LocalX2Apic(
+                    MadtLocalX2Apic::copy_from_slice(buf).ok()?,
+                )
+            }
+            e if e == MadtEntryType::LocalX2ApicNmi as u8 => {
+                MadtEntry::LocalX2ApicNmi(
+                    MadtLocalX2ApicNmi::copy_from_slice(buf).ok()?,
+                )
+            }
+            _ => return None,
+        })
+    }
+}
+
+#[repr(C, packed)]
+#[allow(dead_code)]
+pub struct AcpiDmarDescriptor {
+    // Intel VT-d spec 8.1 DMA Remapping Reporting Structure
+    header: SystemDescriptionTableHeader,
+    host_address_width: u8,
+    flags: u8,
+    _reserved: [u8; 10],
+    // 48 + (variable length remapping structures)
+}
+impl AcpiTable for AcpiDmarDescriptor {
+    const SIGNATURE: &'static [u8; 4] = b"DMAR";
+    type Table = Self;
+}
+const _: () = assert!(size_of::<AcpiDmarDescriptor>() == 48);
+impl AcpiDmarDescriptor {
+    const FLAG_INTR_REMAP: u8 = 1;
+    fn entries(&self) -> &[u8] {
+        // A broken table may be shorter than the fixed part.
+        unsafe {
+            slice::from_raw_parts(
+                (self as *const Self as *const u8).add(size_of::<Self>()),
+                self.header.length().saturating_sub(size_of::<Self>()),
+            )
+        }
+    }
+    pub fn iter(&self) -> DmarIterator {
+        DmarIterator::new(self.entries())
+    }
+    /// The maximum physical address width that DMA can use
+    pub fn host_address_width(&self) -> u32 {
+        self.host_address_width as u32 + 1
+    }
+    pub fn supports_interrupt_remapping(&self) -> bool {
+        self.flags & Self::FLAG_INTR_REMAP != 0
+    }
+}
+
+#[repr(u16)]
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DmarEntryType {
+    Drhd = 0,
+    Rmrr = 1,
+}
+
+#[derive(Debug, Copy, Clone)]
+#[allow(unused)]
+#[repr(packed)]
+struct DmarDrhdHeader {
+    entry_type: u16,
+    length: u16,
+    flags: u8,
+    size: u8,
+    segment: u16,
+    register_base_address: u64,
+}
+const _: () = assert!(size_of::<DmarDrhdHeader>() == 16);
+unsafe impl Sliceable for DmarDrhdHeader {}
+
+/// DMA Remapping Hardware Unit Definition, i.e. an IOMMU and the devices
+/// behind it
+#[derive(Debug, Copy, Clone)]
+pub struct DmarDrhd<'a> {
+    header: DmarDrhdHeader,
+    scopes: &'a [u8],
+}
+impl<'a> DmarDrhd<'a> {
+    const FLAG_INCLUDE_PCI_ALL: u8 = 1;
+    pub fn segment(&self) -> u16 {
+        self.header.segment
+    }
+    pub fn register_base_address(&self) -> u64 {
+        self.header.register_base_address
+    }
+    /// The size of the register set in 4 KiB pages
+    pub fn register_set_pages(&self) -> usize {
+        1 << (self.header.size & 0xF)
+    }
+    /// True if this unit handles all the devices in the segment that are
+    /// not listed in the other units.
+    pub fn includes_all_pci_devices(&self) -> bool {
+        self.header.flags & Self::FLAG_INCLUDE_PCI_ALL != 0
+    }
+    pub fn device_scopes(&self) -> DmarDeviceScopeIterator<'a> {
+        DmarDeviceScopeIterator {
+            buf: self.scopes,
+            index: 0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[allow(unused)]
+#[repr(packed)]
+struct DmarRmrrHeader {
+    entry_type: u16,
+    length: u16,
+    _reserved: u16,
+    segment: u16,
+    base_address: u64,
+    limit_address: u64,
+}
+const _: () = assert!(size_of::<DmarRmrrHeader>() == 24);
+unsafe impl Sliceable for DmarRmrrHeader {}
+
+/// Reserved Memory Region Reporting, i.e. memory that the devices may
+/// access (e.g. for the legacy USB emulation) and should stay accessible
+#[derive(Debug, Copy, Clone)]
+pub struct DmarRmrr<'a> {
+    header: DmarRmrrHeader,
+    scopes: &'a [u8],
+}
+impl<'a> DmarRmrr<'a> {
+    pub fn segment(&self) -> u16 {
+        self.header.segment
+    }
+    pub fn base_address(&self) -> u64 {
+        self.header.base_address
+    }
+    /// The end of the region (exclusive)
+    pub fn end_address(&self) -> u64 {
+        self.header.limit_address + 1
+    }
+    pub fn device_scopes(&self) -> DmarDeviceScopeIterator<'a> {
+        DmarDeviceScopeIterator {
+            buf: self.scopes,
+            index: 0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum DmarEntry<'a> {
+    Drhd(DmarDrhd<'a>),
+    Rmrr(DmarRmrr<'a>),
+    Unknown { entry_type: u16, length: u16 },
+}
+
+pub struct DmarIterator<'a> {
+    buf: &'a [u8],
+    index: usize,
+}
+impl<'a> DmarIterator<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, index: 0 }
+    }
+}
+impl<'a> Iterator for DmarIterator<'a> {
+    type Item = DmarEntry<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let buf = self.buf.get(self.index..)?;
+        let entry_type = u16::from_le_bytes([*buf.first()?, *buf.get(1)?]);
+        let length = u16::from_le_bytes([*buf.get(2)?, *buf.get(3)?]);
+        if length < 4 || length as usize > buf.len() {
+            // Broken entry. Stop here since the next one can not be found.
+            return None;
+        }
+        let buf = &buf[..length as usize];
+        let entry = Self::parse_entry(entry_type, buf)
+            .unwrap_or(DmarEntry::Unknown { entry_type, length });
+        self.index += length as usize;
+        Some(entry)
+    }
+}
+impl<'a> DmarIterator<'a> {
+    /// Returns None for an unknown type, or an entry that is too short for
+    /// its type.
+    fn parse_entry(entry_type: u16, buf: &'a [u8]) -> Option<DmarEntry<'a>> {
+        Some(match entry_type {
+            e if e == DmarEntryType::Drhd as u16 => DmarEntry::Drhd(DmarDrhd {
+                header: DmarDrhdHeader::copy_from_slice(buf).ok()?,
+                scopes: &buf[size_of::<DmarDrhdHeader>()..],
+            }),
+            e if e == DmarEntryType::Rmrr as u16 => DmarEntry::Rmrr(DmarRmrr {
+                header: DmarRmrrHeader::copy_from_slice(buf).ok()?,
+                scopes: &buf[size_of::<DmarRmrrHeader>()..],
+            }),
+            _ => return None,
+        })
+    }
+}
+
+#[repr(u8)]
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DmarDeviceScopeType {
+    PciEndpoint = 1,
+    PciSubHierarchy = 2,
+    IoApic = 3,
+    Hpet = 4,
+    AcpiNamespaceDevice = 5,
+}
+
+/// A device (or the devices behind a bridge) that a remapping structure
+/// applies to. The device is found by walking `path` from `start_bus`.
+#[derive(Debug, Copy, Clone)]
+pub struct DmarDeviceScope<'a> {
+    scope_type: u8,
+    enumeration_id: u8,
+    start_bus: u8,
+    path: &'a [u8],
+}
+impl<'a> DmarDeviceScope<'a> {
+    pub fn scope_type(&self) -> u8 {
+        self.scope_type
+    }
+    pub fn is(&self, scope_type: DmarDeviceScopeType) -> bool {
+        self.scope_type == scope_type as u8
+    }
+    /// The I/O APIC ID or the HPET number, depending on the type
+    pub fn enumeration_id(&self) -> u8 {
+        self.enumeration_id
+    }
+    pub fn start_bus(&self) -> u8 {
+        self.start_bus
+    }
+    /// (device, function) pairs from the start bus to the device
+    pub fn path(&self) -> impl Iterator<Item = (u8, u8)> + 'a {
+        self.path.chunks_exact(2).map(|e| (e[0], e[1]))
+    }
+}
+
+pub struct DmarDeviceScopeIterator<'a> {
+    buf: &'a [u8],
+    index: usize,
+}
+impl<'a> Iterator for DmarDeviceScopeIterator<'a> {
+    type Item = DmarDeviceScope<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let buf = self.buf.get(self.index..)?;
+        let length = *buf.get(1)? as usize;
+        if length < 6 || length > buf.len() {
+            return None;
+        }
+        self.index += length;
+        Some(DmarDeviceScope {
+            scope_type: buf[0],
+            enumeration_id: buf[4],
+            start_bus: buf[5],
+            path: &buf[6..length],
+        })
+    }
+}
+
+/// Port types and subtypes in the DBG2 and the interface types in the SPCR
+pub const DBG2_PORT_TYPE_SERIAL: u16 = 0x8000;
+pub const SERIAL_SUBTYPE_16550: u16 = 0x00;
+pub const SERIAL_SUBTYPE_16450: u16 = 0x01;
+/// 16550-compatible with the parameters defined in the Generic Address
+pub const SERIAL_SUBTYPE_16550_GAS: u16 = 0x12;
+
+#[repr(C, packed)]
+#[allow(dead_code)]
+pub struct AcpiSpcr {
+    // Serial Port Console Redirection Table
+    header: SystemDescriptionTableHeader,
+    interface_type: u8,
+    _reserved: [u8; 3],
+    base_address: GenericAddress,
+    interrupt_type: u8,
+    irq: u8,
+    gsi: u32,
+    baud_rate: u8,
+    parity: u8,
+    stop_bits: u8,
+    flow_control: u8,
+    terminal_type: u8,
+    language: u8,
+    pci_device_id: u16,
+    pci_vendor_id: u16,
+    pci_bus: u8,
+    pci_device: u8,
+    pci_function: u8,
+    pci_flags: u32,
+    pci_segment: u8,
+    // Revision 3 or later
+    uart_clock_frequency: u32,
+}
+impl AcpiTable for AcpiSpcr {
+    const SIGNATURE: &'static [u8; 4] = b"SPCR";
+    type Table = Self;
+}
+const _: () = assert!(size_of::<AcpiSpcr>() == 80);
+impl AcpiSpcr {
+    const INTERRUPT_TYPE_8259: u8 = 1;
+    /// One of the serial port subtypes in the DBG2
+    pub fn interface_type(&self) -> u16 {
+        self.interface_type as u16
+    }
+    pub fn base_address(&self) -> GenericAddress {
+        self.base_address
+    }
+    /// The ISA IRQ of the UART, if it is connected to the legacy PICs
+    pub fn isa_irq(&self) -> Option<u8> {
+        (self.interrupt_type & Self::INTERRUPT_TYPE_8259 != 0)
+            .then_some(self.irq)
+    }
+    /// None means that the baud rate configured by the firmware should be
+    /// kept.
+    pub fn baud_rate(&self) -> Option<u32> {
+        match self.baud_rate {
+            3 => Some(9600),
+            4 => Some(19200),
+            6 => Some(57600),
+            7 => Some(115200),
+            _ => None,
+        }
+    }
+    /// The input clock of the UART in Hz, if it is given
+    pub fn uart_clock_frequency(&self) -> Option<u32> {
+        let freq = self.uart_clock_frequency;
+        (self.header.revision >= 3 && freq != 0).then_some(freq)
+    }
+}
+
+#[repr(C, packed)]
+#[allow(dead_code)]
+pub struct AcpiDbg2 {
+    // Debug Port Table 2
+    header: SystemDescriptionTableHeader,
+    device_info_offset: u32,
+    num_device_info: u32,
+}
+impl AcpiTable for AcpiDbg2 {
+    const SIGNATURE: &'static [u8; 4] = b"DBG2";
+    type Table = Self;
+}
+const _: () = assert!(size_of::<AcpiDbg2>() == 44);
+impl AcpiDbg2 {
+    pub fn devices(&self) -> Dbg2DeviceIterator {
+        Dbg2DeviceIterator::new(
+            self.header.bytes(),
+            self.device_info_offset as usize,
+            self.num_device_info as usize,
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Dbg2Device {
+    port_type: u16,
+    port_subtype: u16,
+    base_address: Option<GenericAddress>,
+}
+impl Dbg2Device {
+    pub fn port_type(&self) -> u16 {
+        self.port_type
+    }
+    pub fn port_subtype(&self) -> u16 {
+        self.port_subtype
+    }
+    /// The first Base Address Register of the device
+    pub fn base_address(&self) -> Option<GenericAddress> {
+        self.base_address
+    }
+}
+
+/// Iterates over the Debug Device Information structures in the DBG2
+pub struct Dbg2DeviceIterator<'a> {
+    buf: &'a [u8],
+    index: usize,
+    remaining: usize,
+}
+impl<'a> Dbg2DeviceIterator<'a> {
+    const DEVICE_INFO_LENGTH: usize = 22;
+    /// `buf` is the whole table, and the first device is at `offset`.
+    pub fn new(buf: &'a [u8], offset: usize, count: usize) -> Self {
+        Self {
+            buf,
+            index: offset,
+            remaining: count,
+        }
+    }
+}
+impl<'a> Iterator for Dbg2DeviceIterator<'a> {
+    type Item = Dbg2Device;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let buf = self.buf.get(self.index..)?;
+        let read_u16 = |offset: usize| {
+            Some(u16::from_le_bytes([
+                *buf.get(offset)?,
+                *buf.get(offset + 1)?,
+            ]))
+        };
+        let length = read_u16(1)? as usize;
+        if length < Self::DEVICE_INFO_LENGTH || length > buf.len() {
+            // Broken entry. Stop here since the next one can not be found.
+            return None;
+        }
+        let num_base_addresses = buf[3];
+        let base_address_offset = read_u16(18)? as usize;
+        let base_address = if num_base_addresses > 0 {
+            GenericAddress::copy_from_slice(
+                buf[..length].get(base_address_offset..)?,
+            )
+            .ok()
+        } else {
+            None
+        };
+        let device = Dbg2Device {
+            port_type: read_u16(12)?,
+            port_subtype: read_u16(14)?,
+            base_address,
+        };
+        self.index += length;
+        self.remaining -= 1;
+        Some(device)
+    }
+}
+
+#[test_case]
+fn madt_iterator_test() {
+    #[rustfmt::skip]
+    let entries = [
+        // Local APIC: uid 0, id 1, enabled
+        0, 8, 0, 1, 1, 0, 0, 0,
+        // I/O APIC: id 2 at 0xFEC00000, GSI base 0
+        1, 12, 2, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0,
+        // IRQ0 -> GSI 2
+        2, 10, 0, 0, 2, 0, 0, 0, 0, 0,
+        // Unknown entry
+        0x7F, 3, 0,
+        // I/O APIC that is too short
+        1, 4, 3, 0,
+        // Local APIC NMI: all processors, LINT1
+        4, 6, 0xFF, 0, 0, 1,
+        // Broken entry (too long)
+        0, 9, 0,
+    ];
+    let entries: alloc::vec::Vec<MadtEntry> =
+        MadtIterator::new(&entries).collect();
+    assert_eq!(entries.len(), 6);
+    assert!(matches!(entries[0],
+        MadtEntry::LocalApic(e) if e.apic_id() == 1 && e.is_usable()));
+    assert!(matches!(entries[1],
+        MadtEntry::IoApic(e) if e.id() == 2 && e.address() == 0xFEC0_0000));
+    assert!(matches!(entries[2],
+        MadtEntry::InterruptSourceOverride(e)
+            if e.source() == 0 && e.gsi() == 2));
+    assert!(matches!(
+        entries[3],
+        MadtEntry::Unknown {
+            entry_type: 0x7F,
+            length: 3
+        }
+    ));
+    assert!(matches!(
+        entries[4],
+        MadtEntry::Unknown {
+            entry_type: 1,
+            length: 4
+        }
+    ));
+    assert!(matches!(entries[5],
+        MadtEntry::LocalApicNmi(e) if e.lint() == 1));
+}
+
+#[test_case]
+fn dmar_iterator_test() {
+    #[rustfmt::skip]
+    let entries = [
+        // DRHD: INCLUDE_PCI_ALL, segment 0, registers at 0xFED90000
+        0, 0, 16, 0, 1, 0, 0, 0, 0x00, 0x00, 0xD9, 0xFE, 0, 0, 0, 0,
+        // RMRR: 0x3E000000..=0x3E0FFFFF for 00:14.0
+        1, 0, 32, 0, 0, 0, 0, 0,
+        0x00, 0x00, 0x00, 0x3E, 0, 0, 0, 0,
+        0xFF, 0xFF, 0x0F, 0x3E, 0, 0, 0, 0,
+        1, 8, 0, 0, 0, 0, 0x14, 0,
+        // RMRR that is too short
+        1, 0, 8, 0, 0, 0, 0, 0,
+        // ATSR (unknown)
+        2, 0, 8, 0, 0, 0, 0, 0,
+        // Broken entry (too long)
+        0, 0, 32, 0,
+    ];
+    let entries: alloc::vec::Vec<DmarEntry> =
+        DmarIterator::new(&entries).collect();
+    assert_eq!(entries.len(), 4);
+    assert!(matches!(entries[0],
+        DmarEntry::Drhd(e) if e.includes_all_pci_devices()
+            && e.register_base_address() == 0xFED9_0000
+            && e.register_set_pages() == 1
+            && e.device_scopes().count() == 0));
+    let DmarEntry::Rmrr(rmrr) = entries[1] else {
+        panic!("RMRR is expected");
+    };
+    assert_eq!(rmrr.base_address(), 0x3E00_0000);
+    assert_eq!(rmrr.end_address(), 0x3E10_0000);
+    let scopes: alloc::vec::Vec<DmarDeviceScope> =
+        rmrr.device_scopes().collect();
+    assert_eq!(scopes.len(), 1);
+    assert!(scopes[0].is(DmarDeviceScopeType::PciEndpoint));
+    assert_eq!(scopes[0].start_bus(), 0);
+    assert!(scopes[0].path().eq([(0x14, 0)]));
+    assert!(matches!(
+        entries[2],
+        DmarEntry::Unknown {
+            entry_type: 1,
+            length: 8
+        }
+    ));
+    assert!(matches!(
+        entries[3],
+        DmarEntry::Unknown {
+            entry_type: 2,
+            length: 8
+        }
+    ));
+}
+
+#[test_case]
+fn dbg2_device_iterator_test() {
+    #[rustfmt::skip]
+    let table = [
+        // (The header and the fields before the first device)
+        0xAA, 0xAA,
+        // 16550-compatible UART with 32-bit registers at 0xFE032000
+        0, 34, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0,
+        0x00, 0x80, 0x12, 0, 0, 0, 22, 0, 0, 0,
+        0, 32, 0, 3, 0x00, 0x20, 0x03, 0xFE, 0, 0, 0, 0,
+        // Net device without base address registers
+        0, 22, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0x02, 0x80, 0, 0, 0, 0, 0, 0, 0, 0,
+        // Broken entry (too long)
+        0, 99, 0,
+    ];
+    let devices: alloc::vec::Vec<Dbg2Device> =
+        Dbg2DeviceIterator::new(&table, 2, 3).collect();
+    assert_eq!(devices.len(), 2);
+    assert_eq!(devices[0].port_type(), DBG2_PORT_TYPE_SERIAL);
+    assert_eq!(devices[0].port_subtype(), SERIAL_SUBTYPE_16550_GAS);
+    let uart = devices[0].base_address().expect("No base address");
+    assert!(uart.is_in_memory_space());
+    assert_eq!(uart.address(), 0xFE03_2000);
+    assert_eq!(uart.bit_width(), 32);
+    assert_eq!(uart.access_width(), 32);
+    assert_eq!(devices[1].port_type(), 0x8002);
+    assert!(devices[1].base_address().is_none());
+    assert_eq!(Dbg2DeviceIterator::new(&table, 2, 1).count(), 1);
+}
+
+#[test_case]
+fn checksum_test() {
+    let mut bytes = [0u8; 40];
+    bytes[..4].copy_from_slice(b"TEST");
+    bytes[4..8].copy_from_slice(&40u32.to_le_bytes());
+    update_checksum(&mut bytes, HEADER_CHECKSUM_OFFSET);
+    let validate = |bytes: &[u8]| {
+        let header = bytes.as_ptr() as *const SystemDescriptionTableHeader;
+        unsafe { &*header }.validate()
+    };
+    assert_eq!(validate(&bytes), Ok(()));
+    bytes[38] = 1;
+    assert!(validate(&bytes).is_err());
+    bytes[4..8].copy_from_slice(&8u32.to_le_bytes());
+    assert!(validate(&bytes).is_err());
+
+    // ACPI 1.0 RSDP only has the first 20 bytes and the RSDT.
+    let mut rsdp = AcpiRsdpStruct {
+        signature: *RSDP_SIGNATURE,
+        checksum: 0,
+        oem_id: *b"WASABI",
+        revision: 0,
+        rsdt_address: 0x1000,
+        length: 0xFFFF_FFFF,
+        xsdt: 0x2000,
+    };
+    let bytes = unsafe {
+        slice::from_raw_parts_mut(
+            &mut rsdp as *mut AcpiRsdpStruct as *mut u8,
+            RSDP_V1_LENGTH,
+        )
+    };
+    update_checksum(bytes, RSDP_CHECKSUM_OFFSET);
+    assert_eq!(rsdp.validate(), Ok(()));
+    assert!(!rsdp.has_xsdt());
+    rsdp.oem_id[0] = b'X';
+    assert!(rsdp.validate().is_err());
+}
@@ -1,19 +1,36 @@
 extern crate alloc;
 
+use crate::error;
+use crate::frame::FrameOwner;
+use crate::frame::FrameSize;
+use crate::frame::Frames;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::frame::FRAME_SIZE_2M;
+use crate::frame::FRAME_SIZE_4K;
+#[cfg(feature = "heap_debug")]
+use crate::heap_debug;
 use crate::result::Result;
-use crate::uefi::EfiMemoryDescriptor;
-use crate::uefi::EfiMemoryType;
-use crate::uefi::MemoryMapHolder;
+use crate::slab::SlabAllocator;
+use crate::slab::SlabCacheStats;
+use crate::slab::NUM_SLAB_CACHES;
+use crate::x86::read_rbp;
+use crate::x86::walk_stack;
 use alloc::alloc::GlobalAlloc;
 use alloc::alloc::Layout;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::borrow::BorrowMut;
 use core::cell::RefCell;
 use core::cmp::max;
+use core::cmp::min;
 use core::fmt;
 use core::mem::size_of;
 use core::ops::DerefMut;
+use core::ptr::copy_nonoverlapping;
 use core::ptr::null_mut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
 
 pub fn round_up_to_nearest_pow2(v: usize) -> Result<usize> {
     1usize
@@ -39,12 +56,14 @@ fn round_up_to_nearest_pow2_tests() {
 /// before: |-- prev -------|---- self ---------------
 /// align:  |--------|-------|-------|-------|-------|
 /// after:  |---------------||-------|----------------
-
+///
+/// All the blocks, allocated or not, are linked in the ascending order of
+/// their addresses so that a freed block can be merged with its neighbors.
 struct Header {
     next_header: Option<Box<Header>>,
     size: usize,
     is_allocated: bool,
-    _reserved: usize,
+    prev_header: *mut Header,
 }
 const HEADER_SIZE: usize = size_of::<Header>();
 #[allow(clippy::assertions_on_constants)]
@@ -62,8 +81,11 @@ impl Header {
     fn is_allocated(&self) -> bool {
         self.is_allocated
     }
+    fn addr(&self) -> usize {
+        self as *const Header as usize
+    }
     fn end_addr(&self) -> usize {
-        self as *const Header as usize + self.size
+        self.addr() + self.size
     }
     unsafe fn new_from_addr(addr: usize) -> Box<Header> {
         let header = addr as *mut Header;
@@ -71,10 +93,71 @@ impl Header {
             next_header: None,
             size: 0,
             is_allocated: false,
-            _reserved: 0,
+            prev_header: null_mut(),
         });
         Box::from_raw(addr as *mut Header)
     }
+    fn set_next(&mut self, next: Option<Box<Header>>) {
+        self.next_header = next;
+        let self_ptr = self as *mut Header;
+        if let Some(next) = self.next_header.as_mut() {
+            next.prev_header = self_ptr;
+        }
+    }
+    fn is_next_free_and_adjacent(&self) -> bool {
+        match &self.next_header {
+            Some(next) => !next.is_allocated && next.addr() == self.end_addr(),
+            None => false,
+        }
+    }
+    /// Merges the next block into self if both are free and adjacent.
+    fn try_merge_next(&mut self) -> bool {
+        if self.is_allocated || !self.is_next_free_and_adjacent() {
+            return false;
+        }
+        let Some(mut next) = self.next_header.take() else {
+            return false;
+        };
+        self.size += next.size;
+        self.set_next(next.next_header.take());
+        // next is leaked here since its memory is a part of self now.
+        Box::leak(next);
+        true
+    }
+    /// Tries to extend this allocated block so that it can hold new_size
+    /// bytes without moving, by taking (a part of) the next free block.
+    fn grow_in_place(&mut self, new_size: usize) -> bool {
+        let required = HEADER_SIZE + new_size.next_multiple_of(HEADER_SIZE);
+        if self.size >= required {
+            return true;
+        }
+        if !self.is_next_free_and_adjacent() {
+            return false;
+        }
+        let Some(mut next) = self.next_header.take() else {
+            return false;
+        };
+        let total = self.size + next.size;
+        if total < required {
+            self.next_header = Some(next);
+            return false;
+        }
+        let next_next = next.next_header.take();
+        Box::leak(next);
+        if total - required >= HEADER_SIZE {
+            // Split the rest of the next block as a new free block
+            let mut rest =
+                unsafe { Self::new_from_addr(self.addr() + required) };
+            rest.size = total - required;
+            rest.set_next(next_next);
+            self.size = required;
+            self.set_next(Some(rest));
+        } else {
+            self.size = total;
+            self.set_next(next_next);
+        }
+        true
+    }
     unsafe fn from_allocated_region(addr: *mut u8) -> Box<Header> {
         let header = addr.sub(HEADER_SIZE) as *mut Header;
         Box::from_raw(header)
@@ -109,7 +192,7 @@ impl Header {
             header_for_allocated.is_allocated = true;
             header_for_allocated.size = size + HEADER_SIZE;
             size_used += header_for_allocated.size;
-            header_for_allocated.next_header = self.next_header.take();
+            header_for_allocated.set_next(self.next_header.take());
             if header_for_allocated.end_addr() != self.end_addr() {
                 // Make a Header for padding
                 let mut header_for_padding = unsafe {
@@ -119,14 +202,14 @@ impl Header {
                 header_for_padding.size =
                     self.end_addr() - header_for_allocated.end_addr();
                 size_used += header_for_padding.size;
-                header_for_padding.next_header =
-                    header_for_allocated.next_header.take();
-                header_for_allocated.next_header = Some(header_for_padding);
+                header_for_padding
+                    .set_next(header_for_allocated.next_header.take());
+                header_for_allocated.set_next(Some(header_for_padding));
             }
             // Shrink self
             assert!(self.size >= size_used + HEADER_SIZE);
             self.size -= size_used;
-            self.next_header = Some(header_for_allocated);
+            self.set_next(Some(header_for_allocated));
             Some(allocated_addr as *mut u8)
         }
     }
@@ -150,13 +233,11 @@ impl fmt::Debug for Header {
 
 pub struct FirstFitAllocator {
     first_header: RefCell<Option<Box<Header>>>,
+    /// If true, the allocator takes more frames from the frame allocator
+    /// when it runs out of free blocks.
+    is_backed_by_frames: AtomicBool,
 }
 
-#[global_allocator]
-pub static ALLOCATOR: FirstFitAllocator = FirstFitAllocator {
-    first_header: RefCell::new(None),
-};
-
 unsafe impl Sync for FirstFitAllocator {}
 
 unsafe impl GlobalAlloc for FirstFitAllocator {
@@ -166,13 +247,283 @@ unsafe impl GlobalAlloc for FirstFitAllocator {
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
         let mut region = Header::from_allocated_region(ptr);
         region.is_allocated = false;
+        region.try_merge_next();
+        let prev = region.prev_header;
         Box::leak(region);
         // region is leaked here to avoid dropping the free info on the memory.
+        if let Some(prev) = prev.as_mut() {
+            prev.try_merge_next();
+        }
+    }
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let mut region = Header::from_allocated_region(ptr);
+        let grown = region.grow_in_place(new_size);
+        Box::leak(region);
+        if grown {
+            return ptr;
+        }
+        let new_layout =
+            Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+/// The kernel heap. Small objects are served by the slab caches, and the
+/// others (including the slab pages) come from the first-fit allocator.
+pub struct KernelAllocator {
+    slab: SlabAllocator,
+    first_fit: FirstFitAllocator,
+    // Sizes are counted as requested by the layouts.
+    bytes_in_use: AtomicUsize,
+    peak_bytes_in_use: AtomicUsize,
+    num_allocs: AtomicUsize,
+    num_frees: AtomicUsize,
+}
+
+#[global_allocator]
+pub static ALLOCATOR: KernelAllocator = KernelAllocator {
+    slab: SlabAllocator::new(),
+    first_fit: FirstFitAllocator::new(),
+    bytes_in_use: AtomicUsize::new(0),
+    peak_bytes_in_use: AtomicUsize::new(0),
+    num_allocs: AtomicUsize::new(0),
+    num_frees: AtomicUsize::new(0),
+};
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct HeapStats {
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    pub num_allocs: usize,
+    pub num_frees: usize,
+    pub num_free_blocks: usize,
+    pub free_bytes: usize,
+    pub largest_free_block: usize,
+}
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "in use: {} bytes (peak: {} bytes), live allocations: {}",
+            self.bytes_in_use,
+            self.peak_bytes_in_use,
+            self.num_allocs - self.num_frees
+        )?;
+        writeln!(f, "allocs: {}, frees: {}", self.num_allocs, self.num_frees)?;
+        write!(
+            f,
+            "free blocks: {}, free: {} bytes, largest free block: {} bytes",
+            self.num_free_blocks, self.free_bytes, self.largest_free_block
+        )
+    }
+}
+
+unsafe impl Sync for KernelAllocator {}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with_options(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.count_dealloc(ptr, layout.size());
+        #[cfg(feature = "heap_debug")]
+        heap_debug::dealloc(ptr, layout, |p, l| self.dealloc_raw(p, l));
+        #[cfg(not(feature = "heap_debug"))]
+        self.dealloc_raw(ptr, layout);
+    }
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let new_layout =
+            Layout::from_size_align_unchecked(new_size, layout.align());
+        if cfg!(feature = "heap_debug") {
+            // Objects always move so that stale pointers can be detected.
+            return self.realloc_by_copy(ptr, layout, new_layout);
+        }
+        match (
+            SlabAllocator::cache_index(layout),
+            SlabAllocator::cache_index(new_layout),
+        ) {
+            (None, None) => {
+                let new_ptr = self.first_fit.realloc(ptr, layout, new_size);
+                if !new_ptr.is_null() {
+                    self.count_dealloc(ptr, layout.size());
+                    self.count_alloc(new_ptr, new_size);
+                }
+                new_ptr
+            }
+            (Some(old), Some(new)) if old == new => {
+                self.count_dealloc(ptr, layout.size());
+                self.count_alloc(ptr, new_size);
+                ptr
+            }
+            _ => self.realloc_by_copy(ptr, layout, new_layout),
+        }
     }
 }
 
+impl KernelAllocator {
+    pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "heap_debug")]
+        let ptr = heap_debug::alloc(layout, |l| self.alloc_raw(l));
+        #[cfg(not(feature = "heap_debug"))]
+        let ptr = self.alloc_raw(layout);
+        self.count_alloc(ptr, layout.size());
+        ptr
+    }
+    fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        match SlabAllocator::cache_index(layout) {
+            Some(index) => self.slab.alloc(index, &self.first_fit),
+            None => self.first_fit.alloc_with_options(layout),
+        }
+    }
+    unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
+        match SlabAllocator::cache_index(layout) {
+            Some(index) => self.slab.dealloc(index, ptr, &self.first_fit),
+            None => self.first_fit.dealloc(ptr, layout),
+        }
+    }
+    unsafe fn realloc_by_copy(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_layout: Layout,
+    ) -> *mut u8 {
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            copy_nonoverlapping(
+                ptr,
+                new_ptr,
+                min(layout.size(), new_layout.size()),
+            );
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+    fn count_alloc(&self, ptr: *mut u8, size: usize) {
+        if ptr.is_null() {
+            return;
+        }
+        self.num_allocs.fetch_add(1, Ordering::Relaxed);
+        let in_use =
+            self.bytes_in_use.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak_bytes_in_use.fetch_max(in_use, Ordering::Relaxed);
+        #[cfg(feature = "heap_tracking")]
+        crate::heap_tracker::track_alloc(ptr, size);
+    }
+    fn count_dealloc(&self, ptr: *mut u8, size: usize) {
+        self.num_frees.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in_use.fetch_sub(size, Ordering::Relaxed);
+        #[cfg(feature = "heap_tracking")]
+        crate::heap_tracker::track_dealloc(ptr);
+        #[cfg(not(feature = "heap_tracking"))]
+        let _ = ptr;
+    }
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            bytes_in_use: self.bytes_in_use.load(Ordering::Relaxed),
+            peak_bytes_in_use: self.peak_bytes_in_use.load(Ordering::Relaxed),
+            num_allocs: self.num_allocs.load(Ordering::Relaxed),
+            num_frees: self.num_frees.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        self.first_fit.for_each_header(|e| {
+            if !e.is_allocated() {
+                let size = e.size - HEADER_SIZE;
+                stats.num_free_blocks += 1;
+                stats.free_bytes += size;
+                stats.largest_free_block = max(stats.largest_free_block, size);
+            }
+        });
+        stats
+    }
+    pub fn init_with_frames(&self) {
+        self.first_fit.init_with_frames()
+    }
+    pub fn first_fit(&self) -> &FirstFitAllocator {
+        &self.first_fit
+    }
+    pub fn slab_stats(&self) -> [SlabCacheStats; NUM_SLAB_CACHES] {
+        self.slab.stats()
+    }
+}
+
+const OOM_BACKTRACE_DEPTH: usize = 16;
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    error!("heap: failed to allocate {layout:?}");
+    error!("{}", ALLOCATOR.stats());
+    if let Some(frames) = FRAME_ALLOCATOR.lock().as_ref() {
+        error!("frame: {} frames are free", frames.num_free_frames());
+    }
+    let mut callers = [0u64; OOM_BACKTRACE_DEPTH];
+    let depth = walk_stack(read_rbp(), &mut callers);
+    error!("backtrace:");
+    for addr in &callers[..depth] {
+        error!("  {addr:#018X}");
+    }
+    panic!("Out of memory");
+}
+
+/// Same as Box::new(), but returns an error instead of calling the alloc
+/// error handler when the memory is exhausted.
+pub fn try_box<T>(value: T) -> Result<Box<T>> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+    let ptr = unsafe { alloc::alloc::alloc(layout) } as *mut T;
+    if ptr.is_null() {
+        return Err("Out of memory");
+    }
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Same as vec![elem; n], but returns an error instead of calling the alloc
+/// error handler when the memory is exhausted. Use this for the buffers
+/// whose sizes come from devices.
+pub fn try_vec<T: Clone>(elem: T, n: usize) -> Result<Vec<T>> {
+    let mut v = Vec::new();
+    v.try_reserve_exact(n).or(Err("Out of memory"))?;
+    v.resize(n, elem);
+    Ok(v)
+}
+
 impl FirstFitAllocator {
+    pub const fn new() -> Self {
+        Self {
+            first_header: RefCell::new(None),
+            is_backed_by_frames: AtomicBool::new(false),
+        }
+    }
     pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
+        let p = self.alloc_from_free_blocks(layout);
+        if !p.is_null() || !self.is_backed_by_frames.load(Ordering::Relaxed) {
+            return p;
+        }
+        if self.grow(layout).is_err() {
+            return null_mut();
+        }
+        self.alloc_from_free_blocks(layout)
+    }
+    fn alloc_from_free_blocks(&self, layout: Layout) -> *mut u8 {
         let mut header = self.first_header.borrow_mut();
         let mut header = header.deref_mut();
         loop {
@@ -190,38 +541,63 @@ impl FirstFitAllocator {
             }
         }
     }
-    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) {
-        for e in memory_map.iter() {
-            if e.memory_type() != EfiMemoryType::CONVENTIONAL_MEMORY {
-                continue;
-            }
-            self.add_free_from_descriptor(e);
-        }
+    /// Lets the allocator take its memory from the frame allocator. This
+    /// should be called after init_frame_allocator().
+    pub fn init_with_frames(&self) {
+        self.is_backed_by_frames.store(true, Ordering::SeqCst);
     }
-    fn add_free_from_descriptor(&self, desc: &EfiMemoryDescriptor) {
-        let mut start_addr = desc.physical_start() as usize;
-        let mut size = desc.number_of_pages() as usize * 4096;
-        // Make sure the allocator does not include the address 0 as a free
-        // area.
-        if start_addr == 0 {
-            start_addr += 4096;
-            size = size.saturating_sub(4096);
-        }
-        if size <= 4096 {
-            return;
+    /// Adds new frames that are large enough to satisfy the layout.
+    fn grow(&self, layout: Layout) -> Result<()> {
+        let size = max(round_up_to_nearest_pow2(layout.size())?, HEADER_SIZE)
+            + HEADER_SIZE * 2
+            + max(layout.align(), HEADER_SIZE);
+        let frames = Frames::alloc(
+            FrameSize::Size2M,
+            size.div_ceil(FRAME_SIZE_2M),
+            FrameOwner::Heap,
+        )
+        .or_else(|_| {
+            Frames::alloc(
+                FrameSize::Size4K,
+                size.div_ceil(FRAME_SIZE_4K),
+                FrameOwner::Heap,
+            )
+        })?;
+        let size = frames.size();
+        let addr = frames.leak();
+        self.add_free_region(addr as usize, size);
+        Ok(())
+    }
+    /// Calls f for each block (allocated or not) in address order.
+    fn for_each_header(&self, mut f: impl FnMut(&Header)) {
+        let first_header = self.first_header.borrow();
+        let mut header = first_header.as_deref();
+        while let Some(e) = header {
+            f(e);
+            header = e.next_header.as_deref();
         }
+    }
+    /// Adds [start_addr, start_addr + size) as a free region. The headers
+    /// are kept sorted by their addresses.
+    fn add_free_region(&self, start_addr: usize, size: usize) {
         let mut header = unsafe { Header::new_from_addr(start_addr) };
-        header.next_header = None;
         header.is_allocated = false;
         header.size = size;
         let mut first_header = self.first_header.borrow_mut();
-        let prev_last = first_header.replace(header);
-        drop(first_header);
-        let mut header = self.first_header.borrow_mut();
-        header.as_mut().unwrap().next_header = prev_last;
-        // It's okay not to be sorted the headers at this point
-        // since all the regions written in memory maps are not contiguous
-        // so that they can't be merged anyway
+        let mut prev = null_mut::<Header>();
+        let mut link = first_header.deref_mut();
+        while link.as_ref().is_some_and(|e| e.addr() < start_addr) {
+            let e = link.as_mut().unwrap();
+            prev = e.as_mut() as *mut Header;
+            link = &mut e.next_header;
+        }
+        header.set_next(link.take());
+        header.prev_header = prev;
+        *link = Some(header);
+        // Frames given to the heap can be contiguous with the existing ones.
+        if let Some(prev) = unsafe { prev.as_mut() } {
+            prev.try_merge_next();
+        }
     }
 }
 
@@ -362,4 +738,136 @@ mod test {
             }
         }
     }
+
+    /// Creates an allocator that manages a dedicated region, so that the
+    /// tests can observe the block list without any interference.
+    fn with_test_allocator(f: impl FnOnce(&FirstFitAllocator, usize)) {
+        const REGION_SIZE: usize = 64 * 1024;
+        let region_layout = Layout::from_size_align(REGION_SIZE, 4096).unwrap();
+        let region = ALLOCATOR.alloc_with_options(region_layout);
+        assert!(!region.is_null());
+        let allocator = FirstFitAllocator::new();
+        allocator.add_free_region(region as usize, REGION_SIZE);
+        f(&allocator, REGION_SIZE);
+        // Headers should not be dropped.
+        core::mem::forget(allocator);
+        unsafe { ALLOCATOR.dealloc(region, region_layout) }
+    }
+    fn count_blocks(allocator: &FirstFitAllocator) -> (usize, usize) {
+        let mut num_free = 0;
+        let mut num_allocated = 0;
+        let mut prev_end = 0;
+        allocator.for_each_header(|e| {
+            assert!(prev_end <= e.addr());
+            prev_end = e.end_addr();
+            if e.is_allocated() {
+                num_allocated += 1;
+            } else {
+                num_free += 1;
+            }
+        });
+        (num_free, num_allocated)
+    }
+
+    #[test_case]
+    fn free_blocks_are_coalesced() {
+        with_test_allocator(|allocator, _| {
+            let layout = Layout::from_size_align(1000, 64).unwrap();
+            let a = allocator.alloc_with_options(layout);
+            let b = allocator.alloc_with_options(layout);
+            let c = allocator.alloc_with_options(layout);
+            assert!(!a.is_null() && !b.is_null() && !c.is_null());
+            assert_eq!(count_blocks(allocator).1, 3);
+            // Free the middle one first, then its neighbors
+            unsafe {
+                allocator.dealloc(b, layout);
+                allocator.dealloc(a, layout);
+                allocator.dealloc(c, layout);
+            }
+            assert_eq!(count_blocks(allocator), (1, 0));
+        });
+    }
+
+    #[test_case]
+    fn large_alloc_after_fragmentation() {
+        with_test_allocator(|allocator, region_size| {
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let mut pointers = [null_mut::<u8>(); 64];
+            for e in pointers.iter_mut() {
+                *e = allocator.alloc_with_options(layout);
+                assert!(!e.is_null());
+            }
+            for e in pointers.iter().step_by(2) {
+                unsafe { allocator.dealloc(*e, layout) }
+            }
+            for e in pointers.iter().skip(1).step_by(2) {
+                unsafe { allocator.dealloc(*e, layout) }
+            }
+            assert_eq!(count_blocks(allocator), (1, 0));
+            let large = Layout::from_size_align(region_size / 2, 8).unwrap();
+            assert!(!allocator.alloc_with_options(large).is_null());
+        });
+    }
+
+    #[test_case]
+    fn realloc_grows_in_place() {
+        with_test_allocator(|allocator, _| {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let a = allocator.alloc_with_options(layout);
+            let b = allocator.alloc_with_options(layout);
+            for i in 0..64 {
+                unsafe { *b.add(i) = i as u8 }
+            }
+            // Objects are carved from the end of a free block, so b is
+            // placed right before a. Growing b needs a to be freed.
+            unsafe { allocator.dealloc(a, layout) };
+            let b2 = unsafe { allocator.realloc(b, layout, 1000) };
+            assert_eq!(b, b2);
+            for i in 0..64 {
+                assert_eq!(unsafe { *b2.add(i) }, i as u8);
+            }
+            let c = allocator.alloc_with_options(layout);
+            let d = allocator.alloc_with_options(layout);
+            // Same for d and c: d absorbs c once c is freed.
+            unsafe { allocator.dealloc(c, layout) };
+            let d2 = unsafe { allocator.realloc(d, layout, 128) };
+            assert_eq!(d, d2);
+            // Shrinking never moves the object.
+            let d3 = unsafe {
+                allocator.realloc(
+                    d2,
+                    Layout::from_size_align(128, 8).unwrap(),
+                    8,
+                )
+            };
+            assert_eq!(d2, d3);
+        });
+    }
+
+    #[test_case]
+    fn heap_stats_count_allocations() {
+        use alloc::boxed::Box;
+        let before = ALLOCATOR.stats();
+        let b = Box::new([0u8; 1000]);
+        let during = ALLOCATOR.stats();
+        assert_eq!(during.num_allocs, before.num_allocs + 1);
+        assert!(during.bytes_in_use >= before.bytes_in_use + 1000);
+        assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+        drop(b);
+        let after = ALLOCATOR.stats();
+        assert_eq!(after.num_frees, before.num_frees + 1);
+        assert!(after.num_free_blocks > 0);
+        assert!(after.largest_free_block <= after.free_bytes);
+    }
+
+    #[test_case]
+    fn try_alloc_helpers() {
+        let b = try_box([7u64; 64]).expect("Failed to alloc");
+        assert!(b.iter().all(|e| *e == 7));
+        let v = try_vec(3u8, 100).expect("Failed to alloc");
+        assert_eq!(v.len(), 100);
+        assert!(v.iter().all(|e| *e == 3));
+        // Far larger than the physical memory
+        assert!(try_vec(0u8, 1 << 40).is_err());
+    }
 }
@@ -0,0 +1,2425 @@
+//! AML (ACPI Machine Language) interpreter
+//!
+//! The DSDT and SSDTs are loaded into a namespace by executing their
+//! top-level terms. Each object in the namespace is keyed by its absolute
+//! path made of 4-char name segments, e.g. `\_SB_.PCI0._PRT`, so iterating
+//! the map visits a parent before its children. Methods are executed
+//! directly from the byte code in the copied tables, which live forever.
+//!
+//! This covers what typical firmware uses. Load / Unload, DataTableRegion
+//! and the region spaces other than SystemMemory, SystemIO and PCI_Config
+//! are not supported, and everything runs on a single thread, so Acquire
+//! and Wait always succeed immediately.
+
+extern crate alloc;
+
+use crate::acpi::global_acpi;
+use crate::acpi::AcpiRsdpStruct;
+use crate::acpi::GenericAddress;
+use crate::clock::now;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::pci::BusDeviceFunction;
+use crate::pci::Pci;
+use crate::result::Result;
+use crate::warn;
+use crate::x86::busy_loop_hint;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::fmt;
+use core::iter::once;
+use core::mem::replace;
+use core::time::Duration;
+
+// 20 ACPI Machine Language (AML) Specification
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ALIAS_OP: u8 = 0x06;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0A;
+const WORD_PREFIX: u8 = 0x0B;
+const DWORD_PREFIX: u8 = 0x0C;
+const STRING_PREFIX: u8 = 0x0D;
+const QWORD_PREFIX: u8 = 0x0E;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const VAR_PACKAGE_OP: u8 = 0x13;
+const METHOD_OP: u8 = 0x14;
+const EXTERNAL_OP: u8 = 0x15;
+const DUAL_NAME_PREFIX: u8 = 0x2E;
+const MULTI_NAME_PREFIX: u8 = 0x2F;
+const EXT_OP_PREFIX: u8 = 0x5B;
+const ROOT_CHAR: u8 = 0x5C;
+const PARENT_PREFIX_CHAR: u8 = 0x5E;
+const LOCAL0_OP: u8 = 0x60;
+const LOCAL7_OP: u8 = 0x67;
+const ARG0_OP: u8 = 0x68;
+const ARG6_OP: u8 = 0x6E;
+const STORE_OP: u8 = 0x70;
+const REF_OF_OP: u8 = 0x71;
+const ADD_OP: u8 = 0x72;
+const CONCAT_OP: u8 = 0x73;
+const SUBTRACT_OP: u8 = 0x74;
+const INCREMENT_OP: u8 = 0x75;
+const DECREMENT_OP: u8 = 0x76;
+const MULTIPLY_OP: u8 = 0x77;
+const DIVIDE_OP: u8 = 0x78;
+const SHIFT_LEFT_OP: u8 = 0x79;
+const SHIFT_RIGHT_OP: u8 = 0x7A;
+const AND_OP: u8 = 0x7B;
+const NAND_OP: u8 = 0x7C;
+const OR_OP: u8 = 0x7D;
+const NOR_OP: u8 = 0x7E;
+const XOR_OP: u8 = 0x7F;
+const NOT_OP: u8 = 0x80;
+const FIND_SET_LEFT_BIT_OP: u8 = 0x81;
+const FIND_SET_RIGHT_BIT_OP: u8 = 0x82;
+const DEREF_OF_OP: u8 = 0x83;
+const CONCAT_RES_OP: u8 = 0x84;
+const MOD_OP: u8 = 0x85;
+const NOTIFY_OP: u8 = 0x86;
+const SIZE_OF_OP: u8 = 0x87;
+const INDEX_OP: u8 = 0x88;
+const MATCH_OP: u8 = 0x89;
+const CREATE_DWORD_FIELD_OP: u8 = 0x8A;
+const CREATE_WORD_FIELD_OP: u8 = 0x8B;
+const CREATE_BYTE_FIELD_OP: u8 = 0x8C;
+const CREATE_BIT_FIELD_OP: u8 = 0x8D;
+const OBJECT_TYPE_OP: u8 = 0x8E;
+const CREATE_QWORD_FIELD_OP: u8 = 0x8F;
+const LAND_OP: u8 = 0x90;
+const LOR_OP: u8 = 0x91;
+const LNOT_OP: u8 = 0x92;
+const LEQUAL_OP: u8 = 0x93;
+const LGREATER_OP: u8 = 0x94;
+const LLESS_OP: u8 = 0x95;
+const TO_BUFFER_OP: u8 = 0x96;
+const TO_DECIMAL_STRING_OP: u8 = 0x97;
+const TO_HEX_STRING_OP: u8 = 0x98;
+const TO_INTEGER_OP: u8 = 0x99;
+const TO_STRING_OP: u8 = 0x9C;
+const COPY_OBJECT_OP: u8 = 0x9D;
+const MID_OP: u8 = 0x9E;
+const CONTINUE_OP: u8 = 0x9F;
+const IF_OP: u8 = 0xA0;
+const ELSE_OP: u8 = 0xA1;
+const WHILE_OP: u8 = 0xA2;
+const NOOP_OP: u8 = 0xA3;
+const RETURN_OP: u8 = 0xA4;
+const BREAK_OP: u8 = 0xA5;
+const BREAK_POINT_OP: u8 = 0xCC;
+const ONES_OP: u8 = 0xFF;
+
+// Opcodes that follow EXT_OP_PREFIX
+const EXT_MUTEX_OP: u8 = 0x01;
+const EXT_EVENT_OP: u8 = 0x02;
+const EXT_COND_REF_OF_OP: u8 = 0x12;
+const EXT_CREATE_FIELD_OP: u8 = 0x13;
+const EXT_STALL_OP: u8 = 0x21;
+const EXT_SLEEP_OP: u8 = 0x22;
+const EXT_ACQUIRE_OP: u8 = 0x23;
+const EXT_SIGNAL_OP: u8 = 0x24;
+const EXT_WAIT_OP: u8 = 0x25;
+const EXT_RESET_OP: u8 = 0x26;
+const EXT_RELEASE_OP: u8 = 0x27;
+const EXT_FROM_BCD_OP: u8 = 0x28;
+const EXT_TO_BCD_OP: u8 = 0x29;
+const EXT_REVISION_OP: u8 = 0x30;
+const EXT_DEBUG_OP: u8 = 0x31;
+const EXT_FATAL_OP: u8 = 0x32;
+const EXT_TIMER_OP: u8 = 0x33;
+const EXT_OP_REGION_OP: u8 = 0x80;
+const EXT_FIELD_OP: u8 = 0x81;
+const EXT_DEVICE_OP: u8 = 0x82;
+const EXT_PROCESSOR_OP: u8 = 0x83;
+const EXT_POWER_RES_OP: u8 = 0x84;
+const EXT_THERMAL_ZONE_OP: u8 = 0x85;
+const EXT_INDEX_FIELD_OP: u8 = 0x86;
+const EXT_BANK_FIELD_OP: u8 = 0x87;
+
+// Field list elements
+const RESERVED_FIELD: u8 = 0x00;
+const ACCESS_FIELD: u8 = 0x01;
+const CONNECT_FIELD: u8 = 0x02;
+const EXTENDED_ACCESS_FIELD: u8 = 0x03;
+
+const REGION_SPACE_SYSTEM_MEMORY: u8 = 0;
+const REGION_SPACE_SYSTEM_IO: u8 = 1;
+const REGION_SPACE_PCI_CONFIG: u8 = 2;
+
+const UPDATE_RULE_WRITE_AS_ONES: u8 = 1;
+const UPDATE_RULE_WRITE_AS_ZEROS: u8 = 2;
+
+// 19.6.96 ObjectType
+const TYPE_UNINITIALIZED: u64 = 0;
+const TYPE_INTEGER: u64 = 1;
+const TYPE_STRING: u64 = 2;
+const TYPE_BUFFER: u64 = 3;
+const TYPE_PACKAGE: u64 = 4;
+const TYPE_FIELD_UNIT: u64 = 5;
+const TYPE_DEVICE: u64 = 6;
+const TYPE_EVENT: u64 = 7;
+const TYPE_METHOD: u64 = 8;
+const TYPE_MUTEX: u64 = 9;
+const TYPE_OPERATION_REGION: u64 = 10;
+const TYPE_POWER_RESOURCE: u64 = 11;
+const TYPE_PROCESSOR: u64 = 12;
+const TYPE_THERMAL_ZONE: u64 = 13;
+
+const NUM_LOCALS: usize = 8;
+const NUM_ARGS: usize = 7;
+// Each nested call takes some of the kernel stack.
+const MAX_CALL_DEPTH: usize = 16;
+const MAX_LOOP_ITERATIONS: usize = 1_000_000;
+const MAX_PACKAGE_ELEMENTS: usize = 0x10000;
+const MAX_BUFFER_SIZE: usize = 0x10000;
+const MAX_ALIAS_DEPTH: usize = 8;
+/// Returned by the Revision opcode and \_REV
+const INTERPRETER_REVISION: u64 = 2;
+
+/// The strings that \_OSI answers as supported. Firmware often enables
+/// features only for recent versions of Windows.
+const SUPPORTED_OSI: [&str; 19] = [
+    "Windows 2000",
+    "Windows 2001",
+    "Windows 2001 SP1",
+    "Windows 2001.1",
+    "Windows 2001 SP2",
+    "Windows 2001.1 SP1",
+    "Windows 2006",
+    "Windows 2006.1",
+    "Windows 2006 SP1",
+    "Windows 2009",
+    "Windows 2012",
+    "Windows 2013",
+    "Windows 2015",
+    "Windows 2016",
+    "Windows 2017",
+    "Windows 2018",
+    "Windows 2020",
+    "Module Device",
+    "Processor Device",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AmlValue {
+    Uninitialized,
+    Integer(u64),
+    String(String),
+    Buffer(Vec<u8>),
+    Package(Vec<AmlValue>),
+    Reference(Reference),
+}
+impl AmlValue {
+    /// Converts the value as an implicit conversion to Integer does.
+    pub fn as_integer(&self) -> Result<u64> {
+        match self {
+            AmlValue::Integer(v) => Ok(*v),
+            AmlValue::String(s) => Ok(parse_hex(s)),
+            AmlValue::Buffer(b) => {
+                let mut bytes = [0u8; 8];
+                let len = b.len().min(bytes.len());
+                bytes[..len].copy_from_slice(&b[..len]);
+                Ok(u64::from_le_bytes(bytes))
+            }
+            _ => Err("AML value can not be converted to an Integer"),
+        }
+    }
+    fn to_buffer(&self) -> Result<Vec<u8>> {
+        match self {
+            AmlValue::Integer(v) => Ok(v.to_le_bytes().to_vec()),
+            AmlValue::String(s) => {
+                Ok(s.chars().map(|c| c as u8).chain(once(0)).collect())
+            }
+            AmlValue::Buffer(b) => Ok(b.clone()),
+            _ => Err("AML value can not be converted to a Buffer"),
+        }
+    }
+    fn to_aml_string(&self) -> Result<String> {
+        match self {
+            AmlValue::Integer(v) => Ok(format!("{v:016X}")),
+            AmlValue::String(s) => Ok(s.clone()),
+            AmlValue::Buffer(b) => Ok(b
+                .iter()
+                .map(|e| format!("{e:02X}"))
+                .collect::<Vec<String>>()
+                .join(" ")),
+            _ => Err("AML value can not be converted to a String"),
+        }
+    }
+    fn object_type(&self) -> u64 {
+        match self {
+            AmlValue::Uninitialized | AmlValue::Reference(_) => {
+                TYPE_UNINITIALIZED
+            }
+            AmlValue::Integer(_) => TYPE_INTEGER,
+            AmlValue::String(_) => TYPE_STRING,
+            AmlValue::Buffer(_) => TYPE_BUFFER,
+            AmlValue::Package(_) => TYPE_PACKAGE,
+        }
+    }
+    fn element(&self, index: usize) -> Result<AmlValue> {
+        let out_of_range = "AML Index is out of range";
+        match self {
+            AmlValue::Package(p) => p.get(index).cloned().ok_or(out_of_range),
+            AmlValue::Buffer(b) => b
+                .get(index)
+                .map(|e| AmlValue::Integer(*e as u64))
+                .ok_or(out_of_range),
+            AmlValue::String(s) => s
+                .chars()
+                .nth(index)
+                .map(|c| AmlValue::Integer(c as u64))
+                .ok_or(out_of_range),
+            _ => Err("AML Index source is not a Package, Buffer or String"),
+        }
+    }
+}
+impl fmt::Display for AmlValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmlValue::Uninitialized => write!(f, "Uninitialized"),
+            AmlValue::Integer(v) => write!(f, "{v:#X}"),
+            AmlValue::String(s) => write!(f, "{s:?}"),
+            AmlValue::Buffer(b) => {
+                write!(
+                    f,
+                    "Buffer({}) {:02X?}",
+                    b.len(),
+                    &b[..b.len().min(16)]
+                )?;
+                if b.len() > 16 {
+                    write!(f, "..")?;
+                }
+                Ok(())
+            }
+            AmlValue::Package(p) => {
+                write!(f, "Package({}) {{", p.len())?;
+                for (i, e) in p.iter().enumerate() {
+                    write!(f, "{}{e}", if i == 0 { "" } else { ", " })?;
+                }
+                write!(f, "}}")
+            }
+            AmlValue::Reference(r) => write!(f, "{r}"),
+        }
+    }
+}
+
+/// Parses the leading hex digits, as the implicit String to Integer
+/// conversion does.
+fn parse_hex(s: &str) -> u64 {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    s.chars()
+        .map_while(|c| c.to_digit(16))
+        .fold(0, |v, d| (v << 4) | d as u64)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Location {
+    Name(String),
+    Local(usize),
+    Arg(usize),
+}
+
+/// A reference to an object, or to an element of it if `index` is set. A
+/// reference to a local or an argument is only valid in the method that
+/// made it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reference {
+    location: Location,
+    index: Option<usize>,
+}
+impl Reference {
+    fn to(location: Location) -> Self {
+        Self {
+            location,
+            index: None,
+        }
+    }
+    /// The absolute path of the named object that this refers to
+    pub fn path(&self) -> Option<&str> {
+        match (&self.location, self.index) {
+            (Location::Name(path), None) => Some(path),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Location::Name(path) => write!(f, "RefOf({path})")?,
+            Location::Local(i) => write!(f, "RefOf(Local{i})")?,
+            Location::Arg(i) => write!(f, "RefOf(Arg{i})")?,
+        }
+        if let Some(index) = self.index {
+            write!(f, "[{index}]")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct OperationRegion {
+    space: u8,
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Clone, Debug)]
+enum FieldKind {
+    Region(String),
+    Index {
+        index: String,
+        data: String,
+    },
+    Bank {
+        region: String,
+        bank: String,
+        value: u64,
+    },
+    Buffer(Location),
+}
+
+#[derive(Clone, Debug)]
+struct FieldUnit {
+    kind: FieldKind,
+    bit_offset: usize,
+    bit_length: usize,
+    access_bytes: usize,
+    update_rule: u8,
+}
+
+#[derive(Clone, Debug)]
+struct AmlMethod {
+    body: &'static [u8],
+    arg_count: usize,
+}
+
+type NativeMethod = fn(&[AmlValue]) -> Result<AmlValue>;
+
+#[derive(Clone, Debug)]
+enum AmlObject {
+    Scope,
+    Device,
+    Processor,
+    PowerResource,
+    ThermalZone,
+    Value(AmlValue),
+    Method(AmlMethod),
+    NativeMethod {
+        arg_count: usize,
+        func: NativeMethod,
+    },
+    OperationRegion(OperationRegion),
+    Field(FieldUnit),
+    Mutex,
+    Event,
+    Alias(String),
+}
+impl AmlObject {
+    fn object_type(&self) -> u64 {
+        match self {
+            AmlObject::Scope | AmlObject::Alias(_) => TYPE_UNINITIALIZED,
+            AmlObject::Device => TYPE_DEVICE,
+            AmlObject::Processor => TYPE_PROCESSOR,
+            AmlObject::PowerResource => TYPE_POWER_RESOURCE,
+            AmlObject::ThermalZone => TYPE_THERMAL_ZONE,
+            AmlObject::Value(v) => v.object_type(),
+            AmlObject::Method(_) | AmlObject::NativeMethod { .. } => {
+                TYPE_METHOD
+            }
+            AmlObject::OperationRegion(_) => TYPE_OPERATION_REGION,
+            AmlObject::Field(_) => TYPE_FIELD_UNIT,
+            AmlObject::Mutex => TYPE_MUTEX,
+            AmlObject::Event => TYPE_EVENT,
+        }
+    }
+}
+impl fmt::Display for AmlObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmlObject::Scope => write!(f, "Scope"),
+            AmlObject::Device => write!(f, "Device"),
+            AmlObject::Processor => write!(f, "Processor"),
+            AmlObject::PowerResource => write!(f, "PowerResource"),
+            AmlObject::ThermalZone => write!(f, "ThermalZone"),
+            AmlObject::Value(v) => write!(f, "{v}"),
+            AmlObject::Method(m) => write!(f, "Method({})", m.arg_count),
+            AmlObject::NativeMethod { arg_count, .. } => {
+                write!(f, "Method({arg_count}) (native)")
+            }
+            AmlObject::OperationRegion(r) => write!(
+                f,
+                "OperationRegion({}, {:#X}, {:#X})",
+                r.space, r.offset, r.length
+            ),
+            AmlObject::Field(e) => write!(
+                f,
+                "Field(bit {:#X}, {} bits)",
+                e.bit_offset, e.bit_length
+            ),
+            AmlObject::Mutex => write!(f, "Mutex"),
+            AmlObject::Event => write!(f, "Event"),
+            AmlObject::Alias(path) => write!(f, "Alias({path})"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct NameString {
+    is_absolute: bool,
+    num_parents: usize,
+    segs: Vec<[u8; 4]>,
+}
+impl fmt::Display for NameString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_absolute {
+            write!(f, "\\")?;
+        }
+        for _ in 0..self.num_parents {
+            write!(f, "^")?;
+        }
+        for (i, seg) in self.segs.iter().enumerate() {
+            if i != 0 {
+                write!(f, ".")?;
+            }
+            for c in seg {
+                write!(f, "{}", *c as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_name_lead(c: u8) -> bool {
+    matches!(
+        c,
+        b'A'..=b'Z'
+            | b'_'
+            | ROOT_CHAR
+            | PARENT_PREFIX_CHAR
+            | DUAL_NAME_PREFIX
+            | MULTI_NAME_PREFIX
+    )
+}
+
+fn join_path(scope: &str, seg: &[u8; 4]) -> String {
+    let mut path = String::from(scope);
+    if scope != "\\" {
+        path.push('.');
+    }
+    path.extend(seg.iter().map(|c| *c as char));
+    path
+}
+
+fn parent_path(path: &str) -> Option<&str> {
+    match path.rfind('.') {
+        Some(i) => Some(&path[..i]),
+        None if path.len() > 1 => Some("\\"),
+        None => None,
+    }
+}
+
+/// Makes an absolute path like `\_SB.PCI0` into the form used as the keys
+/// of the namespace, i.e. `\_SB_.PCI0`.
+pub fn parse_path(path: &str) -> Result<String> {
+    let rest = path
+        .strip_prefix('\\')
+        .ok_or("AML path should start with \\")?;
+    let mut result = String::from("\\");
+    if rest.is_empty() {
+        return Ok(result);
+    }
+    for seg in rest.split('.') {
+        if seg.is_empty()
+            || seg.len() > 4
+            || !seg.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err("Invalid AML path");
+        }
+        let mut padded = [b'_'; 4];
+        for (i, c) in seg.bytes().enumerate() {
+            padded[i] = c.to_ascii_uppercase();
+        }
+        result = join_path(&result, &padded);
+    }
+    Ok(result)
+}
+
+fn resolve_new(scope: &str, name: &NameString) -> Result<String> {
+    let mut path = if name.is_absolute { "\\" } else { scope };
+    for _ in 0..name.num_parents {
+        path = parent_path(path).ok_or("AML name goes above the root")?;
+    }
+    let mut path = String::from(path);
+    for seg in &name.segs {
+        path = join_path(&path, seg);
+    }
+    Ok(path)
+}
+
+struct Cursor {
+    aml: &'static [u8],
+    pos: usize,
+}
+impl Cursor {
+    fn new(aml: &'static [u8]) -> Self {
+        Self { aml, pos: 0 }
+    }
+    fn is_at_end(&self) -> bool {
+        self.pos >= self.aml.len()
+    }
+    fn peek(&self) -> Result<u8> {
+        self.aml
+            .get(self.pos)
+            .copied()
+            .ok_or("Unexpected end of AML")
+    }
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.aml.get(self.pos + offset).copied()
+    }
+    fn byte(&mut self) -> Result<u8> {
+        let v = self.peek()?;
+        self.pos += 1;
+        Ok(v)
+    }
+    fn bytes(&mut self, len: usize) -> Result<&'static [u8]> {
+        let bytes = self
+            .aml
+            .get(self.pos..self.pos + len)
+            .ok_or("Unexpected end of AML")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn integer(&mut self, len: usize) -> Result<u64> {
+        let mut bytes = [0u8; 8];
+        bytes[..len].copy_from_slice(self.bytes(len)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+    fn pkg_length(&mut self) -> Result<usize> {
+        // 20.2.4 Package Length Encoding
+        let lead = self.byte()?;
+        let num_follows = (lead >> 6) as usize;
+        if num_follows == 0 {
+            return Ok((lead & 0x3F) as usize);
+        }
+        let mut len = (lead & 0x0F) as usize;
+        for i in 0..num_follows {
+            len |= (self.byte()? as usize) << (4 + 8 * i);
+        }
+        Ok(len)
+    }
+    /// Reads a PkgLength and returns the end of the package.
+    fn pkg_end(&mut self) -> Result<usize> {
+        let start = self.pos;
+        let end = start + self.pkg_length()?;
+        if end < self.pos || end > self.aml.len() {
+            Err("AML package is out of range")
+        } else {
+            Ok(end)
+        }
+    }
+    /// Returns the bytes up to `end` and moves there.
+    fn slice_to(&mut self, end: usize) -> Result<&'static [u8]> {
+        let slice = self
+            .aml
+            .get(self.pos..end)
+            .ok_or("AML package is out of range")?;
+        self.pos = end;
+        Ok(slice)
+    }
+    fn name_seg(&mut self) -> Result<[u8; 4]> {
+        let seg: [u8; 4] = self.bytes(4)?.try_into().or(Err("Bad NameSeg"))?;
+        let is_valid = matches!(seg[0], b'A'..=b'Z' | b'_')
+            && seg[1..]
+                .iter()
+                .all(|c| matches!(c, b'A'..=b'Z' | b'0'..=b'9' | b'_'));
+        if is_valid {
+            Ok(seg)
+        } else {
+            Err("Invalid AML NameSeg")
+        }
+    }
+    fn name_string(&mut self) -> Result<NameString> {
+        let mut name = NameString::default();
+        if self.peek()? == ROOT_CHAR {
+            self.pos += 1;
+            name.is_absolute = true;
+        } else {
+            while self.peek()? == PARENT_PREFIX_CHAR {
+                self.pos += 1;
+                name.num_parents += 1;
+            }
+        }
+        let num_segs = match self.peek()? {
+            ZERO_OP => {
+                // NullName
+                self.pos += 1;
+                0
+            }
+            DUAL_NAME_PREFIX => {
+                self.pos += 1;
+                2
+            }
+            MULTI_NAME_PREFIX => {
+                self.pos += 1;
+                self.byte()? as usize
+            }
+            _ => 1,
+        };
+        for _ in 0..num_segs {
+            name.segs.push(self.name_seg()?);
+        }
+        Ok(name)
+    }
+}
+
+enum Flow {
+    Next,
+    Return(AmlValue),
+    Break,
+    Continue,
+}
+
+enum Target {
+    Null,
+    Debug,
+    Ref(Reference),
+}
+
+struct Frame {
+    scope: String,
+    args: [AmlValue; NUM_ARGS],
+    locals: [AmlValue; NUM_LOCALS],
+    /// Objects created by the method, which are removed when it returns
+    created: Vec<String>,
+    depth: usize,
+}
+impl Frame {
+    fn new(scope: &str, args: Vec<AmlValue>, depth: usize) -> Self {
+        let mut args = args.into_iter();
+        Self {
+            scope: scope.to_string(),
+            args: core::array::from_fn(|_| {
+                args.next().unwrap_or(AmlValue::Uninitialized)
+            }),
+            locals: core::array::from_fn(|_| AmlValue::Uninitialized),
+            created: Vec::new(),
+            depth,
+        }
+    }
+    fn is_method(&self) -> bool {
+        self.depth > 0
+    }
+}
+
+fn value_at_mut<'a>(
+    objects: &'a mut BTreeMap<String, AmlObject>,
+    frame: &'a mut Frame,
+    location: &Location,
+) -> Result<&'a mut AmlValue> {
+    match location {
+        Location::Local(i) => Ok(&mut frame.locals[*i]),
+        Location::Arg(i) => Ok(&mut frame.args[*i]),
+        Location::Name(path) => match objects.get_mut(path) {
+            Some(AmlObject::Value(v)) => Ok(v),
+            Some(_) => Err("AML object is not a data object"),
+            None => Err("AML name not found"),
+        },
+    }
+}
+
+/// Converts `value` to the type of `dst`, as a Store to a named object does.
+fn convert_like(dst: &AmlValue, value: AmlValue) -> Result<AmlValue> {
+    Ok(match dst {
+        AmlValue::Integer(_) => AmlValue::Integer(value.as_integer()?),
+        AmlValue::String(_) => AmlValue::String(value.to_aml_string()?),
+        AmlValue::Buffer(old) => {
+            // Named buffers keep their size.
+            let mut buf = value.to_buffer()?;
+            buf.resize(old.len(), 0);
+            AmlValue::Buffer(buf)
+        }
+        _ => value,
+    })
+}
+
+fn compare(a: &AmlValue, b: &AmlValue) -> Result<Ordering> {
+    Ok(match a {
+        AmlValue::String(s) => s.as_str().cmp(b.to_aml_string()?.as_str()),
+        AmlValue::Buffer(x) => x.as_slice().cmp(b.to_buffer()?.as_slice()),
+        _ => a.as_integer()?.cmp(&b.as_integer()?),
+    })
+}
+
+fn access_bytes(access_type: u8) -> usize {
+    match access_type & 0x0F {
+        2 => 2,
+        3 => 4,
+        4 => 8,
+        // AnyAcc, ByteAcc and BufferAcc
+        _ => 1,
+    }
+}
+
+fn access_pci_config(
+    bdf: BusDeviceFunction,
+    offset: usize,
+    width: usize,
+    write: Option<u64>,
+) -> Result<u64> {
+    let mcfg = global_acpi()
+        .and_then(|acpi| acpi.mcfg())
+        .ok_or("MCFG not found")?;
+    let pci = Pci::new(mcfg);
+    // The config space is accessed in dwords, and the bytes outside the
+    // field are preserved.
+    let mut result = 0;
+    let mut i = 0;
+    while i < width {
+        let aligned = (offset + i) & !3;
+        let shift = (offset + i - aligned) * 8;
+        let len = (4 - shift / 8).min(width - i);
+        let mask = if len == 4 {
+            u32::MAX
+        } else {
+            ((1u32 << (len * 8)) - 1) << shift
+        };
+        match write {
+            None => {
+                let v = pci.read_register_u32(bdf, aligned)?;
+                result |= (((v & mask) >> shift) as u64) << (i * 8);
+            }
+            Some(w) => {
+                let bits = (((w >> (i * 8)) as u32) << shift) & mask;
+                let old = if mask == u32::MAX {
+                    0
+                } else {
+                    pci.read_register_u32(bdf, aligned)?
+                };
+                pci.write_register_u32(bdf, aligned, (old & !mask) | bits)?;
+            }
+        }
+        i += len;
+    }
+    Ok(result)
+}
+
+fn stall(duration: Duration) {
+    let start = now();
+    if start.is_zero() {
+        // No clock source yet
+        return;
+    }
+    while now() - start < duration {
+        busy_loop_hint();
+    }
+}
+
+fn osi(args: &[AmlValue]) -> Result<AmlValue> {
+    let Some(AmlValue::String(s)) = args.first() else {
+        return Err("_OSI takes a String");
+    };
+    let is_supported = SUPPORTED_OSI.contains(&s.as_str());
+    Ok(AmlValue::Integer(if is_supported { u64::MAX } else { 0 }))
+}
+
+pub struct Namespace {
+    objects: BTreeMap<String, AmlObject>,
+    /// Integers are 32-bit if the DSDT revision is less than 2.
+    is_32bit: bool,
+}
+impl Namespace {
+    pub fn new(dsdt_revision: u8) -> Self {
+        let mut objects = BTreeMap::new();
+        for scope in ["\\_GPE", "\\_PR_", "\\_SB_", "\\_SI_", "\\_TZ_"] {
+            objects.insert(scope.to_string(), AmlObject::Scope);
+        }
+        objects.insert("\\_GL_".to_string(), AmlObject::Mutex);
+        objects.insert(
+            "\\_OS_".to_string(),
+            AmlObject::Value(AmlValue::String(
+                "Microsoft Windows NT".to_string(),
+            )),
+        );
+        objects.insert(
+            "\\_REV".to_string(),
+            AmlObject::Value(AmlValue::Integer(INTERPRETER_REVISION)),
+        );
+        objects.insert(
+            "\\_OSI".to_string(),
+            AmlObject::NativeMethod {
+                arg_count: 1,
+                func: osi,
+            },
+        );
+        Self {
+            objects,
+            is_32bit: dsdt_revision < 2,
+        }
+    }
+    pub fn num_objects(&self) -> usize {
+        self.objects.len()
+    }
+    /// Loads a DSDT or an SSDT by executing its top-level terms.
+    pub fn load(&mut self, aml: &'static [u8]) -> Result<()> {
+        let mut frame = Frame::new("\\", Vec::new(), 0);
+        match self.exec_term_list(aml, &mut frame)? {
+            Flow::Next => Ok(()),
+            _ => Err("Unexpected control flow at the top level of AML"),
+        }
+    }
+    /// Evaluates the object at `path`, e.g. `\_SB.PCI0._PRT`. Methods are
+    /// called with `args`.
+    pub fn evaluate(
+        &mut self,
+        path: &str,
+        args: Vec<AmlValue>,
+    ) -> Result<AmlValue> {
+        let path = self.follow_alias(parse_path(path)?);
+        match self.objects.get(&path) {
+            Some(AmlObject::Method(_) | AmlObject::NativeMethod { .. }) => {
+                self.call(&path, args, 0)
+            }
+            Some(_) => {
+                self.read_named(&path, &mut Frame::new("\\", Vec::new(), 0))
+            }
+            None => Err("AML name not found"),
+        }
+    }
+    /// Writes the objects in the namespace as a tree.
+    pub fn dump(&self, w: &mut dyn fmt::Write) -> Result<()> {
+        for (path, object) in &self.objects {
+            let depth = path.matches('.').count();
+            let name = &path[path.len() - 4..];
+            writeln!(w, "{:indent$}{name} {object}", "", indent = depth * 2)
+                .or(Err("Failed to write"))?;
+        }
+        Ok(())
+    }
+
+    fn ones(&self) -> u64 {
+        if self.is_32bit {
+            u32::MAX as u64
+        } else {
+            u64::MAX
+        }
+    }
+    fn integer(&self, v: u64) -> AmlValue {
+        AmlValue::Integer(v & self.ones())
+    }
+    fn boolean(&self, b: bool) -> AmlValue {
+        self.integer(if b { u64::MAX } else { 0 })
+    }
+    fn exists(&self, path: &str) -> bool {
+        path == "\\" || self.objects.contains_key(path)
+    }
+    fn follow_alias(&self, mut path: String) -> String {
+        for _ in 0..MAX_ALIAS_DEPTH {
+            match self.objects.get(&path) {
+                Some(AmlObject::Alias(target)) => path = target.clone(),
+                _ => break,
+            }
+        }
+        path
+    }
+    /// Finds an existing object. A single NameSeg is searched in the scope
+    /// and then in its parents.
+    fn resolve(&self, scope: &str, name: &NameString) -> Option<String> {
+        if !name.is_absolute && name.num_parents == 0 && name.segs.len() == 1 {
+            let mut scope = scope;
+            loop {
+                let path = join_path(scope, &name.segs[0]);
+                if self.exists(&path) {
+                    return Some(self.follow_alias(path));
+                }
+                scope = parent_path(scope)?;
+            }
+        }
+        let path = resolve_new(scope, name).ok()?;
+        self.exists(&path).then(|| self.follow_alias(path))
+    }
+    fn resolve_or_new(&self, scope: &str, name: &NameString) -> Result<String> {
+        match self.resolve(scope, name) {
+            Some(path) => Ok(path),
+            None => resolve_new(scope, name),
+        }
+    }
+    fn define(&mut self, path: String, object: AmlObject, frame: &mut Frame) {
+        if frame.is_method() {
+            frame.created.push(path.clone());
+        }
+        self.objects.insert(path, object);
+    }
+
+    fn call(
+        &mut self,
+        path: &str,
+        args: Vec<AmlValue>,
+        depth: usize,
+    ) -> Result<AmlValue> {
+        if depth >= MAX_CALL_DEPTH {
+            return Err("AML method calls are nested too deeply");
+        }
+        let method = match self.objects.get(path) {
+            Some(AmlObject::Method(m)) => m.clone(),
+            Some(AmlObject::NativeMethod { func, .. }) => {
+                return func(&args).map(|v| match v {
+                    AmlValue::Integer(v) => self.integer(v),
+                    v => v,
+                });
+            }
+            _ => return Err("AML object is not a method"),
+        };
+        let mut frame = Frame::new(path, args, depth + 1);
+        let result = self.exec_term_list(method.body, &mut frame);
+        let result = match result {
+            Ok(Flow::Return(AmlValue::Reference(r)))
+                if !matches!(r.location, Location::Name(_)) =>
+            {
+                // The locals and the args go away with the frame.
+                self.read_reference(&r, &mut frame)
+            }
+            Ok(Flow::Return(v)) => Ok(v),
+            Ok(_) => Ok(AmlValue::Uninitialized),
+            Err(e) => Err(e),
+        };
+        for path in &frame.created {
+            self.objects.remove(path);
+        }
+        result
+    }
+
+    fn exec_term_list(
+        &mut self,
+        aml: &'static [u8],
+        frame: &mut Frame,
+    ) -> Result<Flow> {
+        let mut c = Cursor::new(aml);
+        while !c.is_at_end() {
+            match self.exec_term(&mut c, frame)? {
+                Flow::Next => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+    /// Executes the body of a Scope, a Device and so on. While loading a
+    /// table, an error in it is reported and the rest of the table is
+    /// loaded.
+    fn exec_scope(
+        &mut self,
+        path: String,
+        body: &'static [u8],
+        frame: &mut Frame,
+    ) -> Result<Flow> {
+        let scope = replace(&mut frame.scope, path);
+        let result = self.exec_term_list(body, frame);
+        let path = replace(&mut frame.scope, scope);
+        match result {
+            Err(e) if !frame.is_method() => {
+                warn!("aml: {e} in {path}");
+                Ok(Flow::Next)
+            }
+            result => result,
+        }
+    }
+    fn exec_term(&mut self, c: &mut Cursor, frame: &mut Frame) -> Result<Flow> {
+        match c.peek()? {
+            NAME_OP => {
+                c.byte()?;
+                let name = c.name_string()?;
+                let value = self.eval(c, frame)?;
+                let path = resolve_new(&frame.scope, &name)?;
+                self.define(path, AmlObject::Value(value), frame);
+            }
+            SCOPE_OP => {
+                c.byte()?;
+                let end = c.pkg_end()?;
+                let name = c.name_string()?;
+                let body = c.slice_to(end)?;
+                let path = match self.resolve(&frame.scope, &name) {
+                    Some(path) => path,
+                    None => {
+                        let path = resolve_new(&frame.scope, &name)?;
+                        self.define(path.clone(), AmlObject::Scope, frame);
+                        path
+                    }
+                };
+                return self.exec_scope(path, body, frame);
+            }
+            METHOD_OP => {
+                c.byte()?;
+                let end = c.pkg_end()?;
+                let name = c.name_string()?;
+                let flags = c.byte()?;
+                let method = AmlMethod {
+                    body: c.slice_to(end)?,
+                    arg_count: (flags & 0b111) as usize,
+                };
+                let path = resolve_new(&frame.scope, &name)?;
+                self.define(path, AmlObject::Method(method), frame);
+            }
+            ALIAS_OP => {
+                c.byte()?;
+                let source = c.name_string()?;
+                let alias = c.name_string()?;
+                let source = self.resolve_or_new(&frame.scope, &source)?;
+                let path = resolve_new(&frame.scope, &alias)?;
+                self.define(path, AmlObject::Alias(source), frame);
+            }
+            EXTERNAL_OP => {
+                c.byte()?;
+                c.name_string()?;
+                // ObjectType and ArgumentCount
+                c.bytes(2)?;
+            }
+            IF_OP => return self.exec_if(c, frame),
+            ELSE_OP => {
+                // The Else of an If that has been handled
+                c.byte()?;
+                c.pos = c.pkg_end()?;
+            }
+            WHILE_OP => return self.exec_while(c, frame),
+            RETURN_OP => {
+                c.byte()?;
+                return Ok(Flow::Return(self.eval(c, frame)?));
+            }
+            BREAK_OP => {
+                c.byte()?;
+                return Ok(Flow::Break);
+            }
+            CONTINUE_OP => {
+                c.byte()?;
+                return Ok(Flow::Continue);
+            }
+            NOOP_OP | BREAK_POINT_OP => {
+                c.byte()?;
+            }
+            NOTIFY_OP => {
+                c.byte()?;
+                let target = self.parse_target(c, frame)?;
+                let value = self.eval_integer(c, frame)?;
+                if let Target::Ref(r) = target {
+                    info!("aml: Notify({r}, {value:#X})");
+                }
+            }
+            CREATE_BIT_FIELD_OP
+            | CREATE_BYTE_FIELD_OP
+            | CREATE_WORD_FIELD_OP
+            | CREATE_DWORD_FIELD_OP
+            | CREATE_QWORD_FIELD_OP => self.exec_create_field(c, frame)?,
+            EXT_OP_PREFIX => return self.exec_ext_term(c, frame),
+            _ => {
+                self.eval(c, frame)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+    fn exec_ext_term(
+        &mut self,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<Flow> {
+        let op = c.peek_at(1).ok_or("Unexpected end of AML")?;
+        match op {
+            EXT_MUTEX_OP | EXT_EVENT_OP => {
+                c.pos += 2;
+                let name = c.name_string()?;
+                let object = if op == EXT_MUTEX_OP {
+                    // SyncFlags
+                    c.byte()?;
+                    AmlObject::Mutex
+                } else {
+                    AmlObject::Event
+                };
+                let path = resolve_new(&frame.scope, &name)?;
+                self.define(path, object, frame);
+            }
+            EXT_OP_REGION_OP => {
+                c.pos += 2;
+                let name = c.name_string()?;
+                let space = c.byte()?;
+                let offset = self.eval_integer(c, frame)?;
+                let length = self.eval_integer(c, frame)?;
+                let region = OperationRegion {
+                    space,
+                    offset,
+                    length,
+                };
+                let path = resolve_new(&frame.scope, &name)?;
+                self.define(path, AmlObject::OperationRegion(region), frame);
+            }
+            EXT_FIELD_OP | EXT_INDEX_FIELD_OP | EXT_BANK_FIELD_OP => {
+                c.pos += 2;
+                self.exec_field(op, c, frame)?;
+            }
+            EXT_DEVICE_OP | EXT_PROCESSOR_OP | EXT_POWER_RES_OP
+            | EXT_THERMAL_ZONE_OP => {
+                c.pos += 2;
+                let end = c.pkg_end()?;
+                let name = c.name_string()?;
+                let object = match op {
+                    EXT_DEVICE_OP => AmlObject::Device,
+                    EXT_PROCESSOR_OP => {
+                        // ProcID, PblkAddr and PblkLen
+                        c.bytes(6)?;
+                        AmlObject::Processor
+                    }
+                    EXT_POWER_RES_OP => {
+                        // SystemLevel and ResourceOrder
+                        c.bytes(3)?;
+                        AmlObject::PowerResource
+                    }
+                    _ => AmlObject::ThermalZone,
+                };
+                let body = c.slice_to(end)?;
+                let path = resolve_new(&frame.scope, &name)?;
+                self.define(path.clone(), object, frame);
+                return self.exec_scope(path, body, frame);
+            }
+            EXT_CREATE_FIELD_OP => self.exec_create_field(c, frame)?,
+            EXT_SLEEP_OP | EXT_STALL_OP => {
+                c.pos += 2;
+                let t = self.eval_integer(c, frame)?;
+                stall(if op == EXT_SLEEP_OP {
+                    Duration::from_millis(t)
+                } else {
+                    Duration::from_micros(t)
+                });
+            }
+            EXT_RELEASE_OP | EXT_SIGNAL_OP | EXT_RESET_OP => {
+                c.pos += 2;
+                self.parse_target(c, frame)?;
+            }
+            EXT_FATAL_OP => {
+                c.pos += 2;
+                // FatalType and FatalCode
+                c.bytes(5)?;
+                self.eval(c, frame)?;
+                return Err("AML Fatal");
+            }
+            _ => {
+                self.eval(c, frame)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+    fn exec_if(&mut self, c: &mut Cursor, frame: &mut Frame) -> Result<Flow> {
+        c.byte()?;
+        let end = c.pkg_end()?;
+        let predicate = self.eval_integer(c, frame)? != 0;
+        let body = c.slice_to(end)?;
+        let else_body = if c.peek().ok() == Some(ELSE_OP) {
+            c.byte()?;
+            let end = c.pkg_end()?;
+            Some(c.slice_to(end)?)
+        } else {
+            None
+        };
+        match (predicate, else_body) {
+            (true, _) => self.exec_term_list(body, frame),
+            (false, Some(body)) => self.exec_term_list(body, frame),
+            (false, None) => Ok(Flow::Next),
+        }
+    }
+    fn exec_while(
+        &mut self,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<Flow> {
+        c.byte()?;
+        let end = c.pkg_end()?;
+        let predicate = c.pos;
+        for _ in 0..MAX_LOOP_ITERATIONS {
+            c.pos = predicate;
+            if self.eval_integer(c, frame)? == 0 {
+                c.pos = end;
+                return Ok(Flow::Next);
+            }
+            let body = c.slice_to(end)?;
+            match self.exec_term_list(body, frame)? {
+                Flow::Next | Flow::Continue => continue,
+                Flow::Break => {
+                    c.pos = end;
+                    return Ok(Flow::Next);
+                }
+                flow => return Ok(flow),
+            }
+        }
+        Err("AML While loop iteration limit exceeded")
+    }
+    fn exec_field(
+        &mut self,
+        op: u8,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<()> {
+        let end = c.pkg_end()?;
+        let name = c.name_string()?;
+        let first = self.resolve_or_new(&frame.scope, &name)?;
+        let kind = match op {
+            EXT_FIELD_OP => FieldKind::Region(first),
+            EXT_INDEX_FIELD_OP => {
+                let data = c.name_string()?;
+                FieldKind::Index {
+                    index: first,
+                    data: self.resolve_or_new(&frame.scope, &data)?,
+                }
+            }
+            _ => {
+                let bank = c.name_string()?;
+                FieldKind::Bank {
+                    region: first,
+                    bank: self.resolve_or_new(&frame.scope, &bank)?,
+                    value: self.eval_integer(c, frame)?,
+                }
+            }
+        };
+        let flags = c.byte()?;
+        let mut access = access_bytes(flags);
+        let update_rule = (flags >> 5) & 0b11;
+        let mut bit_offset = 0;
+        while c.pos < end {
+            match c.peek()? {
+                RESERVED_FIELD => {
+                    c.byte()?;
+                    bit_offset += c.pkg_length()?;
+                }
+                ACCESS_FIELD => {
+                    c.byte()?;
+                    access = access_bytes(c.byte()?);
+                    // AccessAttrib
+                    c.byte()?;
+                }
+                CONNECT_FIELD => {
+                    c.byte()?;
+                    if c.peek()? == BUFFER_OP {
+                        c.byte()?;
+                        c.pos = c.pkg_end()?;
+                    } else {
+                        c.name_string()?;
+                    }
+                }
+                EXTENDED_ACCESS_FIELD => {
+                    c.byte()?;
+                    access = access_bytes(c.byte()?);
+                    // ExtendedAccessAttrib and AccessLength
+                    c.bytes(2)?;
+                }
+                _ => {
+                    let seg = c.name_seg()?;
+                    let bit_length = c.pkg_length()?;
+                    let field = FieldUnit {
+                        kind: kind.clone(),
+                        bit_offset,
+                        bit_length,
+                        access_bytes: access,
+                        update_rule,
+                    };
+                    let path = join_path(&frame.scope, &seg);
+                    self.define(path, AmlObject::Field(field), frame);
+                    bit_offset += bit_length;
+                }
+            }
+        }
+        c.pos = end;
+        Ok(())
+    }
+    fn exec_create_field(
+        &mut self,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<()> {
+        let mut op = c.byte()?;
+        if op == EXT_OP_PREFIX {
+            op = c.byte()?;
+        }
+        let location = self
+            .parse_location(c, frame)?
+            .ok_or("Unsupported AML buffer field source")?;
+        let index = self.eval_integer(c, frame)? as usize;
+        let byte_index_in_bits = || {
+            index
+                .checked_mul(8)
+                .ok_or("AML buffer field is out of range")
+        };
+        let (bit_offset, bit_length) = match op {
+            CREATE_BIT_FIELD_OP => (index, 1),
+            CREATE_BYTE_FIELD_OP => (byte_index_in_bits()?, 8),
+            CREATE_WORD_FIELD_OP => (byte_index_in_bits()?, 16),
+            CREATE_DWORD_FIELD_OP => (byte_index_in_bits()?, 32),
+            CREATE_QWORD_FIELD_OP => (byte_index_in_bits()?, 64),
+            // CreateField
+            _ => (index, self.eval_integer(c, frame)? as usize),
+        };
+        let AmlValue::Buffer(buf) =
+            value_at_mut(&mut self.objects, frame, &location)?
+        else {
+            return Err("AML buffer field source is not a Buffer");
+        };
+        if bit_length == 0
+            || !bit_offset
+                .checked_add(bit_length)
+                .is_some_and(|end| end <= buf.len() * 8)
+        {
+            return Err("AML buffer field is out of range");
+        }
+        let name = c.name_string()?;
+        let field = FieldUnit {
+            kind: FieldKind::Buffer(location),
+            bit_offset,
+            bit_length,
+            access_bytes: 1,
+            update_rule: 0,
+        };
+        let path = resolve_new(&frame.scope, &name)?;
+        self.define(path, AmlObject::Field(field), frame);
+        Ok(())
+    }
+
+    fn eval_integer(
+        &mut self,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<u64> {
+        self.eval(c, frame)?.as_integer()
+    }
+    /// Evaluates a TermArg.
+    fn eval(&mut self, c: &mut Cursor, frame: &mut Frame) -> Result<AmlValue> {
+        let op = c.peek()?;
+        if is_name_lead(op) {
+            let name = c.name_string()?;
+            return self.eval_name(&name, c, frame);
+        }
+        c.byte()?;
+        let value = match op {
+            ZERO_OP => AmlValue::Integer(0),
+            ONE_OP => AmlValue::Integer(1),
+            ONES_OP => AmlValue::Integer(self.ones()),
+            BYTE_PREFIX => AmlValue::Integer(c.integer(1)?),
+            WORD_PREFIX => AmlValue::Integer(c.integer(2)?),
+            DWORD_PREFIX => AmlValue::Integer(c.integer(4)?),
+            QWORD_PREFIX => AmlValue::Integer(c.integer(8)?),
+            STRING_PREFIX => {
+                let mut s = String::new();
+                loop {
+                    match c.byte()? {
+                        0 => break,
+                        ch => s.push(ch as char),
+                    }
+                }
+                AmlValue::String(s)
+            }
+            BUFFER_OP => {
+                let end = c.pkg_end()?;
+                let size = self.eval_integer(c, frame)? as usize;
+                if size > MAX_BUFFER_SIZE {
+                    return Err("AML Buffer is too large");
+                }
+                let initializer = c.slice_to(end)?;
+                let mut buf = initializer.to_vec();
+                buf.resize(size.max(initializer.len()), 0);
+                AmlValue::Buffer(buf)
+            }
+            PACKAGE_OP | VAR_PACKAGE_OP => {
+                let end = c.pkg_end()?;
+                let num_elements = if op == PACKAGE_OP {
+                    c.byte()? as usize
+                } else {
+                    self.eval_integer(c, frame)? as usize
+                };
+                if num_elements > MAX_PACKAGE_ELEMENTS {
+                    return Err("AML Package is too large");
+                }
+                let mut elements = Vec::new();
+                while c.pos < end {
+                    elements.push(self.eval_package_element(c, frame)?);
+                }
+                if elements.len() < num_elements {
+                    elements.resize(num_elements, AmlValue::Uninitialized);
+                }
+                AmlValue::Package(elements)
+            }
+            LOCAL0_OP..=LOCAL7_OP => {
+                frame.locals[(op - LOCAL0_OP) as usize].clone()
+            }
+            ARG0_OP..=ARG6_OP => {
+                match frame.args[(op - ARG0_OP) as usize].clone() {
+                    // Args that have references are dereferenced.
+                    AmlValue::Reference(r) => self.read_reference(&r, frame)?,
+                    v => v,
+                }
+            }
+            STORE_OP | COPY_OBJECT_OP => {
+                let value = self.eval(c, frame)?;
+                let target = self.parse_target(c, frame)?;
+                self.store(&target, value.clone(), frame, op == STORE_OP)?;
+                value
+            }
+            ADD_OP | SUBTRACT_OP | MULTIPLY_OP | SHIFT_LEFT_OP
+            | SHIFT_RIGHT_OP | AND_OP | NAND_OP | OR_OP | NOR_OP | XOR_OP
+            | MOD_OP => {
+                let a = self.eval_integer(c, frame)?;
+                let b = self.eval_integer(c, frame)?;
+                let v = match op {
+                    ADD_OP => a.wrapping_add(b),
+                    SUBTRACT_OP => a.wrapping_sub(b),
+                    MULTIPLY_OP => a.wrapping_mul(b),
+                    SHIFT_LEFT_OP => a.checked_shl(b as u32).unwrap_or(0),
+                    SHIFT_RIGHT_OP => a.checked_shr(b as u32).unwrap_or(0),
+                    AND_OP => a & b,
+                    NAND_OP => !(a & b),
+                    OR_OP => a | b,
+                    NOR_OP => !(a | b),
+                    XOR_OP => a ^ b,
+                    _ => a.checked_rem(b).ok_or("AML division by zero")?,
+                };
+                self.store_result(c, frame, self.integer(v))?
+            }
+            DIVIDE_OP => {
+                let a = self.eval_integer(c, frame)?;
+                let b = self.eval_integer(c, frame)?;
+                if b == 0 {
+                    return Err("AML division by zero");
+                }
+                let remainder = self.parse_target(c, frame)?;
+                self.store(&remainder, AmlValue::Integer(a % b), frame, true)?;
+                self.store_result(c, frame, AmlValue::Integer(a / b))?
+            }
+            NOT_OP | FIND_SET_LEFT_BIT_OP | FIND_SET_RIGHT_BIT_OP => {
+                let a = self.eval_integer(c, frame)?;
+                let v = match op {
+                    NOT_OP => !a,
+                    _ if a == 0 => 0,
+                    FIND_SET_LEFT_BIT_OP => 64 - a.leading_zeros() as u64,
+                    _ => a.trailing_zeros() as u64 + 1,
+                };
+                self.store_result(c, frame, self.integer(v))?
+            }
+            INCREMENT_OP | DECREMENT_OP => {
+                let target = self.parse_target(c, frame)?;
+                let v = self.read_target(&target, frame)?.as_integer()?;
+                let v = if op == INCREMENT_OP {
+                    self.integer(v.wrapping_add(1))
+                } else {
+                    self.integer(v.wrapping_sub(1))
+                };
+                self.store(&target, v.clone(), frame, true)?;
+                v
+            }
+            LAND_OP | LOR_OP => {
+                let a = self.eval_integer(c, frame)? != 0;
+                let b = self.eval_integer(c, frame)? != 0;
+                self.boolean(if op == LAND_OP { a && b } else { a || b })
+            }
+            LNOT_OP => {
+                // LNotEqual and so on are LNot of LEqual and so on.
+                let a = self.eval_integer(c, frame)?;
+                self.boolean(a == 0)
+            }
+            LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+                let a = self.eval(c, frame)?;
+                let b = self.eval(c, frame)?;
+                let ordering = compare(&a, &b)?;
+                self.boolean(
+                    ordering
+                        == match op {
+                            LEQUAL_OP => Ordering::Equal,
+                            LGREATER_OP => Ordering::Greater,
+                            _ => Ordering::Less,
+                        },
+                )
+            }
+            REF_OF_OP => match self.parse_target(c, frame)? {
+                Target::Ref(r) => AmlValue::Reference(r),
+                _ => return Err("RefOf needs an object"),
+            },
+            DEREF_OF_OP => match self.eval(c, frame)? {
+                AmlValue::Reference(r) => self.read_reference(&r, frame)?,
+                AmlValue::String(path) => {
+                    let path = self.follow_alias(parse_path(&path)?);
+                    self.read_named(&path, frame)?
+                }
+                v => v,
+            },
+            INDEX_OP => {
+                let value = match self.parse_location(c, frame)? {
+                    Some(location) => {
+                        let index = self.eval_integer(c, frame)? as usize;
+                        AmlValue::Reference(Reference {
+                            location,
+                            index: Some(index),
+                        })
+                    }
+                    None => {
+                        // A temporary object can not be referred to later,
+                        // so take the element now.
+                        let source = self.eval(c, frame)?;
+                        let index = self.eval_integer(c, frame)? as usize;
+                        source.element(index)?
+                    }
+                };
+                self.store_result(c, frame, value)?
+            }
+            SIZE_OF_OP => {
+                let target = self.parse_target(c, frame)?;
+                AmlValue::Integer(match self.read_target(&target, frame)? {
+                    AmlValue::String(s) => s.chars().count(),
+                    AmlValue::Buffer(b) => b.len(),
+                    AmlValue::Package(p) => p.len(),
+                    _ => {
+                        return Err("SizeOf needs a String, Buffer or Package")
+                    }
+                } as u64)
+            }
+            OBJECT_TYPE_OP => {
+                let target = self.parse_target(c, frame)?;
+                AmlValue::Integer(self.object_type(&target, frame)?)
+            }
+            CONCAT_OP => {
+                let a = self.eval(c, frame)?;
+                let b = self.eval(c, frame)?;
+                let v = match a {
+                    AmlValue::Integer(a) => {
+                        let width = if self.is_32bit { 4 } else { 8 };
+                        let mut buf = a.to_le_bytes()[..width].to_vec();
+                        buf.extend_from_slice(
+                            &b.as_integer()?.to_le_bytes()[..width],
+                        );
+                        AmlValue::Buffer(buf)
+                    }
+                    AmlValue::String(a) => {
+                        AmlValue::String(a + &b.to_aml_string()?)
+                    }
+                    AmlValue::Buffer(mut a) => {
+                        a.extend(b.to_buffer()?);
+                        AmlValue::Buffer(a)
+                    }
+                    _ => return Err("Concatenate needs data objects"),
+                };
+                self.store_result(c, frame, v)?
+            }
+            CONCAT_RES_OP => {
+                let mut a = self.eval(c, frame)?.to_buffer()?;
+                let b = self.eval(c, frame)?.to_buffer()?;
+                // Drop the End Tag of the first resource template.
+                if a.len() >= 2 && a[a.len() - 2] == 0x79 {
+                    a.truncate(a.len() - 2);
+                }
+                a.extend(b);
+                self.store_result(c, frame, AmlValue::Buffer(a))?
+            }
+            TO_BUFFER_OP => {
+                let v = self.eval(c, frame)?.to_buffer()?;
+                self.store_result(c, frame, AmlValue::Buffer(v))?
+            }
+            TO_INTEGER_OP => {
+                let v = match self.eval(c, frame)? {
+                    AmlValue::String(s) => match s.strip_prefix("0x") {
+                        Some(_) => parse_hex(&s),
+                        None => s
+                            .chars()
+                            .map_while(|c| c.to_digit(10))
+                            .fold(0u64, |v, d| {
+                                v.wrapping_mul(10).wrapping_add(d as u64)
+                            }),
+                    },
+                    v => v.as_integer()?,
+                };
+                self.store_result(c, frame, self.integer(v))?
+            }
+            TO_DECIMAL_STRING_OP | TO_HEX_STRING_OP => {
+                let is_hex = op == TO_HEX_STRING_OP;
+                let s = match self.eval(c, frame)? {
+                    AmlValue::Integer(v) if is_hex => format!("{v:X}"),
+                    AmlValue::Integer(v) => format!("{v}"),
+                    AmlValue::Buffer(b) => b
+                        .iter()
+                        .map(|e| {
+                            if is_hex {
+                                format!("0x{e:02X}")
+                            } else {
+                                format!("{e}")
+                            }
+                        })
+                        .collect::<Vec<String>>()
+                        .join(","),
+                    AmlValue::String(s) => s,
+                    _ => return Err("AML value can not be converted"),
+                };
+                self.store_result(c, frame, AmlValue::String(s))?
+            }
+            TO_STRING_OP => {
+                let buf = self.eval(c, frame)?.to_buffer()?;
+                let len = self.eval_integer(c, frame)? as usize;
+                let s = buf
+                    .iter()
+                    .take(len)
+                    .take_while(|e| **e != 0)
+                    .map(|e| *e as char)
+                    .collect();
+                self.store_result(c, frame, AmlValue::String(s))?
+            }
+            MID_OP => {
+                let source = self.eval(c, frame)?;
+                let index = self.eval_integer(c, frame)? as usize;
+                let len = self.eval_integer(c, frame)? as usize;
+                let v = match source {
+                    AmlValue::String(s) => AmlValue::String(
+                        s.chars().skip(index).take(len).collect(),
+                    ),
+                    AmlValue::Buffer(b) => AmlValue::Buffer(
+                        b.into_iter().skip(index).take(len).collect(),
+                    ),
+                    _ => return Err("Mid needs a String or a Buffer"),
+                };
+                self.store_result(c, frame, v)?
+            }
+            MATCH_OP => self.eval_match(c, frame)?,
+            EXT_OP_PREFIX => self.eval_ext(c, frame)?,
+            _ => return Err("Unknown AML opcode"),
+        };
+        Ok(value)
+    }
+    fn eval_ext(
+        &mut self,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<AmlValue> {
+        let value = match c.byte()? {
+            EXT_COND_REF_OF_OP => {
+                let source = self.parse_super_name(c, frame)?;
+                let target = self.parse_target(c, frame)?;
+                match source {
+                    Some(Target::Ref(r)) => {
+                        self.store(
+                            &target,
+                            AmlValue::Reference(r),
+                            frame,
+                            true,
+                        )?;
+                        self.boolean(true)
+                    }
+                    _ => self.boolean(false),
+                }
+            }
+            EXT_ACQUIRE_OP => {
+                self.parse_target(c, frame)?;
+                // Timeout
+                c.integer(2)?;
+                // Acquired
+                self.boolean(false)
+            }
+            EXT_WAIT_OP => {
+                self.parse_target(c, frame)?;
+                self.eval_integer(c, frame)?;
+                // Signaled
+                self.boolean(false)
+            }
+            op @ (EXT_FROM_BCD_OP | EXT_TO_BCD_OP) => {
+                let mut a = self.eval_integer(c, frame)?;
+                let mut v = 0;
+                let mut shift = 0;
+                while a != 0 && shift < 64 {
+                    if op == EXT_FROM_BCD_OP {
+                        v += (a & 0xF) * 10u64.pow(shift / 4);
+                        a >>= 4;
+                    } else {
+                        v |= (a % 10) << shift;
+                        a /= 10;
+                    }
+                    shift += 4;
+                }
+                self.store_result(c, frame, self.integer(v))?
+            }
+            EXT_TIMER_OP => {
+                // In 100 ns units
+                AmlValue::Integer((now().as_nanos() / 100) as u64)
+            }
+            EXT_REVISION_OP => AmlValue::Integer(INTERPRETER_REVISION),
+            _ => return Err("Unknown AML opcode"),
+        };
+        Ok(value)
+    }
+    fn eval_match(
+        &mut self,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<AmlValue> {
+        let AmlValue::Package(package) = self.eval(c, frame)? else {
+            return Err("Match needs a Package");
+        };
+        let op1 = c.byte()?;
+        let obj1 = self.eval(c, frame)?;
+        let op2 = c.byte()?;
+        let obj2 = self.eval(c, frame)?;
+        let start = self.eval_integer(c, frame)? as usize;
+        let is_match = |op: u8, e: &AmlValue, obj: &AmlValue| {
+            if op == 0 {
+                // MTR
+                return true;
+            }
+            let Ok(ordering) = compare(e, obj) else {
+                return false;
+            };
+            match op {
+                1 => ordering.is_eq(),
+                2 => ordering.is_le(),
+                3 => ordering.is_lt(),
+                4 => ordering.is_ge(),
+                5 => ordering.is_gt(),
+                _ => false,
+            }
+        };
+        let index =
+            package.iter().enumerate().skip(start).find_map(|(i, e)| {
+                (is_match(op1, e, &obj1) && is_match(op2, e, &obj2))
+                    .then_some(i)
+            });
+        Ok(match index {
+            Some(i) => AmlValue::Integer(i as u64),
+            None => AmlValue::Integer(self.ones()),
+        })
+    }
+    fn eval_name(
+        &mut self,
+        name: &NameString,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<AmlValue> {
+        let path = self
+            .resolve(&frame.scope, name)
+            .ok_or("AML name not found")?;
+        let arg_count = match self.objects.get(&path) {
+            Some(AmlObject::Method(m)) => m.arg_count,
+            Some(AmlObject::NativeMethod { arg_count, .. }) => *arg_count,
+            _ => return self.read_named(&path, frame),
+        };
+        let mut args = Vec::new();
+        for _ in 0..arg_count {
+            args.push(self.eval(c, frame)?);
+        }
+        self.call(&path, args, frame.depth)
+    }
+    fn eval_package_element(
+        &mut self,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<AmlValue> {
+        if !is_name_lead(c.peek()?) {
+            return self.eval(c, frame);
+        }
+        // Names in a package are references, which may be resolved later.
+        let name = c.name_string()?;
+        let path = self
+            .resolve(&frame.scope, &name)
+            .unwrap_or_else(|| name.to_string());
+        Ok(AmlValue::Reference(Reference::to(Location::Name(path))))
+    }
+    /// Parses a target operand and stores `value` to it.
+    fn store_result(
+        &mut self,
+        c: &mut Cursor,
+        frame: &mut Frame,
+        value: AmlValue,
+    ) -> Result<AmlValue> {
+        let target = self.parse_target(c, frame)?;
+        self.store(&target, value.clone(), frame, true)?;
+        Ok(value)
+    }
+
+    /// Parses a name, a local or an arg that has a data object, without
+    /// consuming anything if the operand is not one of them.
+    fn parse_location(
+        &mut self,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<Option<Location>> {
+        let op = c.peek()?;
+        let location = match op {
+            LOCAL0_OP..=LOCAL7_OP => Location::Local((op - LOCAL0_OP) as usize),
+            ARG0_OP..=ARG6_OP => {
+                let i = (op - ARG0_OP) as usize;
+                match &frame.args[i] {
+                    AmlValue::Reference(r) if r.index.is_none() => {
+                        r.location.clone()
+                    }
+                    _ => Location::Arg(i),
+                }
+            }
+            op if is_name_lead(op) => {
+                let pos = c.pos;
+                let name = c.name_string()?;
+                match self.resolve(&frame.scope, &name) {
+                    Some(path)
+                        if matches!(
+                            self.objects.get(&path),
+                            Some(AmlObject::Value(_))
+                        ) =>
+                    {
+                        return Ok(Some(Location::Name(path)));
+                    }
+                    _ => {
+                        c.pos = pos;
+                        return Ok(None);
+                    }
+                }
+            }
+            _ => return Ok(None),
+        };
+        c.byte()?;
+        Ok(Some(location))
+    }
+    /// Parses a SuperName. Returns None if it is a name that does not
+    /// exist.
+    fn parse_super_name(
+        &mut self,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<Option<Target>> {
+        let op = c.peek()?;
+        if is_name_lead(op) {
+            let name = c.name_string()?;
+            return Ok(self
+                .resolve(&frame.scope, &name)
+                .map(|path| Target::Ref(Reference::to(Location::Name(path)))));
+        }
+        match op {
+            ZERO_OP => {
+                c.byte()?;
+                Ok(Some(Target::Null))
+            }
+            LOCAL0_OP..=LOCAL7_OP | ARG0_OP..=ARG6_OP => {
+                let location =
+                    self.parse_location(c, frame)?.ok_or("Bad AML target")?;
+                Ok(Some(Target::Ref(Reference::to(location))))
+            }
+            EXT_OP_PREFIX if c.peek_at(1) == Some(EXT_DEBUG_OP) => {
+                c.pos += 2;
+                Ok(Some(Target::Debug))
+            }
+            _ => match self.eval(c, frame)? {
+                AmlValue::Reference(r) => Ok(Some(Target::Ref(r))),
+                _ => Err("AML target is not a reference"),
+            },
+        }
+    }
+    fn parse_target(
+        &mut self,
+        c: &mut Cursor,
+        frame: &mut Frame,
+    ) -> Result<Target> {
+        self.parse_super_name(c, frame)?.ok_or("AML name not found")
+    }
+
+    fn store(
+        &mut self,
+        target: &Target,
+        value: AmlValue,
+        frame: &mut Frame,
+        convert: bool,
+    ) -> Result<()> {
+        match target {
+            Target::Null => Ok(()),
+            Target::Debug => {
+                info!("aml: Debug: {value}");
+                Ok(())
+            }
+            Target::Ref(r) => self.write_reference(r, value, frame, convert),
+        }
+    }
+    fn read_target(
+        &mut self,
+        target: &Target,
+        frame: &mut Frame,
+    ) -> Result<AmlValue> {
+        match target {
+            Target::Ref(r) => self.read_reference(r, frame),
+            _ => Err("AML target can not be read"),
+        }
+    }
+    fn object_type(
+        &mut self,
+        target: &Target,
+        frame: &mut Frame,
+    ) -> Result<u64> {
+        if let Target::Ref(Reference {
+            location: Location::Name(path),
+            index: None,
+        }) = target
+        {
+            if let Some(object) = self.objects.get(path) {
+                return Ok(object.object_type());
+            }
+        }
+        Ok(self.read_target(target, frame)?.object_type())
+    }
+    fn read_named(
+        &mut self,
+        path: &str,
+        frame: &mut Frame,
+    ) -> Result<AmlValue> {
+        match self.objects.get(path) {
+            Some(AmlObject::Value(v)) => Ok(v.clone()),
+            Some(AmlObject::Field(f)) => {
+                let f = f.clone();
+                self.read_field(&f, frame)
+            }
+            Some(AmlObject::Method(_) | AmlObject::NativeMethod { .. }) => {
+                self.call(path, Vec::new(), frame.depth)
+            }
+            Some(_) => Ok(AmlValue::Reference(Reference::to(Location::Name(
+                path.to_string(),
+            )))),
+            None => Err("AML name not found"),
+        }
+    }
+    fn write_named(
+        &mut self,
+        path: &str,
+        value: AmlValue,
+        frame: &mut Frame,
+    ) -> Result<()> {
+        let r = Reference::to(Location::Name(path.to_string()));
+        self.write_reference(&r, value, frame, true)
+    }
+    fn read_reference(
+        &mut self,
+        r: &Reference,
+        frame: &mut Frame,
+    ) -> Result<AmlValue> {
+        let value = match &r.location {
+            Location::Local(i) => frame.locals[*i].clone(),
+            Location::Arg(i) => frame.args[*i].clone(),
+            Location::Name(path) => self.read_named(path, frame)?,
+        };
+        match r.index {
+            Some(index) => value.element(index),
+            None => Ok(value),
+        }
+    }
+    fn write_reference(
+        &mut self,
+        r: &Reference,
+        value: AmlValue,
+        frame: &mut Frame,
+        convert: bool,
+    ) -> Result<()> {
+        if let (Location::Name(path), None) = (&r.location, r.index) {
+            match self.objects.get(path) {
+                Some(AmlObject::Field(f)) => {
+                    let f = f.clone();
+                    return self.write_field(&f, &value, frame);
+                }
+                Some(AmlObject::Value(_)) => {}
+                Some(_) => return Err("AML object can not be written"),
+                None => return Err("AML name not found"),
+            }
+        }
+        let dst = value_at_mut(&mut self.objects, frame, &r.location)?;
+        let Some(index) = r.index else {
+            *dst = match r.location {
+                // Locals and args are overwritten as they are.
+                Location::Name(_) if convert => convert_like(dst, value)?,
+                _ => value,
+            };
+            return Ok(());
+        };
+        let out_of_range = "AML Index is out of range";
+        match dst {
+            AmlValue::Package(p) => {
+                *p.get_mut(index).ok_or(out_of_range)? = value;
+            }
+            AmlValue::Buffer(b) => {
+                *b.get_mut(index).ok_or(out_of_range)? =
+                    value.as_integer()? as u8;
+            }
+            _ => return Err("AML Index target is not a Package or Buffer"),
+        }
+        Ok(())
+    }
+
+    fn read_field(
+        &mut self,
+        f: &FieldUnit,
+        frame: &mut Frame,
+    ) -> Result<AmlValue> {
+        if f.bit_length > MAX_BUFFER_SIZE * 8 {
+            return Err("AML field is too large");
+        }
+        let mut bytes = alloc::vec![0u8; f.bit_length.div_ceil(8)];
+        if f.bit_length > 0 {
+            let unit_bits = f.access_bytes * 8;
+            let first = f.bit_offset / unit_bits;
+            let last = (f.bit_offset + f.bit_length - 1) / unit_bits;
+            for unit in first..=last {
+                let v = self.access_field_unit(
+                    f,
+                    unit * f.access_bytes,
+                    None,
+                    frame,
+                )?;
+                for b in 0..unit_bits {
+                    let bit = unit * unit_bits + b;
+                    if bit < f.bit_offset
+                        || bit >= f.bit_offset + f.bit_length
+                        || (v >> b) & 1 == 0
+                    {
+                        continue;
+                    }
+                    let i = bit - f.bit_offset;
+                    bytes[i / 8] |= 1 << (i % 8);
+                }
+            }
+        }
+        if f.bit_length <= 64 {
+            Ok(AmlValue::Integer(AmlValue::Buffer(bytes).as_integer()?))
+        } else {
+            Ok(AmlValue::Buffer(bytes))
+        }
+    }
+    fn write_field(
+        &mut self,
+        f: &FieldUnit,
+        value: &AmlValue,
+        frame: &mut Frame,
+    ) -> Result<()> {
+        let bytes = value.to_buffer()?;
+        let bit_at = |i: usize| {
+            bytes.get(i / 8).is_some_and(|e| (e >> (i % 8)) & 1 == 1)
+        };
+        if f.bit_length == 0 {
+            return Ok(());
+        }
+        let unit_bits = f.access_bytes * 8;
+        let full_mask = u64::MAX >> (64 - unit_bits);
+        let first = f.bit_offset / unit_bits;
+        let last = (f.bit_offset + f.bit_length - 1) / unit_bits;
+        for unit in first..=last {
+            let mut mask = 0;
+            let mut bits = 0;
+            for b in 0..unit_bits {
+                let bit = unit * unit_bits + b;
+                if bit < f.bit_offset || bit >= f.bit_offset + f.bit_length {
+                    continue;
+                }
+                mask |= 1 << b;
+                if bit_at(bit - f.bit_offset) {
+                    bits |= 1 << b;
+                }
+            }
+            let offset = unit * f.access_bytes;
+            let base = if mask == full_mask {
+                0
+            } else {
+                match f.update_rule {
+                    UPDATE_RULE_WRITE_AS_ONES => full_mask,
+                    UPDATE_RULE_WRITE_AS_ZEROS => 0,
+                    _ => self.access_field_unit(f, offset, None, frame)?,
+                }
+            };
+            self.access_field_unit(
+                f,
+                offset,
+                Some((base & !mask) | bits),
+                frame,
+            )?;
+        }
+        Ok(())
+    }
+    /// Reads or writes `f.access_bytes` bytes at `byte_offset` of the
+    /// region or the buffer that the field is in.
+    fn access_field_unit(
+        &mut self,
+        f: &FieldUnit,
+        byte_offset: usize,
+        write: Option<u64>,
+        frame: &mut Frame,
+    ) -> Result<u64> {
+        let width = f.access_bytes;
+        match &f.kind {
+            FieldKind::Region(region) => {
+                self.access_region(region, byte_offset, width, write)
+            }
+            FieldKind::Bank {
+                region,
+                bank,
+                value,
+            } => {
+                self.write_named(bank, AmlValue::Integer(*value), frame)?;
+                self.access_region(region, byte_offset, width, write)
+            }
+            FieldKind::Index { index, data } => {
+                let offset = AmlValue::Integer(byte_offset as u64);
+                self.write_named(index, offset, frame)?;
+                match write {
+                    Some(v) => {
+                        self.write_named(data, AmlValue::Integer(v), frame)?;
+                        Ok(0)
+                    }
+                    None => self.read_named(data, frame)?.as_integer(),
+                }
+            }
+            FieldKind::Buffer(location) => {
+                let value = value_at_mut(&mut self.objects, frame, location)?;
+                let AmlValue::Buffer(buf) = value else {
+                    return Err("AML buffer field is not in a Buffer");
+                };
+                let buf = buf
+                    .get_mut(byte_offset..byte_offset + width)
+                    .ok_or("AML buffer field is out of range")?;
+                match write {
+                    Some(v) => {
+                        buf.copy_from_slice(&v.to_le_bytes()[..width]);
+                        Ok(0)
+                    }
+                    None => {
+                        let mut bytes = [0u8; 8];
+                        bytes[..width].copy_from_slice(buf);
+                        Ok(u64::from_le_bytes(bytes))
+                    }
+                }
+            }
+        }
+    }
+    fn access_region(
+        &mut self,
+        path: &str,
+        byte_offset: usize,
+        width: usize,
+        write: Option<u64>,
+    ) -> Result<u64> {
+        let Some(AmlObject::OperationRegion(region)) = self.objects.get(path)
+        else {
+            return Err("AML OperationRegion not found");
+        };
+        let region = *region;
+        if (byte_offset + width) as u64 > region.length {
+            return Err("AML field is out of its OperationRegion");
+        }
+        let offset = region
+            .offset
+            .checked_add(byte_offset as u64)
+            .ok_or("AML field is out of range")?;
+        match region.space {
+            REGION_SPACE_SYSTEM_MEMORY | REGION_SPACE_SYSTEM_IO => {
+                let reg = GenericAddress::new(
+                    region.space,
+                    (width * 8) as u8,
+                    offset,
+                );
+                match write {
+                    Some(v) => reg.write(v).map(|_| 0),
+                    None => reg.read(),
+                }
+            }
+            REGION_SPACE_PCI_CONFIG => {
+                let bdf = self.pci_device(path)?;
+                access_pci_config(bdf, offset as usize, width, write)
+            }
+            _ => Err("Unsupported AML OperationRegion space"),
+        }
+    }
+    /// The PCI function of the device that has the region, from its _ADR
+    /// and the _BBN of the closest parent that has it. Bridges are not
+    /// taken into account.
+    fn pci_device(&mut self, region: &str) -> Result<BusDeviceFunction> {
+        let device = parent_path(region).ok_or("Bad AML region path")?;
+        let device = device.to_string();
+        let adr_path = join_path(&device, b"_ADR");
+        let adr = if self.exists(&adr_path) {
+            self.evaluate(&adr_path, Vec::new())?.as_integer()?
+        } else {
+            0
+        };
+        let mut bus = 0;
+        let mut scope = Some(device.as_str());
+        while let Some(s) = scope {
+            let bbn_path = join_path(s, b"_BBN");
+            if self.exists(&bbn_path) {
+                bus = self.evaluate(&bbn_path, Vec::new())?.as_integer()?;
+                break;
+            }
+            scope = parent_path(s);
+        }
+        BusDeviceFunction::new(
+            bus as usize,
+            ((adr >> 16) & 0xFFFF) as usize,
+            (adr & 0xFFFF) as usize,
+        )
+    }
+}
+
+static NAMESPACE: Mutex<Option<Namespace>> = Mutex::new(None);
+
+/// Loads the DSDT and the SSDTs into the global namespace.
+pub fn load_global_namespace(acpi: &AcpiRsdpStruct) -> Result<()> {
+    let mut tables = acpi.definition_blocks();
+    let dsdt = tables.next().ok_or("DSDT not found")?;
+    let mut ns = Namespace::new(dsdt.revision());
+    for table in once(dsdt).chain(tables) {
+        if let Err(e) = ns.load(table.aml()) {
+            let signature = core::str::from_utf8(table.signature());
+            warn!("aml: Failed to load {}: {e}", signature.unwrap_or("????"));
+        }
+    }
+    info!("aml: {} objects loaded", ns.num_objects());
+    *NAMESPACE.lock() = Some(ns);
+    Ok(())
+}
+/// Evaluates the object at `path` in the global namespace.
+pub fn evaluate(path: &str, args: Vec<AmlValue>) -> Result<AmlValue> {
+    NAMESPACE
+        .lock()
+        .as_mut()
+        .ok_or("AML namespace is not loaded")?
+        .evaluate(path, args)
+}
+pub fn dump_global_namespace(w: &mut dyn fmt::Write) -> Result<()> {
+    NAMESPACE
+        .lock()
+        .as_ref()
+        .ok_or("AML namespace is not loaded")?
+        .dump(w)
+}
+
+#[cfg(test)]
+fn load_test_aml(aml: &[u8]) -> Namespace {
+    let mut ns = Namespace::new(2);
+    ns.load(alloc::boxed::Box::leak(aml.to_vec().into_boxed_slice()))
+        .unwrap();
+    ns
+}
+
+#[test_case]
+fn aml_name_and_method_test() {
+    #[rustfmt::skip]
+    let mut ns = load_test_aml(&[
+        // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero })
+        0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x07, 0x04, 0x0A, 0x05,
+        0x00, 0x00, 0x00,
+        // Scope (_SB) { Method (ADD1, 1) { Return (Add (Arg0, One)) } }
+        0x10, 0x11, b'_', b'S', b'B', b'_',
+        0x14, 0x0B, b'A', b'D', b'D', b'1', 0x01, 0xA4, 0x72, 0x68, 0x01,
+        0x00,
+    ]);
+    let s5 = ns.evaluate("\\_S5", Vec::new()).unwrap();
+    assert_eq!(s5.element(0), Ok(AmlValue::Integer(5)));
+    assert_eq!(s5.element(3), Ok(AmlValue::Integer(0)));
+    assert_eq!(
+        ns.evaluate("\\_SB.ADD1", alloc::vec![AmlValue::Integer(41)]),
+        Ok(AmlValue::Integer(42))
+    );
+    assert_eq!(
+        ns.evaluate(
+            "\\_OSI",
+            alloc::vec![AmlValue::String("Windows 2015".into())]
+        ),
+        Ok(AmlValue::Integer(u64::MAX))
+    );
+    assert!(ns.evaluate("\\NONE", Vec::new()).is_err());
+}
+
+#[test_case]
+fn aml_control_flow_test() {
+    #[rustfmt::skip]
+    let mut ns = load_test_aml(&[
+        // Method (LOOP) {
+        0x14, 0x1A, b'L', b'O', b'O', b'P', 0x00,
+        // Store (Zero, Local0), Store (Zero, Local1)
+        0x70, 0x00, 0x60, 0x70, 0x00, 0x61,
+        // While (LLess (Local0, 0x0A)) {
+        0xA2, 0x0B, 0x95, 0x60, 0x0A, 0x0A,
+        //   Add (Local1, Local0, Local1), Increment (Local0) }
+        0x72, 0x61, 0x60, 0x61, 0x75, 0x60,
+        // Return (Local1) }
+        0xA4, 0x61,
+        // Method (BUFT, 1) {
+        0x14, 0x3E, b'B', b'U', b'F', b'T', 0x01,
+        // Name (BUF0, Buffer (0x04) { 1, 2, 3, 4 })
+        0x08, b'B', b'U', b'F', b'0', 0x11, 0x07, 0x0A, 0x04, 1, 2, 3, 4,
+        // CreateWordField (BUF0, One, WRD0)
+        0x8B, b'B', b'U', b'F', b'0', 0x01, b'W', b'R', b'D', b'0',
+        // If (LEqual (Arg0, One)) { Return (WRD0) }
+        0xA0, 0x09, 0x93, 0x68, 0x01, 0xA4, b'W', b'R', b'D', b'0',
+        // Else { Store (0x55, Index (BUF0, 0x03))
+        0xA1, 0x16, 0x70, 0x0A, 0x55, 0x88, b'B', b'U', b'F', b'0', 0x0A,
+        0x03, 0x00,
+        //   Return (DerefOf (Index (BUF0, 0x03))) } }
+        0xA4, 0x83, 0x88, b'B', b'U', b'F', b'0', 0x0A, 0x03, 0x00,
+        // Method (BRKT) { Store (Zero, Local0)
+        0x14, 0x17, b'B', b'R', b'K', b'T', 0x00, 0x70, 0x00, 0x60,
+        // While (One) { Increment (Local0)
+        0xA2, 0x0B, 0x01, 0x75, 0x60,
+        //   If (LEqual (Local0, 0x03)) { Break } }
+        0xA0, 0x06, 0x93, 0x60, 0x0A, 0x03, 0xA5,
+        // Return (Local0) }
+        0xA4, 0x60,
+        // Method (BIGB) { Return (Buffer (0x20000) {}) }
+        0x14, 0x0E, b'B', b'I', b'G', b'B', 0x00,
+        0xA4, 0x11, 0x06, 0x0C, 0x00, 0x00, 0x02, 0x00,
+        // Method (BIGF) { Name (BUF1, Buffer (One) { 0 })
+        0x14, 0x1B, b'B', b'I', b'G', b'F', 0x00,
+        0x08, b'B', b'U', b'F', b'1', 0x11, 0x03, 0x01, 0x00,
+        //   CreateDWordField (BUF1, Ones, DW0) Return (One) }
+        0x8A, b'B', b'U', b'F', b'1', 0xFF, b'D', b'W', b'0', b'_',
+        0xA4, 0x01,
+        // Method (BIGL) { Name (BUF2, Buffer (One) { 0 })
+        0x14, 0x20, b'B', b'I', b'G', b'L', 0x00,
+        0x08, b'B', b'U', b'F', b'2', 0x11, 0x03, 0x01, 0x00,
+        //   CreateField (BUF2, Zero, Ones, FLDX) Return (FLDX) }
+        0x5B, 0x13, b'B', b'U', b'F', b'2', 0x00, 0xFF,
+        b'F', b'L', b'D', b'X', 0xA4, b'F', b'L', b'D', b'X',
+    ]);
+    assert_eq!(ns.evaluate("\\LOOP", Vec::new()), Ok(AmlValue::Integer(45)));
+    assert_eq!(ns.evaluate("\\BRKT", Vec::new()), Ok(AmlValue::Integer(3)));
+    let one = alloc::vec![AmlValue::Integer(1)];
+    assert_eq!(ns.evaluate("\\BUFT", one), Ok(AmlValue::Integer(0x0302)));
+    let zero = alloc::vec![AmlValue::Integer(0)];
+    assert_eq!(ns.evaluate("\\BUFT", zero), Ok(AmlValue::Integer(0x55)));
+    // Objects created by a method are gone after it returns.
+    assert!(ns.evaluate("\\BUFT.BUF0", Vec::new()).is_err());
+    assert!(ns.evaluate("\\BIGB", Vec::new()).is_err());
+    assert!(ns.evaluate("\\BIGF", Vec::new()).is_err());
+    assert!(ns.evaluate("\\BIGL", Vec::new()).is_err());
+}
+
+#[test_case]
+fn aml_memory_field_test() {
+    let mem = alloc::boxed::Box::leak(alloc::boxed::Box::new([
+        0x21u8, 0x43, 0x65, 0x87, 0xA9, 0, 0, 0,
+    ]));
+    let addr = (mem.as_ptr() as u64).to_le_bytes();
+    #[rustfmt::skip]
+    let mut aml = alloc::vec![
+        // OperationRegion (MEM0, SystemMemory, addr, 0x08)
+        0x5B, 0x80, b'M', b'E', b'M', b'0', 0x00, 0x0E,
+    ];
+    aml.extend_from_slice(&addr);
+    #[rustfmt::skip]
+    aml.extend_from_slice(&[
+        0x0A, 0x08,
+        // Field (MEM0, ByteAcc, NoLock, Preserve) {
+        //   FLD0, 4, FLD1, 12, , 8, FLD2, 16 }
+        0x5B, 0x81, 0x17, b'M', b'E', b'M', b'0', 0x01,
+        b'F', b'L', b'D', b'0', 0x04, b'F', b'L', b'D', b'1', 0x0C,
+        0x00, 0x08, b'F', b'L', b'D', b'2', 0x10,
+        // Method (WRT1, 1) { Store (Arg0, FLD1) }
+        0x14, 0x0C, b'W', b'R', b'T', b'1', 0x01, 0x70, 0x68, b'F', b'L',
+        b'D', b'1',
+    ]);
+    let mut ns = load_test_aml(&aml);
+    let read = |ns: &mut Namespace, path| ns.evaluate(path, Vec::new());
+    assert_eq!(read(&mut ns, "\\FLD0"), Ok(AmlValue::Integer(0x1)));
+    assert_eq!(read(&mut ns, "\\FLD1"), Ok(AmlValue::Integer(0x432)));
+    assert_eq!(read(&mut ns, "\\FLD2"), Ok(AmlValue::Integer(0xA987)));
+    let arg = alloc::vec![AmlValue::Integer(0xABC)];
+    ns.evaluate("\\WRT1", arg).unwrap();
+    assert_eq!(mem[..3], [0xC1, 0xAB, 0x65]);
+}
@@ -0,0 +1,221 @@
+//! Local APIC
+//!
+//! Uses the x2APIC mode (MSR access) if available, and the xAPIC mode (MMIO
+//! access) otherwise. The state is kept in atomics so that interrupt
+//! handlers can send EOIs without taking any locks.
+//!
+//! c.f. SDM Vol.3A: 11 Advanced Programmable Interrupt Controller (APIC)
+
+use crate::clock::now;
+use crate::info;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::cpuid::cpu_features;
+use crate::x86::read_msr;
+use crate::x86::set_interrupt_handler;
+use crate::x86::with_current_page_table;
+use crate::x86::write_io_port_u8;
+use crate::x86::write_msr;
+use crate::x86::InterruptInfo;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+pub const VECTOR_LAPIC_TIMER: u8 = 0x20;
+pub const VECTOR_PMU: u8 = 0x21;
+pub const VECTOR_ACPI_SCI: u8 = 0x22;
+pub const VECTOR_IOMMU_FAULT: u8 = 0x23;
+pub const VECTOR_SERIAL: u8 = 0x24;
+pub const VECTOR_SPURIOUS: u8 = 0xFF;
+
+const MSR_IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = !0xFFF;
+const MSR_X2APIC_BASE: u32 = 0x800;
+
+const REG_ID: usize = 0x20;
+const REG_VERSION: usize = 0x30;
+const REG_EOI: usize = 0xB0;
+const REG_SVR: usize = 0xF0;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_LVT_PERF: usize = 0x340;
+const REG_LVT_LINT1: usize = 0x360;
+const REG_TIMER_INITIAL_COUNT: usize = 0x380;
+const REG_TIMER_CURRENT_COUNT: usize = 0x390;
+const REG_TIMER_DIVIDE_CONFIG: usize = 0x3E0;
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+pub const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+// Divide by 16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+static LAPIC_ENABLED: AtomicBool = AtomicBool::new(false);
+static LAPIC_X2APIC: AtomicBool = AtomicBool::new(false);
+static LAPIC_MMIO_BASE: AtomicU64 = AtomicU64::new(0);
+static LAPIC_TIMER_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+pub struct LocalApic {
+    x2apic: bool,
+    mmio_base: u64,
+}
+impl LocalApic {
+    /// Returns the Local APIC of the current CPU if init_local_apic() has
+    /// been done.
+    pub fn current() -> Option<Self> {
+        if LAPIC_ENABLED.load(Ordering::Relaxed) {
+            Some(Self {
+                x2apic: LAPIC_X2APIC.load(Ordering::Relaxed),
+                mmio_base: LAPIC_MMIO_BASE.load(Ordering::Relaxed),
+            })
+        } else {
+            None
+        }
+    }
+    fn read(&self, reg: usize) -> u32 {
+        if self.x2apic {
+            read_msr(MSR_X2APIC_BASE + (reg as u32 >> 4)) as u32
+        } else {
+            unsafe {
+                read_volatile((self.mmio_base as usize + reg) as *const u32)
+            }
+        }
+    }
+    fn write(&self, reg: usize, value: u32) {
+        if self.x2apic {
+            unsafe {
+                write_msr(MSR_X2APIC_BASE + (reg as u32 >> 4), value as u64)
+            }
+        } else {
+            unsafe {
+                write_volatile(
+                    (self.mmio_base as usize + reg) as *mut u32,
+                    value,
+                )
+            }
+        }
+    }
+    pub fn id(&self) -> u32 {
+        if self.x2apic {
+            self.read(REG_ID)
+        } else {
+            self.read(REG_ID) >> 24
+        }
+    }
+    pub fn version(&self) -> u32 {
+        self.read(REG_VERSION) & 0xFF
+    }
+    pub fn is_x2apic(&self) -> bool {
+        self.x2apic
+    }
+    pub fn eoi(&self) {
+        self.write(REG_EOI, 0)
+    }
+    pub fn set_lvt_perf(&self, lvt: u32) {
+        self.write(REG_LVT_PERF, lvt)
+    }
+    pub fn lvt_perf(&self) -> u32 {
+        self.read(REG_LVT_PERF)
+    }
+    /// Configures LINT1 to deliver NMIs, which is the usual wiring on PCs.
+    pub fn enable_lint1_nmi(&self) {
+        self.write(REG_LVT_LINT1, LVT_DELIVERY_MODE_NMI)
+    }
+    pub fn start_periodic_timer(&self, vector: u8, interval: Duration) {
+        let ticks_per_ms = LAPIC_TIMER_TICKS_PER_MS.load(Ordering::Relaxed);
+        let ticks = (ticks_per_ms * interval.as_micros() as u64 / 1000)
+            .clamp(1, u32::MAX as u64) as u32;
+        self.write(REG_TIMER_INITIAL_COUNT, 0);
+        self.write(REG_TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+        self.write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+        self.write(REG_TIMER_INITIAL_COUNT, ticks);
+    }
+    pub fn stop_timer(&self) {
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TIMER_INITIAL_COUNT, 0);
+    }
+    fn calibrate_timer(&self) -> Result<u64> {
+        const PERIOD: Duration = Duration::from_millis(10);
+        self.write(REG_TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        let t0 = now();
+        self.write(REG_TIMER_INITIAL_COUNT, u32::MAX);
+        while now() - t0 < PERIOD {
+            busy_loop_hint();
+        }
+        let elapsed = u32::MAX - self.read(REG_TIMER_CURRENT_COUNT);
+        self.write(REG_TIMER_INITIAL_COUNT, 0);
+        let ticks_per_ms = elapsed as u64 / PERIOD.as_millis() as u64;
+        if ticks_per_ms == 0 {
+            Err("Local APIC timer is not running")
+        } else {
+            Ok(ticks_per_ms)
+        }
+    }
+}
+
+/// Masks all the interrupts from the legacy 8259 PICs, so that they do not
+/// conflict with the vectors for exceptions.
+fn disable_legacy_pic() {
+    write_io_port_u8(0xA1, 0xFF);
+    write_io_port_u8(0x21, 0xFF);
+}
+
+fn spurious_interrupt_handler(_info: &InterruptInfo) {
+    // Spurious interrupts should not be EOIed.
+}
+
+/// Enables the Local APIC of the current CPU. This should be called after
+/// the IDT is loaded and the clocksource is initialized.
+pub fn init_local_apic() -> Result<()> {
+    let features = cpu_features();
+    if !features.apic {
+        return Err("Local APIC is not supported");
+    }
+    disable_legacy_pic();
+    let mut apic_base = read_msr(MSR_IA32_APIC_BASE) | APIC_BASE_GLOBAL_ENABLE;
+    if features.x2apic {
+        apic_base |= APIC_BASE_X2APIC_ENABLE;
+    }
+    unsafe { write_msr(MSR_IA32_APIC_BASE, apic_base) }
+    let mmio_base = apic_base & APIC_BASE_ADDR_MASK;
+    if !features.x2apic {
+        unsafe {
+            with_current_page_table(|pt| {
+                pt.create_mapping(
+                    mmio_base,
+                    mmio_base + PAGE_SIZE as u64,
+                    mmio_base,
+                    PageAttr::ReadWriteIo,
+                )
+                .expect("Failed to create mapping for Local APIC")
+            })
+        }
+    }
+    LAPIC_X2APIC.store(features.x2apic, Ordering::SeqCst);
+    LAPIC_MMIO_BASE.store(mmio_base, Ordering::SeqCst);
+    LAPIC_ENABLED.store(true, Ordering::SeqCst);
+    let lapic = LocalApic::current().ok_or("Local APIC is not enabled")?;
+    set_interrupt_handler(VECTOR_SPURIOUS, spurious_interrupt_handler);
+    lapic.write(REG_SVR, SVR_APIC_ENABLE | VECTOR_SPURIOUS as u32);
+    lapic.stop_timer();
+    lapic.set_lvt_perf(LVT_MASKED);
+    let ticks_per_ms = lapic.calibrate_timer()?;
+    LAPIC_TIMER_TICKS_PER_MS.store(ticks_per_ms, Ordering::SeqCst);
+    info!(
+        "Local APIC: id: {}, version: {:#X}, x2apic: {}, timer: {} ticks/ms",
+        lapic.id(),
+        lapic.version(),
+        lapic.is_x2apic(),
+        ticks_per_ms
+    );
+    Ok(())
+}
@@ -0,0 +1,133 @@
+//! Kernel clocksource
+//!
+//! `now()` returns the time elapsed since the HPET was initialized. When
+//! CPUID reports an invariant TSC, the TSC is calibrated against the HPET
+//! and used instead, since reading it requires neither MMIO accesses nor
+//! locks. Otherwise, the HPET main counter is used as a fallback.
+
+use crate::hpet;
+use crate::info;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::cpuid::cpu_features;
+use crate::x86::rdtsc;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+// ns = base_ns + ((tsc - base_tsc) * mult) >> TSC_SHIFT
+const TSC_SHIFT: u32 = 32;
+const CALIBRATION_PERIOD: Duration = Duration::from_millis(10);
+
+static TSC_ENABLED: AtomicBool = AtomicBool::new(false);
+static TSC_FREQ: AtomicU64 = AtomicU64::new(0);
+static TSC_MULT: AtomicU64 = AtomicU64::new(0);
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+static TSC_BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockSource {
+    Tsc,
+    Hpet,
+}
+
+fn calc_mult(freq: u64) -> u64 {
+    ((1_000_000_000u128 << TSC_SHIFT) / freq as u128) as u64
+}
+fn tsc_delta_to_ns(delta: u64, mult: u64) -> u64 {
+    ((delta as u128 * mult as u128) >> TSC_SHIFT) as u64
+}
+
+/// Measures the TSC frequency by comparing it with the HPET main counter
+/// over CALIBRATION_PERIOD.
+pub fn calibrate_tsc() -> Result<u64> {
+    let hpet_freq = hpet::global_freq();
+    let hpet_start =
+        hpet::global_main_counter().ok_or("HPET is not initialized")?;
+    let tsc_start = rdtsc();
+    let hpet_ticks =
+        hpet_freq * CALIBRATION_PERIOD.as_micros() as u64 / 1_000_000;
+    let mut hpet_end;
+    loop {
+        hpet_end = hpet::global_main_counter().unwrap_or(hpet_start);
+        if hpet_end.wrapping_sub(hpet_start) >= hpet_ticks {
+            break;
+        }
+        busy_loop_hint();
+    }
+    let tsc_end = rdtsc();
+    let freq = (tsc_end - tsc_start) as u128 * hpet_freq as u128
+        / hpet_end.wrapping_sub(hpet_start) as u128;
+    if freq == 0 {
+        Err("Failed to calibrate TSC")
+    } else {
+        Ok(freq as u64)
+    }
+}
+
+/// Switches the clocksource to the TSC if it is invariant. This should be
+/// called after the HPET is initialized.
+pub fn init_clocksource() {
+    if !cpu_features().invariant_tsc {
+        info!("clock: TSC is not invariant. Using HPET.");
+        return;
+    }
+    let freq = match calibrate_tsc() {
+        Ok(freq) => freq,
+        Err(e) => {
+            info!("clock: {e}. Using HPET.");
+            return;
+        }
+    };
+    TSC_FREQ.store(freq, Ordering::SeqCst);
+    TSC_MULT.store(calc_mult(freq), Ordering::SeqCst);
+    TSC_BASE_NS
+        .store(hpet::global_timestamp().as_nanos() as u64, Ordering::SeqCst);
+    TSC_BASE.store(rdtsc(), Ordering::SeqCst);
+    TSC_ENABLED.store(true, Ordering::SeqCst);
+    info!("clock: Using invariant TSC @ {} kHz", freq / 1000);
+}
+
+pub fn clocksource() -> ClockSource {
+    if TSC_ENABLED.load(Ordering::Relaxed) {
+        ClockSource::Tsc
+    } else {
+        ClockSource::Hpet
+    }
+}
+pub fn tsc_freq() -> Option<u64> {
+    match clocksource() {
+        ClockSource::Tsc => Some(TSC_FREQ.load(Ordering::Relaxed)),
+        ClockSource::Hpet => None,
+    }
+}
+
+/// Returns the current time since boot. This never takes a lock.
+pub fn now() -> Duration {
+    if TSC_ENABLED.load(Ordering::Relaxed) {
+        let delta = rdtsc().wrapping_sub(TSC_BASE.load(Ordering::Relaxed));
+        let ns = TSC_BASE_NS.load(Ordering::Relaxed)
+            + tsc_delta_to_ns(delta, TSC_MULT.load(Ordering::Relaxed));
+        Duration::from_nanos(ns)
+    } else {
+        hpet::global_timestamp()
+    }
+}
+
+#[test_case]
+fn tsc_delta_to_ns_test() {
+    // 1 GHz
+    let mult = calc_mult(1_000_000_000);
+    assert_eq!(tsc_delta_to_ns(0, mult), 0);
+    assert_eq!(tsc_delta_to_ns(1_000_000_000, mult), 1_000_000_000);
+    // 2.5 GHz
+    let mult = calc_mult(2_500_000_000);
+    let ns = tsc_delta_to_ns(2_500_000_000, mult);
+    assert!((999_999_990..=1_000_000_000).contains(&ns));
+    // 3 GHz, one hour
+    let mult = calc_mult(3_000_000_000);
+    let ns = tsc_delta_to_ns(3_000_000_000 * 3600, mult);
+    let expected = 3600 * 1_000_000_000u64;
+    assert!(expected - ns < 1_000_000);
+}
@@ -1,29 +1,49 @@
 extern crate alloc;
 
+use crate::acpi::global_acpi;
+use crate::allocator::ALLOCATOR;
+use crate::aml;
+use crate::clock::clocksource;
+use crate::clock::now;
 use crate::error;
 use crate::executor::sleep;
 use crate::executor::spawn_global;
 use crate::executor::yield_execution;
+use crate::frame::FRAME_ALLOCATOR;
 use crate::graphics::draw_button;
 use crate::graphics::Rect;
 use crate::gui::global_vram_resolutions;
 use crate::gui::GLOBAL_VRAM;
-use crate::hpet::global_timestamp;
 use crate::info;
+use crate::init::is_reclaimable;
 use crate::init::EFI_MEMORY_MAP;
 use crate::input::MouseEvent;
 use crate::input::PointerPosition;
 use crate::input::GLOBAL_INPUT_MANAGER;
+use crate::iommu;
 use crate::keyboard::KeyEvent;
+use crate::keyboard::SerialKeyDecoder;
+use crate::power;
 use crate::print;
+use crate::print::hexdump_bytes;
 use crate::println;
+use crate::profiler;
 use crate::result::Result;
+use crate::serial;
+use crate::serial::SerialPort;
 use crate::tablet::set_debug_mouse;
+use crate::uefi::EfiMemoryDescriptor;
 use crate::warn;
+use crate::x86::cpuid::cpu_features;
+use crate::x86::fpu::enabled_xcr0;
+use crate::x86::fpu::is_xsave_enabled;
+use crate::x86::fpu::xsave_area_size;
 use alloc::string::String;
 use alloc::vec::Vec;
 use core::time::Duration;
 
+/// A line editor that runs the commands. It does not depend on where the
+/// KeyEvents come from, e.g. the USB keyboard or the serial port.
 #[derive(Default)]
 pub struct Console {
     input_buf: String,
@@ -35,6 +55,13 @@ impl Console {
                 self.input_buf.push(c);
                 print!("{c}");
             }
+            KeyEvent::Backspace => {
+                if self.input_buf.pop().is_some() {
+                    // Move back, overwrite with a space and move back again
+                    print!("\x08 \x08");
+                }
+            }
+            KeyEvent::None => {}
             KeyEvent::Enter => {
                 println!();
                 if let Err(e) = run_cmd(&self.input_buf) {
@@ -47,6 +74,18 @@ impl Console {
     }
 }
 
+/// Runs a Console on the serial port. This should be spawned after
+/// init_serial_driver().
+pub async fn serial_console_task() -> Result<()> {
+    let mut console = Console::default();
+    let mut decoder = SerialKeyDecoder::default();
+    info!("Started the console on the serial port");
+    loop {
+        let c = serial::read().await?;
+        console.handle_key_down(decoder.decode(c));
+    }
+}
+
 pub fn run_cmd_debug(args: &[&str]) -> Result<()> {
     if "mouse" == *args.get(1).unwrap_or(&"") {
         match *args.get(2).unwrap_or(&"") {
@@ -69,17 +108,207 @@ pub fn run_cmd_debug(args: &[&str]) -> Result<()> {
 }
 
 pub fn run_cmd_show(args: &[&str]) -> Result<()> {
-    if "mmap" == *args.get(1).unwrap_or(&"") {
-        if let Some(mmap) = EFI_MEMORY_MAP.lock().as_ref() {
-            for e in mmap.iter() {
+    match *args.get(1).unwrap_or(&"") {
+        "mmap" => {
+            if let Some(mmap) = EFI_MEMORY_MAP.lock().as_ref() {
+                let frames = FRAME_ALLOCATOR.lock();
+                let is_reclaimed = |e: &EfiMemoryDescriptor| {
+                    let last_page = e.physical_start()
+                        + e.number_of_pages().saturating_sub(1) * 4096;
+                    is_reclaimable(e)
+                        && frames
+                            .as_ref()
+                            .and_then(|f| f.owner_of(last_page))
+                            .is_some()
+                };
+                let mut reclaimed_pages = 0;
+                for e in mmap.iter() {
+                    if is_reclaimed(e) {
+                        println!("{e:?} (reclaimed)");
+                        reclaimed_pages += e.number_of_pages();
+                    } else {
+                        println!("{e:?}");
+                    }
+                }
+                println!(
+                    "Reclaimed: {reclaimed_pages} pages ({} MiB)",
+                    reclaimed_pages * 4096 / 1024 / 1024
+                );
+            } else {
+                println!("EFI_MEMORY_MAP is not set")
+            }
+        }
+        "mem" => {
+            if let Some(frames) = FRAME_ALLOCATOR.lock().as_ref() {
+                println!("{frames}");
+            } else {
+                println!("FRAME_ALLOCATOR is not set")
+            }
+        }
+        "heap" => match *args.get(2).unwrap_or(&"") {
+            "leaks" => run_cmd_show_heap_leaks()?,
+            _ => {
+                println!("{}", ALLOCATOR.stats());
+                #[cfg(feature = "heap_debug")]
+                println!("{}", crate::heap_debug::LIMITATION_NOTE);
+            }
+        },
+        "locks" => run_cmd_show_locks()?,
+        "acpi" => run_cmd_show_acpi(&args[1..])?,
+        "serial" => println!("{:?}", serial::serial_stats()),
+        "iommu" => {
+            let mut s = String::new();
+            iommu::dump(&mut s)?;
+            print!("{s}");
+        }
+        "slab" => {
+            for e in ALLOCATOR.slab_stats() {
+                println!("{e}");
+            }
+        }
+        "cpu" => {
+            println!("{:?}", cpu_features());
+            println!(
+                "fpu: xsave: {}, xcr0: {:#X}, save area: {} bytes",
+                is_xsave_enabled(),
+                enabled_xcr0(),
+                xsave_area_size()
+            );
+        }
+        _ => {
+            info!("Usage:");
+            info!("- show mmap");
+            info!("- show mem");
+            info!("- show heap [leaks]");
+            info!("- show slab");
+            info!("- show locks");
+            info!("- show acpi [dump <signature|index>]");
+            info!("- show acpi fadt|madt|ns|eval <path>");
+            info!("- show cpu");
+            info!("- show iommu");
+            info!("- show serial");
+        }
+    }
+    Ok(())
+}
+
+fn run_cmd_show_acpi(args: &[&str]) -> Result<()> {
+    let acpi = global_acpi().ok_or("ACPI tables are not available")?;
+    match *args.get(1).unwrap_or(&"") {
+        "" => {
+            for (i, e) in acpi.tables().enumerate() {
+                println!("{i:2}: {e}");
+            }
+        }
+        "dump" => {
+            let name = args.get(2).ok_or("Usage: show acpi dump <table>")?;
+            let table = match name.parse::<usize>() {
+                Ok(i) => acpi.tables().nth(i),
+                Err(_) => acpi.tables().find(|e| e.signature() == *name),
+            }
+            .ok_or("ACPI table not found")?;
+            hexdump_bytes(table.bytes());
+        }
+        "fadt" => {
+            println!("{:?}", acpi.fadt().ok_or("FADT not found")?);
+        }
+        "madt" => {
+            let madt = acpi.madt().ok_or("MADT not found")?;
+            println!(
+                "Local APIC: {:#X}, 8259 PICs: {}",
+                madt.local_apic_address(),
+                madt.has_8259_pics()
+            );
+            for e in madt.iter() {
                 println!("{e:?}");
             }
-        } else {
-            println!("EFI_MEMORY_MAP is not set")
+        }
+        "ns" => {
+            // The namespace is large, so write it only to the serial port.
+            aml::dump_global_namespace(&mut SerialPort::default())?;
+            info!("AML namespace dumped to the serial port");
+        }
+        "eval" => {
+            let path = args.get(2).ok_or("Usage: show acpi eval <path>")?;
+            println!("{}", aml::evaluate(path, Vec::new())?);
+        }
+        _ => {
+            info!("Usage:");
+            info!("- show acpi");
+            info!("- show acpi dump <signature|index>");
+            info!("- show acpi fadt|madt|ns|eval <path>");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "heap_tracking")]
+fn run_cmd_show_heap_leaks() -> Result<()> {
+    // The report can be long, so write it only to the serial port.
+    crate::heap_tracker::dump_leaks(&mut SerialPort::default())?;
+    info!("live allocations dumped to the serial port");
+    Ok(())
+}
+#[cfg(not(feature = "heap_tracking"))]
+fn run_cmd_show_heap_leaks() -> Result<()> {
+    Err("Build with `--features heap_tracking` to track allocations")
+}
+
+#[cfg(feature = "lockdep")]
+fn run_cmd_show_locks() -> Result<()> {
+    let mut s = String::new();
+    crate::lockdep::dump(&mut s)?;
+    print!("{s}");
+    Ok(())
+}
+#[cfg(not(feature = "lockdep"))]
+fn run_cmd_show_locks() -> Result<()> {
+    Err("Build with `--features lockdep` to track locks")
+}
+
+#[cfg(feature = "heap_debug")]
+pub fn run_cmd_heap(args: &[&str]) -> Result<()> {
+    match *args.get(1).unwrap_or(&"") {
+        "check" => {
+            let num_checked = crate::heap_debug::check();
+            info!("heap: {num_checked} objects are OK");
+        }
+        _ => {
+            info!("Usage:");
+            info!("- heap check");
+        }
+    }
+    Ok(())
+}
+#[cfg(not(feature = "heap_debug"))]
+pub fn run_cmd_heap(_args: &[&str]) -> Result<()> {
+    Err("Build with `--features heap_debug` to check the heap")
+}
+
+pub fn run_cmd_profile(args: &[&str]) -> Result<()> {
+    match *args.get(1).unwrap_or(&"") {
+        "start" => {
+            let source = profiler::start()?;
+            info!("profiler started (source: {source:?})");
+        }
+        "stop" => {
+            profiler::stop()?;
+            info!(
+                "profiler stopped ({} samples, {} dropped)",
+                profiler::num_samples(),
+                profiler::num_dropped_samples()
+            );
+        }
+        "dump" => {
+            // The report can be long, so write it only to the serial port.
+            profiler::dump(&mut SerialPort::default())?;
+            info!("profile dumped to the serial port");
+        }
+        _ => {
+            info!("Usage:");
+            info!("- profile start|stop|dump");
         }
     }
-    info!("Usage:");
-    info!("- show mmap");
     Ok(())
 }
 
@@ -89,12 +318,19 @@ pub fn run_cmd(cmdline: &str) -> Result<()> {
     if let Some(&cmd) = args.first() {
         match cmd {
             "time" => {
-                println!("{:?}", global_timestamp());
+                println!("{:?} ({:?})", now(), clocksource());
                 Ok(())
             }
             "debug" => run_cmd_debug(&args),
             "show" => run_cmd_show(&args),
             "demo" => run_cmd_demo(&args),
+            "profile" => run_cmd_profile(&args),
+            "heap" => run_cmd_heap(&args),
+            "reboot" => power::reboot(),
+            "shutdown" => {
+                power::request_shutdown();
+                Ok(())
+            }
             "" => Ok(()),
             _ => Err("Unknown command"),
         }
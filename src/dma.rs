@@ -0,0 +1,329 @@
+//! DMA buffers
+//!
+//! DMA buffers are taken from the frame allocator so that their physical
+//! addresses are fixed (and identity-mapped), and they are mapped as
+//! uncacheable while they are alive. Devices often restrict the addresses
+//! they can use, e.g. xHCI data structures must not cross 64 KiB boundaries
+//! and some devices can only address the first 4 GiB. Such restrictions are
+//! given as DmaConstraints.
+//!
+//! If DmaConstraints::device() is given, the buffer is also mapped in the
+//! IOMMU only for that device while it is alive.
+
+extern crate alloc;
+
+use crate::frame::FrameOwner;
+use crate::frame::Frames;
+use crate::frame::FRAME_SIZE_4K;
+use crate::iommu;
+use crate::pci::BusDeviceFunction;
+use crate::result::Result;
+use crate::warn;
+use crate::x86::set_identity_mapping_attr;
+use crate::x86::PageAttr;
+use alloc::vec::Vec;
+use core::cmp::max;
+use core::slice;
+
+pub const DMA_ADDR_LIMIT_4G: u64 = 1 << 32;
+/// Size of the buffers that a DmaPool carves blocks from
+const DMA_POOL_CHUNK_SIZE: usize = FRAME_SIZE_4K;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DmaConstraints {
+    align: usize,
+    /// 0 means no boundary
+    boundary: usize,
+    /// The buffer should end at or below this address
+    addr_limit: u64,
+    /// The device that the buffer is mapped for in the IOMMU
+    device: Option<BusDeviceFunction>,
+}
+impl DmaConstraints {
+    pub const fn new() -> Self {
+        Self {
+            align: 1,
+            boundary: 0,
+            addr_limit: u64::MAX,
+            device: None,
+        }
+    }
+    pub const fn align(mut self, align: usize) -> Self {
+        self.align = align;
+        self
+    }
+    /// The buffer should not cross a multiple of `boundary`.
+    pub const fn boundary(mut self, boundary: usize) -> Self {
+        self.boundary = boundary;
+        self
+    }
+    pub const fn addr_limit(mut self, addr_limit: u64) -> Self {
+        self.addr_limit = addr_limit;
+        self
+    }
+    pub const fn below_4g(self) -> Self {
+        self.addr_limit(DMA_ADDR_LIMIT_4G)
+    }
+    /// Only `bdf` can access the buffer when the IOMMU is enabled.
+    pub const fn device(mut self, bdf: BusDeviceFunction) -> Self {
+        self.device = Some(bdf);
+        self
+    }
+    fn validate(&self, size: usize) -> Result<()> {
+        if !self.align.is_power_of_two() {
+            Err("DMA alignment should be a power of 2")
+        } else if self.boundary != 0 && !self.boundary.is_power_of_two() {
+            Err("DMA boundary should be a power of 2")
+        } else if self.boundary != 0 && size > self.boundary {
+            Err("DMA buffer is larger than the boundary")
+        } else if size == 0 {
+            Err("DMA buffer should not be empty")
+        } else {
+            Ok(())
+        }
+    }
+    /// Returns true if [addr, addr + size) meets the constraints.
+    pub fn is_satisfied_by(&self, addr: u64, size: usize) -> bool {
+        let end = addr + size as u64;
+        addr % self.align as u64 == 0
+            && end <= self.addr_limit
+            && (self.boundary == 0
+                || addr / self.boundary as u64
+                    == (end - 1) / self.boundary as u64)
+    }
+}
+impl Default for DmaConstraints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A zero-initialized, uncacheable buffer for DMA. The cache attribute of
+/// the pages is restored when it is dropped.
+pub struct DmaBuffer {
+    frames: Frames,
+    size: usize,
+    device: Option<BusDeviceFunction>,
+}
+impl DmaBuffer {
+    pub fn new(size: usize, constraints: DmaConstraints) -> Result<Self> {
+        constraints.validate(size)?;
+        let mut frames = Frames::alloc_constrained(
+            size,
+            max(constraints.align, FRAME_SIZE_4K),
+            constraints.boundary,
+            constraints.addr_limit,
+            FrameOwner::Dma,
+        )?;
+        frames.fill_zero();
+        if let Some(bdf) = constraints.device {
+            iommu::map(bdf, frames.addr(), frames.size())?;
+        }
+        set_identity_mapping_attr(
+            frames.addr(),
+            frames.addr() + frames.size() as u64,
+            PageAttr::ReadWriteIo,
+        );
+        Ok(Self {
+            frames,
+            size,
+            device: constraints.device,
+        })
+    }
+    /// The address that devices should use to access this buffer.
+    pub fn phys_addr(&self) -> u64 {
+        // Physical memory is identity-mapped.
+        self.frames.addr()
+    }
+    pub fn as_ptr(&self) -> *const u8 {
+        self.frames.as_mut_ptr()
+    }
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.frames.as_mut_ptr()
+    }
+    pub fn len(&self) -> usize {
+        self.size
+    }
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.size) }
+    }
+}
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if let Some(bdf) = self.device {
+            if let Err(e) =
+                iommu::unmap(bdf, self.frames.addr(), self.frames.size())
+            {
+                warn!("dma: Failed to unmap the buffer for {bdf}: {e}");
+            }
+        }
+        set_identity_mapping_attr(
+            self.frames.addr(),
+            self.frames.addr() + self.frames.size() as u64,
+            PageAttr::ReadWriteKernel,
+        );
+    }
+}
+
+/// A block allocated from a DmaPool. It should be returned with
+/// DmaPool::free().
+#[derive(Debug, PartialEq, Eq)]
+pub struct DmaBlock {
+    phys_addr: u64,
+    size: usize,
+}
+impl DmaBlock {
+    pub fn phys_addr(&self) -> u64 {
+        self.phys_addr
+    }
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.phys_addr as *mut u8
+    }
+    pub fn len(&self) -> usize {
+        self.size
+    }
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+}
+
+/// Fixed-size DMA blocks that are smaller than a page (e.g. device
+/// contexts), carved from page-sized DmaBuffers.
+pub struct DmaPool {
+    block_size: usize,
+    constraints: DmaConstraints,
+    chunks: Vec<DmaBuffer>,
+    free_blocks: Vec<u64>,
+    num_allocated: usize,
+}
+impl DmaPool {
+    pub fn new(block_size: usize, constraints: DmaConstraints) -> Result<Self> {
+        constraints.validate(block_size)?;
+        if block_size > DMA_POOL_CHUNK_SIZE
+            || constraints.align > DMA_POOL_CHUNK_SIZE
+        {
+            return Err("Use DmaBuffer for blocks larger than a page");
+        }
+        Ok(Self {
+            block_size,
+            constraints,
+            chunks: Vec::new(),
+            free_blocks: Vec::new(),
+            num_allocated: 0,
+        })
+    }
+    fn grow(&mut self) -> Result<()> {
+        let mut chunk_constraints = DmaConstraints::new()
+            .align(DMA_POOL_CHUNK_SIZE)
+            .addr_limit(self.constraints.addr_limit);
+        chunk_constraints.device = self.constraints.device;
+        let chunk = DmaBuffer::new(DMA_POOL_CHUNK_SIZE, chunk_constraints)?;
+        let start = chunk.phys_addr();
+        let end = start + DMA_POOL_CHUNK_SIZE as u64;
+        let mut addr = start;
+        while addr + self.block_size as u64 <= end {
+            if self.constraints.is_satisfied_by(addr, self.block_size) {
+                self.free_blocks.push(addr);
+                addr += self.block_size as u64;
+            } else {
+                // Move to the next aligned address, and then to the next
+                // boundary if it does not help.
+                let next = addr.next_multiple_of(self.constraints.align as u64);
+                addr = if next != addr {
+                    next
+                } else {
+                    (addr + 1).next_multiple_of(max(
+                        self.constraints.boundary,
+                        self.constraints.align,
+                    ) as u64)
+                };
+            }
+        }
+        // Hand out the lower addresses first
+        self.free_blocks.reverse();
+        self.chunks.push(chunk);
+        Ok(())
+    }
+    pub fn alloc(&mut self) -> Result<DmaBlock> {
+        if self.free_blocks.is_empty() {
+            self.grow()?;
+        }
+        let phys_addr = self.free_blocks.pop().ok_or("DmaPool is exhausted")?;
+        self.num_allocated += 1;
+        Ok(DmaBlock {
+            phys_addr,
+            size: self.block_size,
+        })
+    }
+    pub fn free(&mut self, block: DmaBlock) -> Result<()> {
+        let addr = block.phys_addr;
+        if !self.chunks.iter().any(|e| {
+            e.phys_addr() <= addr
+                && addr < e.phys_addr() + DMA_POOL_CHUNK_SIZE as u64
+        }) {
+            return Err("DmaBlock does not belong to this pool");
+        }
+        unsafe { block.as_mut_ptr().write_bytes(0, block.size) };
+        self.free_blocks.push(addr);
+        self.num_allocated -= 1;
+        Ok(())
+    }
+    pub fn num_allocated(&self) -> usize {
+        self.num_allocated
+    }
+}
+
+#[test_case]
+fn dma_constraints_test() {
+    let c = DmaConstraints::new()
+        .align(64)
+        .boundary(0x1_0000)
+        .below_4g();
+    assert!(c.is_satisfied_by(0x1_0000, 0x1_0000));
+    assert!(!c.is_satisfied_by(0x1_0020, 0x40));
+    assert!(!c.is_satisfied_by(0xFFC0, 0x80));
+    assert!(!c.is_satisfied_by(0xFFFF_FFC0, 0x80));
+    assert!(c.validate(0x1_0001).is_err());
+    assert!(DmaConstraints::new().align(3).validate(1).is_err());
+}
+
+#[test_case]
+fn dma_buffer_constraints_test() {
+    let c = DmaConstraints::new()
+        .align(0x2000)
+        .boundary(0x1_0000)
+        .below_4g();
+    let mut buf = DmaBuffer::new(0x3000, c).expect("Failed to alloc");
+    assert!(c.is_satisfied_by(buf.phys_addr(), buf.len()));
+    assert!(buf.as_slice().iter().all(|e| *e == 0));
+    buf.as_mut_slice()[0] = 1;
+}
+
+#[test_case]
+fn dma_pool_test() {
+    let c = DmaConstraints::new().align(32).boundary(256);
+    let mut pool = DmaPool::new(96, c).expect("Failed to create a pool");
+    let mut blocks = Vec::new();
+    for _ in 0..64 {
+        let block = pool.alloc().expect("Failed to alloc");
+        assert!(c.is_satisfied_by(block.phys_addr(), block.len()));
+        assert!(!blocks.contains(&block));
+        blocks.push(block);
+    }
+    assert_eq!(pool.num_allocated(), 64);
+    for block in blocks {
+        pool.free(block).expect("Failed to free");
+    }
+    assert_eq!(pool.num_allocated(), 0);
+    let foreign = DmaBlock {
+        phys_addr: 0x1000,
+        size: 96,
+    };
+    assert!(pool.free(foreign).is_err());
+}
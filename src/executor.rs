@@ -1,17 +1,19 @@
 extern crate alloc;
-use crate::hpet::global_timestamp;
+use crate::clock::now;
 use crate::info;
 use crate::mutex::Mutex;
 use crate::result::Result;
 use crate::x86::busy_loop_hint;
 use alloc::boxed::Box;
 use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use core::fmt::Debug;
 use core::future::Future;
 use core::panic::Location;
 use core::pin::Pin;
 use core::ptr::null;
 use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering;
 use core::task::Context;
 use core::task::Poll;
@@ -20,8 +22,11 @@ use core::task::RawWakerVTable;
 use core::task::Waker;
 use core::time::Duration;
 
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(0);
+
 struct Task<T> {
     future: Pin<Box<dyn Future<Output = Result<T>>>>,
+    id: usize,
     created_at_file: &'static str,
     created_at_line: u32,
 }
@@ -29,6 +34,7 @@ impl<T> Task<T> {
     #[track_caller]
     fn new(future: impl Future<Output = Result<T>> + 'static) -> Task<T> {
         Task {
+            id: NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst),
             // Pin the task here to avoid invalidating the self references used
             // in  the future
             future: Box::pin(future),
@@ -39,6 +45,14 @@ impl<T> Task<T> {
     fn poll(&mut self, context: &mut Context) -> Poll<Result<T>> {
         self.future.as_mut().poll(context)
     }
+    fn info(&self, is_running: bool) -> TaskInfo {
+        TaskInfo {
+            id: self.id,
+            created_at_file: self.created_at_file,
+            created_at_line: self.created_at_line,
+            is_running,
+        }
+    }
 }
 impl<T> Debug for Task<T> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -74,12 +88,33 @@ pub fn block_on<T>(
     }
 }
 
+/// A snapshot of a task in the executor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: usize,
+    pub created_at_file: &'static str,
+    pub created_at_line: u32,
+    /// True if the task is being polled, i.e. it is the caller
+    pub is_running: bool,
+}
+
 pub struct Executor {
     task_queue: Option<VecDeque<Task<()>>>,
+    running: Option<TaskInfo>,
 }
 impl Executor {
     const fn new() -> Self {
-        Self { task_queue: None }
+        Self {
+            task_queue: None,
+            running: None,
+        }
+    }
+    fn tasks(&mut self) -> Vec<TaskInfo> {
+        let running = self.running;
+        running
+            .into_iter()
+            .chain(self.task_queue().iter().map(|t| t.info(false)))
+            .collect()
     }
     fn task_queue(&mut self) -> &mut VecDeque<Task<()>> {
         if self.task_queue.is_none() {
@@ -93,17 +128,24 @@ impl Executor {
     fn run(executor: &Mutex<Option<Self>>) -> ! {
         info!("Executor starts running...");
         loop {
-            let task =
-                executor.lock().as_mut().map(|e| e.task_queue().pop_front());
+            let task = executor.lock().as_mut().map(|e| {
+                let task = e.task_queue().pop_front();
+                e.running = task.as_ref().map(|t| t.info(true));
+                task
+            });
             if let Some(Some(mut task)) = task {
                 let waker = no_op_waker();
                 let mut context = Context::from_waker(&waker);
                 match task.poll(&mut context) {
                     Poll::Ready(result) => {
+                        if let Some(e) = executor.lock().as_mut() {
+                            e.running = None;
+                        }
                         info!("Task completed: {:?}: {:?}", task, result);
                     }
                     Poll::Pending => {
                         if let Some(e) = executor.lock().as_mut() {
+                            e.running = None;
                             e.task_queue().push_back(task)
                         }
                     }
@@ -142,14 +184,14 @@ struct TimeoutFuture {
 impl TimeoutFuture {
     fn new(duration: Duration) -> Self {
         Self {
-            time_out: global_timestamp() + duration,
+            time_out: now() + duration,
         }
     }
 }
 impl Future for TimeoutFuture {
     type Output = ();
     fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
-        if self.time_out < global_timestamp() {
+        if self.time_out < now() {
             Poll::Ready(())
         } else {
             Poll::Pending
@@ -166,6 +208,14 @@ pub fn spawn_global(future: impl Future<Output = Result<()>> + 'static) {
     let task = Task::new(future);
     GLOBAL_EXECUTOR.lock().get_or_insert_default().enqueue(task);
 }
+/// Returns the tasks in the global executor, starting with the running one.
+pub fn global_tasks() -> Vec<TaskInfo> {
+    GLOBAL_EXECUTOR
+        .lock()
+        .as_mut()
+        .map(|e| e.tasks())
+        .unwrap_or_default()
+}
 pub fn start_global_executor() -> ! {
     info!("Starting global executor loop");
     Executor::run(&GLOBAL_EXECUTOR);
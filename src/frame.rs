@@ -0,0 +1,572 @@
+//! Physical page frame allocator
+//!
+//! Keeps the owner of every 4 KiB frame in the CONVENTIONAL_MEMORY regions
+//! of the UEFI memory map, one byte per frame. The owner maps themselves
+//! live at the beginning of one of the regions. The boot services and ACPI
+//! reclaimable regions are added later, once they are no longer used. The heap,
+//! page tables and DMA buffers take their memory from here.
+
+use crate::error;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::result::Result;
+use crate::uefi::EfiMemoryDescriptor;
+use crate::uefi::EfiMemoryType;
+use crate::uefi::MemoryMapHolder;
+use core::fmt;
+use core::ptr::write_bytes;
+use core::slice;
+
+pub const FRAME_SIZE_4K: usize = 4096;
+pub const FRAME_SIZE_2M: usize = 2 * 1024 * 1024;
+const MAX_REGIONS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameSize {
+    Size4K,
+    Size2M,
+}
+impl FrameSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            FrameSize::Size4K => FRAME_SIZE_4K,
+            FrameSize::Size2M => FRAME_SIZE_2M,
+        }
+    }
+    fn num_4k_frames(self) -> usize {
+        self.bytes() / FRAME_SIZE_4K
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameOwner {
+    Free = 0,
+    FrameAllocator,
+    Heap,
+    PageTable,
+    Dma,
+    Acpi,
+    Other,
+}
+impl FrameOwner {
+    const ALL: [FrameOwner; 7] = [
+        FrameOwner::Free,
+        FrameOwner::FrameAllocator,
+        FrameOwner::Heap,
+        FrameOwner::PageTable,
+        FrameOwner::Dma,
+        FrameOwner::Acpi,
+        FrameOwner::Other,
+    ];
+}
+
+struct FrameRegion {
+    start: u64,
+    owners: &'static mut [FrameOwner],
+    num_free: usize,
+}
+impl FrameRegion {
+    fn end(&self) -> u64 {
+        self.start + (self.owners.len() * FRAME_SIZE_4K) as u64
+    }
+    fn index_range(&self, addr: u64, count: usize) -> Result<(usize, usize)> {
+        if addr % FRAME_SIZE_4K as u64 != 0 {
+            return Err("Frame address is not aligned");
+        }
+        if addr < self.start
+            || addr + (count * FRAME_SIZE_4K) as u64 > self.end()
+        {
+            return Err("Frames are out of the region");
+        }
+        let begin = ((addr - self.start) as usize) / FRAME_SIZE_4K;
+        Ok((begin, begin + count))
+    }
+    /// Finds `count` contiguous free frames that start at a multiple of
+    /// `align` frames, do not cross a multiple of `boundary` frames (if not
+    /// 0) and end below `addr_limit`, and marks them as owned by `owner`.
+    fn alloc(
+        &mut self,
+        count: usize,
+        align: usize,
+        boundary: usize,
+        addr_limit: u64,
+        owner: FrameOwner,
+    ) -> Option<u64> {
+        if self.num_free < count || (boundary != 0 && count > boundary) {
+            return None;
+        }
+        let base = self.start as usize / FRAME_SIZE_4K;
+        let limit = (addr_limit / FRAME_SIZE_4K as u64) as usize;
+        let mut i = base.next_multiple_of(align) - base;
+        while i + count <= self.owners.len() {
+            let first = base + i;
+            if first + count > limit {
+                return None;
+            }
+            if boundary != 0
+                && first / boundary != (first + count - 1) / boundary
+            {
+                i = (first + 1)
+                    .next_multiple_of(boundary)
+                    .next_multiple_of(align)
+                    - base;
+                continue;
+            }
+            match self.owners[i..i + count]
+                .iter()
+                .rposition(|e| *e != FrameOwner::Free)
+            {
+                Some(used) => {
+                    i = (first + used + 1).next_multiple_of(align) - base;
+                }
+                None => {
+                    self.owners[i..i + count].fill(owner);
+                    self.num_free -= count;
+                    return Some(self.start + (i * FRAME_SIZE_4K) as u64);
+                }
+            }
+        }
+        None
+    }
+    fn reserve(
+        &mut self,
+        addr: u64,
+        count: usize,
+        owner: FrameOwner,
+    ) -> Result<()> {
+        let (begin, end) = self.index_range(addr, count)?;
+        let owners = &mut self.owners[begin..end];
+        if owners.iter().any(|e| *e != FrameOwner::Free) {
+            return Err("Frames are already in use");
+        }
+        owners.fill(owner);
+        self.num_free -= count;
+        Ok(())
+    }
+    fn free(&mut self, addr: u64, count: usize) -> Result<()> {
+        let (begin, end) = self.index_range(addr, count)?;
+        let owners = &mut self.owners[begin..end];
+        if owners.iter().any(|e| *e == FrameOwner::Free) {
+            return Err("Frames are already free");
+        }
+        owners.fill(FrameOwner::Free);
+        self.num_free += count;
+        Ok(())
+    }
+    fn count(&self, owner: FrameOwner) -> usize {
+        self.owners.iter().filter(|e| **e == owner).count()
+    }
+}
+
+pub struct FrameAllocator {
+    regions: [Option<FrameRegion>; MAX_REGIONS],
+}
+impl FrameAllocator {
+    pub const fn new() -> Self {
+        const NO_REGION: Option<FrameRegion> = None;
+        Self {
+            regions: [NO_REGION; MAX_REGIONS],
+        }
+    }
+    /// # Safety
+    /// The frames in [start, start + owners.len() * 4K) should not be used by
+    /// anyone else, and `owners` should be filled with FrameOwner::Free.
+    unsafe fn add_region(
+        &mut self,
+        start: u64,
+        owners: &'static mut [FrameOwner],
+    ) -> Result<()> {
+        let slot = self
+            .regions
+            .iter_mut()
+            .find(|e| e.is_none())
+            .ok_or("Too many frame regions")?;
+        let num_free = owners.len();
+        *slot = Some(FrameRegion {
+            start,
+            owners,
+            num_free,
+        });
+        Ok(())
+    }
+    fn regions(&self) -> impl Iterator<Item = &FrameRegion> {
+        self.regions.iter().flatten()
+    }
+    fn region_mut(&mut self, addr: u64) -> Result<&mut FrameRegion> {
+        self.regions
+            .iter_mut()
+            .flatten()
+            .find(|e| e.start <= addr && addr < e.end())
+            .ok_or("No frame region contains the address")
+    }
+    /// Allocates `count` contiguous 4 KiB frames aligned to `align` frames.
+    pub fn alloc(
+        &mut self,
+        count: usize,
+        align: usize,
+        owner: FrameOwner,
+    ) -> Result<u64> {
+        self.alloc_constrained(count, align, 0, u64::MAX, owner)
+    }
+    /// Same as alloc(), but the frames do not cross a multiple of `boundary`
+    /// frames (if not 0) and end below `addr_limit`.
+    pub fn alloc_constrained(
+        &mut self,
+        count: usize,
+        align: usize,
+        boundary: usize,
+        addr_limit: u64,
+        owner: FrameOwner,
+    ) -> Result<u64> {
+        if count == 0
+            || !align.is_power_of_two()
+            || (boundary != 0 && !boundary.is_power_of_two())
+            || owner == FrameOwner::Free
+        {
+            return Err("Invalid frame allocation request");
+        }
+        self.regions
+            .iter_mut()
+            .flatten()
+            .find_map(|e| e.alloc(count, align, boundary, addr_limit, owner))
+            .ok_or("Out of physical frames")
+    }
+    pub fn reserve(
+        &mut self,
+        addr: u64,
+        count: usize,
+        owner: FrameOwner,
+    ) -> Result<()> {
+        self.region_mut(addr)?.reserve(addr, count, owner)
+    }
+    pub fn free(&mut self, addr: u64, count: usize) -> Result<()> {
+        self.region_mut(addr)?.free(addr, count)
+    }
+    pub fn owner_of(&self, addr: u64) -> Option<FrameOwner> {
+        let region =
+            self.regions().find(|e| e.start <= addr && addr < e.end())?;
+        Some(region.owners[(addr - region.start) as usize / FRAME_SIZE_4K])
+    }
+    pub fn num_frames(&self) -> usize {
+        self.regions().map(|e| e.owners.len()).sum()
+    }
+    pub fn num_free_frames(&self) -> usize {
+        self.regions().map(|e| e.num_free).sum()
+    }
+    pub fn num_frames_owned_by(&self, owner: FrameOwner) -> usize {
+        self.regions().map(|e| e.count(owner)).sum()
+    }
+}
+impl fmt::Display for FrameAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for e in self.regions() {
+            writeln!(
+                f,
+                "{:#018X}-{:#018X}: {:8} frames, free: {:8}, used: {:8}",
+                e.start,
+                e.end(),
+                e.owners.len(),
+                e.num_free,
+                e.owners.len() - e.num_free
+            )?;
+        }
+        let num_free = self.num_free_frames();
+        writeln!(
+            f,
+            "total: {} frames, free: {} frames ({} MiB), used: {} frames",
+            self.num_frames(),
+            num_free,
+            num_free * FRAME_SIZE_4K / 1024 / 1024,
+            self.num_frames() - num_free
+        )?;
+        for owner in FrameOwner::ALL.iter().skip(1) {
+            write!(f, "{owner:?}: {} ", self.num_frames_owned_by(*owner))?;
+        }
+        Ok(())
+    }
+}
+
+pub static FRAME_ALLOCATOR: Mutex<Option<FrameAllocator>> = Mutex::new(None);
+
+/// Collects the memory map entries that match `filter` as (start, number of
+/// frames) pairs. Adjacent entries are merged into one region.
+fn collect_regions(
+    memory_map: &MemoryMapHolder,
+    filter: impl Fn(&EfiMemoryDescriptor) -> bool,
+) -> ([(u64, usize); MAX_REGIONS], usize) {
+    let mut regions = [(0u64, 0usize); MAX_REGIONS];
+    let mut num_regions = 0;
+    for e in memory_map.iter() {
+        if !filter(e) {
+            continue;
+        }
+        let mut start = e.physical_start();
+        let mut num_frames = e.number_of_pages() as usize;
+        // Make sure that the address 0 is never handed out.
+        if start == 0 {
+            start += FRAME_SIZE_4K as u64;
+            num_frames = num_frames.saturating_sub(1);
+        }
+        if num_frames == 0 {
+            continue;
+        }
+        if let Some(last) = regions[..num_regions].last_mut() {
+            if last.0 + (last.1 * FRAME_SIZE_4K) as u64 == start {
+                last.1 += num_frames;
+                continue;
+            }
+        }
+        if num_regions == MAX_REGIONS {
+            error!("frame: too many regions. Ignoring {e:?}");
+            continue;
+        }
+        regions[num_regions] = (start, num_frames);
+        num_regions += 1;
+    }
+    (regions, num_regions)
+}
+
+fn num_owner_map_frames(regions: &[(u64, usize)]) -> usize {
+    regions
+        .iter()
+        .map(|e| e.1)
+        .sum::<usize>()
+        .div_ceil(FRAME_SIZE_4K)
+}
+
+impl FrameAllocator {
+    /// Adds the regions with their owner maps placed at `owners`.
+    ///
+    /// # Safety
+    /// The frames in the regions should not be used by anyone else, and
+    /// `owners` should point to num_owner_map_frames(regions) unused frames.
+    unsafe fn add_regions(
+        &mut self,
+        regions: &[(u64, usize)],
+        owners: *mut FrameOwner,
+    ) -> Result<()> {
+        let total_frames: usize = regions.iter().map(|e| e.1).sum();
+        write_bytes(owners as *mut u8, 0, total_frames);
+        let mut owners = owners;
+        for (start, num_frames) in regions {
+            self.add_region(
+                *start,
+                slice::from_raw_parts_mut(owners, *num_frames),
+            )?;
+            owners = owners.add(*num_frames);
+        }
+        Ok(())
+    }
+}
+
+pub fn init_frame_allocator(memory_map: &MemoryMapHolder) {
+    let (regions, num_regions) = collect_regions(memory_map, |e| {
+        e.memory_type() == EfiMemoryType::CONVENTIONAL_MEMORY
+    });
+    let regions = &regions[..num_regions];
+    let meta_frames = num_owner_map_frames(regions);
+    let meta_addr = regions
+        .iter()
+        .find(|e| e.1 > meta_frames)
+        .expect("No region is large enough for the frame allocator")
+        .0;
+    let mut allocator = FrameAllocator::new();
+    unsafe {
+        allocator
+            .add_regions(regions, meta_addr as *mut FrameOwner)
+            .expect("Failed to add frame regions");
+    }
+    allocator
+        .reserve(meta_addr, meta_frames, FrameOwner::FrameAllocator)
+        .expect("Failed to reserve frames for the owner maps");
+    info!(
+        "frame: {} frames in {} regions, owner maps at {:#X}",
+        allocator.num_frames(),
+        num_regions,
+        meta_addr
+    );
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
+/// Hands the memory of the memory map entries that match `filter` to the
+/// frame allocator, and returns the number of frames added. The owner maps
+/// for them are taken from the frames that are already managed.
+///
+/// # Safety
+/// The memory of the matching entries should not be used anymore.
+pub unsafe fn add_frames_from_memory_map(
+    memory_map: &MemoryMapHolder,
+    filter: impl Fn(&EfiMemoryDescriptor) -> bool,
+) -> Result<usize> {
+    let (regions, num_regions) = collect_regions(memory_map, filter);
+    let regions = &regions[..num_regions];
+    if regions.is_empty() {
+        return Ok(0);
+    }
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator
+        .as_mut()
+        .ok_or("Frame allocator is not initialized")?;
+    let meta_addr = allocator.alloc(
+        num_owner_map_frames(regions),
+        1,
+        FrameOwner::FrameAllocator,
+    )?;
+    allocator.add_regions(regions, meta_addr as *mut FrameOwner)?;
+    Ok(regions.iter().map(|e| e.1).sum())
+}
+
+/// Contiguous physical frames that are freed on drop.
+/// Physical addresses are identity-mapped, so they can be accessed directly.
+pub struct Frames {
+    addr: u64,
+    num_frames: usize,
+}
+impl Frames {
+    pub fn alloc(
+        size: FrameSize,
+        count: usize,
+        owner: FrameOwner,
+    ) -> Result<Self> {
+        let num_frames = count * size.num_4k_frames();
+        let addr = FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .ok_or("Frame allocator is not initialized")?
+            .alloc(num_frames, size.num_4k_frames(), owner)?;
+        Ok(Self { addr, num_frames })
+    }
+    /// Allocates frames that can hold `size` bytes, aligned to `align`.
+    pub fn alloc_bytes(
+        size: usize,
+        align: usize,
+        owner: FrameOwner,
+    ) -> Result<Self> {
+        let num_frames = size.max(1).div_ceil(FRAME_SIZE_4K);
+        let align = align.div_ceil(FRAME_SIZE_4K).max(1);
+        let addr = FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .ok_or("Frame allocator is not initialized")?
+            .alloc(num_frames, align, owner)?;
+        Ok(Self { addr, num_frames })
+    }
+    /// Allocates frames that can hold `size` bytes, aligned to `align`,
+    /// not crossing a multiple of `boundary` (if not 0) and ending below
+    /// `addr_limit`.
+    pub fn alloc_constrained(
+        size: usize,
+        align: usize,
+        boundary: usize,
+        addr_limit: u64,
+        owner: FrameOwner,
+    ) -> Result<Self> {
+        let num_frames = size.max(1).div_ceil(FRAME_SIZE_4K);
+        let align = align.div_ceil(FRAME_SIZE_4K).max(1);
+        let boundary = boundary.div_ceil(FRAME_SIZE_4K);
+        let addr = FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .ok_or("Frame allocator is not initialized")?
+            .alloc_constrained(
+                num_frames, align, boundary, addr_limit, owner,
+            )?;
+        Ok(Self { addr, num_frames })
+    }
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.addr as *mut u8
+    }
+    pub fn size(&self) -> usize {
+        self.num_frames * FRAME_SIZE_4K
+    }
+    pub fn fill_zero(&mut self) {
+        unsafe { write_bytes(self.as_mut_ptr(), 0, self.size()) }
+    }
+    /// Gives up the ownership without freeing the frames.
+    pub fn leak(self) -> u64 {
+        let addr = self.addr;
+        core::mem::forget(self);
+        addr
+    }
+}
+impl Drop for Frames {
+    fn drop(&mut self) {
+        if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            if let Err(e) = allocator.free(self.addr, self.num_frames) {
+                error!("frame: failed to free {:#X}: {e}", self.addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    extern crate alloc;
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    fn test_allocator(regions: &[(u64, usize)]) -> FrameAllocator {
+        let mut allocator = FrameAllocator::new();
+        for (start, num_frames) in regions {
+            let owners = vec![FrameOwner::Free; *num_frames];
+            unsafe {
+                allocator
+                    .add_region(*start, Box::leak(owners.into_boxed_slice()))
+                    .unwrap();
+            }
+        }
+        allocator
+    }
+
+    #[test_case]
+    fn alloc_and_free_frames() {
+        let mut a = test_allocator(&[(0x1000, 3), (0x10_0000, 16)]);
+        assert_eq!(a.num_frames(), 19);
+        let f0 = a.alloc(2, 1, FrameOwner::Heap).unwrap();
+        assert_eq!(f0, 0x1000);
+        // Does not fit in the rest of the first region
+        let f1 = a.alloc(2, 1, FrameOwner::Dma).unwrap();
+        assert_eq!(f1, 0x10_0000);
+        assert_eq!(a.num_free_frames(), 15);
+        assert_eq!(a.num_frames_owned_by(FrameOwner::Dma), 2);
+        assert!(a.free(f1, 2).is_ok());
+        assert!(a.free(f1, 2).is_err());
+        assert!(a.free(0x5000_0000, 1).is_err());
+        assert_eq!(a.num_free_frames(), 17);
+    }
+
+    #[test_case]
+    fn alloc_aligned_frames() {
+        let mut a = test_allocator(&[(0x1F_F000, 1024)]);
+        let f = a.alloc(512, 512, FrameOwner::Heap).unwrap();
+        assert_eq!(f, 0x20_0000);
+        assert!(a.alloc(512, 512, FrameOwner::Heap).is_err());
+        assert!(a.reserve(0x1F_F000, 1, FrameOwner::Other).is_ok());
+        assert!(a.reserve(0x1F_F000, 1, FrameOwner::Other).is_err());
+        assert!(a.alloc(1, 1, FrameOwner::Free).is_err());
+    }
+
+    #[test_case]
+    fn alloc_constrained_frames() {
+        // 0xE000..0x1_2000 crosses the 64 KiB boundary at 0x1_0000
+        let mut a = test_allocator(&[(0xE000, 4), (0x10_0000, 16)]);
+        let f = a.alloc_constrained(2, 1, 16, u64::MAX, FrameOwner::Dma);
+        assert_eq!(f, Ok(0xE000));
+        let f = a.alloc_constrained(2, 1, 16, u64::MAX, FrameOwner::Dma);
+        assert_eq!(f, Ok(0x1_0000));
+        // Nothing fits below the limit
+        let f = a.alloc_constrained(1, 1, 0, 0x10_0000, FrameOwner::Dma);
+        assert!(f.is_err());
+        let f = a.alloc_constrained(1, 1, 0, 0x10_2000, FrameOwner::Dma);
+        assert_eq!(f, Ok(0x10_0000));
+        // Larger than the boundary
+        assert!(a
+            .alloc_constrained(4, 1, 2, u64::MAX, FrameOwner::Dma)
+            .is_err());
+    }
+}
@@ -1,5 +1,9 @@
 use crate::mutex::Mutex;
 use crate::result::Result;
+use crate::x86::fpu::is_avx_usable;
+use core::arch::x86_64::__m256i;
+use core::arch::x86_64::_mm256_set1_epi32;
+use core::arch::x86_64::_mm256_storeu_si256;
 use core::cmp::max;
 use core::cmp::min;
 use core::fmt;
@@ -37,17 +41,6 @@ pub trait Bitmap {
     }
 }
 
-/// # Safety
-///
-/// (x, y) must be a valid point in the buf.
-unsafe fn unchecked_draw_point<T: Bitmap>(
-    buf: &mut T,
-    color: u32,
-    x: i64,
-    y: i64,
-) {
-    *buf.unchecked_pixel_at_mut(x, y) = color;
-}
 pub fn draw_point<T: Bitmap>(
     buf: &mut T,
     color: u32,
@@ -58,6 +51,36 @@ pub fn draw_point<T: Bitmap>(
     Ok(())
 }
 
+/// # Safety
+///
+/// dst must be valid for writing count u32 values, and AVX should be usable
+/// (see x86::fpu::is_avx_usable()).
+#[target_feature(enable = "avx")]
+unsafe fn fill_u32_avx(dst: *mut u32, count: usize, value: u32) {
+    let v = _mm256_set1_epi32(value as i32);
+    let mut i = 0;
+    while i + 8 <= count {
+        _mm256_storeu_si256(dst.add(i) as *mut __m256i, v);
+        i += 8;
+    }
+    while i < count {
+        *dst.add(i) = value;
+        i += 1;
+    }
+}
+/// # Safety
+///
+/// dst must be valid for writing count u32 values.
+unsafe fn fill_u32(dst: *mut u32, count: usize, value: u32) {
+    if is_avx_usable() {
+        fill_u32_avx(dst, count, value)
+    } else {
+        for i in 0..count {
+            *dst.add(i) = value;
+        }
+    }
+}
+
 pub fn fill_rect<T: Bitmap>(
     buf: &mut T,
     color: u32,
@@ -74,10 +97,10 @@ pub fn fill_rect<T: Bitmap>(
         return Err("Out of Range");
     }
     for y in py..py + h {
-        for x in px..px + w {
-            unsafe {
-                unchecked_draw_point(buf, color, x, y);
-            }
+        // SAFETY: all the pixels in the row are validated by the checks above
+        // and pixels in a row are contiguous in the buf.
+        unsafe {
+            fill_u32(buf.unchecked_pixel_at_mut(px, y), w as usize, color);
         }
     }
     Ok(())
@@ -290,6 +313,22 @@ impl<'a, T: Bitmap> fmt::Write for BitmapTextWriter<'a, T> {
                 .or(Err(fmt::Error))?;
                 continue;
             }
+            if c == '\x08' {
+                // Backspace: erase the previous character on the line.
+                if self.cursor_x >= 8 {
+                    self.cursor_x -= 8;
+                    fill_rect(
+                        &mut *self.buf.lock(),
+                        0x000000,
+                        self.cursor_x,
+                        self.cursor_y,
+                        8,
+                        16,
+                    )
+                    .or(Err(fmt::Error))?;
+                }
+                continue;
+            }
             draw_font_fg(
                 &mut *self.buf.lock(),
                 self.cursor_x,
@@ -371,6 +410,59 @@ mod scalar_range_tests {
     }
 }
 
+#[cfg(test)]
+mod fill_rect_tests {
+    extern crate alloc;
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    struct TestBitmap {
+        buf: Vec<u32>,
+        width: i64,
+        height: i64,
+    }
+    impl Bitmap for TestBitmap {
+        fn bytes_per_pixel(&self) -> i64 {
+            4
+        }
+        fn pixels_per_line(&self) -> i64 {
+            self.width
+        }
+        fn width(&self) -> i64 {
+            self.width
+        }
+        fn height(&self) -> i64 {
+            self.height
+        }
+        fn buf_mut(&mut self) -> *mut u8 {
+            self.buf.as_mut_ptr() as *mut u8
+        }
+    }
+
+    #[test_case]
+    fn fills_only_inside_of_rect() {
+        let (width, height) = (37, 5);
+        let mut bmp = TestBitmap {
+            buf: vec![0; (width * height) as usize],
+            width,
+            height,
+        };
+        assert!(fill_rect(&mut bmp, 0xff00ff, 3, 1, 30, 3).is_ok());
+        for y in 0..height {
+            for x in 0..width {
+                let expected = if (3..33).contains(&x) && (1..4).contains(&y) {
+                    0xff00ff
+                } else {
+                    0
+                };
+                assert_eq!(bmp.buf[(y * width + x) as usize], expected);
+            }
+        }
+        assert!(fill_rect(&mut bmp, 0xff00ff, 30, 1, 8, 1).is_err());
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub struct Rect {
     x: i64,
@@ -0,0 +1,336 @@
+//! Debug allocator mode (enabled with the `heap_debug` feature)
+//!
+//! Every allocation is surrounded by red zones filled with a guard pattern:
+//!
+//! |<- FRONT ------------------->|<- size --------->|<- BACK ->|
+//! | DebugHeader | guard bytes   | object           | guard    |
+//!                               ^ returned pointer
+//!
+//! The headers of the live allocations are linked so that `check()` can
+//! verify all of them. The header also marks whether the object is allocated
+//! or freed, and the mark is checked on every free to detect double frees.
+//! Freed objects are filled with a poison pattern and kept in a quarantine
+//! for a while before they are really freed, which makes writes after free
+//! detectable. Any violation panics with a report.
+//!
+//! Limitation: a double free is caught only until the memory is reused.
+//! Once a block leaves the quarantine and is handed out again, a stale
+//! pointer to it has a valid header, so freeing it releases the new object
+//! silently. The quarantine keeps the last QUARANTINE_SIZE frees, which is
+//! the window that is guaranteed to be covered.
+
+extern crate alloc;
+
+use crate::executor::sleep;
+use crate::frame::FrameOwner;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::mutex::Mutex;
+use crate::result::Result;
+use crate::warn;
+use alloc::alloc::Layout;
+use core::cmp::max;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::time::Duration;
+
+const MAGIC_ALLOCATED: u64 = 0xA110_CA7E_DA11_0CED;
+const MAGIC_FREED: u64 = 0xF4EE_DF4E_EDF4_EED0;
+const GUARD_BYTE: u8 = 0xFD;
+const POISON_BYTE: u8 = 0xDF;
+const UNINIT_BYTE: u8 = 0xCD;
+const FRONT_ZONE_SIZE: usize = 64;
+const BACK_ZONE_SIZE: usize = 32;
+const QUARANTINE_SIZE: usize = 64;
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Shown in `show heap` so that a clean report is not over-trusted.
+pub const LIMITATION_NOTE: &str = "heap_debug: double frees are detected \
+     reliably only for the last 64 freed blocks; a stale free after the \
+     memory is reused goes unreported";
+// Keep the note in sync.
+const _: () = assert!(QUARANTINE_SIZE == 64);
+
+#[repr(C)]
+struct DebugHeader {
+    // The allocator behind may reuse the first word of a freed block, so
+    // the magic is not placed there.
+    next: *mut DebugHeader,
+    prev: *mut DebugHeader,
+    magic: u64,
+    size: usize,
+    align: usize,
+    front_size: usize,
+}
+const _: () = assert!(size_of::<DebugHeader>() + 16 <= FRONT_ZONE_SIZE);
+
+impl DebugHeader {
+    fn object(&self) -> *mut u8 {
+        unsafe { (self as *const Self as *mut u8).add(self.front_size) }
+    }
+    fn raw_layout(&self) -> Layout {
+        raw_layout(self.size, self.align)
+    }
+    fn front_guard(&self) -> &[u8] {
+        let start = size_of::<DebugHeader>();
+        unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self as *const u8).add(start),
+                self.front_size - start,
+            )
+        }
+    }
+    fn back_guard(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self.object().add(self.size),
+                BACK_ZONE_SIZE,
+            )
+        }
+    }
+    fn object_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.object(), self.size) }
+    }
+    /// Panics if the red zones are broken.
+    fn verify_guards(&self) {
+        if let Some(i) =
+            self.front_guard().iter().rposition(|e| *e != GUARD_BYTE)
+        {
+            panic!(
+                "heap: underflow: {} bytes before the {}-byte object at {:p} \
+                 are overwritten",
+                self.front_guard().len() - i,
+                self.size,
+                self.object()
+            );
+        }
+        if let Some(i) = self.back_guard().iter().position(|e| *e != GUARD_BYTE)
+        {
+            panic!(
+                "heap: overflow: byte +{} after the {}-byte object at {:p} is \
+                 {:#04X}",
+                i,
+                self.size,
+                self.object(),
+                self.back_guard()[i]
+            );
+        }
+    }
+    /// Panics if the object is modified after it is freed.
+    fn verify_poison(&self) {
+        if let Some(i) =
+            self.object_bytes().iter().position(|e| *e != POISON_BYTE)
+        {
+            panic!(
+                "heap: use after free: byte +{} of the freed {}-byte object \
+                 at {:p} is {:#04X}",
+                i,
+                self.size,
+                self.object(),
+                self.object_bytes()[i]
+            );
+        }
+        self.verify_guards();
+    }
+}
+
+fn front_zone_size(align: usize) -> usize {
+    max(align, FRONT_ZONE_SIZE)
+}
+fn raw_layout(size: usize, align: usize) -> Layout {
+    let front = front_zone_size(align);
+    Layout::from_size_align(front + size + BACK_ZONE_SIZE, front)
+        .expect("heap: invalid layout for the debug allocator")
+}
+
+struct DebugState {
+    live: *mut DebugHeader,
+    quarantine: [*mut DebugHeader; QUARANTINE_SIZE],
+    quarantine_next: usize,
+}
+impl DebugState {
+    unsafe fn link(&mut self, header: *mut DebugHeader) {
+        (*header).prev = null_mut();
+        (*header).next = self.live;
+        if let Some(next) = self.live.as_mut() {
+            next.prev = header;
+        }
+        self.live = header;
+    }
+    unsafe fn unlink(&mut self, header: *mut DebugHeader) {
+        let header = &mut *header;
+        if let Some(prev) = header.prev.as_mut() {
+            prev.next = header.next;
+        } else {
+            self.live = header.next;
+        }
+        if let Some(next) = header.next.as_mut() {
+            next.prev = header.prev;
+        }
+    }
+    /// Puts the header into the quarantine and returns the one evicted.
+    fn quarantine(&mut self, header: *mut DebugHeader) -> *mut DebugHeader {
+        let evicted = self.quarantine[self.quarantine_next];
+        self.quarantine[self.quarantine_next] = header;
+        self.quarantine_next = (self.quarantine_next + 1) % QUARANTINE_SIZE;
+        evicted
+    }
+    fn is_quarantined(&self, header: *mut DebugHeader) -> bool {
+        self.quarantine.iter().any(|e| *e == header)
+    }
+}
+unsafe impl Send for DebugState {}
+
+static DEBUG_STATE: Mutex<DebugState> = Mutex::new(DebugState {
+    live: null_mut(),
+    quarantine: [null_mut(); QUARANTINE_SIZE],
+    quarantine_next: 0,
+});
+
+/// Allocates an object with red zones. `raw_alloc` is used to allocate the
+/// memory including the red zones.
+pub fn alloc(layout: Layout, raw_alloc: impl Fn(Layout) -> *mut u8) -> *mut u8 {
+    let raw = raw_alloc(raw_layout(layout.size(), layout.align()));
+    if raw.is_null() {
+        return raw;
+    }
+    let front_size = front_zone_size(layout.align());
+    let header = raw as *mut DebugHeader;
+    unsafe {
+        raw.write_bytes(GUARD_BYTE, front_size);
+        header.write(DebugHeader {
+            next: null_mut(),
+            prev: null_mut(),
+            magic: MAGIC_ALLOCATED,
+            size: layout.size(),
+            align: layout.align(),
+            front_size,
+        });
+        let object = raw.add(front_size);
+        object.write_bytes(UNINIT_BYTE, layout.size());
+        object
+            .add(layout.size())
+            .write_bytes(GUARD_BYTE, BACK_ZONE_SIZE);
+        DEBUG_STATE.lock().link(header);
+        object
+    }
+}
+
+fn frame_owner_of(addr: usize) -> Option<FrameOwner> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .and_then(|e| e.owner_of(addr as u64))
+}
+
+/// Verifies and frees an object allocated by `alloc()`. `raw_dealloc` is
+/// called for the objects evicted from the quarantine.
+///
+/// # Safety
+/// ptr should be a pointer that was returned by `alloc()`.
+pub unsafe fn dealloc(
+    ptr: *mut u8,
+    layout: Layout,
+    raw_dealloc: impl Fn(*mut u8, Layout),
+) {
+    let front_size = front_zone_size(layout.align());
+    match frame_owner_of(ptr as usize) {
+        Some(FrameOwner::Heap) | None => {}
+        Some(owner) => panic!(
+            "heap: free of a foreign pointer {ptr:p} (frame owner: {owner:?})"
+        ),
+    }
+    let header = ptr.sub(front_size) as *mut DebugHeader;
+    let evicted = {
+        let mut state = DEBUG_STATE.lock();
+        match (*header).magic {
+            MAGIC_ALLOCATED => {}
+            MAGIC_FREED => {
+                // The mark is kept after the object leaves the quarantine
+                // until the memory is reused by another allocation.
+                panic!(
+                    "heap: double free of the {}-byte object at {ptr:p} \
+                     (quarantined: {})",
+                    (*header).size,
+                    state.is_quarantined(header)
+                )
+            }
+            _ => panic!(
+                "heap: free of {ptr:p} that is not allocated by the heap (or \
+                 its header is corrupted)"
+            ),
+        }
+        if (*header).size != layout.size() || (*header).align != layout.align()
+        {
+            panic!(
+                "heap: {ptr:p} is allocated with (size: {}, align: {}) but \
+                 freed with (size: {}, align: {})",
+                (*header).size,
+                (*header).align,
+                layout.size(),
+                layout.align()
+            );
+        }
+        (*header).verify_guards();
+        state.unlink(header);
+        (*header).magic = MAGIC_FREED;
+        ptr.write_bytes(POISON_BYTE, layout.size());
+        state.quarantine(header)
+    };
+    if let Some(evicted) = evicted.as_ref() {
+        evicted.verify_poison();
+        raw_dealloc(
+            evicted as *const DebugHeader as *mut u8,
+            evicted.raw_layout(),
+        );
+    }
+}
+
+/// Verifies the red zones of all the live objects and the poison of the
+/// quarantined ones. Panics on the first violation, and returns the number
+/// of objects checked otherwise.
+pub fn check() -> usize {
+    let state = DEBUG_STATE.lock();
+    let mut num_checked = 0;
+    let mut header = state.live;
+    while let Some(e) = unsafe { header.as_ref() } {
+        if e.magic != MAGIC_ALLOCATED {
+            panic!("heap: corrupted header at {header:p}");
+        }
+        e.verify_guards();
+        num_checked += 1;
+        header = e.next;
+    }
+    for e in state.quarantine.iter() {
+        if let Some(e) = unsafe { e.as_ref() } {
+            e.verify_poison();
+            num_checked += 1;
+        }
+    }
+    num_checked
+}
+
+pub async fn heap_check_task() -> Result<()> {
+    warn!("heap: debug allocator is enabled. It will be slow.");
+    loop {
+        check();
+        sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[test_case]
+fn red_zones_test() {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    let b = Box::new([0x5Au8; 100]);
+    let header =
+        unsafe { &*(b.as_ptr().sub(FRONT_ZONE_SIZE) as *const DebugHeader) };
+    assert_eq!(header.magic, MAGIC_ALLOCATED);
+    assert_eq!(header.size, 100);
+    assert!(header.back_guard().iter().all(|e| *e == GUARD_BYTE));
+    let mut v = Vec::<u64>::with_capacity(4);
+    v.push(1);
+    assert!(check() >= 2);
+    drop(v);
+    drop(b);
+    assert!(check() > 0);
+}
@@ -0,0 +1,230 @@
+//! Live allocation tracking (enabled with the `heap_tracking` feature)
+//!
+//! Records the pointer, size and the call stack of every live heap
+//! allocation in a fixed-size table, so that the allocations that are never
+//! freed can be grouped by their call sites. The table is not allocated
+//! from the heap to avoid recursion.
+//!
+//! The table is an open-addressing hash table with linear probing, keyed by
+//! the pointer. Slots are emptied by shifting the following entries back,
+//! so a lookup can stop at the first empty slot.
+
+extern crate alloc;
+
+use crate::mutex::Mutex;
+use crate::result::Result;
+use crate::x86::read_rbp;
+use crate::x86::walk_stack;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+
+const MAX_TRACKED_ALLOCATIONS: usize = 8192;
+const CALLER_DEPTH: usize = 6;
+
+#[derive(Clone, Copy)]
+pub struct AllocationRecord {
+    pub ptr: usize,
+    pub size: usize,
+    /// Allocation sequence number, to tell older allocations from newer ones
+    pub seq: usize,
+    /// Return addresses, innermost first
+    pub callers: [u64; CALLER_DEPTH],
+}
+impl AllocationRecord {
+    const EMPTY: Self = Self {
+        ptr: 0,
+        size: 0,
+        seq: 0,
+        callers: [0; CALLER_DEPTH],
+    };
+}
+
+struct Tracker {
+    records: [AllocationRecord; MAX_TRACKED_ALLOCATIONS],
+    next_seq: usize,
+    num_live: usize,
+    /// Allocations that were not recorded since the table was full
+    num_dropped: usize,
+    /// Frees of pointers that are not in the table, i.e. the dropped ones
+    /// and the ones allocated before the tracking started
+    num_unknown_frees: usize,
+}
+impl Tracker {
+    fn slot_hint(ptr: usize) -> usize {
+        // Allocations are at least 8-byte aligned
+        (ptr >> 3) % MAX_TRACKED_ALLOCATIONS
+    }
+    fn insert(
+        &mut self,
+        ptr: usize,
+        size: usize,
+        callers: [u64; CALLER_DEPTH],
+    ) {
+        let hint = Self::slot_hint(ptr);
+        let slot = (0..MAX_TRACKED_ALLOCATIONS)
+            .map(|i| (hint + i) % MAX_TRACKED_ALLOCATIONS)
+            .find(|i| self.records[*i].ptr == 0);
+        let Some(slot) = slot else {
+            self.num_dropped += 1;
+            return;
+        };
+        self.records[slot] = AllocationRecord {
+            ptr,
+            size,
+            seq: self.next_seq,
+            callers,
+        };
+        self.next_seq += 1;
+        self.num_live += 1;
+    }
+    fn find(&self, ptr: usize) -> Option<usize> {
+        let hint = Self::slot_hint(ptr);
+        (0..MAX_TRACKED_ALLOCATIONS)
+            .map(|i| (hint + i) % MAX_TRACKED_ALLOCATIONS)
+            .take_while(|i| self.records[*i].ptr != 0)
+            .find(|i| self.records[*i].ptr == ptr)
+    }
+    fn remove(&mut self, ptr: usize) {
+        let Some(mut hole) = self.find(ptr) else {
+            self.num_unknown_frees += 1;
+            return;
+        };
+        self.records[hole] = AllocationRecord::EMPTY;
+        self.num_live -= 1;
+        // Move the following entries of the cluster into the hole unless
+        // their hint is in (hole, i], so that every entry stays reachable
+        // from its hint without crossing an empty slot.
+        // c.f. Knuth, TAOCP Vol.3, 6.4 Algorithm R
+        let mut i = hole;
+        loop {
+            i = (i + 1) % MAX_TRACKED_ALLOCATIONS;
+            let ptr = self.records[i].ptr;
+            if ptr == 0 {
+                break;
+            }
+            let hint = Self::slot_hint(ptr);
+            let stays = if hole <= i {
+                hole < hint && hint <= i
+            } else {
+                hole < hint || hint <= i
+            };
+            if !stays {
+                self.records[hole] = self.records[i];
+                self.records[i] = AllocationRecord::EMPTY;
+                hole = i;
+            }
+        }
+    }
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    records: [AllocationRecord::EMPTY; MAX_TRACKED_ALLOCATIONS],
+    next_seq: 0,
+    num_live: 0,
+    num_dropped: 0,
+    num_unknown_frees: 0,
+});
+
+pub fn track_alloc(ptr: *mut u8, size: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    let mut callers = [0u64; CALLER_DEPTH];
+    walk_stack(read_rbp(), &mut callers);
+    TRACKER.lock().insert(ptr as usize, size, callers);
+}
+
+pub fn track_dealloc(ptr: *mut u8) {
+    TRACKER.lock().remove(ptr as usize);
+}
+
+/// Returns a copy of the records of the live allocations.
+pub fn live_allocations() -> Vec<AllocationRecord> {
+    // Reserve the space before taking the lock, since allocating while the
+    // lock is held would deadlock.
+    let mut records = Vec::with_capacity(MAX_TRACKED_ALLOCATIONS);
+    let tracker = TRACKER.lock();
+    for e in tracker.records.iter() {
+        if e.ptr != 0 {
+            records.push(*e);
+        }
+    }
+    records
+}
+
+/// Writes the live allocations grouped by their call stacks, largest first.
+pub fn dump_leaks(w: &mut dyn fmt::Write) -> Result<()> {
+    let records = live_allocations();
+    let mut sites: BTreeMap<[u64; CALLER_DEPTH], (usize, usize, usize)> =
+        BTreeMap::new();
+    for e in records.iter() {
+        let site = sites.entry(e.callers).or_insert((0, 0, usize::MAX));
+        site.0 += 1;
+        site.1 += e.size;
+        site.2 = site.2.min(e.seq);
+    }
+    let mut sites: Vec<_> = sites.into_iter().collect();
+    sites.sort_by_key(|(_, (_, bytes, _))| usize::MAX - bytes);
+    for (callers, (count, bytes, oldest_seq)) in sites.iter() {
+        write!(
+            w,
+            "{bytes:10} bytes in {count:6} allocs (oldest #{oldest_seq}):"
+        )
+        .or(Err("Failed to write"))?;
+        for addr in callers.iter().take_while(|e| **e != 0) {
+            write!(w, " {addr:#X}").or(Err("Failed to write"))?;
+        }
+        writeln!(w).or(Err("Failed to write"))?;
+    }
+    let tracker = TRACKER.lock();
+    writeln!(
+        w,
+        "# live: {}, dropped: {}, unknown frees: {}, next seq: #{}",
+        tracker.num_live,
+        tracker.num_dropped,
+        tracker.num_unknown_frees,
+        tracker.next_seq
+    )
+    .or(Err("Failed to write"))
+}
+
+#[test_case]
+fn track_alloc_dealloc_test() {
+    let is_live = |ptr: usize| live_allocations().iter().any(|e| e.ptr == ptr);
+    // Fake pointers that map to the same slot hint
+    let p0 = 0xDEAD_0000usize;
+    let p1 = p0 + MAX_TRACKED_ALLOCATIONS * 8;
+    track_alloc(p0 as *mut u8, 16);
+    track_alloc(p1 as *mut u8, 32);
+    assert!(is_live(p0) && is_live(p1));
+    track_dealloc(p0 as *mut u8);
+    assert!(!is_live(p0));
+    // Still found after the slot before it is emptied
+    assert!(is_live(p1));
+    track_dealloc(p1 as *mut u8);
+    assert!(!is_live(p1));
+}
+
+#[test_case]
+fn track_dealloc_shift_test() {
+    let is_live = |ptr: usize| live_allocations().iter().any(|e| e.ptr == ptr);
+    let unknown_frees = || TRACKER.lock().num_unknown_frees;
+    // p0 and p1 share a hint, and p2 hints at the slot that p1 takes.
+    let p0 = 0xBEEF_0000usize;
+    let p1 = p0 + MAX_TRACKED_ALLOCATIONS * 8;
+    let p2 = p0 + 8;
+    track_alloc(p0 as *mut u8, 16);
+    track_alloc(p1 as *mut u8, 16);
+    track_alloc(p2 as *mut u8, 16);
+    track_dealloc(p0 as *mut u8);
+    assert!(!is_live(p0));
+    assert!(is_live(p1) && is_live(p2));
+    track_dealloc(p2 as *mut u8);
+    track_dealloc(p1 as *mut u8);
+    assert!(!is_live(p1) && !is_live(p2));
+    // A pointer that is not tracked does not touch the live entries.
+    let before = unknown_frees();
+    track_dealloc(p0 as *mut u8);
+    assert_eq!(unknown_frees(), before + 1);
+}
@@ -1,7 +1,11 @@
-use crate::mutex::Mutex;
+use crate::mutex::IrqSpinLock;
 use core::mem::size_of;
+use core::ptr::null_mut;
 use core::ptr::read_volatile;
 use core::ptr::write_volatile;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
 use core::time::Duration;
 
 const TIMER_CONFIG_LEVEL_TRIGGER: u64 = 1 << 1;
@@ -81,15 +85,33 @@ impl Hpet {
         self.freq
     }
 }
-static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
+static HPET: IrqSpinLock<Option<Hpet>> = IrqSpinLock::new(None);
+// The main counter is read-only after the initialization, so it can be read
+// without taking the HPET lock. These are set by set_global_hpet().
+static HPET_MAIN_COUNTER: AtomicPtr<u64> = AtomicPtr::new(null_mut());
+static HPET_FREQ: AtomicU64 = AtomicU64::new(0);
 pub fn set_global_hpet(hpet: Hpet) {
-    assert!(HPET.lock().is_none());
-    *HPET.lock() = Some(hpet);
+    let mut locked = HPET.lock();
+    assert!(locked.is_none());
+    let hpet = locked.insert(hpet);
+    HPET_FREQ.store(hpet.freq(), Ordering::SeqCst);
+    HPET_MAIN_COUNTER
+        .store(&mut hpet.registers.main_counter_value, Ordering::SeqCst);
+}
+pub fn global_main_counter() -> Option<u64> {
+    let counter = HPET_MAIN_COUNTER.load(Ordering::Relaxed);
+    if counter.is_null() {
+        None
+    } else {
+        Some(unsafe { read_volatile(counter) })
+    }
+}
+pub fn global_freq() -> u64 {
+    HPET_FREQ.load(Ordering::Relaxed)
 }
 pub fn global_timestamp() -> Duration {
-    if let Some(hpet) = &*HPET.lock() {
-        let ns =
-            hpet.main_counter() as u128 * 1_000_000_000 / hpet.freq() as u128;
+    if let Some(counter) = global_main_counter() {
+        let ns = counter as u128 * 1_000_000_000 / global_freq() as u128;
         Duration::from_nanos(ns as u64)
     } else {
         Duration::ZERO
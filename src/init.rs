@@ -2,6 +2,8 @@ extern crate alloc;
 
 use crate::acpi::AcpiRsdpStruct;
 use crate::allocator::ALLOCATOR;
+use crate::frame::add_frames_from_memory_map;
+use crate::frame::init_frame_allocator;
 use crate::graphics::draw_test_pattern;
 use crate::graphics::fill_rect;
 use crate::graphics::Bitmap;
@@ -12,11 +14,16 @@ use crate::mutex::Mutex;
 use crate::pci::Pci;
 use crate::uefi::exit_from_efi_boot_services;
 use crate::uefi::EfiHandle;
+use crate::uefi::EfiMemoryDescriptor;
 use crate::uefi::EfiMemoryType;
 use crate::uefi::EfiMemoryType::*;
 use crate::uefi::EfiSystemTable;
 use crate::uefi::MemoryMapHolder;
 use crate::uefi::VramBufferInfo;
+use crate::x86::cpuid::init_cpu_features;
+use crate::x86::enable_nx_if_supported;
+use crate::x86::fpu::init_fpu;
+use crate::x86::read_rsp;
 use crate::x86::write_cr3;
 use crate::x86::PageAttr;
 use crate::x86::PAGE_SIZE;
@@ -36,17 +43,24 @@ pub fn init_basic_runtime(
         efi_system_table,
         &mut memory_map,
     );
-    ALLOCATOR.init_with_mmap(&memory_map);
+    init_frame_allocator(&memory_map);
+    ALLOCATOR.init_with_frames();
+    init_cpu_features();
+    init_fpu();
     *EFI_MEMORY_MAP.lock() = Some(memory_map.clone());
     memory_map
 }
 
 pub fn init_paging(memory_map: &MemoryMapHolder) {
+    if enable_nx_if_supported() {
+        info!("EFER.NXE enabled");
+    }
     let mut table = PML4::new();
     let mut end_of_mem = 0x1_0000_0000u64;
     for e in memory_map.iter() {
         match e.memory_type() {
-            CONVENTIONAL_MEMORY | LOADER_CODE | LOADER_DATA => {
+            CONVENTIONAL_MEMORY | LOADER_CODE | LOADER_DATA
+            | BOOT_SERVICES_CODE | BOOT_SERVICES_DATA | ACPI_RECLAIM_MEMORY => {
                 end_of_mem = max(
                     end_of_mem,
                     e.physical_start()
@@ -68,6 +82,42 @@ pub fn init_paging(memory_map: &MemoryMapHolder) {
     }
 }
 
+pub fn is_reclaimable(e: &EfiMemoryDescriptor) -> bool {
+    matches!(
+        e.memory_type(),
+        BOOT_SERVICES_CODE | BOOT_SERVICES_DATA | ACPI_RECLAIM_MEMORY
+    )
+}
+
+/// Hands the memory used by the UEFI boot services and the ACPI reclaimable
+/// memory to the frame allocator. The ACPI tables are copied before that,
+/// and the copy is returned. This should be called after init_paging() and
+/// init_exceptions(), since the tables made by the firmware live there.
+pub fn reclaim_boot_memory(
+    memory_map: &MemoryMapHolder,
+    acpi: &AcpiRsdpStruct,
+) -> &'static AcpiRsdpStruct {
+    let acpi = acpi.copy_tables().expect("Failed to copy ACPI tables");
+    // We are still running on the stack given by the firmware.
+    let rsp = read_rsp();
+    let is_stack = |e: &EfiMemoryDescriptor| {
+        e.physical_start() <= rsp
+            && rsp < e.physical_start() + e.number_of_pages() * PAGE_SIZE as u64
+    };
+    let num_frames = unsafe {
+        add_frames_from_memory_map(memory_map, |e| {
+            is_reclaimable(e) && !is_stack(e)
+        })
+    }
+    .expect("Failed to reclaim boot memory");
+    info!(
+        "Reclaimed {num_frames} frames ({} MiB) of boot services and ACPI \
+         memory",
+        num_frames * PAGE_SIZE / 1024 / 1024
+    );
+    acpi
+}
+
 pub fn init_hpet(acpi: &AcpiRsdpStruct) {
     let hpet = acpi.hpet().expect("Failed to get HPET from ACPI");
     let hpet = hpet
@@ -0,0 +1,250 @@
+//! I/O APIC
+//!
+//! Routes the interrupts from devices, identified by Global System
+//! Interrupt (GSI) numbers, to the Local APIC of the boot CPU. The I/O APICs
+//! and the Interrupt Source Overrides for ISA IRQs come from the MADT.
+//!
+//! c.f. 82093AA I/O Advanced Programmable Interrupt Controller (IOAPIC)
+
+extern crate alloc;
+
+use crate::acpi::global_acpi;
+use crate::acpi::AcpiRsdpStruct;
+use crate::acpi::MadtEntry;
+use crate::apic::LocalApic;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::result::Result;
+use crate::x86::with_current_page_table;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use alloc::vec::Vec;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+
+const REG_IOREGSEL: u64 = 0x00;
+const REG_IOWIN: u64 = 0x10;
+
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL_BASE: u32 = 0x10;
+
+const REDIR_ACTIVE_LOW: u64 = 1 << 13;
+const REDIR_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+const REDIR_DESTINATION_SHIFT: u64 = 56;
+
+// MPS INTI flags in Interrupt Source Overrides. 0b00 means "conforms to the
+// specifications of the bus", i.e. the default is used.
+const MPS_INTI_POLARITY_MASK: u16 = 0b11;
+const MPS_INTI_POLARITY_ACTIVE_HIGH: u16 = 0b01;
+const MPS_INTI_POLARITY_ACTIVE_LOW: u16 = 0b11;
+const MPS_INTI_TRIGGER_SHIFT: u16 = 2;
+const MPS_INTI_TRIGGER_EDGE: u16 = 0b01;
+const MPS_INTI_TRIGGER_LEVEL: u16 = 0b11;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    num_entries: u32,
+}
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            write_volatile((self.base + REG_IOREGSEL) as *mut u32, reg);
+            read_volatile((self.base + REG_IOWIN) as *const u32)
+        }
+    }
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            write_volatile((self.base + REG_IOREGSEL) as *mut u32, reg);
+            write_volatile((self.base + REG_IOWIN) as *mut u32, value);
+        }
+    }
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.num_entries).contains(&gsi)
+    }
+    fn redirection(&self, index: u32) -> u64 {
+        let reg = IOREDTBL_BASE + index * 2;
+        (self.read(reg) as u64) | ((self.read(reg + 1) as u64) << 32)
+    }
+    fn set_redirection(&self, index: u32, entry: u64) {
+        let reg = IOREDTBL_BASE + index * 2;
+        // Mask the entry while it is updated half by half.
+        self.write(reg, REDIR_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// Finds the I/O APICs in the MADT and masks all of their inputs. This
+/// should be called after init_local_apic().
+pub fn init_io_apics(acpi: &AcpiRsdpStruct) -> Result<()> {
+    let madt = acpi.madt().ok_or("MADT not found")?;
+    let mut io_apics = Vec::new();
+    for e in madt.iter() {
+        let MadtEntry::IoApic(e) = e else {
+            continue;
+        };
+        let base = e.address();
+        unsafe {
+            with_current_page_table(|pt| {
+                pt.create_mapping(
+                    base,
+                    base + PAGE_SIZE as u64,
+                    base,
+                    PageAttr::ReadWriteIo,
+                )
+            })?
+        }
+        let mut io_apic = IoApic {
+            base,
+            gsi_base: e.gsi_base(),
+            num_entries: 0,
+        };
+        io_apic.num_entries = ((io_apic.read(IOAPICVER) >> 16) & 0xFF) + 1;
+        for i in 0..io_apic.num_entries {
+            io_apic.set_redirection(i, REDIR_MASKED);
+        }
+        info!(
+            "I/O APIC: id: {}, address: {:#X}, GSI: {}..{}",
+            e.id(),
+            base,
+            io_apic.gsi_base,
+            io_apic.gsi_base + io_apic.num_entries
+        );
+        io_apics.push(io_apic);
+    }
+    if io_apics.is_empty() {
+        return Err("I/O APIC not found");
+    }
+    *IO_APICS.lock() = io_apics;
+    Ok(())
+}
+
+/// Applies the MPS INTI flags of an Interrupt Source Override to the
+/// defaults.
+fn apply_mps_inti_flags(
+    flags: u16,
+    trigger: Trigger,
+    polarity: Polarity,
+) -> (Trigger, Polarity) {
+    let trigger = match (flags >> MPS_INTI_TRIGGER_SHIFT) & 0b11 {
+        MPS_INTI_TRIGGER_EDGE => Trigger::Edge,
+        MPS_INTI_TRIGGER_LEVEL => Trigger::Level,
+        _ => trigger,
+    };
+    let polarity = match flags & MPS_INTI_POLARITY_MASK {
+        MPS_INTI_POLARITY_ACTIVE_HIGH => Polarity::ActiveHigh,
+        MPS_INTI_POLARITY_ACTIVE_LOW => Polarity::ActiveLow,
+        _ => polarity,
+    };
+    (trigger, polarity)
+}
+
+/// Returns the GSI, the trigger mode and the polarity of an ISA IRQ, taking
+/// the Interrupt Source Overrides in the MADT into account. `trigger` and
+/// `polarity` are used if no override specifies them.
+pub fn isa_irq_to_gsi(
+    irq: u32,
+    trigger: Trigger,
+    polarity: Polarity,
+) -> (u32, Trigger, Polarity) {
+    let iso = global_acpi().and_then(|acpi| acpi.madt()).and_then(|madt| {
+        madt.iter().find_map(|e| match e {
+            MadtEntry::InterruptSourceOverride(e)
+                if e.source() as u32 == irq =>
+            {
+                Some(e)
+            }
+            _ => None,
+        })
+    });
+    match iso {
+        Some(iso) => {
+            let (trigger, polarity) =
+                apply_mps_inti_flags(iso.flags(), trigger, polarity);
+            (iso.gsi(), trigger, polarity)
+        }
+        None => (irq, trigger, polarity),
+    }
+}
+
+/// Delivers the GSI to `vector` on the current CPU and unmasks it.
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    trigger: Trigger,
+    polarity: Polarity,
+) -> Result<()> {
+    let lapic = LocalApic::current().ok_or("Local APIC is not initialized")?;
+    let apic_id = u8::try_from(lapic.id())
+        .or(Err("APIC ID is too large for I/O APIC"))?;
+    let mut entry =
+        vector as u64 | ((apic_id as u64) << REDIR_DESTINATION_SHIFT);
+    if trigger == Trigger::Level {
+        entry |= REDIR_LEVEL_TRIGGERED;
+    }
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIR_ACTIVE_LOW;
+    }
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics
+        .iter()
+        .find(|e| e.handles(gsi))
+        .ok_or("No I/O APIC handles the GSI")?;
+    io_apic.set_redirection(gsi - io_apic.gsi_base, entry);
+    Ok(())
+}
+
+/// Routes an ISA IRQ, which is edge-triggered and active-high unless
+/// overridden, to `vector`. Returns the GSI of the IRQ.
+pub fn route_isa_irq(irq: u8, vector: u8) -> Result<u32> {
+    let (gsi, trigger, polarity) =
+        isa_irq_to_gsi(irq as u32, Trigger::Edge, Polarity::ActiveHigh);
+    route_gsi(gsi, vector, trigger, polarity)?;
+    Ok(gsi)
+}
+
+pub fn mask_gsi(gsi: u32) -> Result<()> {
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics
+        .iter()
+        .find(|e| e.handles(gsi))
+        .ok_or("No I/O APIC handles the GSI")?;
+    let index = gsi - io_apic.gsi_base;
+    io_apic.set_redirection(index, io_apic.redirection(index) | REDIR_MASKED);
+    Ok(())
+}
+
+#[test_case]
+fn apply_mps_inti_flags_test() {
+    let defaults = (Trigger::Edge, Polarity::ActiveHigh);
+    assert_eq!(apply_mps_inti_flags(0, defaults.0, defaults.1), defaults);
+    assert_eq!(
+        apply_mps_inti_flags(0b1111, defaults.0, defaults.1),
+        (Trigger::Level, Polarity::ActiveLow)
+    );
+    // Level-triggered, conforming to the bus for the polarity
+    assert_eq!(
+        apply_mps_inti_flags(0b1100, Trigger::Level, Polarity::ActiveLow),
+        (Trigger::Level, Polarity::ActiveLow)
+    );
+    assert_eq!(
+        apply_mps_inti_flags(0b0101, Trigger::Level, Polarity::ActiveLow),
+        defaults
+    );
+}
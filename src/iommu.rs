@@ -0,0 +1,818 @@
+//! Intel VT-d IOMMU (DMA remapping)
+//!
+//! Each device that does DMA is attached to its own domain, i.e. a set of
+//! second-level page tables that translates the addresses used by the
+//! device (IOVAs) into physical addresses. IOVAs are the same as the
+//! physical addresses, so drivers do not have to care about the
+//! translation, but an isolated device can only access the DmaBuffers
+//! allocated for it (see DmaConstraints::device()) and its Reserved Memory
+//! Regions (RMRRs) in the DMAR. Devices whose drivers still let them access
+//! other memory (e.g. the heap) are attached in pass-through mode instead.
+//! Blocked accesses are reported via the fault event interrupt.
+//!
+//! Try it with `-device intel-iommu` on QEMU (see scripts/launch_qemu.sh).
+//!
+//! c.f. Intel Virtualization Technology for Directed I/O Architecture
+//! Specification
+
+extern crate alloc;
+
+use crate::acpi::AcpiRsdpStruct;
+use crate::acpi::DmarDeviceScope;
+use crate::acpi::DmarDeviceScopeType;
+use crate::acpi::DmarEntry;
+use crate::apic::LocalApic;
+use crate::apic::VECTOR_IOMMU_FAULT;
+use crate::dma::DmaBuffer;
+use crate::dma::DmaConstraints;
+use crate::info;
+use crate::mutex::IrqSpinLock;
+use crate::mutex::Mutex;
+use crate::pci::BusDeviceFunction;
+use crate::pci::Pci;
+use crate::result::Result;
+use crate::serial::SerialPort;
+use crate::warn;
+use crate::x86::busy_loop_hint;
+use crate::x86::set_interrupt_handler;
+use crate::x86::with_current_page_table;
+use crate::x86::InterruptInfo;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use alloc::collections::btree_map::Entry;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+use core::ops::Range;
+use core::ops::RangeInclusive;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+// 11.4 Register Descriptions
+const REG_VER: u64 = 0x00;
+const REG_CAP: u64 = 0x08;
+const REG_ECAP: u64 = 0x10;
+const REG_GCMD: u64 = 0x18;
+const REG_GSTS: u64 = 0x1C;
+const REG_RTADDR: u64 = 0x20;
+const REG_CCMD: u64 = 0x28;
+const REG_FSTS: u64 = 0x34;
+const REG_FECTL: u64 = 0x38;
+const REG_FEDATA: u64 = 0x3C;
+const REG_FEADDR: u64 = 0x40;
+const REG_FEUADDR: u64 = 0x44;
+// Relative to the IOTLB register set at ECAP.IRO
+const REG_IOTLB: u64 = 0x08;
+
+const CAP_ND_MASK: u64 = 0b111;
+const CAP_RWBF: u64 = 1 << 4;
+const CAP_SAGAW_SHIFT: u64 = 8;
+const CAP_SAGAW_39BIT: u64 = 1 << 1;
+const CAP_SAGAW_48BIT: u64 = 1 << 2;
+const CAP_MGAW_SHIFT: u64 = 16;
+const CAP_FRO_SHIFT: u64 = 24;
+const CAP_NFR_SHIFT: u64 = 40;
+const ECAP_PT: u64 = 1 << 6;
+const ECAP_IRO_SHIFT: u64 = 8;
+
+const GCMD_TE: u32 = 1 << 31;
+const GCMD_SRTP: u32 = 1 << 30;
+const GCMD_WBF: u32 = 1 << 27;
+// The bits of GSTS that keep their state in GCMD. The others are one-shot.
+const GSTS_PERSISTENT_MASK: u32 = 0x96FF_FFFF;
+
+const CCMD_ICC: u64 = 1 << 63;
+const CCMD_CIRG_GLOBAL: u64 = 0b01 << 61;
+const IOTLB_IVT: u64 = 1 << 63;
+const IOTLB_IIRG_GLOBAL: u64 = 0b01 << 60;
+const IOTLB_IIRG_DOMAIN: u64 = 0b10 << 60;
+const IOTLB_DID_SHIFT: u64 = 32;
+const IOTLB_DR: u64 = 1 << 49;
+const IOTLB_DW: u64 = 1 << 48;
+
+const FSTS_PFO: u32 = 1 << 0;
+const FSTS_PPF: u32 = 1 << 1;
+const FSTS_FRI_SHIFT: u32 = 8;
+const FECTL_IM: u32 = 1 << 31;
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+const MSI_DESTINATION_SHIFT: u32 = 12;
+// In the upper 64 bits of a fault recording register
+const FRCD_F: u64 = 1 << 63;
+const FRCD_T_READ: u64 = 1 << 62;
+const FRCD_FR_SHIFT: u64 = 32;
+const FRCD_SID_MASK: u64 = 0xFFFF;
+
+// 9.1 Root Entry, 9.3 Context Entry (in the lower 64 bits)
+const ENTRY_PRESENT: u64 = 1;
+const CONTEXT_TT_PASS_THROUGH: u64 = 0b10 << 2;
+// In the upper 64 bits of a context entry
+const CONTEXT_AW_39BIT: u64 = 1;
+const CONTEXT_AW_48BIT: u64 = 2;
+const CONTEXT_DID_SHIFT: u64 = 8;
+// 9.8 Second-Level Paging Entries
+const PTE_READ: u64 = 1 << 0;
+const PTE_WRITE: u64 = 1 << 1;
+const PTE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const NUM_ENTRIES_IN_TABLE: usize = 512;
+const WAIT_LOOPS: usize = 10_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DomainType {
+    /// The device can only access the memory mapped for it
+    Isolated,
+    /// The device can access all of the physical memory
+    PassThrough,
+}
+
+fn table_constraints() -> DmaConstraints {
+    DmaConstraints::new().align(PAGE_SIZE)
+}
+
+/// Index of the entry for `iova` in a second-level page table at `level`,
+/// where the leaf tables are at level 1.
+fn page_table_index(iova: u64, level: usize) -> usize {
+    ((iova >> (12 + 9 * (level - 1))) as usize) & (NUM_ENTRIES_IN_TABLE - 1)
+}
+
+/// The second-level page tables of a device
+struct Domain {
+    id: u16,
+    domain_type: DomainType,
+    levels: usize,
+    /// The page tables. The first one is the top level table.
+    tables: Vec<DmaBuffer>,
+    num_mapped_pages: usize,
+}
+impl Domain {
+    fn new(id: u16, levels: usize) -> Result<Self> {
+        Ok(Self {
+            id,
+            domain_type: DomainType::Isolated,
+            levels,
+            tables: alloc::vec![DmaBuffer::new(
+                PAGE_SIZE,
+                table_constraints()
+            )?],
+            num_mapped_pages: 0,
+        })
+    }
+    fn root(&self) -> u64 {
+        self.tables[0].phys_addr()
+    }
+    /// The end of the IOVA space (exclusive)
+    fn iova_limit(&self) -> u64 {
+        1 << (12 + 9 * self.levels)
+    }
+    /// Returns the leaf entry for `iova`. The intermediate tables are
+    /// created if `create` is true.
+    fn leaf_entry(&mut self, iova: u64, create: bool) -> Result<*mut u64> {
+        let mut table = self.root();
+        for level in (2..=self.levels).rev() {
+            let entry = unsafe {
+                (table as *mut u64).add(page_table_index(iova, level))
+            };
+            let value = unsafe { read_volatile(entry) };
+            if value & (PTE_READ | PTE_WRITE) != 0 {
+                table = value & PTE_ADDR_MASK;
+                continue;
+            }
+            if !create {
+                return Err("IOVA is not mapped");
+            }
+            let next = DmaBuffer::new(PAGE_SIZE, table_constraints())?;
+            table = next.phys_addr();
+            unsafe { write_volatile(entry, table | PTE_READ | PTE_WRITE) };
+            self.tables.push(next);
+        }
+        Ok(unsafe { (table as *mut u64).add(page_table_index(iova, 1)) })
+    }
+    /// Maps [addr, addr + size) to the same IOVAs.
+    fn map(&mut self, addr: u64, size: usize) -> Result<()> {
+        let start = addr & !(PAGE_SIZE as u64 - 1);
+        let end = (addr + size as u64).next_multiple_of(PAGE_SIZE as u64);
+        if end > self.iova_limit() {
+            return Err("Address is out of the IOVA space of the domain");
+        }
+        for page in (start..end).step_by(PAGE_SIZE) {
+            let entry = self.leaf_entry(page, true)?;
+            unsafe {
+                if read_volatile(entry) == 0 {
+                    self.num_mapped_pages += 1;
+                }
+                write_volatile(entry, page | PTE_READ | PTE_WRITE);
+            }
+        }
+        Ok(())
+    }
+    fn unmap(&mut self, addr: u64, size: usize) -> Result<()> {
+        let start = addr & !(PAGE_SIZE as u64 - 1);
+        let end = (addr + size as u64).next_multiple_of(PAGE_SIZE as u64);
+        for page in (start..end).step_by(PAGE_SIZE) {
+            let entry = self.leaf_entry(page, false)?;
+            unsafe {
+                if read_volatile(entry) != 0 {
+                    self.num_mapped_pages -= 1;
+                }
+                write_volatile(entry, 0);
+            }
+        }
+        Ok(())
+    }
+    /// Returns the physical address that `iova` is translated into.
+    #[cfg(test)]
+    fn translate(&mut self, iova: u64) -> Option<u64> {
+        let entry = self.leaf_entry(iova, false).ok()?;
+        let value = unsafe { read_volatile(entry) };
+        (value & (PTE_READ | PTE_WRITE) != 0).then_some(
+            (value & PTE_ADDR_MASK) | (iova & (PAGE_SIZE as u64 - 1)),
+        )
+    }
+}
+
+/// The devices that a Device Scope in the DMAR refers to
+#[derive(Clone, Debug)]
+enum ScopeMatch {
+    Device(BusDeviceFunction),
+    /// A bridge and the buses behind it
+    Bridge(BusDeviceFunction, RangeInclusive<usize>),
+}
+impl ScopeMatch {
+    /// Resolves the path of the scope by following the secondary bus
+    /// numbers of the bridges on it.
+    fn new(pci: &Pci, scope: &DmarDeviceScope) -> Option<Self> {
+        let is_bridge = scope.is(DmarDeviceScopeType::PciSubHierarchy);
+        if !is_bridge && !scope.is(DmarDeviceScopeType::PciEndpoint) {
+            return None;
+        }
+        let mut bus = scope.start_bus() as usize;
+        let mut bdf = None;
+        for (device, function) in scope.path() {
+            if let Some(bridge) = bdf {
+                bus = Self::bridge_bus_range(pci, bridge)?.0;
+            }
+            bdf = Some(
+                BusDeviceFunction::new(bus, device as usize, function as usize)
+                    .ok()?,
+            );
+        }
+        let bdf = bdf?;
+        if is_bridge {
+            let (secondary, subordinate) = Self::bridge_bus_range(pci, bdf)?;
+            Some(Self::Bridge(bdf, secondary..=subordinate))
+        } else {
+            Some(Self::Device(bdf))
+        }
+    }
+    /// Returns the secondary and the subordinate bus numbers of a bridge.
+    fn bridge_bus_range(
+        pci: &Pci,
+        bridge: BusDeviceFunction,
+    ) -> Option<(usize, usize)> {
+        let buses = pci.read_register_u32(bridge, 0x18).ok()?;
+        Some((
+            ((buses >> 8) & 0xFF) as usize,
+            ((buses >> 16) & 0xFF) as usize,
+        ))
+    }
+    /// The device itself, or the bridge
+    fn bdf(&self) -> BusDeviceFunction {
+        match self {
+            Self::Device(e) | Self::Bridge(e, _) => *e,
+        }
+    }
+    fn matches(&self, bdf: BusDeviceFunction) -> bool {
+        match self {
+            Self::Device(e) => *e == bdf,
+            Self::Bridge(e, buses) => *e == bdf || buses.contains(&bdf.bus()),
+        }
+    }
+}
+
+struct ReservedMemoryRegion {
+    range: Range<u64>,
+    devices: Vec<ScopeMatch>,
+}
+
+/// Registers and tables of a DMA Remapping Hardware Unit
+struct RemappingUnit {
+    base: u64,
+    cap: u64,
+    ecap: u64,
+    includes_all_pci_devices: bool,
+    devices: Vec<ScopeMatch>,
+    levels: usize,
+    root_table: DmaBuffer,
+    /// Context tables indexed by the bus number
+    context_tables: BTreeMap<usize, DmaBuffer>,
+    domains: BTreeMap<BusDeviceFunction, Domain>,
+    next_domain_id: u16,
+    translation_enabled: bool,
+}
+impl RemappingUnit {
+    fn read32(&self, reg: u64) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+    fn write32(&self, reg: u64, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+    fn read64(&self, reg: u64) -> u64 {
+        unsafe { read_volatile((self.base + reg) as *const u64) }
+    }
+    fn write64(&self, reg: u64, value: u64) {
+        unsafe { write_volatile((self.base + reg) as *mut u64, value) }
+    }
+    fn iotlb_reg(&self) -> u64 {
+        ((self.ecap >> ECAP_IRO_SHIFT) & 0x3FF) * 16 + REG_IOTLB
+    }
+    fn wait_for(&self, cond: impl Fn() -> bool) -> Result<()> {
+        for _ in 0..WAIT_LOOPS {
+            if cond() {
+                return Ok(());
+            }
+            busy_loop_hint();
+        }
+        Err("IOMMU did not respond")
+    }
+    /// Sets or clears a bit in GCMD and waits for the same bit of GSTS to
+    /// follow it.
+    fn global_command(&self, bit: u32, enable: bool) -> Result<()> {
+        let status = self.read32(REG_GSTS) & GSTS_PERSISTENT_MASK;
+        let command = if enable { status | bit } else { status & !bit };
+        self.write32(REG_GCMD, command);
+        self.wait_for(|| (self.read32(REG_GSTS) & bit != 0) == enable)
+    }
+    /// Makes the updates of the tables visible to the hardware, which is
+    /// required on units that have write buffers.
+    fn flush_write_buffer(&self) -> Result<()> {
+        if self.cap & CAP_RWBF == 0 {
+            return Ok(());
+        }
+        let status = self.read32(REG_GSTS) & GSTS_PERSISTENT_MASK;
+        self.write32(REG_GCMD, status | GCMD_WBF);
+        self.wait_for(|| self.read32(REG_GSTS) & GCMD_WBF == 0)
+    }
+    fn invalidate_context_cache(&self) -> Result<()> {
+        self.flush_write_buffer()?;
+        self.write64(REG_CCMD, CCMD_ICC | CCMD_CIRG_GLOBAL);
+        self.wait_for(|| self.read64(REG_CCMD) & CCMD_ICC == 0)
+    }
+    /// Invalidates the IOTLB entries of the domain, or all of them if
+    /// `domain_id` is None.
+    fn invalidate_iotlb(&self, domain_id: Option<u16>) -> Result<()> {
+        self.flush_write_buffer()?;
+        let granularity = match domain_id {
+            Some(id) => IOTLB_IIRG_DOMAIN | ((id as u64) << IOTLB_DID_SHIFT),
+            None => IOTLB_IIRG_GLOBAL,
+        };
+        let reg = self.iotlb_reg();
+        self.write64(reg, IOTLB_IVT | granularity | IOTLB_DR | IOTLB_DW);
+        self.wait_for(|| self.read64(reg) & IOTLB_IVT == 0)
+    }
+    /// Enables the translation once the unit has a context entry. Until
+    /// then, the unit is left disabled so that it does not block any DMA.
+    fn enable_translation(&mut self) -> Result<()> {
+        if self.translation_enabled || self.domains.is_empty() {
+            return Ok(());
+        }
+        self.global_command(GCMD_TE, true)?;
+        self.translation_enabled = true;
+        Ok(())
+    }
+    fn handles(&self, bdf: BusDeviceFunction) -> bool {
+        self.devices.iter().any(|e| e.matches(bdf))
+    }
+    fn num_domain_ids(&self) -> usize {
+        1 << (4 + 2 * (self.cap & CAP_ND_MASK))
+    }
+    fn context_entry_high(&self, domain_id: u16) -> u64 {
+        let aw = if self.levels == 4 {
+            CONTEXT_AW_48BIT
+        } else {
+            CONTEXT_AW_39BIT
+        };
+        aw | ((domain_id as u64) << CONTEXT_DID_SHIFT)
+    }
+    /// Points the context entry of the device to its domain.
+    fn set_context_entry(&mut self, bdf: BusDeviceFunction) -> Result<()> {
+        let domain = self.domains.get(&bdf).ok_or("Domain not found")?;
+        let low = match domain.domain_type {
+            DomainType::Isolated => domain.root() | ENTRY_PRESENT,
+            DomainType::PassThrough => CONTEXT_TT_PASS_THROUGH | ENTRY_PRESENT,
+        };
+        let high = self.context_entry_high(domain.id);
+        let context_table = match self.context_tables.get(&bdf.bus()) {
+            Some(e) => e.phys_addr(),
+            None => {
+                let table = DmaBuffer::new(PAGE_SIZE, table_constraints())?;
+                let addr = table.phys_addr();
+                let root_entry = (self.root_table.phys_addr() as *mut u64)
+                    .wrapping_add(bdf.bus() * 2 /* 128-bit entries */);
+                unsafe { write_volatile(root_entry, addr | ENTRY_PRESENT) };
+                self.context_tables.insert(bdf.bus(), table);
+                addr
+            }
+        };
+        let entry = (context_table as *mut u64).wrapping_add(
+            (bdf.device() * 8 + bdf.function()) * 2, /* 128-bit entries */
+        );
+        unsafe {
+            // Clear the present bit first so that the hardware does not see
+            // a half-updated entry.
+            write_volatile(entry, 0);
+            write_volatile(entry.add(1), high);
+            write_volatile(entry, low);
+        }
+        self.invalidate_context_cache()?;
+        self.invalidate_iotlb(None)
+    }
+    /// Returns the domain of the device. It is created as an isolated one,
+    /// with the RMRRs of the device mapped, if it does not exist yet.
+    fn domain(
+        &mut self,
+        bdf: BusDeviceFunction,
+        rmrrs: &[ReservedMemoryRegion],
+    ) -> Result<&mut Domain> {
+        let id = self.next_domain_id;
+        let num_domain_ids = self.num_domain_ids();
+        let levels = self.levels;
+        if let Entry::Vacant(entry) = self.domains.entry(bdf) {
+            if id as usize >= num_domain_ids {
+                return Err("No more domain IDs");
+            }
+            let mut domain = Domain::new(id, levels)?;
+            for rmrr in rmrrs.iter() {
+                if rmrr.devices.iter().any(|e| e.matches(bdf)) {
+                    domain.map(
+                        rmrr.range.start,
+                        (rmrr.range.end - rmrr.range.start) as usize,
+                    )?;
+                }
+            }
+            entry.insert(domain);
+            self.next_domain_id += 1;
+            self.set_context_entry(bdf)?;
+        }
+        self.domains.get_mut(&bdf).ok_or("Domain not found")
+    }
+}
+
+struct Iommu {
+    units: Vec<RemappingUnit>,
+    rmrrs: Vec<ReservedMemoryRegion>,
+}
+impl Iommu {
+    /// Returns the index of the unit that handles the device. Units that
+    /// list the device in their scopes take precedence over the
+    /// INCLUDE_PCI_ALL unit.
+    fn unit_index(&self, bdf: BusDeviceFunction) -> Option<usize> {
+        self.units.iter().position(|e| e.handles(bdf)).or_else(|| {
+            self.units.iter().position(|e| e.includes_all_pci_devices)
+        })
+    }
+}
+
+static IOMMU: Mutex<Option<Iommu>> = Mutex::new(None);
+
+/// The registers that the fault event handler needs, which can be accessed
+/// without taking IOMMU.
+#[derive(Clone, Copy)]
+struct FaultRecordingRegisters {
+    base: u64,
+    offset: u64,
+    count: usize,
+}
+static FAULT_RECORDING_REGISTERS: IrqSpinLock<Vec<FaultRecordingRegisters>> =
+    IrqSpinLock::new(Vec::new());
+static NUM_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+fn fault_event_handler(_info: &InterruptInfo) {
+    let mut w = SerialPort::default();
+    for regs in FAULT_RECORDING_REGISTERS.lock().iter() {
+        let read32 = |reg: u64| unsafe {
+            read_volatile((regs.base + reg) as *const u32)
+        };
+        let write32 = |reg: u64, value: u32| unsafe {
+            write_volatile((regs.base + reg) as *mut u32, value)
+        };
+        let status = read32(REG_FSTS);
+        if status & FSTS_PPF != 0 {
+            let mut index = ((status >> FSTS_FRI_SHIFT) & 0xFF) as usize;
+            for _ in 0..regs.count {
+                let record = regs.offset + (index as u64) * 16;
+                let high = (read32(record + 8) as u64)
+                    | ((read32(record + 12) as u64) << 32);
+                if high & FRCD_F == 0 {
+                    break;
+                }
+                let low = (read32(record) as u64)
+                    | ((read32(record + 4) as u64) << 32);
+                let sid = (high & FRCD_SID_MASK) as usize;
+                NUM_FAULTS.fetch_add(1, Ordering::SeqCst);
+                let _ = writeln!(
+                    w,
+                    "[IOMMU] DMA fault: {:02X}:{:02X}.{} {} {:#X} \
+                     reason={:#04X}",
+                    sid >> 8,
+                    (sid >> 3) & 0x1F,
+                    sid & 0b111,
+                    if high & FRCD_T_READ != 0 {
+                        "read"
+                    } else {
+                        "write"
+                    },
+                    low & PTE_ADDR_MASK,
+                    (high >> FRCD_FR_SHIFT) & 0xFF,
+                );
+                // The F bit is cleared by writing 1.
+                write32(record + 12, (FRCD_F >> 32) as u32);
+                index = (index + 1) % regs.count;
+            }
+        }
+        write32(REG_FSTS, FSTS_PFO);
+    }
+    if let Some(lapic) = LocalApic::current() {
+        lapic.eoi();
+    }
+}
+
+fn init_unit(
+    base: u64,
+    pages: usize,
+    includes_all_pci_devices: bool,
+    devices: Vec<ScopeMatch>,
+    fault_event_address: u32,
+) -> Result<RemappingUnit> {
+    unsafe {
+        with_current_page_table(|pt| {
+            pt.create_mapping(
+                base,
+                base + (pages * PAGE_SIZE) as u64,
+                base,
+                PageAttr::ReadWriteIo,
+            )
+        })?
+    }
+    let mut unit = RemappingUnit {
+        base,
+        cap: 0,
+        ecap: 0,
+        includes_all_pci_devices,
+        devices,
+        levels: 0,
+        root_table: DmaBuffer::new(PAGE_SIZE, table_constraints())?,
+        context_tables: BTreeMap::new(),
+        domains: BTreeMap::new(),
+        // Domain ID 0 is reserved when CAP.CM is set, so start from 1.
+        next_domain_id: 1,
+        translation_enabled: false,
+    };
+    unit.cap = unit.read64(REG_CAP);
+    unit.ecap = unit.read64(REG_ECAP);
+    let sagaw = unit.cap >> CAP_SAGAW_SHIFT;
+    unit.levels = if sagaw & CAP_SAGAW_48BIT != 0 {
+        4
+    } else if sagaw & CAP_SAGAW_39BIT != 0 {
+        3
+    } else {
+        return Err("IOMMU does not support 3 or 4-level page tables");
+    };
+    if unit.ecap & ECAP_PT == 0 {
+        return Err("IOMMU does not support pass-through");
+    }
+    // Translation may be left enabled by the firmware.
+    unit.global_command(GCMD_TE, false)?;
+    unit.write64(REG_RTADDR, unit.root_table.phys_addr());
+    unit.global_command(GCMD_SRTP, true)?;
+    unit.invalidate_context_cache()?;
+    unit.invalidate_iotlb(None)?;
+    let regs = FaultRecordingRegisters {
+        base,
+        offset: ((unit.cap >> CAP_FRO_SHIFT) & 0x3FF) * 16,
+        count: ((unit.cap >> CAP_NFR_SHIFT) & 0xFF) as usize + 1,
+    };
+    FAULT_RECORDING_REGISTERS.lock().push(regs);
+    unit.write32(REG_FECTL, FECTL_IM);
+    unit.write32(REG_FEDATA, VECTOR_IOMMU_FAULT as u32);
+    unit.write32(REG_FEADDR, fault_event_address);
+    unit.write32(REG_FEUADDR, 0);
+    unit.write32(REG_FECTL, 0);
+    info!(
+        "IOMMU: address: {:#X}, version: {:#X}, cap: {:#X}, ecap: {:#X}, \
+         {}-level page tables, MGAW: {}",
+        base,
+        unit.read32(REG_VER),
+        unit.cap,
+        unit.ecap,
+        unit.levels,
+        ((unit.cap >> CAP_MGAW_SHIFT) & 0x3F) + 1
+    );
+    Ok(unit)
+}
+
+/// Enables DMA remapping on the units in the DMAR. The devices that have
+/// RMRRs are attached here with the regions mapped, and the translation is
+/// enabled on the units that have any device attached. After this, devices
+/// behind such units can not do DMA until they are attached with
+/// attach_device(). This should be called after init_local_apic() and
+/// before the PCI drivers start.
+pub fn init_iommu(acpi: &AcpiRsdpStruct) -> Result<()> {
+    let dmar = acpi.dmar().ok_or("DMAR not found")?;
+    let pci = Pci::new(acpi.mcfg().ok_or("MCFG not found")?);
+    let lapic = LocalApic::current().ok_or("Local APIC is not initialized")?;
+    let apic_id = u8::try_from(lapic.id())
+        .or(Err("APIC ID is too large for the fault event interrupt"))?;
+    let fault_event_address =
+        MSI_ADDRESS_BASE | ((apic_id as u32) << MSI_DESTINATION_SHIFT);
+    let scope_matches = |scopes: &mut dyn Iterator<Item = DmarDeviceScope>| {
+        scopes
+            .filter_map(|e| ScopeMatch::new(&pci, &e))
+            .collect::<Vec<ScopeMatch>>()
+    };
+    set_interrupt_handler(VECTOR_IOMMU_FAULT, fault_event_handler);
+    let mut iommu = Iommu {
+        units: Vec::new(),
+        rmrrs: Vec::new(),
+    };
+    // DRHDs come before RMRRs in the DMAR, so collect all the RMRRs first
+    // to map them before the translation is enabled.
+    for e in dmar.iter() {
+        if let DmarEntry::Rmrr(e) = e {
+            if e.segment() == 0 {
+                iommu.rmrrs.push(ReservedMemoryRegion {
+                    range: e.base_address()..e.end_address(),
+                    devices: scope_matches(&mut e.device_scopes()),
+                })
+            }
+        }
+    }
+    for e in dmar.iter() {
+        let DmarEntry::Drhd(e) = e else {
+            continue;
+        };
+        if e.segment() != 0 {
+            continue;
+        }
+        match init_unit(
+            e.register_base_address(),
+            e.register_set_pages(),
+            e.includes_all_pci_devices(),
+            scope_matches(&mut e.device_scopes()),
+            fault_event_address,
+        ) {
+            Ok(unit) => iommu.units.push(unit),
+            Err(err) => warn!(
+                "IOMMU at {:#X} is not enabled: {err}",
+                e.register_base_address()
+            ),
+        }
+    }
+    if iommu.units.is_empty() {
+        return Err("No IOMMU is enabled");
+    }
+    // Devices behind a bridge get the RMRRs mapped when they are attached.
+    for rmrr in iommu.rmrrs.iter() {
+        for bdf in rmrr.devices.iter().map(|e| e.bdf()) {
+            let Some(index) = iommu.unit_index(bdf) else {
+                continue;
+            };
+            if let Err(e) = iommu.units[index].domain(bdf, &iommu.rmrrs) {
+                warn!("IOMMU: Failed to map the RMRRs for {bdf}: {e}");
+            }
+        }
+    }
+    for unit in iommu.units.iter_mut() {
+        unit.enable_translation()?;
+    }
+    *IOMMU.lock() = Some(iommu);
+    Ok(())
+}
+
+/// Lets the device do DMA. It is a no-op if the device is not behind any
+/// IOMMU.
+pub fn attach_device(
+    bdf: BusDeviceFunction,
+    domain_type: DomainType,
+) -> Result<()> {
+    let mut iommu = IOMMU.lock();
+    let Some(iommu) = iommu.as_mut() else {
+        return Ok(());
+    };
+    let Some(index) = iommu.unit_index(bdf) else {
+        return Ok(());
+    };
+    let unit = &mut iommu.units[index];
+    let domain = unit.domain(bdf, &iommu.rmrrs)?;
+    if domain.domain_type != domain_type {
+        domain.domain_type = domain_type;
+        unit.set_context_entry(bdf)?;
+    }
+    unit.enable_translation()
+}
+
+/// Makes [addr, addr + size) accessible from the device at the same
+/// address.
+pub fn map(bdf: BusDeviceFunction, addr: u64, size: usize) -> Result<()> {
+    let mut iommu = IOMMU.lock();
+    let Some(iommu) = iommu.as_mut() else {
+        return Ok(());
+    };
+    let Some(index) = iommu.unit_index(bdf) else {
+        return Ok(());
+    };
+    let unit = &mut iommu.units[index];
+    let domain = unit.domain(bdf, &iommu.rmrrs)?;
+    domain.map(addr, size)?;
+    let id = domain.id;
+    // Units in the caching mode (e.g. the emulated ones) may cache
+    // not-present entries, so invalidate them as well.
+    unit.invalidate_iotlb(Some(id))?;
+    unit.enable_translation()
+}
+
+pub fn unmap(bdf: BusDeviceFunction, addr: u64, size: usize) -> Result<()> {
+    let mut iommu = IOMMU.lock();
+    let Some(iommu) = iommu.as_mut() else {
+        return Ok(());
+    };
+    let Some(index) = iommu.unit_index(bdf) else {
+        return Ok(());
+    };
+    let unit = &mut iommu.units[index];
+    let domain = unit.domains.get_mut(&bdf).ok_or("Domain not found")?;
+    domain.unmap(addr, size)?;
+    let id = domain.id;
+    unit.invalidate_iotlb(Some(id))
+}
+
+pub fn num_faults() -> usize {
+    NUM_FAULTS.load(Ordering::SeqCst)
+}
+
+pub fn dump(w: &mut dyn fmt::Write) -> Result<()> {
+    let iommu = IOMMU.lock();
+    let iommu = iommu.as_ref().ok_or("IOMMU is not enabled")?;
+    let result = (|| -> fmt::Result {
+        for unit in iommu.units.iter() {
+            writeln!(
+                w,
+                "IOMMU at {:#X}: {} domains{}{}",
+                unit.base,
+                unit.domains.len(),
+                if unit.translation_enabled {
+                    ""
+                } else {
+                    " (translation disabled)"
+                },
+                if unit.includes_all_pci_devices {
+                    " (includes all PCI devices)"
+                } else {
+                    ""
+                }
+            )?;
+            for (bdf, domain) in unit.domains.iter() {
+                writeln!(
+                    w,
+                    "  {bdf}: domain {}, {:?}, {} pages mapped",
+                    domain.id, domain.domain_type, domain.num_mapped_pages
+                )?;
+            }
+        }
+        for rmrr in iommu.rmrrs.iter() {
+            writeln!(
+                w,
+                "RMRR: {:#X}..{:#X} for {:?}",
+                rmrr.range.start, rmrr.range.end, rmrr.devices
+            )?;
+        }
+        writeln!(w, "Faults: {}", num_faults())
+    })();
+    result.or(Err("Failed to write the IOMMU state"))
+}
+
+#[test_case]
+fn page_table_index_test() {
+    let iova = (3 << 39) | (5 << 30) | (7 << 21) | (9 << 12) | 0xABC;
+    assert_eq!(page_table_index(iova, 4), 3);
+    assert_eq!(page_table_index(iova, 3), 5);
+    assert_eq!(page_table_index(iova, 2), 7);
+    assert_eq!(page_table_index(iova, 1), 9);
+}
+
+#[test_case]
+fn domain_map_test() {
+    let mut domain = Domain::new(1, 3).expect("Failed to create a domain");
+    domain.map(0x1234_5678, 0x1000).expect("Failed to map");
+    assert_eq!(domain.num_mapped_pages, 2);
+    assert_eq!(domain.translate(0x1234_5678), Some(0x1234_5678));
+    assert_eq!(domain.translate(0x1234_6FFF), Some(0x1234_6FFF));
+    assert_eq!(domain.translate(0x1234_7000), None);
+    assert!(domain.map(1 << 39, 0x1000).is_err());
+    domain.unmap(0x1234_5000, 0x2000).expect("Failed to unmap");
+    assert_eq!(domain.num_mapped_pages, 0);
+    assert_eq!(domain.translate(0x1234_5678), None);
+    assert!(domain.unmap(1 << 30, 0x1000).is_err());
+}
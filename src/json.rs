@@ -0,0 +1,356 @@
+extern crate alloc;
+
+use crate::result::Result;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+// c.f. https://www.rfc-editor.org/rfc/rfc8259
+
+// Limits the recursion of the parser so that a malformed input can not
+// exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
+/// A JSON value. Only integers are supported as numbers, and the members of
+/// an object are kept in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+impl JsonValue {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            input: s.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.parse_value(0)?;
+        parser.skip_whitespace();
+        if parser.pos != parser.input.len() {
+            return Err("JSON: Unexpected data after the value");
+        }
+        Ok(value)
+    }
+    pub fn object<'a>(
+        members: impl IntoIterator<Item = (&'a str, JsonValue)>,
+    ) -> Self {
+        Self::Object(members.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+    /// Returns the member of an object
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            Self::Object(members) => {
+                members.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+            }
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+impl From<bool> for JsonValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+impl From<i64> for JsonValue {
+    fn from(value: i64) -> Self {
+        Self::Number(value)
+    }
+}
+impl From<&str> for JsonValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.into())
+    }
+}
+impl From<String> for JsonValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+impl From<Vec<JsonValue>> for JsonValue {
+    fn from(value: Vec<JsonValue>) -> Self {
+        Self::Array(value)
+    }
+}
+
+fn write_escaped(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Writes the value in the compact form, i.e. without any whitespace.
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => write_escaped(f, s),
+            Self::Array(values) => {
+                write!(f, "[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{v}")?;
+                }
+                write!(f, "]")
+            }
+            Self::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in members.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write_escaped(f, k)?;
+                    write!(f, ":{v}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).cloned()
+    }
+    fn next(&mut self) -> Result<u8> {
+        let c = self.peek().ok_or("JSON: Unexpected end of input")?;
+        self.pos += 1;
+        Ok(c)
+    }
+    fn expect(&mut self, c: u8) -> Result<()> {
+        if self.next()? == c {
+            Ok(())
+        } else {
+            Err("JSON: Unexpected character")
+        }
+    }
+    fn expect_literal(&mut self, literal: &str) -> Result<()> {
+        for c in literal.bytes() {
+            self.expect(c)?;
+        }
+        Ok(())
+    }
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+    fn parse_value(&mut self, depth: usize) -> Result<JsonValue> {
+        if depth >= MAX_DEPTH {
+            return Err("JSON: Too deeply nested");
+        }
+        self.skip_whitespace();
+        match self.peek().ok_or("JSON: Unexpected end of input")? {
+            b'n' => self.expect_literal("null").map(|_| JsonValue::Null),
+            b't' => self.expect_literal("true").map(|_| JsonValue::Bool(true)),
+            b'f' => {
+                self.expect_literal("false").map(|_| JsonValue::Bool(false))
+            }
+            b'"' => self.parse_string().map(JsonValue::String),
+            b'-' | b'0'..=b'9' => self.parse_number().map(JsonValue::Number),
+            b'[' => self.parse_array(depth),
+            b'{' => self.parse_object(depth),
+            _ => Err("JSON: Unexpected character"),
+        }
+    }
+    fn parse_number(&mut self) -> Result<i64> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.pos += 1;
+        }
+        if matches!(self.peek(), Some(b'.' | b'e' | b'E')) {
+            return Err("JSON: Only integers are supported");
+        }
+        let s = core::str::from_utf8(&self.input[start..self.pos])
+            .or(Err("JSON: Invalid number"))?;
+        s.parse::<i64>().or(Err("JSON: Invalid number"))
+    }
+    fn parse_hex4(&mut self) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = (self.next()? as char)
+                .to_digit(16)
+                .ok_or("JSON: Invalid \\u escape")?;
+            value = value * 16 + digit;
+        }
+        Ok(value)
+    }
+    fn parse_string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => break,
+                b'\\' => {
+                    let c = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\x08',
+                        b'f' => '\x0c',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.parse_hex4()?;
+                            if (0xD800..0xDC00).contains(&code) {
+                                // A surrogate pair
+                                self.expect_literal("\\u")?;
+                                let low = self.parse_hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err("JSON: Invalid surrogate pair");
+                                }
+                                code = 0x10000
+                                    + ((code - 0xD800) << 10)
+                                    + (low - 0xDC00);
+                            }
+                            char::from_u32(code)
+                                .ok_or("JSON: Invalid \\u escape")?
+                        }
+                        _ => return Err("JSON: Invalid escape"),
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                c if c < 0x20 => {
+                    return Err("JSON: Control character in a string")
+                }
+                c => bytes.push(c),
+            }
+        }
+        String::from_utf8(bytes).or(Err("JSON: Invalid UTF-8"))
+    }
+    fn parse_array(&mut self, depth: usize) -> Result<JsonValue> {
+        self.expect(b'[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(JsonValue::Array(values));
+        }
+        loop {
+            values.push(self.parse_value(depth + 1)?);
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => continue,
+                b']' => return Ok(JsonValue::Array(values)),
+                _ => return Err("JSON: Expected , or ]"),
+            }
+        }
+    }
+    fn parse_object(&mut self, depth: usize) -> Result<JsonValue> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(JsonValue::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            members.push((key, self.parse_value(depth + 1)?));
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => continue,
+                b'}' => return Ok(JsonValue::Object(members)),
+                _ => return Err("JSON: Expected , or }"),
+            }
+        }
+    }
+}
+
+#[test_case]
+fn json_parse_test() {
+    use alloc::format;
+    use alloc::vec;
+    let v = JsonValue::parse(
+        r#" {"id": 3, "method": "peek",
+            "params": {"addr": -16, "ok": [true, false, null]}} "#,
+    )
+    .expect("Failed to parse");
+    assert_eq!(v.get("id"), Some(&JsonValue::Number(3)));
+    assert_eq!(v.get("method").and_then(|v| v.as_str()), Some("peek"));
+    let params = v.get("params").unwrap();
+    assert_eq!(params.get("addr").and_then(|v| v.as_i64()), Some(-16));
+    assert_eq!(
+        params.get("ok"),
+        Some(&JsonValue::Array(vec![
+            JsonValue::Bool(true),
+            JsonValue::Bool(false),
+            JsonValue::Null
+        ]))
+    );
+    assert_eq!(v.get("missing"), None);
+    assert_eq!(
+        format!("{}", params),
+        r#"{"addr":-16,"ok":[true,false,null]}"#
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            JsonValue::object([("id", 3.into()), ("s", "x".into())])
+        ),
+        r#"{"id":3,"s":"x"}"#
+    );
+    assert_eq!(JsonValue::parse("[]"), Ok(JsonValue::Array(vec![])));
+    assert_eq!(JsonValue::parse("{ }"), Ok(JsonValue::Object(vec![])));
+    assert!(JsonValue::parse("").is_err());
+    assert!(JsonValue::parse("[1,]").is_err());
+    assert!(JsonValue::parse("{\"a\" 1}").is_err());
+    assert!(JsonValue::parse("1.5").is_err());
+    assert!(JsonValue::parse("nul").is_err());
+    assert!(JsonValue::parse("1 2").is_err());
+    assert!(JsonValue::parse(&"[".repeat(MAX_DEPTH + 1)).is_err());
+}
+
+#[test_case]
+fn json_string_test() {
+    use alloc::format;
+    let v = JsonValue::parse(r#""a\"\\\/\n\t\u00e9\ud83d\ude00""#).unwrap();
+    assert_eq!(v.as_str(), Some("a\"\\/\n\t\u{e9}\u{1f600}"));
+    assert_eq!(format!("{v}"), "\"a\\\"\\\\/\\n\\t\u{e9}\u{1f600}\"");
+    assert_eq!(format!("{}", JsonValue::from("\x01\r")), r#""\u0001\r""#);
+    assert!(JsonValue::parse("\"\\ud83d\"").is_err());
+    assert!(JsonValue::parse("\"\\x\"").is_err());
+    assert!(JsonValue::parse("\"a\nb\"").is_err());
+    assert!(JsonValue::parse("\"abc").is_err());
+}
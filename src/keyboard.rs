@@ -16,6 +16,7 @@ pub enum KeyEvent {
     Char(char),
     Unknown(u8),
     Enter,
+    Backspace,
 }
 impl KeyEvent {
     pub fn from_usb_key_id(usage_id: u8) -> Self {
@@ -24,7 +25,7 @@ impl KeyEvent {
             4..=29 => KeyEvent::Char((b'a' + usage_id - 4) as char),
             30..=39 => KeyEvent::Char((b'0' + (usage_id + 1) % 10) as char),
             40 => KeyEvent::Enter,
-            42 => KeyEvent::Char(0x08 as char),
+            42 => KeyEvent::Backspace,
             44 => KeyEvent::Char(' '),
             45 => KeyEvent::Char('-'),
             51 => KeyEvent::Char(':'),
@@ -38,11 +39,73 @@ impl KeyEvent {
         match self {
             KeyEvent::Char(c) => Some(*c),
             KeyEvent::Enter => Some('\n'),
+            KeyEvent::Backspace => Some(0x08 as char),
             _ => None,
         }
     }
 }
 
+/// Converts bytes from a serial terminal into KeyEvents. CR, LF and CR LF
+/// are an Enter, and both BS and DEL are a Backspace. Escape sequences
+/// (e.g. arrow keys) are ignored.
+#[derive(Default)]
+pub struct SerialKeyDecoder {
+    last_was_cr: bool,
+    escape: EscapeState,
+}
+#[derive(Default, PartialEq, Eq)]
+enum EscapeState {
+    #[default]
+    None,
+    /// After ESC
+    Escape,
+    /// After ESC [ (CSI), until the final byte
+    ControlSequence,
+    /// After ESC O (SS3), which takes one more byte
+    SingleShift,
+}
+impl SerialKeyDecoder {
+    pub fn decode(&mut self, byte: u8) -> KeyEvent {
+        let last_was_cr = core::mem::replace(&mut self.last_was_cr, false);
+        match self.escape {
+            EscapeState::Escape => {
+                self.escape = match byte {
+                    b'[' => EscapeState::ControlSequence,
+                    b'O' => EscapeState::SingleShift,
+                    _ => EscapeState::None,
+                };
+                return KeyEvent::None;
+            }
+            EscapeState::SingleShift => {
+                self.escape = EscapeState::None;
+                return KeyEvent::None;
+            }
+            EscapeState::ControlSequence => {
+                if (0x40..=0x7E).contains(&byte) {
+                    self.escape = EscapeState::None;
+                }
+                return KeyEvent::None;
+            }
+            EscapeState::None => {}
+        }
+        match byte {
+            b'\r' => {
+                self.last_was_cr = true;
+                KeyEvent::Enter
+            }
+            b'\n' if last_was_cr => KeyEvent::None,
+            b'\n' => KeyEvent::Enter,
+            0x08 | 0x7F => KeyEvent::Backspace,
+            0x1B => {
+                self.escape = EscapeState::Escape;
+                KeyEvent::None
+            }
+            0x20..=0x7E => KeyEvent::Char(byte as char),
+            _ => KeyEvent::Unknown(byte),
+        }
+    }
+}
+
 pub struct UsbKeyboardDriver;
 impl UsbKeyboardDriver {
     async fn run(
@@ -72,10 +135,16 @@ impl UsbKeyboardDriver {
         .await?;
         let mut prev_pressed = BTreeSet::new();
         let mut console = Console::default();
+        let mut report_buf = xhc.alloc_dma_buffer(8)?;
         loop {
             let pressed = {
-                let report =
-                    request_hid_report(xhc, slot, ctrl_ep_ring).await?;
+                let report = request_hid_report(
+                    xhc,
+                    slot,
+                    ctrl_ep_ring,
+                    &mut report_buf,
+                )
+                .await?;
                 BTreeSet::from_iter(
                     report.into_iter().skip(2).filter(|id| *id != 0),
                 )
@@ -109,3 +178,26 @@ impl UsbDeviceDriver for UsbKeyboardDriver {
         });
     }
 }
+
+#[test_case]
+fn serial_key_decoder_test() {
+    let mut decoder = SerialKeyDecoder::default();
+    let events: Vec<KeyEvent> = b"ls\x7F\r\n\n\x1b[A\x1bOPx\x08\x01"
+        .iter()
+        .map(|b| decoder.decode(*b))
+        .filter(|e| *e != KeyEvent::None)
+        .collect();
+    assert_eq!(
+        events,
+        [
+            KeyEvent::Char('l'),
+            KeyEvent::Char('s'),
+            KeyEvent::Backspace,
+            KeyEvent::Enter,
+            KeyEvent::Enter,
+            KeyEvent::Char('x'),
+            KeyEvent::Backspace,
+            KeyEvent::Unknown(0x01),
+        ]
+    );
+}
@@ -1,4 +1,5 @@
 #![no_std]
+#![feature(alloc_error_handler)]
 #![feature(offset_of)]
 #![feature(custom_test_frameworks)]
 #![feature(sync_unsafe_cell)]
@@ -11,23 +12,41 @@
 #![no_main]
 pub mod acpi;
 pub mod allocator;
+pub mod aml;
+pub mod apic;
 pub mod bits;
+pub mod clock;
 pub mod cui;
+pub mod dma;
 pub mod executor;
+pub mod frame;
 pub mod graphics;
 pub mod gui;
+#[cfg(feature = "heap_debug")]
+pub mod heap_debug;
+#[cfg(feature = "heap_tracking")]
+pub mod heap_tracker;
 pub mod hpet;
 pub mod init;
 pub mod input;
+pub mod ioapic;
+pub mod iommu;
+pub mod json;
 pub mod keyboard;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
 pub mod mmio;
 pub mod mutex;
 pub mod pci;
+pub mod power;
 pub mod print;
+pub mod profiler;
 pub mod qemu;
 pub mod range;
+pub mod remote;
 pub mod result;
 pub mod serial;
+pub mod slab;
 pub mod slice;
 pub mod tablet;
 pub mod uefi;
@@ -0,0 +1,332 @@
+//! Lock dependency tracking (enabled with the `lockdep` feature)
+//!
+//! Locks created at the same place belong to the same lock class. Every time
+//! a lock is about to be taken while other locks are held, the order of the
+//! classes is recorded, and a cycle in the recorded order (e.g. A -> B and
+//! B -> A, or A -> B -> C -> A) is reported as a possible deadlock the first
+//! time it happens. The locks that are
+//! currently held are kept in a stack, assuming that there is only one CPU.
+//!
+//! Nothing here allocates or takes another lock, since this is called from
+//! the allocator and the printer through their locks. The state is protected
+//! by a flag that is taken with interrupts disabled instead.
+
+use crate::error;
+use crate::result::Result;
+use crate::x86::are_interrupts_enabled;
+use crate::x86::busy_loop_hint;
+use crate::x86::disable_interrupts;
+use crate::x86::enable_interrupts;
+use core::cell::SyncUnsafeCell;
+use core::fmt;
+use core::panic::Location;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+const MAX_LOCK_CLASSES: usize = 64;
+const MAX_HELD_LOCKS: usize = 32;
+
+type Site = &'static Location<'static>;
+
+#[derive(Clone, Copy)]
+struct HeldLock {
+    /// Where the lock was created, i.e. its class
+    class: Site,
+    /// Where the lock was taken
+    taken_at: Site,
+    id: usize,
+    class_index: Option<usize>,
+}
+
+struct Inversion {
+    class: Site,
+    caller: Site,
+    held: HeldLock,
+    /// The recorded order from `class` to `held.class`, as (class, where it
+    /// was taken while holding the previous one). The first one is `class`.
+    path: [Option<(Site, Site)>; MAX_LOCK_CLASSES],
+}
+
+struct LockdepState {
+    classes: [Option<Site>; MAX_LOCK_CLASSES],
+    /// after[a][b]: where b was first taken while holding a
+    after: [[Option<Site>; MAX_LOCK_CLASSES]; MAX_LOCK_CLASSES],
+    reported: [[bool; MAX_LOCK_CLASSES]; MAX_LOCK_CLASSES],
+    held: [Option<HeldLock>; MAX_HELD_LOCKS],
+    num_held: usize,
+    num_lookups_over_limit: usize,
+}
+impl LockdepState {
+    fn class_index(&mut self, class: Site) -> Option<usize> {
+        let slot = self
+            .classes
+            .iter()
+            .position(|e| e.is_none() || *e == Some(class));
+        match slot {
+            Some(i) => {
+                self.classes[i] = Some(class);
+                Some(i)
+            }
+            None => {
+                self.num_lookups_over_limit += 1;
+                None
+            }
+        }
+    }
+    /// Returns the classes on a shortest path from `from` to `to` in the
+    /// recorded order, excluding `from`, in the reverse order.
+    fn find_path(
+        &self,
+        from: usize,
+        to: usize,
+    ) -> Option<([usize; MAX_LOCK_CLASSES], usize)> {
+        let mut parent = [None; MAX_LOCK_CLASSES];
+        let mut queue = [0; MAX_LOCK_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from;
+        while head < tail {
+            let i = queue[head];
+            head += 1;
+            for next in 0..MAX_LOCK_CLASSES {
+                if self.after[i][next].is_none()
+                    || next == from
+                    || parent[next].is_some()
+                {
+                    continue;
+                }
+                parent[next] = Some(i);
+                if next == to {
+                    let mut path = [0; MAX_LOCK_CLASSES];
+                    let mut len = 0;
+                    let mut e = to;
+                    while e != from {
+                        path[len] = e;
+                        len += 1;
+                        e = parent[e]?;
+                    }
+                    return Some((path, len));
+                }
+                queue[tail] = next;
+                tail += 1;
+            }
+        }
+        None
+    }
+    /// Records that `class` is about to be taken under the held locks, and
+    /// returns the first inversion that is not reported yet.
+    fn check_order(&mut self, class: Site, caller: Site) -> Option<Inversion> {
+        let index = self.class_index(class)?;
+        let mut inversion = None;
+        for i in 0..self.num_held {
+            let Some(held) = self.held[i] else {
+                continue;
+            };
+            let Some(held_index) = held.class_index else {
+                continue;
+            };
+            if held_index == index {
+                // Locks of the same class (e.g. per-device locks) may be
+                // nested in a fixed order, which we can not tell.
+                continue;
+            }
+            // A cycle is found when its last edge is recorded, so known
+            // edges do not need the search.
+            let is_new = self.after[held_index][index].is_none();
+            let (a, b) = (index.min(held_index), index.max(held_index));
+            if is_new && !self.reported[a][b] && inversion.is_none() {
+                if let Some((reversed, len)) = self.find_path(index, held_index)
+                {
+                    self.reported[a][b] = true;
+                    let mut path = [None; MAX_LOCK_CLASSES];
+                    path[0] = Some((class, caller));
+                    let mut prev = index;
+                    for (i, e) in reversed[..len].iter().rev().enumerate() {
+                        path[i + 1] =
+                            self.classes[*e].zip(self.after[prev][*e]);
+                        prev = *e;
+                    }
+                    inversion = Some(Inversion {
+                        class,
+                        caller,
+                        held,
+                        path,
+                    });
+                }
+            }
+            self.after[held_index][index].get_or_insert(caller);
+        }
+        inversion
+    }
+    fn push(&mut self, lock: HeldLock) {
+        if self.num_held < MAX_HELD_LOCKS {
+            self.held[self.num_held] = Some(lock);
+            self.num_held += 1;
+        }
+    }
+    fn remove(&mut self, id: usize) {
+        // Locks are not always released in the reverse order.
+        let Some(i) = self.held[..self.num_held]
+            .iter()
+            .rposition(|e| e.map(|e| e.id) == Some(id))
+        else {
+            return;
+        };
+        self.held.copy_within(i + 1..self.num_held, i);
+        self.num_held -= 1;
+        self.held[self.num_held] = None;
+    }
+    fn num_dependencies(&self) -> usize {
+        self.after.iter().flatten().filter(|e| e.is_some()).count()
+    }
+}
+
+static IS_STATE_TAKEN: AtomicBool = AtomicBool::new(false);
+static STATE: SyncUnsafeCell<LockdepState> =
+    SyncUnsafeCell::new(LockdepState {
+        classes: [None; MAX_LOCK_CLASSES],
+        after: [[None; MAX_LOCK_CLASSES]; MAX_LOCK_CLASSES],
+        reported: [[false; MAX_LOCK_CLASSES]; MAX_LOCK_CLASSES],
+        held: [None; MAX_HELD_LOCKS],
+        num_held: 0,
+        num_lookups_over_limit: 0,
+    });
+
+fn with_state<R>(f: impl FnOnce(&mut LockdepState) -> R) -> R {
+    let were_interrupts_enabled = are_interrupts_enabled();
+    disable_interrupts();
+    while IS_STATE_TAKEN
+        .compare_exchange_weak(
+            false,
+            true,
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        )
+        .is_err()
+    {
+        busy_loop_hint();
+    }
+    let result = f(unsafe { &mut *STATE.get() });
+    IS_STATE_TAKEN.store(false, Ordering::Release);
+    if were_interrupts_enabled {
+        enable_interrupts();
+    }
+    result
+}
+
+/// Called before spinning to take a lock of `class` at `caller`.
+pub fn check_order(class: Site, caller: Site) {
+    let Some(e) = with_state(|state| state.check_order(class, caller)) else {
+        return;
+    };
+    // Report after releasing the state, since printing takes locks.
+    error!("lockdep: possible deadlock: lock order inversion");
+    error!("  taking {} at {}", e.class, e.caller);
+    error!(
+        "  while holding {} (taken at {})",
+        e.held.class, e.held.taken_at
+    );
+    error!("  but {} has been taken after {}:", e.held.class, e.class);
+    for (prev, (class, taken_at)) in
+        e.path.iter().flatten().zip(e.path.iter().skip(1).flatten())
+    {
+        error!("    {class} taken at {taken_at} while holding {}", prev.0);
+    }
+}
+
+/// Called when the lock `id` of `class` is taken at `taken_at`.
+pub fn acquired(class: Site, id: usize, taken_at: Site) {
+    with_state(|state| {
+        let class_index = state.class_index(class);
+        state.push(HeldLock {
+            class,
+            taken_at,
+            id,
+            class_index,
+        })
+    });
+}
+
+/// Called when the lock `id` is released.
+pub fn released(id: usize) {
+    with_state(|state| state.remove(id));
+}
+
+/// Writes the locks that are currently held, outermost first.
+pub fn dump(w: &mut dyn fmt::Write) -> Result<()> {
+    let mut held = [None; MAX_HELD_LOCKS];
+    let (num_classes, num_deps, num_over_limit) = with_state(|state| {
+        held = state.held;
+        (
+            state.classes.iter().flatten().count(),
+            state.num_dependencies(),
+            state.num_lookups_over_limit,
+        )
+    });
+    for (i, e) in held.iter().flatten().enumerate() {
+        writeln!(w, "#{i}: lock {} taken at {}", e.class, e.taken_at)
+            .or(Err("Failed to write"))?;
+    }
+    writeln!(
+        w,
+        "# lock classes: {num_classes} ({num_over_limit} lookups over the \
+         limit), dependencies: {num_deps}"
+    )
+    .or(Err("Failed to write"))
+}
+
+#[test_case]
+fn lock_order_inversion_test() {
+    use crate::mutex::Mutex;
+    let a = Mutex::new(0);
+    let b = Mutex::new(0);
+    {
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+    let is_reported = |a: &Mutex<i32>, b: &Mutex<i32>| {
+        with_state(|state| {
+            let a = state.class_index(a.created_at()).unwrap();
+            let b = state.class_index(b.created_at()).unwrap();
+            state.reported[a.min(b)][a.max(b)]
+        })
+    };
+    assert!(!is_reported(&a, &b));
+    {
+        let _b = b.lock();
+        let _a = a.lock();
+        assert!(with_state(|state| state.num_held) >= 2);
+    }
+    assert!(is_reported(&a, &b));
+}
+
+#[test_case]
+fn lock_order_cycle_test() {
+    use crate::mutex::Mutex;
+    let a = Mutex::new(0);
+    let b = Mutex::new(0);
+    let c = Mutex::new(0);
+    let index = |m: &Mutex<i32>| {
+        with_state(|state| state.class_index(m.created_at()).unwrap())
+    };
+    let (ia, ib, ic) = (index(&a), index(&b), index(&c));
+    {
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+    {
+        let _b = b.lock();
+        let _c = c.lock();
+    }
+    assert!(!with_state(|state| state.reported[ia.min(ic)][ia.max(ic)]));
+    assert_eq!(
+        with_state(|state| state.find_path(ia, ic)).map(|e| e.1),
+        Some(2)
+    );
+    {
+        let _c = c.lock();
+        let _a = a.lock();
+    }
+    assert!(with_state(|state| state.reported[ia.min(ic)][ia.max(ic)]));
+    // A -> B -> C is consistent with the order, so it is not reported.
+    assert!(!with_state(|state| state.reported[ia.min(ib)][ia.max(ib)]));
+}
@@ -2,12 +2,14 @@
 #![no_main]
 #![feature(offset_of)]
 
+use core::fmt::Write;
 use core::panic::PanicInfo;
-use core::ptr::read_volatile;
-use core::ptr::write_volatile;
-use core::time::Duration;
+use wasabi::acpi::set_global_acpi;
+use wasabi::aml::load_global_namespace;
+use wasabi::apic::init_local_apic;
+use wasabi::clock::init_clocksource;
+use wasabi::cui::serial_console_task;
 use wasabi::error;
-use wasabi::executor::sleep;
 use wasabi::executor::spawn_global;
 use wasabi::executor::start_global_executor;
 use wasabi::gui::set_global_vram;
@@ -18,18 +20,30 @@ use wasabi::init::init_display;
 use wasabi::init::init_hpet;
 use wasabi::init::init_paging;
 use wasabi::init::init_pci;
+use wasabi::init::reclaim_boot_memory;
 use wasabi::input::input_task;
+use wasabi::ioapic::init_io_apics;
+use wasabi::iommu::init_iommu;
+use wasabi::power::init_sci;
+use wasabi::power::register_shutdown_handler;
+use wasabi::power::shutdown_task;
 use wasabi::print::hexdump_struct;
 use wasabi::println;
 use wasabi::qemu::exit_qemu;
 use wasabi::qemu::QemuExitCode;
+use wasabi::remote::remote_control_task;
+use wasabi::serial::flush_serial_drivers;
+use wasabi::serial::init_serial_console;
+use wasabi::serial::init_serial_driver;
 use wasabi::serial::SerialPort;
 use wasabi::uefi::init_vram;
 use wasabi::uefi::locate_loaded_image_protocol;
 use wasabi::uefi::EfiHandle;
 use wasabi::uefi::EfiSystemTable;
 use wasabi::warn;
+use wasabi::x86::enable_interrupts;
 use wasabi::x86::init_exceptions;
+use wasabi::x86::mce::init_machine_check;
 
 #[no_mangle]
 fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
@@ -55,55 +69,50 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
     init_allocator(&memory_map);
     let (_gdt, _idt) = init_exceptions();
     init_paging(&memory_map);
+    let acpi = reclaim_boot_memory(&memory_map, acpi);
+    set_global_acpi(acpi);
+    if let Err(e) = init_serial_console(acpi) {
+        info!("serial: Using COM1 as the console: {e}");
+    }
     init_hpet(acpi);
+    init_clocksource();
+    if let Err(e) = init_local_apic() {
+        error!("Failed to init Local APIC: {e}");
+    }
+    if let Err(e) = init_io_apics(acpi) {
+        error!("Failed to init I/O APICs: {e}");
+    }
+    init_machine_check();
+    if let Err(e) = init_iommu(acpi) {
+        warn!("IOMMU is not enabled: {e}");
+    }
     init_pci(acpi);
-    let serial_task = async {
-        let sp = SerialPort::default();
-        if let Err(e) = sp.loopback_test() {
-            error!("{e:?}");
-            return Err("serial: loopback test failed");
-        }
-        info!("Started to monitor serial port");
-        loop {
-            if let Some(v) = sp.try_read() {
-                let c = char::from_u32(v as u32);
-                info!("serial input: {v:#04X} = {c:?}");
-            }
-            sleep(Duration::from_millis(20)).await;
-        }
-    };
-    spawn_global(serial_task);
-    let abp_uart_task = async {
-        // https://caro.su/msx/ocm_de1/16550.pdf
-        sleep(Duration::from_millis(1000)).await;
-        let base_addr = 0xfe032000_usize; // chromebook boten/bookem
-        let reg_rx_data = base_addr as *mut u8;
-        let reg_line_status = (base_addr + 0b101) as *mut u8;
-        unsafe {
-            write_volatile((base_addr + 1) as *mut u8, 0x00);
-            write_volatile((base_addr + 3) as *mut u8, 0x80);
-            write_volatile((base_addr) as *mut u8, 1);
-            write_volatile((base_addr + 1) as *mut u8, 0);
-            write_volatile((base_addr + 3) as *mut u8, 0x03);
-            write_volatile((base_addr + 2) as *mut u8, 0xC7);
-            write_volatile((base_addr + 4) as *mut u8, 0x0B);
-        }
-        loop {
-            sleep(Duration::from_millis(1000)).await;
-            info!("----");
-            let data = unsafe { read_volatile(reg_rx_data) };
-            info!("DATA:      {data:#010X}");
-            let status = unsafe { read_volatile(reg_line_status) };
-            info!("STATUS:    {status:#010b}");
-        }
-    };
-    spawn_global(abp_uart_task);
+    if let Err(e) = load_global_namespace(acpi) {
+        error!("Failed to load the AML namespace: {e}");
+    }
+    if let Err(e) = init_sci() {
+        error!("Failed to init SCI: {e}");
+    }
+    if let Err(e) = init_serial_driver() {
+        error!("Failed to init the serial driver: {e}");
+    }
+    // All the interrupt handlers and routes are set up above.
+    enable_interrupts();
+    spawn_global(serial_console_task());
+    spawn_global(remote_control_task());
     spawn_global(input_task());
+    register_shutdown_handler("serial", flush_serial_drivers);
+    spawn_global(shutdown_task());
+    #[cfg(feature = "heap_debug")]
+    spawn_global(wasabi::heap_debug::heap_check_task());
     start_global_executor()
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    error!("PANIC: {info:?}");
+    // Write to the serial port only. The printer and the VRAM may be locked
+    // by the code that panicked, and error!() would deadlock on them.
+    let mut sw = SerialPort::default();
+    let _ = writeln!(sw, "PANIC: {info:?}");
     exit_qemu(QemuExitCode::Fail);
 }
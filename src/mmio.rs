@@ -1,11 +1,17 @@
 extern crate alloc;
 
-use crate::x86::disable_cache;
+use crate::dma::DmaBuffer;
+use crate::dma::DmaConstraints;
+use crate::result::Result;
 use alloc::boxed::Box;
+use core::cmp::max;
+use core::marker::PhantomData;
 use core::marker::PhantomPinned;
+use core::mem::align_of;
+use core::mem::size_of;
 use core::mem::ManuallyDrop;
-use core::mem::MaybeUninit;
 use core::pin::Pin;
+use core::ptr::drop_in_place;
 
 pub struct Mmio<T: Sized> {
     inner: ManuallyDrop<Pin<Box<T>>>,
@@ -33,41 +39,47 @@ impl<T> AsRef<T> for Mmio<T> {
     }
 }
 
-#[repr(align(4096))]
-pub struct IoBoxInner<T: Sized> {
-    data: T,
-    _pinned: PhantomPinned,
-}
-impl<T: Sized> IoBoxInner<T> {
-    pub fn new(data: T) -> Self {
-        Self {
-            data,
-            _pinned: PhantomPinned,
-        }
-    }
-}
-
+/// A typed DMA buffer. It occupies dedicated physical frames that are
+/// mapped as uncacheable while it is alive.
 pub struct IoBox<T: Sized> {
-    inner: Pin<Box<IoBoxInner<T>>>,
+    buf: DmaBuffer,
+    _phantom: PhantomData<T>,
+    _pinned: PhantomPinned,
 }
 impl<T: Sized> IoBox<T> {
     pub fn new() -> Self {
-        let inner = Box::pin(IoBoxInner::new(unsafe {
-            MaybeUninit::<T>::zeroed().assume_init()
-        }));
-        let this = Self { inner };
-        disable_cache(&this);
-        this
+        Self::new_with_constraints(DmaConstraints::new())
+            .expect("Failed to allocate IoBox")
+    }
+    pub fn new_with_constraints(constraints: DmaConstraints) -> Result<Self> {
+        // The contents are zero-initialized.
+        let buf = DmaBuffer::new(
+            max(size_of::<T>(), 1),
+            constraints.align(max(align_of::<T>(), 1)),
+        )?;
+        Ok(Self {
+            buf,
+            _phantom: PhantomData,
+            _pinned: PhantomPinned,
+        })
+    }
+    pub fn phys_addr(&self) -> u64 {
+        self.buf.phys_addr()
     }
     /// # Safety
     /// Same rules as Pin::get_unchecked_mut() applies.
     pub unsafe fn get_unchecked_mut(&mut self) -> &mut T {
-        &mut self.inner.as_mut().get_unchecked_mut().data
+        &mut *(self.buf.as_mut_ptr() as *mut T)
     }
 }
 impl<T> AsRef<T> for IoBox<T> {
     fn as_ref(&self) -> &T {
-        &self.inner.as_ref().get_ref().data
+        unsafe { &*(self.buf.as_ptr() as *const T) }
+    }
+}
+impl<T: Sized> Drop for IoBox<T> {
+    fn drop(&mut self) {
+        unsafe { drop_in_place(self.buf.as_mut_ptr() as *mut T) }
     }
 }
 impl<T: Sized> Default for IoBox<T> {
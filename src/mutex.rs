@@ -1,4 +1,4 @@
-//! Simple thread-safe mutex
+//! Spin locks
 //!
 //! As the doc of SyncUnsafeCell says,
 //! `SyncUnsafeCell::get()` can be used to get
@@ -6,115 +6,196 @@
 //! ensure that the access to the object pointed
 //! is unique before dereferencing it.
 //!
-//! This mutex protects the data with AtomicBool
+//! The locks here protect the data with atomics
 //! to ensure that the access to the contents
-//! is unique so taking a mutable reference
-//! to it will be safe.
+//! is unique (or shared but read-only) so taking
+//! a reference to it will be safe.
+//!
+//! - SpinLock (a.k.a. Mutex): a fair ticket lock.
+//! - IrqSpinLock: a SpinLock that disables interrupts while it is held, for the
+//!   data that interrupt handlers may touch.
+//! - RwLock: multiple readers or one writer.
+//!
+//! Locks spin forever by default. With the `deadlock_detector` feature, a
+//! lock that can not be taken for a long time panics with the locations of
+//! the lock and its holder. With the `lockdep` feature, the order of taking
+//! locks is checked (see lockdep.rs).
 
 use crate::result::Result;
+use crate::x86::are_interrupts_enabled;
+use crate::x86::busy_loop_hint;
+use crate::x86::disable_interrupts;
+use crate::x86::enable_interrupts;
 use core::cell::SyncUnsafeCell;
 use core::fmt::Debug;
+use core::mem::ManuallyDrop;
 use core::ops::Deref;
 use core::ops::DerefMut;
 use core::panic::Location;
-use core::sync::atomic::AtomicBool;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicPtr;
 use core::sync::atomic::AtomicU32;
 use core::sync::atomic::Ordering;
 
-pub struct MutexGuard<'a, T> {
-    mutex: &'a Mutex<T>,
+pub type Mutex<T> = SpinLock<T>;
+pub type MutexGuard<'a, T> = SpinLockGuard<'a, T>;
+
+#[cfg(feature = "deadlock_detector")]
+const DEADLOCK_SPIN_LIMIT: usize = 1 << 24;
+
+/// Where a lock is created and last taken, for debugging.
+struct LockSite {
+    created_at: &'static Location<'static>,
+    taken_at: AtomicPtr<Location<'static>>,
+}
+impl LockSite {
+    #[track_caller]
+    const fn new() -> Self {
+        Self {
+            created_at: Location::caller(),
+            taken_at: AtomicPtr::new(null_mut()),
+        }
+    }
+    #[cfg(feature = "lockdep")]
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+    #[track_caller]
+    fn set_taken(&self) {
+        let location = Location::caller() as *const Location as *mut Location;
+        self.taken_at.store(location, Ordering::Relaxed);
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::acquired(
+            self.created_at,
+            self.id(),
+            Location::caller(),
+        );
+    }
+    fn set_released(&self) {
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::released(self.id());
+    }
+    fn taken_at(&self) -> Option<&'static Location<'static>> {
+        unsafe { self.taken_at.load(Ordering::Relaxed).as_ref() }
+    }
+    /// Spins until `try_take` returns true, and records the caller as the
+    /// holder.
+    #[track_caller]
+    fn spin_until(&self, kind: &str, mut try_take: impl FnMut() -> bool) {
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::check_order(self.created_at, Location::caller());
+        #[cfg(feature = "deadlock_detector")]
+        let mut spins = 0;
+        while !try_take() {
+            busy_loop_hint();
+            #[cfg(feature = "deadlock_detector")]
+            {
+                spins += 1;
+                if spins == DEADLOCK_SPIN_LIMIT {
+                    panic!(
+                        "Deadlock detected: failed to take {kind} created at \
+                         {}, caller: {}, last taken at: {:?}",
+                        self.created_at,
+                        Location::caller(),
+                        self.taken_at(),
+                    );
+                }
+            }
+        }
+        #[cfg(not(feature = "deadlock_detector"))]
+        let _ = kind;
+        self.set_taken();
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
     data: &'a mut T,
     location: Location<'a>,
 }
-impl<'a, T> MutexGuard<'a, T> {
+impl<'a, T> SpinLockGuard<'a, T> {
     #[track_caller]
-    unsafe fn new(mutex: &'a Mutex<T>, data: &SyncUnsafeCell<T>) -> Self {
+    unsafe fn new(lock: &'a SpinLock<T>, data: &SyncUnsafeCell<T>) -> Self {
         Self {
-            mutex,
+            lock,
             data: &mut *data.get(),
             location: *Location::caller(),
         }
     }
 }
-unsafe impl<'a, T> Sync for MutexGuard<'a, T> {}
-impl<'a, T> Deref for MutexGuard<'a, T> {
+unsafe impl<'a, T> Sync for SpinLockGuard<'a, T> {}
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
         self.data
     }
 }
-impl<'a, T> DerefMut for MutexGuard<'a, T> {
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.data
     }
 }
-impl<'a, T> Drop for MutexGuard<'a, T> {
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
     fn drop(&mut self) {
-        self.mutex.is_taken.store(false, Ordering::SeqCst)
+        self.lock.site.set_released();
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
     }
 }
-impl<'a, T> Debug for MutexGuard<'a, T> {
+impl<'a, T> Debug for SpinLockGuard<'a, T> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "MutexGuard {{ location: {:?} }}", self.location)
+        write!(f, "SpinLockGuard {{ location: {:?} }}", self.location)
     }
 }
 
-pub struct Mutex<T> {
+/// A ticket lock. Waiters take the lock in the order they arrived.
+pub struct SpinLock<T> {
     data: SyncUnsafeCell<T>,
-    is_taken: AtomicBool,
-    taker_line_num: AtomicU32,
-    created_at_file: &'static str,
-    created_at_line: u32,
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    site: LockSite,
 }
-impl<T: Sized> Debug for Mutex<T> {
+impl<T: Sized> Debug for SpinLock<T> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(
-            f,
-            "Mutex @ {}:{}",
-            self.created_at_file, self.created_at_line
-        )
+        write!(f, "SpinLock @ {}", self.site.created_at)
     }
 }
-impl<T: Sized> Mutex<T> {
+impl<T: Sized> SpinLock<T> {
     #[track_caller]
     pub const fn new(data: T) -> Self {
         Self {
             data: SyncUnsafeCell::new(data),
-            is_taken: AtomicBool::new(false),
-            taker_line_num: AtomicU32::new(0),
-            created_at_file: Location::caller().file(),
-            created_at_line: Location::caller().line(),
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            site: LockSite::new(),
         }
     }
     #[track_caller]
-    fn try_lock(&self) -> Result<MutexGuard<T>> {
+    pub fn try_lock(&self) -> Result<SpinLockGuard<T>> {
+        let ticket = self.now_serving.load(Ordering::Relaxed);
         if self
-            .is_taken
-            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .next_ticket
+            .compare_exchange(
+                ticket,
+                ticket.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
             .is_ok()
         {
-            self.taker_line_num
-                .store(Location::caller().line(), Ordering::SeqCst);
-            Ok(unsafe { MutexGuard::new(self, &self.data) })
+            self.site.set_taken();
+            Ok(unsafe { SpinLockGuard::new(self, &self.data) })
         } else {
             Err("Lock failed")
         }
     }
     #[track_caller]
-    pub fn lock(&self) -> MutexGuard<T> {
-        for _ in 0..10000 {
-            if let Ok(locked) = self.try_lock() {
-                return locked;
-            }
-        }
-        panic!(
-            "Failed to lock Mutex at {}:{}, caller: {:?}, taker_line_num: {}",
-            self.created_at_file,
-            self.created_at_line,
-            Location::caller(),
-            self.taker_line_num.load(Ordering::SeqCst),
-        )
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        self.site.spin_until("SpinLock", || {
+            self.now_serving.load(Ordering::Acquire) == ticket
+        });
+        unsafe { SpinLockGuard::new(self, &self.data) }
     }
     pub fn under_locked<R: Sized>(
         &self,
@@ -123,11 +204,232 @@ impl<T: Sized> Mutex<T> {
         let mut locked = self.lock();
         f(&mut *locked)
     }
+    /// Returns the location where the lock was taken last time.
+    pub fn taken_at(&self) -> Option<&'static Location<'static>> {
+        self.site.taken_at()
+    }
+    pub fn created_at(&self) -> &'static Location<'static> {
+        self.site.created_at
+    }
+}
+unsafe impl<T> Sync for SpinLock<T> {}
+impl<T: Default> Default for SpinLock<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    guard: ManuallyDrop<SpinLockGuard<'a, T>>,
+    were_interrupts_enabled: bool,
+}
+impl<'a, T> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+impl<'a, T> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+impl<'a, T> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // Release the lock before interrupts can come in.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.were_interrupts_enabled {
+            enable_interrupts();
+        }
+    }
+}
+
+/// A SpinLock that disables interrupts while it is held, so that an
+/// interrupt handler that takes the same lock can not deadlock. The
+/// interrupt flag is restored when the guard is dropped.
+pub struct IrqSpinLock<T> {
+    lock: SpinLock<T>,
+}
+impl<T: Sized> Debug for IrqSpinLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "IrqSpinLock @ {}", self.lock.site.created_at)
+    }
+}
+impl<T: Sized> IrqSpinLock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            lock: SpinLock::new(data),
+        }
+    }
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        let were_interrupts_enabled = are_interrupts_enabled();
+        disable_interrupts();
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(self.lock.lock()),
+            were_interrupts_enabled,
+        }
+    }
+}
+impl<T: Default> Default for IrqSpinLock<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+const RW_WRITER: u32 = 1 << 31;
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.site.set_released();
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.site.set_released();
+        self.lock.state.fetch_and(!RW_WRITER, Ordering::Release);
+    }
+}
+
+/// A lock that allows either multiple readers or one writer.
+pub struct RwLock<T> {
+    data: SyncUnsafeCell<T>,
+    /// RW_WRITER if taken by a writer, the number of readers otherwise
+    state: AtomicU32,
+    site: LockSite,
+}
+impl<T: Sized> Debug for RwLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "RwLock @ {}", self.site.created_at)
+    }
+}
+impl<T: Sized> RwLock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            data: SyncUnsafeCell::new(data),
+            state: AtomicU32::new(0),
+            site: LockSite::new(),
+        }
+    }
+    fn try_take_read(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        state & RW_WRITER == 0
+            && self
+                .state
+                .compare_exchange_weak(
+                    state,
+                    state + 1,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+    fn try_take_write(&self) -> bool {
+        self.state
+            .compare_exchange_weak(
+                0,
+                RW_WRITER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        self.site
+            .spin_until("RwLock (read)", || self.try_take_read());
+        RwLockReadGuard { lock: self }
+    }
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.site
+            .spin_until("RwLock (write)", || self.try_take_write());
+        RwLockWriteGuard { lock: self }
+    }
 }
-unsafe impl<T> Sync for Mutex<T> {}
-impl<T: Default> Default for Mutex<T> {
+unsafe impl<T> Sync for RwLock<T> {}
+impl<T: Default> Default for RwLock<T> {
     #[track_caller]
     fn default() -> Self {
         Self::new(T::default())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn spin_lock_test() {
+        let lock = SpinLock::new(1);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.try_lock().is_err());
+        }
+        assert_eq!(*lock.try_lock().expect("Failed to lock"), 2);
+        assert_eq!(*lock.lock(), 2);
+    }
+
+    #[test_case]
+    fn irq_spin_lock_restores_interrupt_flag() {
+        let lock = IrqSpinLock::new(0);
+        let were_enabled = are_interrupts_enabled();
+        {
+            let mut guard = lock.lock();
+            assert!(!are_interrupts_enabled());
+            *guard += 1;
+        }
+        assert_eq!(are_interrupts_enabled(), were_enabled);
+        assert_eq!(*lock.lock(), 1);
+    }
+
+    #[test_case]
+    fn rw_lock_test() {
+        let lock = RwLock::new(3);
+        {
+            let r0 = lock.read();
+            let r1 = lock.read();
+            assert_eq!(*r0 + *r1, 6);
+            assert!(!lock.try_take_write());
+        }
+        {
+            let mut w = lock.write();
+            *w = 4;
+            assert!(!lock.try_take_read());
+        }
+        assert_eq!(*lock.read(), 4);
+    }
+}
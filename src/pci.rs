@@ -1,6 +1,8 @@
 use crate::acpi::AcpiMcfgDescriptor;
 use crate::error;
 use crate::info;
+use crate::iommu;
+use crate::iommu::DomainType;
 use crate::result::Result;
 use crate::x86::with_current_page_table;
 use crate::x86::PageAttr;
@@ -255,7 +257,21 @@ impl Pci {
             flags | cmd_and_status,
         )
     }
+    /// Lets the device do DMA to any physical memory.
     pub fn enable_bus_master(&self, bdf: BusDeviceFunction) -> Result<()> {
+        iommu::attach_device(bdf, DomainType::PassThrough)?;
+        self.set_command_and_status_flags(
+            bdf,
+            1 << 2, /* Bus Master Enable */
+        )
+    }
+    /// Lets the device do DMA only to the DmaBuffers allocated for it, if
+    /// it is behind an IOMMU.
+    pub fn enable_isolated_bus_master(
+        &self,
+        bdf: BusDeviceFunction,
+    ) -> Result<()> {
+        iommu::attach_device(bdf, DomainType::Isolated)?;
         self.set_command_and_status_flags(
             bdf,
             1 << 2, /* Bus Master Enable */
@@ -0,0 +1,333 @@
+//! Reboot and shutdown via ACPI
+//!
+//! Reboot writes the reset value to the reset register in the FADT.
+//! Shutdown enters the S5 sleep state by writing SLP_TYPx | SLP_EN to the
+//! PM1a/PM1b control blocks, where SLP_TYPx come from the \_S5 object in
+//! the AML namespace, or from a scan of the DSDT if it can not be evaluated.
+//!
+//! A press of the fixed power button (e.g. `system_powerdown` of QEMU) is
+//! reported via the SCI as PWRBTN_STS, and it requests a graceful shutdown:
+//! shutdown_task() runs the registered shutdown handlers and then enters S5.
+//! Tasks can also wait for the request with wait_for_shutdown_request().
+
+extern crate alloc;
+
+use crate::acpi::global_acpi;
+use crate::acpi::AcpiFadt;
+use crate::acpi::GenericAddress;
+use crate::aml;
+use crate::aml::AmlValue;
+use crate::apic::LocalApic;
+use crate::apic::VECTOR_ACPI_SCI;
+use crate::error;
+use crate::executor::yield_execution;
+use crate::info;
+use crate::ioapic::isa_irq_to_gsi;
+use crate::ioapic::route_gsi;
+use crate::ioapic::Polarity;
+use crate::ioapic::Trigger;
+use crate::mutex::IrqSpinLock;
+use crate::mutex::Mutex;
+use crate::result::Result;
+use crate::warn;
+use crate::x86::busy_loop_hint;
+use crate::x86::read_io_port_u8;
+use crate::x86::set_interrupt_handler;
+use crate::x86::write_io_port_u8;
+use crate::x86::InterruptInfo;
+use crate::x86::InterruptsDisabled;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+const SLP_TYP_SHIFT: u64 = 10;
+const SLP_TYP_MASK: u64 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u64 = 1 << 13;
+// The registers take effect asynchronously, so wait for a while before
+// giving up.
+const POWER_OFF_WAIT_LOOPS: usize = 100_000_000;
+const ACPI_ENABLE_WAIT_LOOPS: usize = 10_000_000;
+
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_PACKAGE_OP: u8 = 0x12;
+
+// 4.8.3.1 PM1 Event Grouping
+const PM1_STS_PWRBTN: u64 = 1 << 8;
+const PM1_EN_PWRBTN: u64 = 1 << 8;
+// 4.8.3.2 PM1 Control Grouping
+const PM1_CNT_SCI_EN: u64 = 1;
+
+/// PM1a and PM1b Event Blocks as (status, enable), for the SCI handler
+type Pm1EventBlocks = [Option<(GenericAddress, GenericAddress)>; 2];
+static PM1_EVENT_BLOCKS: IrqSpinLock<Option<Pm1EventBlocks>> =
+    IrqSpinLock::new(None);
+
+pub type ShutdownHandler = fn() -> Result<()>;
+static SHUTDOWN_HANDLERS: Mutex<Vec<(&'static str, ShutdownHandler)>> =
+    Mutex::new(Vec::new());
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+fn fadt() -> Result<&'static AcpiFadt> {
+    global_acpi()
+        .ok_or("ACPI tables are not available")?
+        .fadt()
+        .ok_or("FADT not found")
+}
+
+/// Reads an integer element of a package (ZeroOp, OneOp or ByteConst) and
+/// returns the value and the rest of the bytes.
+fn parse_small_integer(aml: &[u8]) -> Result<(u8, &[u8])> {
+    match aml {
+        [AML_ZERO_OP, rest @ ..] => Ok((0, rest)),
+        [AML_ONE_OP, rest @ ..] => Ok((1, rest)),
+        [AML_BYTE_PREFIX, v, rest @ ..] => Ok((*v, rest)),
+        _ => Err("Unexpected AML in \\_S5"),
+    }
+}
+
+/// Finds `Name (_S5, Package () { SLP_TYPa, SLP_TYPb, ... })` in the AML
+/// and returns (SLP_TYPa, SLP_TYPb).
+fn find_s5_sleep_types(aml: &[u8]) -> Result<(u8, u8)> {
+    let pos = aml
+        .windows(4)
+        .enumerate()
+        .find_map(|(i, e)| {
+            let is_name = i >= 1 && aml[i - 1] == AML_NAME_OP
+                || i >= 2 && aml[i - 2] == AML_NAME_OP && aml[i - 1] == b'\\';
+            (e == b"_S5_" && is_name).then_some(i + 4)
+        })
+        .ok_or("\\_S5 not found in DSDT")?;
+    let package = &aml[pos..];
+    let [AML_PACKAGE_OP, pkg_length, ..] = package else {
+        return Err("\\_S5 is not a package");
+    };
+    // The upper 2 bits of the first byte of PkgLength is the number of the
+    // bytes that follow. Then NumElements comes.
+    let elements = package
+        .get(2 + (pkg_length >> 6) as usize + 1..)
+        .ok_or("\\_S5 is too short")?;
+    let (slp_typa, elements) = parse_small_integer(elements)?;
+    let (slp_typb, _) = parse_small_integer(elements)?;
+    Ok((slp_typa, slp_typb))
+}
+
+/// Returns (SLP_TYPa, SLP_TYPb) of \_S5. If the interpreter fails on it,
+/// falls back to a scan of the DSDT, which handles the common static form.
+fn s5_sleep_types() -> Result<(u8, u8)> {
+    match evaluate_s5_sleep_types() {
+        Ok(types) => Ok(types),
+        Err(e) => {
+            warn!("power: Failed to evaluate \\_S5 ({e}), scanning the DSDT");
+            let aml = fadt()?.dsdt_aml().ok_or("DSDT not found")?;
+            find_s5_sleep_types(aml)
+        }
+    }
+}
+
+/// Returns (SLP_TYPa, SLP_TYPb) from `Package () { SLP_TYPa, SLP_TYPb, ... }`
+/// of \_S5.
+fn evaluate_s5_sleep_types() -> Result<(u8, u8)> {
+    let s5 = aml::evaluate("\\_S5", Vec::new())?;
+    let AmlValue::Package(elements) = s5 else {
+        return Err("\\_S5 is not a package");
+    };
+    let slp_typa = elements.first().ok_or("\\_S5 is empty")?.as_integer()?;
+    let slp_typb = match elements.get(1) {
+        Some(e) => e.as_integer()?,
+        None => 0,
+    };
+    Ok((slp_typa as u8, slp_typb as u8))
+}
+
+pub fn reboot() -> Result<()> {
+    let (reg, value) = fadt()?
+        .reset_register()
+        .ok_or("Reset register is not supported")?;
+    // Interrupts are enabled again if it fails.
+    let _interrupts_disabled = InterruptsDisabled::new();
+    reg.write(value as u64)?;
+    for _ in 0..POWER_OFF_WAIT_LOOPS {
+        busy_loop_hint();
+    }
+    Err("Reset register did not work")
+}
+
+pub fn shutdown() -> Result<()> {
+    let fadt = fadt()?;
+    let (slp_typa, slp_typb) = s5_sleep_types()?;
+    let pm1a = fadt.pm1a_cnt_blk().ok_or("PM1a_CNT_BLK not found")?;
+    let pm1b = fadt.pm1b_cnt_blk();
+    // Interrupts are enabled again if it fails.
+    let _interrupts_disabled = InterruptsDisabled::new();
+    let enter_s5 = |reg: &GenericAddress, slp_typ: u8| {
+        let value = reg.read()? & !SLP_TYP_MASK;
+        reg.write(value | ((slp_typ as u64) << SLP_TYP_SHIFT) | SLP_EN)
+    };
+    // Both blocks should be written for the transition to happen.
+    if let Some(pm1b) = pm1b {
+        enter_s5(&pm1b, slp_typb)?;
+    }
+    enter_s5(&pm1a, slp_typa)?;
+    for _ in 0..POWER_OFF_WAIT_LOOPS {
+        busy_loop_hint();
+    }
+    Err("Failed to enter S5")
+}
+
+/// Switches the platform from legacy mode to ACPI mode, where the fixed
+/// events are reported via the SCI instead of SMIs.
+fn enable_acpi_mode(fadt: &AcpiFadt) -> Result<()> {
+    let pm1a_cnt = fadt.pm1a_cnt_blk().ok_or("PM1a_CNT_BLK not found")?;
+    if pm1a_cnt.read()? & PM1_CNT_SCI_EN != 0 {
+        return Ok(());
+    }
+    let (smi_cmd, acpi_enable) = fadt
+        .acpi_enable_command()
+        .ok_or("ACPI mode is disabled and can not be enabled")?;
+    write_io_port_u8(smi_cmd, acpi_enable);
+    for _ in 0..ACPI_ENABLE_WAIT_LOOPS {
+        if pm1a_cnt.read()? & PM1_CNT_SCI_EN != 0 {
+            return Ok(());
+        }
+        busy_loop_hint();
+    }
+    Err("Failed to enable ACPI mode")
+}
+
+/// Disables and clears all the GPEs. They are not handled yet, and an
+/// enabled GPE would keep the level-triggered SCI asserted.
+fn disable_gpes(fadt: &AcpiFadt) {
+    for (port, len) in [fadt.gpe0_blk(), fadt.gpe1_blk()].into_iter().flatten()
+    {
+        let half = len / 2;
+        for i in 0..half {
+            write_io_port_u8(port + (half + i) as u16, 0);
+            // The status bits are cleared by writing 1s.
+            let status = read_io_port_u8(port + i as u16);
+            write_io_port_u8(port + i as u16, status);
+        }
+    }
+}
+
+fn sci_interrupt_handler(_info: &InterruptInfo) {
+    let mut is_power_button_pressed = false;
+    if let Some(blocks) = PM1_EVENT_BLOCKS.lock().as_ref() {
+        for (status, _) in blocks.iter().flatten() {
+            if status.read().is_ok_and(|v| v & PM1_STS_PWRBTN != 0) {
+                // The status bits are cleared by writing 1s.
+                let _ = status.write(PM1_STS_PWRBTN);
+                is_power_button_pressed = true;
+            }
+        }
+    }
+    if is_power_button_pressed {
+        request_shutdown();
+    }
+    if let Some(lapic) = LocalApic::current() {
+        lapic.eoi();
+    }
+}
+
+/// Switches to ACPI mode and enables the SCI for the fixed power button.
+/// This should be called after init_io_apics().
+pub fn init_sci() -> Result<()> {
+    let fadt = fadt()?;
+    if fadt.is_hw_reduced() {
+        return Err("HW-reduced ACPI platforms do not have the SCI");
+    }
+    let blocks = [fadt.pm1a_evt_blk(), fadt.pm1b_evt_blk()];
+    if blocks[0].is_none() {
+        return Err("PM1a_EVT_BLK not found");
+    }
+    enable_acpi_mode(fadt)?;
+    disable_gpes(fadt);
+    for (status, enable) in blocks.iter().flatten() {
+        enable.write(0)?;
+        status.write(status.read()?)?;
+    }
+    *PM1_EVENT_BLOCKS.lock() = Some(blocks);
+    // The SCI is a sharable, level-triggered and active-low interrupt
+    // unless it is overridden.
+    let (gsi, trigger, polarity) = isa_irq_to_gsi(
+        fadt.sci_int() as u32,
+        Trigger::Level,
+        Polarity::ActiveLow,
+    );
+    set_interrupt_handler(VECTOR_ACPI_SCI, sci_interrupt_handler);
+    route_gsi(gsi, VECTOR_ACPI_SCI, trigger, polarity)?;
+    if fadt.has_fixed_power_button() {
+        for (_, enable) in blocks.iter().flatten() {
+            enable.write(PM1_EN_PWRBTN)?;
+        }
+    } else {
+        warn!(
+            "power: The control method power button is not supported, so \
+             the power button will be ignored"
+        );
+    }
+    info!("power: SCI is enabled (GSI {gsi}, {trigger:?}, {polarity:?})");
+    Ok(())
+}
+
+/// Registers a handler that is called before the system powers off for a
+/// shutdown request, e.g. to flush the state of a driver.
+pub fn register_shutdown_handler(name: &'static str, handler: ShutdownHandler) {
+    SHUTDOWN_HANDLERS.lock().push((name, handler));
+}
+
+/// Requests a graceful shutdown. This can be called from interrupt
+/// handlers.
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+pub async fn wait_for_shutdown_request() {
+    while !is_shutdown_requested() {
+        yield_execution().await;
+    }
+}
+
+/// Waits for a shutdown request, then runs the shutdown handlers and
+/// powers off the system. If it fails, waits for the next request.
+pub async fn shutdown_task() -> Result<()> {
+    loop {
+        wait_for_shutdown_request().await;
+        info!("power: Shutdown requested");
+        // Let the other tasks waiting for the request run once.
+        yield_execution().await;
+        let handlers = SHUTDOWN_HANDLERS.lock().clone();
+        for (name, handler) in handlers {
+            if let Err(e) = handler() {
+                warn!("power: Shutdown handler {name} failed: {e}");
+            }
+        }
+        if let Err(e) = shutdown() {
+            error!("power: Shutdown failed: {e}");
+        }
+        SHUTDOWN_REQUESTED.store(false, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn find_s5_sleep_types_test() {
+    // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero })
+    let aml = [
+        0x08, 0x5C, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x07, 0x04, 0x0A, 0x05, 0x00,
+        0x00, 0x00,
+    ];
+    assert_eq!(find_s5_sleep_types(&aml), Ok((5, 0)));
+    // Name (_S5, Package (0x04) { Zero, One, ... }) after another _S5_
+    let aml = [
+        b'_', b'S', b'5', b'_', 0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04,
+        0x00, 0x01, 0x00, 0x00,
+    ];
+    assert_eq!(find_s5_sleep_types(&aml), Ok((0, 1)));
+    assert!(find_s5_sleep_types(&[0x08, b'_', b'S', b'4', b'_']).is_err());
+}
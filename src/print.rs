@@ -1,19 +1,66 @@
+extern crate alloc;
+
 use crate::graphics::BitmapTextWriter;
 use crate::gui::GLOBAL_VRAM;
-use crate::mutex::Mutex;
+use crate::mutex::IrqSpinLock;
+use crate::result::Result;
 use crate::serial::SerialPort;
 use crate::uefi::VramBufferInfo;
+use alloc::string::String;
 use core::fmt;
 use core::mem::size_of;
 use core::slice;
 
-static GLOBAL_PRINTER: Mutex<BitmapTextWriter<VramBufferInfo>> =
-    Mutex::new(BitmapTextWriter::new(&GLOBAL_VRAM));
+static GLOBAL_PRINTER: IrqSpinLock<BitmapTextWriter<VramBufferInfo>> =
+    IrqSpinLock::new(BitmapTextWriter::new(&GLOBAL_VRAM));
+
+// While this is Some, the output is also appended to it. The buffer does not
+// grow, so that printing never allocates (e.g. when the allocation failed).
+static CAPTURED_OUTPUT: IrqSpinLock<Option<String>> = IrqSpinLock::new(None);
+const MAX_CAPTURED_OUTPUT: usize = 64 * 1024;
+
+struct CaptureWriter<'a> {
+    buf: &'a mut String,
+}
+impl fmt::Write for CaptureWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(self.buf.capacity() - self.buf.len());
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf.push_str(&s[..len]);
+        if len == s.len() {
+            Ok(())
+        } else {
+            Err(fmt::Error)
+        }
+    }
+}
 
 pub fn global_print(args: fmt::Arguments) {
     let mut writer = SerialPort::default();
     fmt::write(&mut writer, args).unwrap();
     let _ = fmt::write(&mut *GLOBAL_PRINTER.lock(), args);
+    if let Some(buf) = CAPTURED_OUTPUT.lock().as_mut() {
+        let _ = fmt::write(&mut CaptureWriter { buf }, args);
+    }
+}
+
+/// Calls f() and returns what is printed during that, as well as its result.
+/// The output is truncated at MAX_CAPTURED_OUTPUT bytes. This can not be
+/// nested.
+pub fn capture_output<T>(f: impl FnOnce() -> T) -> Result<(T, String)> {
+    let buf = String::with_capacity(MAX_CAPTURED_OUTPUT);
+    {
+        let mut captured = CAPTURED_OUTPUT.lock();
+        if captured.is_some() {
+            return Err("Output is already being captured");
+        }
+        *captured = Some(buf);
+    }
+    let result = f();
+    let captured = CAPTURED_OUTPUT.lock().take().unwrap_or_default();
+    Ok((result, captured))
 }
 
 #[macro_export]
@@ -0,0 +1,284 @@
+//! Sampling profiler
+//!
+//! Samples are taken from the performance counter overflow interrupt (PMI)
+//! when the CPU has an architectural PMU, or from the periodic Local APIC
+//! timer interrupt otherwise. Each sample records the interrupted RIP and the
+//! return addresses found by walking the frame pointer (RBP) chain, which is
+//! available since the kernel is built with `-Cforce-frame-pointers`.
+//!
+//! The interrupt handler does not take any locks nor allocate memory. The
+//! sample buffer is allocated on `start()` and filled by the handler through
+//! an atomic index.
+
+extern crate alloc;
+
+use crate::apic::LocalApic;
+use crate::apic::LVT_MASKED;
+use crate::apic::VECTOR_LAPIC_TIMER;
+use crate::apic::VECTOR_PMU;
+use crate::result::Result;
+use crate::x86::clear_interrupt_handler;
+use crate::x86::cpuid::cpu_features;
+use crate::x86::read_msr;
+use crate::x86::set_interrupt_handler;
+use crate::x86::walk_stack;
+use crate::x86::write_msr;
+use crate::x86::InterruptInfo;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use core::fmt;
+use core::fmt::Write;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+const MAX_STACK_DEPTH: usize = 16;
+const MAX_SAMPLES: usize = 8192;
+const TIMER_INTERVAL: Duration = Duration::from_millis(1);
+// Number of unhalted core cycles between samples
+const PMU_SAMPLE_PERIOD: u64 = 1_000_000;
+
+const MSR_IA32_PMC0: u32 = 0xC1;
+const MSR_IA32_PERFEVTSEL0: u32 = 0x186;
+const MSR_IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const MSR_IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+// UnHalted Core Cycles (Event Num. 3CH, Umask 00H)
+const PERFEVTSEL_UNHALTED_CORE_CYCLES: u64 = 0x3C;
+const PERFEVTSEL_USR: u64 = 1 << 16;
+const PERFEVTSEL_OS: u64 = 1 << 17;
+const PERFEVTSEL_INT: u64 = 1 << 20;
+const PERFEVTSEL_EN: u64 = 1 << 22;
+
+#[derive(Copy, Clone)]
+struct Sample {
+    depth: usize,
+    frames: [u64; MAX_STACK_DEPTH],
+}
+impl Sample {
+    const fn empty() -> Self {
+        Self {
+            depth: 0,
+            frames: [0; MAX_STACK_DEPTH],
+        }
+    }
+    fn frames(&self) -> &[u64] {
+        &self.frames[..self.depth]
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SampleSource {
+    None = 0,
+    Pmu = 1,
+    Timer = 2,
+}
+
+static SAMPLES: AtomicPtr<Sample> = AtomicPtr::new(null_mut());
+static NEXT_SAMPLE: AtomicUsize = AtomicUsize::new(0);
+static DROPPED_SAMPLES: AtomicUsize = AtomicUsize::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static SOURCE: AtomicU8 = AtomicU8::new(SampleSource::None as u8);
+// Cached in start_pmu() so that the PMI handler does not read cpu_features(),
+// which takes a lock.
+static PMU_COUNTER_MASK: AtomicUsize = AtomicUsize::new(0);
+static PMU_VERSION: AtomicU8 = AtomicU8::new(0);
+
+fn record_sample(info: &InterruptInfo) {
+    if !RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+    let samples = SAMPLES.load(Ordering::Relaxed);
+    let index = NEXT_SAMPLE.fetch_add(1, Ordering::Relaxed);
+    if samples.is_null() || index >= MAX_SAMPLES {
+        DROPPED_SAMPLES.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    // SAFETY: each index is handed out only once by fetch_add above, and the
+    // buffer has MAX_SAMPLES elements.
+    let sample = unsafe { &mut *samples.add(index) };
+    sample.frames[0] = info.rip();
+    sample.depth = 1 + walk_stack(info.rbp(), &mut sample.frames[1..]);
+}
+
+fn timer_interrupt_handler(info: &InterruptInfo) {
+    record_sample(info);
+    if let Some(lapic) = LocalApic::current() {
+        lapic.eoi();
+    }
+}
+
+fn pmu_reload() {
+    let mask = PMU_COUNTER_MASK.load(Ordering::Relaxed) as u64;
+    unsafe { write_msr(MSR_IA32_PMC0, PMU_SAMPLE_PERIOD.wrapping_neg() & mask) }
+}
+
+fn pmu_interrupt_handler(info: &InterruptInfo) {
+    record_sample(info);
+    pmu_reload();
+    if PMU_VERSION.load(Ordering::Relaxed) >= 2 {
+        // Clear the overflow status of PMC0
+        unsafe { write_msr(MSR_IA32_PERF_GLOBAL_OVF_CTRL, 1) }
+    }
+    if let Some(lapic) = LocalApic::current() {
+        // The LVT entry is masked by the CPU on PMI. Unmask it again.
+        lapic.set_lvt_perf(VECTOR_PMU as u32);
+        lapic.eoi();
+    }
+}
+
+fn has_pmu() -> bool {
+    let features = cpu_features();
+    features.pmu_version >= 1
+        && features.pmu_num_counters >= 1
+        && features.pmu_counter_width > 0
+}
+
+fn start_pmu(lapic: &LocalApic) {
+    let features = cpu_features();
+    let width = features.pmu_counter_width as u32;
+    PMU_VERSION.store(features.pmu_version, Ordering::SeqCst);
+    PMU_COUNTER_MASK.store(
+        1usize.checked_shl(width).unwrap_or(0).wrapping_sub(1),
+        Ordering::SeqCst,
+    );
+    set_interrupt_handler(VECTOR_PMU, pmu_interrupt_handler);
+    lapic.set_lvt_perf(VECTOR_PMU as u32);
+    unsafe {
+        write_msr(MSR_IA32_PERFEVTSEL0, 0);
+        pmu_reload();
+        write_msr(
+            MSR_IA32_PERFEVTSEL0,
+            PERFEVTSEL_UNHALTED_CORE_CYCLES
+                | PERFEVTSEL_USR
+                | PERFEVTSEL_OS
+                | PERFEVTSEL_INT
+                | PERFEVTSEL_EN,
+        );
+        if features.pmu_version >= 2 {
+            write_msr(
+                MSR_IA32_PERF_GLOBAL_CTRL,
+                read_msr(MSR_IA32_PERF_GLOBAL_CTRL) | 1,
+            );
+        }
+    }
+}
+
+fn stop_pmu(lapic: &LocalApic) {
+    unsafe { write_msr(MSR_IA32_PERFEVTSEL0, 0) }
+    lapic.set_lvt_perf(LVT_MASKED);
+    clear_interrupt_handler(VECTOR_PMU);
+}
+
+/// Starts sampling. Samples are taken only while interrupts are enabled.
+pub fn start() -> Result<SampleSource> {
+    if RUNNING.load(Ordering::SeqCst) {
+        return Err("Profiler is already running");
+    }
+    let lapic = LocalApic::current().ok_or("Local APIC is not initialized")?;
+    if SAMPLES.load(Ordering::SeqCst).is_null() {
+        let samples = vec![Sample::empty(); MAX_SAMPLES].into_boxed_slice();
+        SAMPLES.store(Box::leak(samples).as_mut_ptr(), Ordering::SeqCst);
+    }
+    NEXT_SAMPLE.store(0, Ordering::SeqCst);
+    DROPPED_SAMPLES.store(0, Ordering::SeqCst);
+    RUNNING.store(true, Ordering::SeqCst);
+    let source = if has_pmu() {
+        start_pmu(&lapic);
+        SampleSource::Pmu
+    } else {
+        set_interrupt_handler(VECTOR_LAPIC_TIMER, timer_interrupt_handler);
+        lapic.start_periodic_timer(VECTOR_LAPIC_TIMER, TIMER_INTERVAL);
+        SampleSource::Timer
+    };
+    SOURCE.store(source as u8, Ordering::SeqCst);
+    Ok(source)
+}
+
+pub fn stop() -> Result<()> {
+    if !RUNNING.load(Ordering::SeqCst) {
+        return Err("Profiler is not running");
+    }
+    let lapic = LocalApic::current().ok_or("Local APIC is not initialized")?;
+    match source() {
+        SampleSource::Pmu => stop_pmu(&lapic),
+        SampleSource::Timer => {
+            lapic.stop_timer();
+            clear_interrupt_handler(VECTOR_LAPIC_TIMER);
+        }
+        SampleSource::None => {}
+    }
+    RUNNING.store(false, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn source() -> SampleSource {
+    match SOURCE.load(Ordering::SeqCst) {
+        1 => SampleSource::Pmu,
+        2 => SampleSource::Timer,
+        _ => SampleSource::None,
+    }
+}
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+pub fn num_samples() -> usize {
+    NEXT_SAMPLE.load(Ordering::SeqCst).min(MAX_SAMPLES)
+}
+pub fn num_dropped_samples() -> usize {
+    DROPPED_SAMPLES.load(Ordering::SeqCst)
+}
+
+/// Returns the stack in the folded format (outermost frame first, separated
+/// by `;`), as consumed by flamegraph.pl and similar tools.
+fn fold_stack(frames: &[u64]) -> String {
+    let mut folded = String::new();
+    for (i, addr) in frames.iter().rev().enumerate() {
+        if i != 0 {
+            folded.push(';');
+        }
+        let _ = write!(folded, "{addr:#x}");
+    }
+    folded
+}
+
+/// Writes the recorded samples as a folded stack report, one line per
+/// unique stack with its sample count.
+pub fn dump(w: &mut dyn fmt::Write) -> Result<()> {
+    if is_running() {
+        return Err("Stop the profiler before dumping");
+    }
+    let samples = SAMPLES.load(Ordering::SeqCst);
+    if samples.is_null() {
+        return Err("No samples recorded");
+    }
+    let mut stacks: BTreeMap<String, usize> = BTreeMap::new();
+    for i in 0..num_samples() {
+        let sample = unsafe { &*samples.add(i) };
+        *stacks.entry(fold_stack(sample.frames())).or_default() += 1;
+    }
+    for (stack, count) in stacks.iter() {
+        writeln!(w, "{stack} {count}").or(Err("Failed to write"))?;
+    }
+    writeln!(
+        w,
+        "# source: {:?}, samples: {}, dropped: {}",
+        source(),
+        num_samples(),
+        num_dropped_samples()
+    )
+    .or(Err("Failed to write"))
+}
+
+#[test_case]
+fn fold_stack_test() {
+    assert_eq!(fold_stack(&[]), "");
+    assert_eq!(fold_stack(&[0x1234]), "0x1234");
+    assert_eq!(fold_stack(&[0x3, 0x2, 0x1]), "0x1;0x2;0x3");
+}
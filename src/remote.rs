@@ -0,0 +1,371 @@
+extern crate alloc;
+
+use crate::cui::run_cmd;
+use crate::executor::global_tasks;
+use crate::graphics::Bitmap;
+use crate::gui::GLOBAL_VRAM;
+use crate::info;
+use crate::json::JsonValue;
+use crate::print::capture_output;
+use crate::result::Result;
+use crate::serial::attach_serial_port;
+use crate::serial::SerialPort;
+use crate::serial::COM2_IRQ;
+use crate::x86::read_cr3;
+use crate::x86::PAGE_SIZE;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+
+// A machine-readable protocol to control the kernel over COM2.
+//
+// Each message is a little-endian u32 length followed by that many bytes of
+// UTF-8 JSON. A request is {"id": any, "method": string, "params": object},
+// and the response is {"id": (same as the request), "result": any} on
+// success, or {"id": ..., "error": string} on failure.
+//
+// Methods:
+// - run_cmd {"cmd": string} => {"output": string}
+// - peek {"addr": addr, "size": number} => {"data": hex string}
+// - poke {"addr": addr, "data": hex string} => {}
+// - tasks {} => [{"id", "file", "line", "running"}]
+// - screenshot {"x", "y", "width", "height" (all optional)} => {"width",
+//   "height", "format": "rgb888", "data": base64 string}
+//
+// addr is a number or a string of a hex number like "0x1000".
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+const MAX_PEEK_SIZE: usize = 4096;
+// The kernel uses the lower half of the 48-bit address space only.
+const MAX_ADDR: u64 = 1 << 47;
+
+fn base64_encode(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut s = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).cloned().unwrap_or(0),
+            chunk.get(2).cloned().unwrap_or(0),
+        ];
+        let v = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(TABLE[(v >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for v in data {
+        let _ = write!(s, "{v:02x}");
+    }
+    s
+}
+
+fn hex_decode(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return Err("Invalid hex string");
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16).or(Err("Invalid hex string"))
+        })
+        .collect()
+}
+
+fn param<'a>(params: &'a JsonValue, key: &str) -> Result<&'a JsonValue> {
+    params.get(key).ok_or("Missing parameter")
+}
+
+fn param_u64(params: &JsonValue, key: &str) -> Result<u64> {
+    match param(params, key)? {
+        JsonValue::Number(n) => u64::try_from(*n).or(Err("Negative number")),
+        JsonValue::String(s) => {
+            let s = s.strip_prefix("0x").ok_or("Expected a hex number")?;
+            u64::from_str_radix(s, 16).or(Err("Invalid hex number"))
+        }
+        _ => Err("Expected a number"),
+    }
+}
+
+fn param_str<'a>(params: &'a JsonValue, key: &str) -> Result<&'a str> {
+    param(params, key)?.as_str().ok_or("Expected a string")
+}
+
+/// Returns an error unless all the pages in [addr, addr + size) are mapped
+/// (and writable if `write` is true), to avoid page faults on peek and poke.
+fn check_mapped(addr: u64, size: usize, write: bool) -> Result<()> {
+    let end = addr
+        .checked_add(size as u64)
+        .filter(|end| *end <= MAX_ADDR)
+        .ok_or("Address is out of range")?;
+    // SAFETY: The page table is only read, and it is not modified while this
+    // task runs since the executor runs tasks one by one.
+    let pml4 = unsafe { &*read_cr3() };
+    let mut page = addr & !(PAGE_SIZE as u64 - 1);
+    while page < end {
+        let mapping = pml4.translate(page)?;
+        if write && !mapping.is_writable() {
+            return Err("Page is read-only");
+        }
+        page += PAGE_SIZE as u64;
+    }
+    Ok(())
+}
+
+fn method_run_cmd(params: &JsonValue) -> Result<JsonValue> {
+    let cmd = param_str(params, "cmd")?;
+    let (result, output) = capture_output(|| run_cmd(cmd))?;
+    result?;
+    Ok(JsonValue::object([("output", output.into())]))
+}
+
+fn method_peek(params: &JsonValue) -> Result<JsonValue> {
+    let addr = param_u64(params, "addr")?;
+    let size = param_u64(params, "size")? as usize;
+    if size > MAX_PEEK_SIZE {
+        return Err("size is too large");
+    }
+    check_mapped(addr, size, false)?;
+    // Read byte by byte with volatile since it can be MMIO.
+    let data: Vec<u8> = (addr..addr + size as u64)
+        .map(|p| unsafe { read_volatile(p as *const u8) })
+        .collect();
+    Ok(JsonValue::object([("data", hex_encode(&data).into())]))
+}
+
+fn method_poke(params: &JsonValue) -> Result<JsonValue> {
+    let addr = param_u64(params, "addr")?;
+    let data = hex_decode(param_str(params, "data")?)?;
+    check_mapped(addr, data.len(), true)?;
+    for (p, v) in (addr..).zip(data) {
+        unsafe { write_volatile(p as *mut u8, v) }
+    }
+    Ok(JsonValue::object([]))
+}
+
+fn method_tasks() -> Result<JsonValue> {
+    Ok(global_tasks()
+        .iter()
+        .map(|t| {
+            JsonValue::object([
+                ("id", (t.id as i64).into()),
+                ("file", t.created_at_file.into()),
+                ("line", (t.created_at_line as i64).into()),
+                ("running", t.is_running.into()),
+            ])
+        })
+        .collect::<Vec<JsonValue>>()
+        .into())
+}
+
+fn method_screenshot(params: &JsonValue) -> Result<JsonValue> {
+    let optional = |key, default| match params.get(key) {
+        Some(_) => i64::try_from(param_u64(params, key)?)
+            .or(Err("Rect is out of the screen")),
+        None => Ok(default),
+    };
+    let last = |start: i64, len: i64| {
+        if len > 0 {
+            start.checked_add(len - 1)
+        } else {
+            None
+        }
+    };
+    // Copy the pixels and release the VRAM before encoding them, since
+    // printing takes the same lock.
+    let (w, h, data) = {
+        let mut vram = GLOBAL_VRAM.lock();
+        let x = optional("x", 0)?;
+        let y = optional("y", 0)?;
+        if !vram.is_in_x_range(x) || !vram.is_in_y_range(y) {
+            return Err("Rect is out of the screen");
+        }
+        let w = optional("width", vram.width() - x)?;
+        let h = optional("height", vram.height() - y)?;
+        match (last(x, w), last(y, h)) {
+            (Some(x1), Some(y1))
+                if vram.is_in_x_range(x1) && vram.is_in_y_range(y1) => {}
+            _ => return Err("Rect is out of the screen"),
+        }
+        let mut data = Vec::with_capacity((w * h * 3) as usize);
+        for py in y..y + h {
+            for px in x..x + w {
+                let c = *vram.pixel_at_mut(px, py).ok_or("Out of Range")?;
+                data.extend_from_slice(&[
+                    (c >> 16) as u8,
+                    (c >> 8) as u8,
+                    c as u8,
+                ]);
+            }
+        }
+        (w, h, data)
+    };
+    Ok(JsonValue::object([
+        ("width", w.into()),
+        ("height", h.into()),
+        ("format", "rgb888".into()),
+        ("data", base64_encode(&data).into()),
+    ]))
+}
+
+fn dispatch(method: &str, params: &JsonValue) -> Result<JsonValue> {
+    match method {
+        "run_cmd" => method_run_cmd(params),
+        "peek" => method_peek(params),
+        "poke" => method_poke(params),
+        "tasks" => method_tasks(),
+        "screenshot" => method_screenshot(params),
+        _ => Err("Unknown method"),
+    }
+}
+
+fn error_response(id: JsonValue, e: &str) -> JsonValue {
+    JsonValue::object([("id", id), ("error", e.into())])
+}
+
+fn handle_request(request: &[u8]) -> JsonValue {
+    let request = match core::str::from_utf8(request)
+        .or(Err("Request is not UTF-8"))
+        .and_then(JsonValue::parse)
+    {
+        Ok(request) => request,
+        Err(e) => return error_response(JsonValue::Null, e),
+    };
+    let id = request.get("id").cloned().unwrap_or(JsonValue::Null);
+    let Some(method) = request.get("method").and_then(|v| v.as_str()) else {
+        return error_response(id, "Missing method");
+    };
+    let no_params = JsonValue::object([]);
+    let params = request.get("params").unwrap_or(&no_params);
+    match dispatch(method, params) {
+        Ok(result) => JsonValue::object([("id", id), ("result", result)]),
+        Err(e) => error_response(id, e),
+    }
+}
+
+/// Serves the requests on COM2. This should be spawned after
+/// init_io_apics().
+pub async fn remote_control_task() -> Result<()> {
+    let port = attach_serial_port(SerialPort::new_for_com2(), COM2_IRQ)?;
+    info!("remote: Listening on COM2");
+    loop {
+        let mut len = [0u8; 4];
+        port.read_exact(&mut len).await?;
+        let len = u32::from_le_bytes(len) as usize;
+        let response = if len > MAX_REQUEST_SIZE {
+            // Skip the body to keep the framing.
+            for _ in 0..len {
+                port.read().await?;
+            }
+            error_response(JsonValue::Null, "Request is too large")
+        } else {
+            let mut request = vec![0; len];
+            port.read_exact(&mut request).await?;
+            handle_request(&request)
+        };
+        let response = format!("{response}");
+        port.write(&(response.len() as u32).to_le_bytes()).await?;
+        port.write(response.as_bytes()).await?;
+    }
+}
+
+#[test_case]
+fn base64_encode_test() {
+    assert_eq!(base64_encode(b""), "");
+    assert_eq!(base64_encode(b"f"), "Zg==");
+    assert_eq!(base64_encode(b"fo"), "Zm8=");
+    assert_eq!(base64_encode(b"foo"), "Zm9v");
+    assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+    assert_eq!(base64_encode(&[0xFF, 0xEF]), "/+8=");
+}
+
+#[test_case]
+fn hex_test() {
+    assert_eq!(hex_encode(&[0x00, 0xAB, 0x7F]), "00ab7f");
+    assert_eq!(hex_decode("00aB7f"), Ok(vec![0x00, 0xAB, 0x7F]));
+    assert!(hex_decode("abc").is_err());
+    assert!(hex_decode("zz").is_err());
+}
+
+#[test_case]
+fn handle_request_test() {
+    let call =
+        |request: &str| format!("{}", handle_request(request.as_bytes()));
+    assert_eq!(
+        call("{"),
+        r#"{"id":null,"error":"JSON: Unexpected end of input"}"#
+    );
+    assert_eq!(
+        call(r#"{"id":1,"method":"nop"}"#),
+        r#"{"id":1,"error":"Unknown method"}"#
+    );
+    assert_eq!(
+        call(
+            r#"{"id":"a","method":"peek","params":{"addr":"0x800000000000"}}"#
+        ),
+        r#"{"id":"a","error":"Missing parameter"}"#
+    );
+    assert_eq!(
+        call(r#"{"method":"poke","params":{"addr":"0x800000000000"}}"#),
+        r#"{"id":null,"error":"Missing parameter"}"#
+    );
+    assert_eq!(
+        call(
+            r#"{"method":"peek","params":{"addr":"0x7fffffffffff","size":2}}"#
+        ),
+        r#"{"id":null,"error":"Address is out of range"}"#
+    );
+    let mut buf = [0x12u8, 0x34, 0x56, 0x78];
+    let addr = buf.as_mut_ptr() as u64;
+    assert_eq!(
+        call(&format!(
+            r#"{{"id":2,"method":"peek",
+                "params":{{"addr":"{addr:#x}","size":4}}}}"#
+        )),
+        r#"{"id":2,"result":{"data":"12345678"}}"#
+    );
+    assert_eq!(
+        call(&format!(
+            r#"{{"id":3,"method":"poke",
+                "params":{{"addr":{},"data":"abcd"}}}}"#,
+            addr + 1
+        )),
+        r#"{"id":3,"result":{}}"#
+    );
+    assert_eq!(unsafe { read_volatile(&buf) }, [0x12, 0xAB, 0xCD, 0x78]);
+    for params in [
+        r#"{"x":-1}"#,
+        r#"{"x":0,"width":9223372036854775807}"#,
+        r#"{"x":9223372036854775807,"width":2}"#,
+        r#"{"y":"0xffffffffffffffff"}"#,
+        r#"{"width":0}"#,
+    ] {
+        let response = handle_request(
+            format!(r#"{{"method":"screenshot","params":{params}}}"#)
+                .as_bytes(),
+        );
+        assert!(response.get("error").is_some(), "{params}: {response}");
+    }
+    let response = handle_request(
+        br#"{"id":4,"method":"run_cmd","params":{"cmd":"show"}}"#,
+    );
+    let output = response
+        .get("result")
+        .and_then(|r| r.get("output"))
+        .and_then(|v| v.as_str())
+        .expect("No output");
+    assert!(output.contains("show mmap"));
+}
@@ -1,54 +1,231 @@
+extern crate alloc;
+
+use crate::acpi::AcpiRsdpStruct;
+use crate::acpi::AcpiSpcr;
+use crate::acpi::GenericAddress;
+use crate::acpi::DBG2_PORT_TYPE_SERIAL;
+use crate::acpi::SERIAL_SUBTYPE_16450;
+use crate::acpi::SERIAL_SUBTYPE_16550;
+use crate::acpi::SERIAL_SUBTYPE_16550_GAS;
+use crate::apic::LocalApic;
+use crate::apic::VECTOR_SERIAL;
+use crate::executor::yield_execution;
+use crate::info;
+use crate::ioapic::route_isa_irq;
+use crate::mutex::IrqSpinLock;
 use crate::result::Result;
+use crate::x86::are_interrupts_enabled;
 use crate::x86::busy_loop_hint;
+use crate::x86::disable_interrupts;
+use crate::x86::enable_interrupts;
 use crate::x86::read_io_port_u8;
+use crate::x86::set_interrupt_handler;
+use crate::x86::with_current_page_table;
 use crate::x86::write_io_port_u8;
+use crate::x86::InterruptInfo;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
 
 // c.f. https://wiki.osdev.org/Serial_Ports
+// c.f. https://caro.su/msx/ocm_de1/16550.pdf
+
+const COM1_BASE: u16 = 0x3f8;
+const COM1_IRQ: u8 = 4;
+const COM2_BASE: u16 = 0x2f8;
+pub const COM2_IRQ: u8 = 3;
+const NO_IRQ: u8 = 0xFF;
+/// The clock of the UARTs on PCs, which gives 115200 baud with divisor 1
+const DEFAULT_UART_CLOCK: u32 = 1_843_200;
+const NUM_REGISTERS: usize = 8;
+const FIFO_SIZE: usize = 16;
+const RX_BUFFER_SIZE: usize = 4096;
+const TX_BUFFER_SIZE: usize = 4096;
+const NUM_SERIAL_DRIVERS: usize = 2;
 
+const REG_DATA: usize = 0;
+const REG_IER: usize = 1;
+const REG_FCR: usize = 2;
+const REG_LCR: usize = 3;
+const REG_MCR: usize = 4;
+const REG_LSR: usize = 5;
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+// Enable FIFO, clear them, with 14-byte threshold
+const FCR_ENABLE_AND_CLEAR_FIFOS: u8 = 0xC7;
+// DTR, RTS, and OUT2 which connects the IRQ line on PCs
+const MCR_NORMAL: u8 = 0x0B;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// How the registers of a 16550-compatible UART are accessed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UartRegisters {
+    /// I/O ports at `base + reg`
+    Io { base: u16 },
+    /// MMIO at `base + reg * stride`, accessed in `width` bytes
+    Mmio { base: u64, stride: u8, width: u8 },
+}
+
+// The console port, kept in atomics so that the interrupt handlers and the
+// panic handler can write to it without taking any locks. A zero MMIO base
+// means the I/O port at CONSOLE_IO_BASE.
+static CONSOLE_MMIO_BASE: AtomicU64 = AtomicU64::new(0);
+static CONSOLE_MMIO_STRIDE: AtomicU8 = AtomicU8::new(1);
+static CONSOLE_MMIO_WIDTH: AtomicU8 = AtomicU8::new(1);
+static CONSOLE_IO_BASE: AtomicU16 = AtomicU16::new(COM1_BASE);
+static CONSOLE_ISA_IRQ: AtomicU8 = AtomicU8::new(COM1_IRQ);
+
+#[derive(Clone, Copy, Debug)]
 pub struct SerialPort {
-    base: u16,
+    regs: UartRegisters,
 }
 impl SerialPort {
     pub fn new(base: u16) -> Self {
-        Self { base }
+        Self {
+            regs: UartRegisters::Io { base },
+        }
+    }
+    /// A UART whose registers are `stride` bytes apart from `base`, and
+    /// accessed in `width` bytes. The registers should be mapped as MMIO.
+    pub fn new_mmio(base: u64, stride: u8, width: u8) -> Result<Self> {
+        if !matches!(stride, 1 | 2 | 4) {
+            Err("Unsupported UART register stride")
+        } else if !matches!(width, 1 | 2 | 4) || width > stride {
+            Err("Unsupported UART register width")
+        } else {
+            Ok(Self {
+                regs: UartRegisters::Mmio {
+                    base,
+                    stride,
+                    width,
+                },
+            })
+        }
     }
     pub fn new_for_com1() -> Self {
         // Use COM1 at I/O port 0x3f8
-        Self::new(0x3f8)
+        Self::new(COM1_BASE)
+    }
+    pub fn new_for_com2() -> Self {
+        Self::new(COM2_BASE)
+    }
+    /// A UART described by a Generic Address in the SPCR or the DBG2, where
+    /// the bit width is the register stride.
+    pub fn from_generic_address(gas: &GenericAddress) -> Result<Self> {
+        if gas.is_in_io_space() {
+            let base = u16::try_from(gas.address())
+                .or(Err("UART I/O port is out of range"))?;
+            Ok(Self::new(base))
+        } else if gas.is_in_memory_space() {
+            let stride = (gas.bit_width() / 8).max(1);
+            let width = (gas.access_width() / 8).clamp(1, stride);
+            Self::new_mmio(gas.address(), stride, width)
+        } else {
+            Err("Unsupported address space for UART")
+        }
+    }
+    pub fn registers(&self) -> UartRegisters {
+        self.regs
+    }
+    fn read_reg(&self, reg: usize) -> u8 {
+        match self.regs {
+            UartRegisters::Io { base } => read_io_port_u8(base + reg as u16),
+            UartRegisters::Mmio {
+                base,
+                stride,
+                width,
+            } => {
+                let addr = base + (reg * stride as usize) as u64;
+                unsafe {
+                    match width {
+                        4 => read_volatile(addr as *const u32) as u8,
+                        2 => read_volatile(addr as *const u16) as u8,
+                        _ => read_volatile(addr as *const u8),
+                    }
+                }
+            }
+        }
+    }
+    fn write_reg(&self, reg: usize, value: u8) {
+        match self.regs {
+            UartRegisters::Io { base } => {
+                write_io_port_u8(base + reg as u16, value)
+            }
+            UartRegisters::Mmio {
+                base,
+                stride,
+                width,
+            } => {
+                let addr = base + (reg * stride as usize) as u64;
+                unsafe {
+                    match width {
+                        4 => write_volatile(addr as *mut u32, value as u32),
+                        2 => write_volatile(addr as *mut u16, value as u16),
+                        _ => write_volatile(addr as *mut u8, value),
+                    }
+                }
+            }
+        }
     }
     pub fn init(&mut self) {
+        // baud rate = (115200 / BAUD_DIVISOR)
+        self.init_with_divisor(0x0001)
+    }
+    pub fn init_with_divisor(&mut self, divisor: u16) {
         // Disable all interrupts
-        write_io_port_u8(self.base + 1, 0x00);
+        self.write_reg(REG_IER, 0x00);
         // Enable DLAB (set baud rate divisor)
-        write_io_port_u8(self.base + 3, 0x80);
-        // baud rate = (115200 / BAUD_DIVISOR)
-        const BAUD_DIVISOR: u16 = 0x0001;
-        write_io_port_u8(self.base, (BAUD_DIVISOR & 0xff) as u8);
-        write_io_port_u8(self.base + 1, (BAUD_DIVISOR >> 8) as u8);
+        self.write_reg(REG_LCR, 0x80);
+        self.write_reg(0, (divisor & 0xff) as u8);
+        self.write_reg(1, (divisor >> 8) as u8);
         // 8 bits, no parity, one stop bit
-        write_io_port_u8(self.base + 3, 0x03);
-        // Enable FIFO, clear them, with 14-byte threshold
-        write_io_port_u8(self.base + 2, 0xC7);
+        self.write_reg(REG_LCR, 0x03);
+        self.write_reg(REG_FCR, FCR_ENABLE_AND_CLEAR_FIFOS);
         // IRQs enabled, RTS/DSR set
-        write_io_port_u8(self.base + 4, 0x0B);
+        self.write_reg(REG_MCR, MCR_NORMAL);
     }
     pub fn loopback_test(&self) -> Result<()> {
         // Set in loopback mode
-        write_io_port_u8(self.base + 4, 0x1e);
+        self.write_reg(REG_MCR, 0x1e);
         self.send_char('T');
         if self.try_read().ok_or("loopback_test failed: No response")? != b'T' {
             return Err("loopback_test failed: wrong data received");
         }
         // Return to the normal mode
-        write_io_port_u8(self.base + 4, 0x0f);
+        self.write_reg(REG_MCR, MCR_NORMAL);
         Ok(())
     }
+    /// Sends a byte by polling, which works in any context (e.g. in panic
+    /// handlers) and is used for the logs.
     pub fn send_char(&self, c: char) {
-        while (read_io_port_u8(self.base + 5) & 0x20) == 0 {
+        loop {
+            // Check and write with interrupts disabled, so that the
+            // interrupt handler can not fill the FIFO in between.
+            let were_interrupts_enabled = are_interrupts_enabled();
+            disable_interrupts();
+            let is_thr_empty = self.read_reg(REG_LSR) & LSR_THR_EMPTY != 0;
+            if is_thr_empty {
+                self.write_reg(REG_DATA, c as u8);
+            }
+            if were_interrupts_enabled {
+                enable_interrupts();
+            }
+            if is_thr_empty {
+                return;
+            }
             busy_loop_hint();
         }
-        write_io_port_u8(self.base, c as u8)
     }
     pub fn send_str(&self, s: &str) {
         let mut sc = s.chars();
@@ -58,25 +235,452 @@ impl SerialPort {
         }
     }
     pub fn try_read(&self) -> Option<u8> {
-        if read_io_port_u8(self.base + 5) & 0x01 == 0 {
+        if self.read_reg(REG_LSR) & LSR_DATA_READY == 0 {
             None
         } else {
-            let c = read_io_port_u8(self.base);
-            // Enable FIFO, clear them, with 14-byte threshold
-            write_io_port_u8(self.base + 2, 0xC7);
-            Some(c)
+            Some(self.read_reg(REG_DATA))
         }
     }
 }
 impl fmt::Write for SerialPort {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let serial = Self::default();
-        serial.send_str(s);
+        self.send_str(s);
         Ok(())
     }
 }
 impl Default for SerialPort {
+    /// The console port, which is COM1 unless init_serial_console() finds
+    /// another one.
     fn default() -> Self {
-        Self::new_for_com1()
+        let base = CONSOLE_MMIO_BASE.load(Ordering::Relaxed);
+        if base == 0 {
+            Self::new(CONSOLE_IO_BASE.load(Ordering::Relaxed))
+        } else {
+            Self {
+                regs: UartRegisters::Mmio {
+                    base,
+                    stride: CONSOLE_MMIO_STRIDE.load(Ordering::Relaxed),
+                    width: CONSOLE_MMIO_WIDTH.load(Ordering::Relaxed),
+                },
+            }
+        }
+    }
+}
+
+fn set_console(port: &SerialPort, isa_irq: Option<u8>) {
+    CONSOLE_ISA_IRQ.store(isa_irq.unwrap_or(NO_IRQ), Ordering::SeqCst);
+    match port.regs {
+        UartRegisters::Io { base } => {
+            CONSOLE_MMIO_BASE.store(0, Ordering::SeqCst);
+            CONSOLE_IO_BASE.store(base, Ordering::SeqCst);
+        }
+        UartRegisters::Mmio {
+            base,
+            stride,
+            width,
+        } => {
+            CONSOLE_MMIO_STRIDE.store(stride, Ordering::SeqCst);
+            CONSOLE_MMIO_WIDTH.store(width, Ordering::SeqCst);
+            CONSOLE_MMIO_BASE.store(base, Ordering::SeqCst);
+        }
+    }
+}
+
+fn is_16550_compatible(subtype: u16) -> bool {
+    matches!(
+        subtype,
+        SERIAL_SUBTYPE_16550 | SERIAL_SUBTYPE_16450 | SERIAL_SUBTYPE_16550_GAS
+    )
+}
+
+struct ConsoleConfig {
+    port: SerialPort,
+    /// None to keep the baud rate configured by the firmware
+    divisor: Option<u16>,
+    isa_irq: Option<u8>,
+}
+
+fn console_from_spcr(spcr: &AcpiSpcr) -> Result<ConsoleConfig> {
+    if !is_16550_compatible(spcr.interface_type()) {
+        return Err("SPCR: Unsupported UART interface type");
+    }
+    let port = SerialPort::from_generic_address(&spcr.base_address())?;
+    let divisor = match spcr.baud_rate() {
+        Some(baud_rate) => {
+            let clock =
+                spcr.uart_clock_frequency().unwrap_or(DEFAULT_UART_CLOCK);
+            let divisor = u16::try_from(clock / (16 * baud_rate))
+                .or(Err("SPCR: Baud rate divisor is too large"))?;
+            if divisor == 0 {
+                return Err("SPCR: Baud rate is too high for the clock");
+            }
+            Some(divisor)
+        }
+        None => None,
+    };
+    Ok(ConsoleConfig {
+        port,
+        divisor,
+        isa_irq: spcr.isa_irq(),
+    })
+}
+
+/// Returns the console UART from the SPCR, or the first 16550-compatible
+/// serial port in the DBG2 if the SPCR is not found or not usable.
+fn find_console(acpi: &AcpiRsdpStruct) -> Result<ConsoleConfig> {
+    if let Some(spcr) = acpi.spcr() {
+        match console_from_spcr(spcr) {
+            Ok(config) => return Ok(config),
+            Err(e) if acpi.dbg2().is_none() => return Err(e),
+            Err(e) => info!("serial: {e}. Trying DBG2."),
+        }
+    }
+    let dbg2 = acpi.dbg2().ok_or("Neither SPCR nor DBG2 is found")?;
+    let gas = dbg2
+        .devices()
+        .filter(|e| {
+            e.port_type() == DBG2_PORT_TYPE_SERIAL
+                && is_16550_compatible(e.port_subtype())
+        })
+        .find_map(|e| e.base_address())
+        .ok_or("DBG2: No 16550-compatible serial port")?;
+    Ok(ConsoleConfig {
+        port: SerialPort::from_generic_address(&gas)?,
+        divisor: None,
+        isa_irq: None,
+    })
+}
+
+/// Switches the console from COM1 to the UART described in the SPCR or the
+/// DBG2, if any. This should be called after init_paging().
+pub fn init_serial_console(acpi: &AcpiRsdpStruct) -> Result<()> {
+    let ConsoleConfig {
+        mut port,
+        divisor,
+        isa_irq,
+    } = find_console(acpi)?;
+    if let UartRegisters::Mmio { base, stride, .. } = port.regs {
+        let start = base & !(PAGE_SIZE as u64 - 1);
+        let end = (base + (NUM_REGISTERS * stride as usize) as u64)
+            .next_multiple_of(PAGE_SIZE as u64);
+        unsafe {
+            with_current_page_table(|pt| {
+                pt.create_mapping(start, end, start, PageAttr::ReadWriteIo)
+            })?
+        }
+    }
+    if let Some(divisor) = divisor {
+        port.init_with_divisor(divisor);
+    }
+    set_console(&port, isa_irq);
+    info!(
+        "serial: console: {:?}, divisor: {:?}, IRQ: {:?}",
+        port.regs, divisor, isa_irq
+    );
+    Ok(())
+}
+
+/// A fixed-size FIFO that can be used in interrupt handlers since it does
+/// not allocate.
+struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+    /// Returns false if the buffer is full.
+    fn push(&mut self, value: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = value;
+        self.len += 1;
+        true
+    }
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let value = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(value)
+    }
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+struct SerialDriver {
+    port: SerialPort,
+    rx: RingBuffer<RX_BUFFER_SIZE>,
+    tx: RingBuffer<TX_BUFFER_SIZE>,
+    /// True if the last byte that read_line() took was a CR, to treat CR LF
+    /// as a single line break.
+    last_was_cr: bool,
+    stats: SerialStats,
+}
+impl SerialDriver {
+    fn enable_tx_interrupt(&self, enable: bool) {
+        let ier = IER_RX_AVAILABLE | IER_LINE_STATUS;
+        let ier = if enable { ier | IER_THR_EMPTY } else { ier };
+        self.port.write_reg(REG_IER, ier);
+    }
+    fn handle_interrupt(&mut self) {
+        loop {
+            let lsr = self.port.read_reg(REG_LSR);
+            if lsr & LSR_OVERRUN != 0 {
+                self.stats.rx_overruns += 1;
+            }
+            if lsr & LSR_DATA_READY == 0 {
+                break;
+            }
+            let c = self.port.read_reg(REG_DATA);
+            self.stats.rx_bytes += 1;
+            if !self.rx.push(c) {
+                self.stats.rx_dropped += 1;
+            }
+        }
+        if self.port.read_reg(REG_LSR) & LSR_THR_EMPTY != 0 {
+            for _ in 0..FIFO_SIZE {
+                let Some(c) = self.tx.pop() else {
+                    break;
+                };
+                self.port.write_reg(REG_DATA, c);
+                self.stats.tx_bytes += 1;
+            }
+            if self.tx.is_empty() {
+                self.enable_tx_interrupt(false);
+            }
+        }
+    }
+    /// Sends all the queued bytes by polling.
+    fn flush(&mut self) {
+        while let Some(c) = self.tx.pop() {
+            self.port.send_char(c as char);
+            self.stats.tx_bytes += 1;
+        }
+        self.enable_tx_interrupt(false);
+    }
+}
+
+// Slot 0 is for the console, and the others are for attach_serial_port().
+static SERIAL_DRIVERS: [IrqSpinLock<Option<SerialDriver>>; NUM_SERIAL_DRIVERS] =
+    [IrqSpinLock::new(None), IrqSpinLock::new(None)];
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SerialStats {
+    pub rx_bytes: usize,
+    pub tx_bytes: usize,
+    /// Bytes lost in the UART since the FIFO was full
+    pub rx_overruns: usize,
+    /// Bytes dropped since the RX buffer was full
+    pub rx_dropped: usize,
+}
+/// Sends the bytes queued on all the ports by polling, so that they are not
+/// lost on shutdown.
+pub fn flush_serial_drivers() -> Result<()> {
+    for driver in SERIAL_DRIVERS.iter() {
+        if let Some(driver) = driver.lock().as_mut() {
+            driver.flush();
+        }
+    }
+    Ok(())
+}
+
+/// Returns the stats of the console port.
+pub fn serial_stats() -> SerialStats {
+    SerialDevice::CONSOLE.stats().unwrap_or_default()
+}
+
+fn serial_interrupt_handler(_info: &InterruptInfo) {
+    // All the ports share the vector, so check all of them.
+    for driver in SERIAL_DRIVERS.iter() {
+        if let Some(driver) = driver.lock().as_mut() {
+            driver.handle_interrupt();
+        }
+    }
+    if let Some(lapic) = LocalApic::current() {
+        lapic.eoi();
+    }
+}
+
+fn attach(index: usize, port: SerialPort, irq: u8) -> Result<SerialDevice> {
+    if SERIAL_DRIVERS
+        .iter()
+        .any(|d| d.lock().as_ref().is_some_and(|d| d.port.regs == port.regs))
+    {
+        return Err("The UART is already attached");
+    }
+    port.loopback_test()?;
+    port.write_reg(REG_IER, 0);
+    port.write_reg(REG_FCR, FCR_ENABLE_AND_CLEAR_FIFOS);
+    while port.try_read().is_some() {}
+    let driver = SerialDriver {
+        port,
+        rx: RingBuffer::new(),
+        tx: RingBuffer::new(),
+        last_was_cr: false,
+        stats: SerialStats::default(),
+    };
+    driver.enable_tx_interrupt(false);
+    *SERIAL_DRIVERS[index].lock() = Some(driver);
+    set_interrupt_handler(VECTOR_SERIAL, serial_interrupt_handler);
+    let gsi = route_isa_irq(irq, VECTOR_SERIAL)?;
+    info!(
+        "serial: {:?} is interrupt-driven (IRQ {irq}, GSI {gsi})",
+        port.regs
+    );
+    Ok(SerialDevice { index })
+}
+
+/// Makes the console UART interrupt-driven for read() and write(). The logs
+/// are still written by polling. This should be called after
+/// init_io_apics() and init_serial_console().
+pub fn init_serial_driver() -> Result<()> {
+    let irq = CONSOLE_ISA_IRQ.load(Ordering::SeqCst);
+    if irq == NO_IRQ {
+        return Err("IRQ of the console UART is unknown");
+    }
+    attach(SerialDevice::CONSOLE.index, SerialPort::default(), irq)?;
+    Ok(())
+}
+
+/// Makes a UART other than the console interrupt-driven. This should be
+/// called after init_io_apics().
+pub fn attach_serial_port(port: SerialPort, irq: u8) -> Result<SerialDevice> {
+    let index = (1..NUM_SERIAL_DRIVERS)
+        .find(|i| SERIAL_DRIVERS[*i].lock().is_none())
+        .ok_or("No free slot for the serial driver")?;
+    attach(index, port, irq)
+}
+
+/// A handle to an interrupt-driven UART.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SerialDevice {
+    index: usize,
+}
+impl SerialDevice {
+    /// The console port, which is attached by init_serial_driver()
+    pub const CONSOLE: Self = Self { index: 0 };
+    fn with_driver<T>(
+        &self,
+        f: impl FnOnce(&mut SerialDriver) -> T,
+    ) -> Result<T> {
+        let mut driver = SERIAL_DRIVERS[self.index].lock();
+        let driver =
+            driver.as_mut().ok_or("Serial driver is not initialized")?;
+        Ok(f(driver))
+    }
+    pub fn stats(&self) -> Result<SerialStats> {
+        self.with_driver(|d| d.stats)
+    }
+    /// Returns a received byte, waiting for it if needed.
+    pub async fn read(&self) -> Result<u8> {
+        loop {
+            if let Some(c) = self.with_driver(|d| d.rx.pop())? {
+                return Ok(c);
+            }
+            yield_execution().await;
+        }
+    }
+    /// Fills the buffer with the received bytes, waiting for them if needed.
+    pub async fn read_exact(&self, buf: &mut [u8]) -> Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled = self.with_driver(|d| {
+                let mut filled = filled;
+                while let Some(c) = buf.get_mut(filled) {
+                    let Some(v) = d.rx.pop() else {
+                        break;
+                    };
+                    *c = v;
+                    filled += 1;
+                }
+                filled
+            })?;
+            if filled < buf.len() {
+                yield_execution().await;
+            }
+        }
+        Ok(())
+    }
+    /// Returns a line without the line break. CR, LF and CR LF are accepted
+    /// as a line break.
+    pub async fn read_line(&self) -> Result<String> {
+        let mut line = Vec::new();
+        loop {
+            let c = self.read().await?;
+            let last_was_cr = self.with_driver(|d| {
+                core::mem::replace(&mut d.last_was_cr, c == b'\r')
+            })?;
+            match c {
+                b'\n' if last_was_cr && line.is_empty() => {}
+                b'\r' | b'\n' => {
+                    return Ok(String::from_utf8_lossy(&line).into_owned())
+                }
+                _ => line.push(c),
+            }
+        }
+    }
+    /// Queues the data to be sent, waiting while the TX buffer is full.
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        let mut data = data;
+        while !data.is_empty() {
+            data = self.with_driver(|d| {
+                let queued = data.iter().take_while(|c| d.tx.push(**c)).count();
+                if !d.tx.is_empty() {
+                    // The interrupt comes immediately if THR is already empty.
+                    d.enable_tx_interrupt(true);
+                }
+                &data[queued..]
+            })?;
+            if !data.is_empty() {
+                yield_execution().await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a byte from the console port.
+pub async fn read() -> Result<u8> {
+    SerialDevice::CONSOLE.read().await
+}
+
+/// Reads a line from the console port.
+pub async fn read_line() -> Result<String> {
+    SerialDevice::CONSOLE.read_line().await
+}
+
+/// Writes the data to the console port.
+pub async fn write(data: &[u8]) -> Result<()> {
+    SerialDevice::CONSOLE.write(data).await
+}
+
+#[test_case]
+fn ring_buffer_test() {
+    let mut rb = RingBuffer::<4>::new();
+    assert_eq!(rb.pop(), None);
+    for i in 0..4 {
+        assert!(rb.push(i));
     }
+    assert!(!rb.push(4));
+    assert_eq!(rb.pop(), Some(0));
+    assert_eq!(rb.pop(), Some(1));
+    assert!(rb.push(5));
+    assert!(rb.push(6));
+    assert!(!rb.push(7));
+    assert_eq!(rb.pop(), Some(2));
+    assert_eq!(rb.pop(), Some(3));
+    assert_eq!(rb.pop(), Some(5));
+    assert_eq!(rb.pop(), Some(6));
+    assert!(rb.is_empty());
 }
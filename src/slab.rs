@@ -0,0 +1,280 @@
+//! Slab allocator for small objects
+//!
+//! Objects up to SLAB_MAX_OBJECT_SIZE bytes are served from 4 KiB slab
+//! pages, one power-of-two size class per cache. A slab page starts with a
+//! SlabPage header followed by objects of the same size, and the free
+//! objects in a page are linked through their first word. Pages that have
+//! free objects are kept in a per-cache list. Slab pages are taken from and
+//! returned to the first-fit allocator.
+
+extern crate alloc;
+
+use crate::allocator::FirstFitAllocator;
+use crate::allocator::LAYOUT_PAGE_4K;
+use alloc::alloc::GlobalAlloc;
+use alloc::alloc::Layout;
+use core::cell::RefCell;
+use core::cmp::max;
+use core::fmt;
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+pub const SLAB_MIN_OBJECT_SIZE: usize = 16;
+pub const SLAB_MAX_OBJECT_SIZE: usize = 512;
+pub const NUM_SLAB_CACHES: usize = 6;
+const SLAB_PAGE_SIZE: usize = 4096;
+const _: () = assert!(
+    SLAB_MIN_OBJECT_SIZE << (NUM_SLAB_CACHES - 1) == SLAB_MAX_OBJECT_SIZE
+);
+const _: () = assert!(SLAB_PAGE_SIZE == LAYOUT_PAGE_4K.size());
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct SlabPage {
+    next: *mut SlabPage,
+    prev: *mut SlabPage,
+    free_list: *mut FreeObject,
+    num_in_use: usize,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SlabCacheStats {
+    pub object_size: usize,
+    pub num_pages: usize,
+    pub num_objects_in_use: usize,
+    pub num_allocs: usize,
+    pub num_frees: usize,
+}
+impl fmt::Display for SlabCacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "slab-{:<4}: pages: {:5}, in use: {:7}, allocs: {:9}, frees: {:9}",
+            self.object_size,
+            self.num_pages,
+            self.num_objects_in_use,
+            self.num_allocs,
+            self.num_frees
+        )
+    }
+}
+
+struct SlabCache {
+    /// Pages that have at least one free object
+    partial: *mut SlabPage,
+    stats: SlabCacheStats,
+}
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            partial: null_mut(),
+            stats: SlabCacheStats {
+                object_size,
+                num_pages: 0,
+                num_objects_in_use: 0,
+                num_allocs: 0,
+                num_frees: 0,
+            },
+        }
+    }
+    fn object_size(&self) -> usize {
+        self.stats.object_size
+    }
+    fn first_object_offset(&self) -> usize {
+        // Objects are aligned to their size since the page is 4K-aligned.
+        size_of::<SlabPage>().next_multiple_of(self.object_size())
+    }
+    unsafe fn push_partial(&mut self, page: *mut SlabPage) {
+        (*page).prev = null_mut();
+        (*page).next = self.partial;
+        if let Some(next) = self.partial.as_mut() {
+            next.prev = page;
+        }
+        self.partial = page;
+    }
+    unsafe fn remove_partial(&mut self, page: *mut SlabPage) {
+        let page = &mut *page;
+        if let Some(prev) = page.prev.as_mut() {
+            prev.next = page.next;
+        } else {
+            self.partial = page.next;
+        }
+        if let Some(next) = page.next.as_mut() {
+            next.prev = page.prev;
+        }
+        page.next = null_mut();
+        page.prev = null_mut();
+    }
+    unsafe fn new_page(&mut self, backing: &FirstFitAllocator) -> bool {
+        let page = backing.alloc_with_options(LAYOUT_PAGE_4K) as *mut SlabPage;
+        if page.is_null() {
+            return false;
+        }
+        let mut free_list = null_mut::<FreeObject>();
+        let mut offset = SLAB_PAGE_SIZE - self.object_size();
+        while offset >= self.first_object_offset() {
+            let obj = (page as *mut u8).add(offset) as *mut FreeObject;
+            (*obj).next = free_list;
+            free_list = obj;
+            offset -= self.object_size();
+        }
+        page.write(SlabPage {
+            next: null_mut(),
+            prev: null_mut(),
+            free_list,
+            num_in_use: 0,
+        });
+        self.push_partial(page);
+        self.stats.num_pages += 1;
+        true
+    }
+    unsafe fn alloc(&mut self, backing: &FirstFitAllocator) -> *mut u8 {
+        if self.partial.is_null() && !self.new_page(backing) {
+            return null_mut();
+        }
+        let page = self.partial;
+        let obj = (*page).free_list;
+        (*page).free_list = (*obj).next;
+        (*page).num_in_use += 1;
+        if (*page).free_list.is_null() {
+            // The page is full. It will come back when an object is freed.
+            self.remove_partial(page);
+        }
+        self.stats.num_objects_in_use += 1;
+        self.stats.num_allocs += 1;
+        obj as *mut u8
+    }
+    unsafe fn dealloc(&mut self, ptr: *mut u8, backing: &FirstFitAllocator) {
+        let page = (ptr as usize & !(SLAB_PAGE_SIZE - 1)) as *mut SlabPage;
+        let was_full = (*page).free_list.is_null();
+        let obj = ptr as *mut FreeObject;
+        (*obj).next = (*page).free_list;
+        (*page).free_list = obj;
+        (*page).num_in_use -= 1;
+        self.stats.num_objects_in_use -= 1;
+        self.stats.num_frees += 1;
+        if was_full {
+            self.push_partial(page);
+        }
+        // Keep the last page to avoid allocating a page again right away.
+        let is_last_page = self.partial == page && (*page).next.is_null();
+        if (*page).num_in_use == 0 && !is_last_page {
+            self.remove_partial(page);
+            backing.dealloc(page as *mut u8, LAYOUT_PAGE_4K);
+            self.stats.num_pages -= 1;
+        }
+    }
+}
+
+pub struct SlabAllocator {
+    caches: RefCell<[SlabCache; NUM_SLAB_CACHES]>,
+}
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        Self {
+            caches: RefCell::new([
+                SlabCache::new(16),
+                SlabCache::new(32),
+                SlabCache::new(64),
+                SlabCache::new(128),
+                SlabCache::new(256),
+                SlabCache::new(512),
+            ]),
+        }
+    }
+    /// Returns the index of the cache that can serve the layout, or None if
+    /// the layout should go to the first-fit allocator.
+    pub fn cache_index(layout: Layout) -> Option<usize> {
+        let size =
+            max(max(layout.size(), layout.align()), SLAB_MIN_OBJECT_SIZE);
+        if size > SLAB_MAX_OBJECT_SIZE {
+            None
+        } else {
+            Some(
+                (size.next_power_of_two().trailing_zeros()
+                    - SLAB_MIN_OBJECT_SIZE.trailing_zeros())
+                    as usize,
+            )
+        }
+    }
+    pub fn alloc(&self, index: usize, backing: &FirstFitAllocator) -> *mut u8 {
+        unsafe { self.caches.borrow_mut()[index].alloc(backing) }
+    }
+    /// # Safety
+    /// ptr should be allocated from the cache `index` of this allocator.
+    pub unsafe fn dealloc(
+        &self,
+        index: usize,
+        ptr: *mut u8,
+        backing: &FirstFitAllocator,
+    ) {
+        self.caches.borrow_mut()[index].dealloc(ptr, backing)
+    }
+    pub fn stats(&self) -> [SlabCacheStats; NUM_SLAB_CACHES] {
+        let caches = self.caches.borrow();
+        let mut stats = [SlabCacheStats::default(); NUM_SLAB_CACHES];
+        for (s, c) in stats.iter_mut().zip(caches.iter()) {
+            *s = c.stats;
+        }
+        stats
+    }
+}
+impl Default for SlabAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::allocator::ALLOCATOR;
+
+    #[test_case]
+    fn cache_index_test() {
+        let index = |size, align| {
+            SlabAllocator::cache_index(
+                Layout::from_size_align(size, align).unwrap(),
+            )
+        };
+        assert_eq!(index(1, 1), Some(0));
+        assert_eq!(index(16, 8), Some(0));
+        assert_eq!(index(17, 8), Some(1));
+        assert_eq!(index(8, 64), Some(2));
+        assert_eq!(index(512, 8), Some(5));
+        assert_eq!(index(513, 8), None);
+        assert_eq!(index(8, 4096), None);
+    }
+
+    #[test_case]
+    fn slab_alloc_free() {
+        let slab = SlabAllocator::new();
+        let backing = ALLOCATOR.first_fit();
+        let index = 2;
+        let mut pointers = [null_mut::<u8>(); 200];
+        for (i, e) in pointers.iter_mut().enumerate() {
+            *e = slab.alloc(index, backing);
+            assert!(!e.is_null());
+            assert_eq!(*e as usize % 64, 0);
+            unsafe { e.write_bytes(i as u8, 64) };
+        }
+        for (i, e) in pointers.iter().enumerate() {
+            for k in 0..64 {
+                assert_eq!(unsafe { *e.add(k) }, i as u8);
+            }
+        }
+        let stats = slab.stats()[index];
+        assert_eq!(stats.num_objects_in_use, 200);
+        assert!(stats.num_pages >= 200 * 64 / SLAB_PAGE_SIZE);
+        for e in pointers.iter() {
+            unsafe { slab.dealloc(index, *e, backing) };
+        }
+        let stats = slab.stats()[index];
+        assert_eq!(stats.num_objects_in_use, 0);
+        assert_eq!(stats.num_frees, 200);
+        // Only one empty page is kept for the future use.
+        assert_eq!(stats.num_pages, 1);
+    }
+}
@@ -1,5 +1,6 @@
 extern crate alloc;
 
+use crate::allocator::try_vec;
 use crate::bits::extract_bits;
 use crate::bits::extract_bits_from_le_bytes;
 use crate::executor::spawn_global;
@@ -20,7 +21,6 @@ use alloc::collections::VecDeque;
 use alloc::format;
 use alloc::rc::Rc;
 use alloc::string::ToString;
-use alloc::vec;
 use alloc::vec::Vec;
 use core::ops::RangeInclusive;
 use core::sync::atomic::AtomicBool;
@@ -294,7 +294,7 @@ impl UsbTabletDriver {
             } else {
                 return Err("report size is zero");
             };
-        let mut prev_report = vec![0u8; report_size_in_byte];
+        let mut prev_report = try_vec(0u8, report_size_in_byte)?;
         let desc_button_l = input_report_items
             .iter()
             .find(|e| e.usage == UsbHidUsage::Button(1))
@@ -317,8 +317,11 @@ impl UsbTabletDriver {
             .ok_or("Absolute pointer Y not found")?;
 
         let (vw, vh) = global_vram_resolutions();
+        let mut report_buf = xhc.alloc_dma_buffer(8)?;
         loop {
-            let report = request_hid_report(xhc, slot, ctrl_ep_ring).await?;
+            let report =
+                request_hid_report(xhc, slot, ctrl_ep_ring, &mut report_buf)
+                    .await?;
             if report == prev_report {
                 continue;
             }
@@ -39,6 +39,13 @@ const EFI_ACPI_TABLE_GUID: EfiGuid = EfiGuid {
     data3: [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
 };
 
+const EFI_ACPI_10_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0xeb9d2d30,
+    data1: 0x2d88,
+    data2: 0x11d3,
+    data3: [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d],
+};
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 #[must_use]
 #[repr(u64)]
@@ -229,8 +236,10 @@ impl EfiSystemTable {
         }
         None
     }
+    /// Returns the RSDP, preferring the one for ACPI 2.0 or later.
     pub fn acpi_table(&self) -> Option<&'static AcpiRsdpStruct> {
         self.lookup_config_table(&EFI_ACPI_TABLE_GUID)
+            .or_else(|| self.lookup_config_table(&EFI_ACPI_10_TABLE_GUID))
             .map(|t| unsafe { &*(t.vendor_table as *const AcpiRsdpStruct) })
     }
 }
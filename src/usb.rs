@@ -1,14 +1,13 @@
 extern crate alloc;
 
+use crate::dma::DmaBuffer;
 use crate::result::Result;
 use crate::slice::Sliceable;
 use crate::xhci::CommandRing;
 use crate::xhci::Controller;
-use alloc::boxed::Box;
 use alloc::rc::Rc;
 use alloc::string::String;
 use alloc::string::ToString;
-use alloc::vec;
 use alloc::vec::Vec;
 use core::marker::PhantomPinned;
 use core::mem::size_of;
@@ -199,8 +198,7 @@ pub async fn request_device_descriptor(
     slot: u8,
     ctrl_ep_ring: &mut CommandRing,
 ) -> Result<UsbDeviceDescriptor> {
-    let buf = vec![0; size_of::<UsbDeviceDescriptor>()];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let mut buf = xhc.alloc_dma_buffer(size_of::<UsbDeviceDescriptor>())?;
     xhc.request_descriptor(
         slot,
         ctrl_ep_ring,
@@ -210,7 +208,7 @@ pub async fn request_device_descriptor(
         &mut buf,
     )
     .await?;
-    UsbDeviceDescriptor::copy_from_slice(buf.as_ref().get_ref())
+    UsbDeviceDescriptor::copy_from_slice(buf.as_slice())
 }
 pub async fn request_string_descriptor(
     xhc: &Rc<Controller>,
@@ -219,8 +217,7 @@ pub async fn request_string_descriptor(
     lang_id: u16,
     index: u8,
 ) -> Result<String> {
-    let buf = vec![0; 128];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let mut buf = xhc.alloc_dma_buffer(128)?;
     xhc.request_descriptor(
         slot,
         ctrl_ep_ring,
@@ -230,7 +227,7 @@ pub async fn request_string_descriptor(
         &mut buf,
     )
     .await?;
-    Ok(String::from_utf8_lossy(&buf[2..])
+    Ok(String::from_utf8_lossy(&buf.as_slice()[2..])
         .to_string()
         .replace('\0', ""))
 }
@@ -240,8 +237,7 @@ pub async fn request_string_descriptor_zero(
     slot: u8,
     ctrl_ep_ring: &mut CommandRing,
 ) -> Result<Vec<u8>> {
-    let buf = vec![0; 8];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let mut buf = xhc.alloc_dma_buffer(8)?;
     xhc.request_descriptor(
         slot,
         ctrl_ep_ring,
@@ -251,15 +247,14 @@ pub async fn request_string_descriptor_zero(
         &mut buf,
     )
     .await?;
-    Ok(buf.as_ref().get_ref().to_vec())
+    Ok(buf.as_slice().to_vec())
 }
 pub async fn request_config_descriptor_and_rest(
     xhc: &Rc<Controller>,
     slot: u8,
     ctrl_ep_ring: &mut CommandRing,
 ) -> Result<Vec<UsbDescriptor>> {
-    let buf = vec![0u8; size_of::<ConfigDescriptor>()];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let mut buf = xhc.alloc_dma_buffer(size_of::<ConfigDescriptor>())?;
     xhc.request_descriptor(
         slot,
         ctrl_ep_ring,
@@ -269,10 +264,8 @@ pub async fn request_config_descriptor_and_rest(
         &mut buf,
     )
     .await?;
-    let config_descriptor =
-        ConfigDescriptor::copy_from_slice(buf.as_ref().get_ref())?;
-    let buf = vec![0; config_descriptor.total_length()];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let config_descriptor = ConfigDescriptor::copy_from_slice(buf.as_slice())?;
+    let mut buf = xhc.alloc_dma_buffer(config_descriptor.total_length())?;
     xhc.request_descriptor(
         slot,
         ctrl_ep_ring,
@@ -282,20 +275,20 @@ pub async fn request_config_descriptor_and_rest(
         &mut buf,
     )
     .await?;
-    let iter = DescriptorIterator::new(&buf);
+    let iter = DescriptorIterator::new(buf.as_slice());
     let descriptors: Vec<UsbDescriptor> = iter.collect();
     Ok(descriptors)
 }
+/// Polls a report into buf. buf should be allocated with
+/// Controller::alloc_dma_buffer() once and reused for each poll.
 pub async fn request_hid_report(
     xhc: &Rc<Controller>,
     slot: u8,
     ctrl_ep_ring: &mut CommandRing,
+    buf: &mut DmaBuffer,
 ) -> Result<Vec<u8>> {
-    let buf = vec![0u8; 8];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
-    xhc.request_report_bytes(slot, ctrl_ep_ring, &mut buf)
-        .await?;
-    Ok(buf.to_vec())
+    xhc.request_report_bytes(slot, ctrl_ep_ring, buf).await?;
+    Ok(buf.as_slice().to_vec())
 }
 
 pub fn pick_interface_with_triple(
@@ -340,8 +333,7 @@ pub async fn request_hid_report_descriptor(
     desc_size: usize,
 ) -> Result<Vec<u8>> {
     // 7.1.1 Get_Descriptor Request
-    let buf = vec![0u8; desc_size];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let mut buf = xhc.alloc_dma_buffer(desc_size)?;
     xhc.request_descriptor_for_interface(
         slot,
         ctrl_ep_ring,
@@ -351,7 +343,7 @@ pub async fn request_hid_report_descriptor(
         &mut buf,
     )
     .await?;
-    Ok((*buf).to_vec())
+    Ok(buf.as_slice().to_vec())
 }
 #[derive(Debug, Copy, Clone, Default)]
 #[allow(unused)]
@@ -1,8 +1,13 @@
 extern crate alloc;
 
+pub mod cpuid;
+pub mod fpu;
+pub mod mce;
+
 use crate::error;
+use crate::frame::FrameOwner;
+use crate::frame::Frames;
 use crate::info;
-use crate::mmio::IoBox;
 use crate::result::Result;
 use alloc::boxed::Box;
 use core::arch::asm;
@@ -12,14 +17,64 @@ use core::marker::PhantomData;
 use core::mem::offset_of;
 use core::mem::size_of;
 use core::mem::size_of_val;
+use core::mem::transmute;
 use core::mem::ManuallyDrop;
 use core::mem::MaybeUninit;
 use core::pin::Pin;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
 
 pub fn hlt() {
     unsafe { asm!("hlt") }
 }
 
+pub fn enable_interrupts() {
+    unsafe { asm!("sti") }
+}
+pub fn disable_interrupts() {
+    unsafe { asm!("cli") }
+}
+pub fn are_interrupts_enabled() -> bool {
+    read_rflags() & RFLAGS_IF != 0
+}
+/// Disables interrupts while it is alive, and restores the interrupt flag
+/// when it is dropped.
+#[must_use]
+pub struct InterruptsDisabled {
+    were_interrupts_enabled: bool,
+}
+impl InterruptsDisabled {
+    pub fn new() -> Self {
+        let were_interrupts_enabled = are_interrupts_enabled();
+        disable_interrupts();
+        Self {
+            were_interrupts_enabled,
+        }
+    }
+}
+impl Default for InterruptsDisabled {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Drop for InterruptsDisabled {
+    fn drop(&mut self) {
+        if self.were_interrupts_enabled {
+            enable_interrupts();
+        }
+    }
+}
+pub const RFLAGS_IF: u64 = 1 << 9;
+pub fn read_rflags() -> u64 {
+    let mut rflags: u64;
+    unsafe {
+        asm!("pushfq",
+            "pop rax",
+            out("rax") rflags)
+    }
+    rflags
+}
+
 pub fn busy_loop_hint() {
     unsafe { asm!("pause") }
 }
@@ -40,6 +95,112 @@ pub fn write_io_port_u8(port: u16, data: u8) {
             in("dx") port)
     }
 }
+pub fn read_io_port_u16(port: u16) -> u16 {
+    let mut data: u16;
+    unsafe {
+        asm!("in ax, dx",
+            out("ax") data,
+            in("dx") port)
+    }
+    data
+}
+pub fn write_io_port_u16(port: u16, data: u16) {
+    unsafe {
+        asm!("out dx, ax",
+            in("ax") data,
+            in("dx") port)
+    }
+}
+pub fn read_io_port_u32(port: u16) -> u32 {
+    let mut data: u32;
+    unsafe {
+        asm!("in eax, dx",
+            out("eax") data,
+            in("dx") port)
+    }
+    data
+}
+pub fn write_io_port_u32(port: u16, data: u32) {
+    unsafe {
+        asm!("out dx, eax",
+            in("eax") data,
+            in("dx") port)
+    }
+}
+
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!("rdtsc",
+            out("eax") lo,
+            out("edx") hi)
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+
+pub fn read_msr(msr: u32) -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!("rdmsr",
+            in("ecx") msr,
+            out("eax") lo,
+            out("edx") hi)
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+/// # Safety
+/// Writing to MSRs can change the CPU behavior in any way, so it is the
+/// caller's responsibility to pass a valid MSR index and value.
+pub unsafe fn write_msr(msr: u32, data: u64) {
+    asm!("wrmsr",
+        in("ecx") msr,
+        in("eax") data as u32,
+        in("edx") (data >> 32) as u32)
+}
+
+pub const MSR_EFER: u32 = 0xC000_0080;
+const EFER_NXE: u64 = 1 << 11;
+
+/// Enables the Execute-Disable bit in page table entries, if the CPU
+/// supports it.
+pub fn enable_nx_if_supported() -> bool {
+    if !cpuid::cpu_features().nx {
+        return false;
+    }
+    unsafe { write_msr(MSR_EFER, read_msr(MSR_EFER) | EFER_NXE) }
+    true
+}
+
+pub fn read_cr0() -> u64 {
+    let mut cr0: u64;
+    unsafe {
+        asm!("mov rax, cr0",
+            out("rax") cr0)
+    }
+    cr0
+}
+/// # Safety
+/// Writing to CR0 can change the CPU behavior in any way.
+pub unsafe fn write_cr0(cr0: u64) {
+    asm!("mov cr0, rax",
+        in("rax") cr0)
+}
+pub fn read_cr4() -> u64 {
+    let mut cr4: u64;
+    unsafe {
+        asm!("mov rax, cr4",
+            out("rax") cr4)
+    }
+    cr4
+}
+/// # Safety
+/// Writing to CR4 can change the CPU behavior in any way.
+pub unsafe fn write_cr4(cr4: u64) {
+    asm!("mov cr4, rax",
+        in("rax") cr4)
+}
 
 pub fn read_cr3() -> *mut PML4 {
     let mut cr3: *mut PML4;
@@ -56,20 +217,37 @@ const ATTR_PRESENT: u64 = 1 << 0;
 const ATTR_WRITABLE: u64 = 1 << 1;
 const ATTR_WRITE_THROUGH: u64 = 1 << 3;
 const ATTR_CACHE_DISABLE: u64 = 1 << 4;
+const ATTR_PAGE_SIZE: u64 = 1 << 7;
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
 
 #[derive(Debug, Copy, Clone)]
 #[repr(u64)]
 pub enum PageAttr {
     NotPresent = 0,
+    ReadOnlyKernel = ATTR_PRESENT,
     ReadWriteKernel = ATTR_PRESENT | ATTR_WRITABLE,
     ReadWriteIo =
         ATTR_PRESENT | ATTR_WRITABLE | ATTR_WRITE_THROUGH | ATTR_CACHE_DISABLE,
 }
+/// `attr` is the attributes of the page (the lower 12 bits of the entry).
+/// ATTR_WRITABLE is cleared if any of the upper level entries is read-only.
 #[derive(Debug, Eq, PartialEq)]
 pub enum TranslationResult {
-    PageMapped4K { phys: u64 },
-    PageMapped2M { phys: u64 },
-    PageMapped1G { phys: u64 },
+    PageMapped4K { phys: u64, attr: u64 },
+    PageMapped2M { phys: u64, attr: u64 },
+    PageMapped1G { phys: u64, attr: u64 },
+}
+impl TranslationResult {
+    pub fn attr(&self) -> u64 {
+        match self {
+            Self::PageMapped4K { attr, .. }
+            | Self::PageMapped2M { attr, .. }
+            | Self::PageMapped1G { attr, .. } => *attr,
+        }
+    }
+    pub fn is_writable(&self) -> bool {
+        self.attr() & ATTR_WRITABLE != 0
+    }
 }
 
 #[repr(transparent)]
@@ -90,6 +268,16 @@ impl<const LEVEL: usize, NEXT> Entry<LEVEL, NEXT> {
     fn is_user(&self) -> bool {
         (self.read_value() & (1 << 2)) != 0
     }
+    /// True if this entry maps a 2MiB or 1GiB page instead of a table.
+    fn is_large_page(&self) -> bool {
+        LEVEL != 1 && (self.read_value() & ATTR_PAGE_SIZE) != 0
+    }
+    /// The physical address that corresponds to `virt` in the page mapped by
+    /// this entry.
+    fn page_phys(&self, virt: u64) -> u64 {
+        let offset_mask = (1u64 << ((LEVEL - 1) * 9 + 12)) - 1;
+        (self.read_value() & ADDR_MASK & !offset_mask) | (virt & offset_mask)
+    }
     fn format(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
@@ -129,10 +317,15 @@ impl<const LEVEL: usize, NEXT> Entry<LEVEL, NEXT> {
         if self.is_present() {
             Err("Page is already populated")
         } else {
-            let next: Box<NEXT> =
-                Box::new(unsafe { MaybeUninit::zeroed().assume_init() });
-            self.value =
-                Box::into_raw(next) as u64 | PageAttr::ReadWriteKernel as u64;
+            // Tables are taken directly from the frame allocator, and all
+            // entries filled with 0 are valid.
+            let mut frame = Frames::alloc_bytes(
+                size_of::<NEXT>(),
+                PAGE_SIZE,
+                FrameOwner::PageTable,
+            )?;
+            frame.fill_zero();
+            self.value = frame.leak() | PageAttr::ReadWriteKernel as u64;
             Ok(self)
         }
     }
@@ -200,6 +393,47 @@ impl PML4 {
         // This is safe since entries filled with 0 is valid.
         unsafe { MaybeUninit::zeroed().assume_init() }
     }
+    /// Walks the table to find where `virt` is mapped to.
+    pub fn translate(&self, virt: u64) -> Result<TranslationResult> {
+        let e = &self.entry[self.calc_index(virt)];
+        let mut writable = e.is_writable();
+        let attr = |value: u64, writable: bool| {
+            let attr = value & ATTR_MASK;
+            if writable {
+                attr
+            } else {
+                attr & !ATTR_WRITABLE
+            }
+        };
+        let pdpt = e.table()?;
+        let e = &pdpt.entry[pdpt.calc_index(virt)];
+        if e.is_present() && e.is_large_page() {
+            return Ok(TranslationResult::PageMapped1G {
+                phys: e.page_phys(virt),
+                attr: attr(e.read_value(), writable),
+            });
+        }
+        writable &= e.is_writable();
+        let pd = e.table()?;
+        let e = &pd.entry[pd.calc_index(virt)];
+        if e.is_present() && e.is_large_page() {
+            return Ok(TranslationResult::PageMapped2M {
+                phys: e.page_phys(virt),
+                attr: attr(e.read_value(), writable),
+            });
+        }
+        writable &= e.is_writable();
+        let pt = e.table()?;
+        let e = &pt.entry[pt.calc_index(virt)];
+        if e.is_present() {
+            Ok(TranslationResult::PageMapped4K {
+                phys: e.page_phys(virt),
+                attr: attr(e.read_value(), writable),
+            })
+        } else {
+            Err("Page Not Found")
+        }
+    }
     pub fn create_mapping(
         &mut self,
         virt_start: u64,
@@ -296,12 +530,6 @@ pub unsafe fn write_gs(selector: u16) {
                 in("ax") selector)
 }
 
-#[allow(dead_code)]
-#[repr(C)]
-#[derive(Clone, Copy)]
-struct FPUContenxt {
-    data: [u8; 512],
-}
 #[allow(dead_code)]
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -334,20 +562,37 @@ struct InterruptContext {
     ss: u64,
 }
 const _: () = assert!(size_of::<InterruptContext>() == 8 * 5);
-#[allow(dead_code)]
 #[repr(C)]
 #[derive(Clone, Copy)]
-struct InterruptInfo {
+pub struct InterruptInfo {
     // This struct is placed at top of the interrupt stack.
-    // Should be aligned on 16-byte boundaries to pass the
-    // alignment checks done by FXSAVE / FXRSTOR
-    fpu_context: FPUContenxt, // used by FXSAVE / FXRSTOR
-    _dummy: u64,
+    // The FPU / SIMD state is saved below this struct by XSAVE (or FXSAVE),
+    // with a size that depends on the features enabled in XCR0.
     greg: GeneralRegisterContext,
     error_code: u64,
     ctx: InterruptContext,
 }
-const _: () = assert!(size_of::<InterruptInfo>() == (16 + 4 + 1) * 8 + 8 + 512);
+const _: () = assert!(size_of::<InterruptInfo>() == (16 + 4 + 1) * 8);
+impl InterruptInfo {
+    pub fn rip(&self) -> u64 {
+        self.ctx.rip
+    }
+    pub fn rsp(&self) -> u64 {
+        self.ctx.rsp
+    }
+    pub fn rbp(&self) -> u64 {
+        self.greg.rbp
+    }
+    pub fn cs(&self) -> u64 {
+        self.ctx.cs
+    }
+    pub fn rflags(&self) -> u64 {
+        self.ctx.rflags
+    }
+    pub fn error_code(&self) -> u64 {
+        self.error_code
+    }
+}
 impl fmt::Debug for InterruptInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -447,20 +692,34 @@ macro_rules! interrupt_entrypoint_with_ecode {
     };
 }
 
+interrupt_entrypoint!(2);
 interrupt_entrypoint!(3);
 interrupt_entrypoint!(6);
 interrupt_entrypoint_with_ecode!(8);
 interrupt_entrypoint_with_ecode!(13);
 interrupt_entrypoint_with_ecode!(14);
+interrupt_entrypoint!(18);
 interrupt_entrypoint!(32);
+interrupt_entrypoint!(33);
+interrupt_entrypoint!(34);
+interrupt_entrypoint!(35);
+interrupt_entrypoint!(36);
+interrupt_entrypoint!(255);
 
 extern "sysv64" {
+    fn interrupt_entrypoint2();
     fn interrupt_entrypoint3();
     fn interrupt_entrypoint6();
     fn interrupt_entrypoint8();
     fn interrupt_entrypoint13();
     fn interrupt_entrypoint14();
+    fn interrupt_entrypoint18();
     fn interrupt_entrypoint32();
+    fn interrupt_entrypoint33();
+    fn interrupt_entrypoint34();
+    fn interrupt_entrypoint35();
+    fn interrupt_entrypoint36();
+    fn interrupt_entrypoint255();
 }
 
 global_asm!(
@@ -482,24 +741,45 @@ inthandler_common:
     push rbx
     push rdx
     push rax
-    // FPU State
-    sub rsp, 512 + 8
-    fxsave64[rsp]
     // 1st parameter: pointer to the saved CPU state
     mov rdi, rsp
-    // Align the stack to 16-bytes boundary
     mov rbp, rsp
-    and rsp, -16
     // 2nd parameter: Int#
     mov rsi, rcx
-
+    // FPU / SIMD State
+    // Allocate the save area and align it to 64-bytes boundary, as required
+    // by XSAVE / XRSTOR (FXSAVE / FXRSTOR require 16-bytes alignment)
+    sub rsp, [rip + {xsave_area_size}]
+    and rsp, -64
+    cmp byte ptr [rip + {xsave_enabled}], 0
+    je 2f
+    // XRSTOR requires the XSAVE header (bytes 512..576) except XSTATE_BV
+    // to be zero. Clear it before saving.
+    xor eax, eax
+    mov [rsp + 512 + 8 * 0], rax
+    mov [rsp + 512 + 8 * 1], rax
+    mov [rsp + 512 + 8 * 2], rax
+    mov [rsp + 512 + 8 * 3], rax
+    mov [rsp + 512 + 8 * 4], rax
+    mov [rsp + 512 + 8 * 5], rax
+    mov [rsp + 512 + 8 * 6], rax
+    mov [rsp + 512 + 8 * 7], rax
+    // Save all the components enabled in XCR0
+    mov eax, -1
+    mov edx, -1
+    xsave64 [rsp]
     call inthandler
-
+    mov eax, -1
+    mov edx, -1
+    xrstor64 [rsp]
+    jmp 3f
+2:
+    fxsave64 [rsp]
+    call inthandler
+    fxrstor64 [rsp]
+3:
     mov rsp, rbp
     //
-    fxrstor64[rsp]
-    add rsp, 512 + 8
-    //
     pop rax
     pop rdx
     pop rbx
@@ -518,7 +798,9 @@ inthandler_common:
     pop rcx
     add rsp, 8 // for Error Code
     iretq
-"#
+"#,
+    xsave_area_size = sym fpu::XSAVE_AREA_SIZE,
+    xsave_enabled = sym fpu::XSAVE_ENABLED,
 );
 
 pub fn read_cr2() -> u64 {
@@ -530,8 +812,40 @@ pub fn read_cr2() -> u64 {
     cr2
 }
 
+/// A handler for an interrupt vector, registered via set_interrupt_handler().
+/// It is called with interrupts disabled, so it should not take any locks
+/// that can be held by the interrupted code.
+pub type InterruptHandler = fn(&InterruptInfo);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_INTERRUPT_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static INTERRUPT_HANDLERS: [AtomicUsize; 256] = [NO_INTERRUPT_HANDLER; 256];
+
+/// Registers a handler for the vector. Note that the vector should have an
+/// entrypoint in the IDT (see Idt::new()) to be handled.
+pub fn set_interrupt_handler(vector: u8, handler: InterruptHandler) {
+    INTERRUPT_HANDLERS[vector as usize]
+        .store(handler as *const () as usize, Ordering::SeqCst);
+}
+pub fn clear_interrupt_handler(vector: u8) {
+    INTERRUPT_HANDLERS[vector as usize].store(0, Ordering::SeqCst);
+}
+fn interrupt_handler(vector: usize) -> Option<InterruptHandler> {
+    let handler = INTERRUPT_HANDLERS.get(vector)?.load(Ordering::SeqCst);
+    if handler == 0 {
+        None
+    } else {
+        // SAFETY: non-zero values are stored only by set_interrupt_handler()
+        Some(unsafe { transmute::<usize, InterruptHandler>(handler) })
+    }
+}
+
 #[no_mangle]
 extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
+    if let Some(handler) = interrupt_handler(index) {
+        handler(info);
+        return;
+    }
     error!("Interrupt Info: {:?}", info);
     error!("Exception {index:#04X}: ");
     match index {
@@ -665,6 +979,14 @@ impl Idt {
             IdtAttr::IntGateDPL0,
             int_handler_unimplemented,
         ); 0x100];
+        // NMI and #MC use their own stacks since they can happen while
+        // handling other interrupts.
+        entries[2] = IdtDescriptor::new(
+            segment_selector,
+            3,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint2,
+        );
         entries[3] = IdtDescriptor::new(
             segment_selector,
             1,
@@ -697,12 +1019,48 @@ impl Idt {
             IdtAttr::IntGateDPL0,
             interrupt_entrypoint14,
         );
+        entries[18] = IdtDescriptor::new(
+            segment_selector,
+            4,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint18,
+        );
         entries[32] = IdtDescriptor::new(
             segment_selector,
             1,
             IdtAttr::IntGateDPL0,
             interrupt_entrypoint32,
         );
+        entries[33] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint33,
+        );
+        entries[34] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint34,
+        );
+        entries[35] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint35,
+        );
+        entries[36] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint36,
+        );
+        entries[255] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint255,
+        );
         let limit = size_of_val(&entries) as u16;
         let entries = Box::pin(entries);
         let params = IdtrParameters {
@@ -942,26 +1300,155 @@ pub unsafe fn put_current_page_table(mut table: ManuallyDrop<Box<PML4>>) {
     // Set CR3 to reflect the updates and drop TLB caches.
     write_cr3(Box::into_raw(ManuallyDrop::take(&mut table)))
 }
+/// Returns what callback returns.
+///
 /// # Safety
 /// This function modifies the page table as callback does, so
 /// anything bad can happen if there are some mistakes.
-pub unsafe fn with_current_page_table<F>(callback: F)
+pub unsafe fn with_current_page_table<F, R>(callback: F) -> R
 where
-    F: FnOnce(&mut PML4),
+    F: FnOnce(&mut PML4) -> R,
 {
     let mut table = take_current_page_table();
-    callback(&mut table);
-    put_current_page_table(table)
+    let result = callback(&mut table);
+    put_current_page_table(table);
+    result
+}
+
+const CACHE_LINE_SIZE: u64 = 64;
+
+/// Writes back and invalidates the cache lines that cover [start, end).
+pub fn flush_cache_range(start: u64, end: u64) {
+    let mut addr = start & !(CACHE_LINE_SIZE - 1);
+    while addr < end {
+        unsafe { asm!("clflush [{}]", in(reg) addr) }
+        addr += CACHE_LINE_SIZE;
+    }
+    unsafe { asm!("mfence") }
+}
+
+/// Invalidates the TLB entries for the pages that cover [start, end),
+/// including the global ones that are kept across CR3 writes.
+pub fn invalidate_tlb_range(start: u64, end: u64) {
+    let mut addr = start & !(PAGE_SIZE as u64 - 1);
+    while addr < end {
+        unsafe { asm!("invlpg [{}]", in(reg) addr) }
+        addr += PAGE_SIZE as u64;
+    }
 }
 
-pub fn disable_cache<T: Sized>(io_box: &IoBox<T>) {
-    let region = io_box.as_ref();
-    let vstart = region as *const T as u64;
-    let vend = vstart + size_of_val(region) as u64;
+/// Remaps [start, end) to the same physical addresses with `attr`. This is
+/// used to switch the cache attribute of the pages used for DMA.
+///
+/// The lines that were cached with the old attribute are flushed as well,
+/// so that devices do not miss the data written before the switch.
+/// c.f. SDM Vol.3A: 12.12.4 Programming the PAT
+pub fn set_identity_mapping_attr(start: u64, end: u64, attr: PageAttr) {
     unsafe {
         with_current_page_table(|pt| {
-            pt.create_mapping(vstart, vend, vstart, PageAttr::ReadWriteIo)
+            pt.create_mapping(start, end, start, attr)
                 .expect("Failed to create mapping")
         })
     }
+    invalidate_tlb_range(start, end);
+    flush_cache_range(start, end);
+}
+
+pub fn read_rbp() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp) }
+    rbp
+}
+
+pub fn read_rsp() -> u64 {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp) }
+    rsp
+}
+
+// Stop walking the frame pointer chain if the next frame is this far or
+// farther from the current one, since it is likely to be broken.
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+/// Walks the frame pointer chain starting from rbp and stores the return
+/// addresses into frames. Returns the number of frames stored.
+pub fn walk_stack(mut rbp: u64, frames: &mut [u64]) -> usize {
+    let mut depth = 0;
+    while depth < frames.len() {
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        // Layout of a frame: [rbp] = caller's rbp, [rbp + 8] = return address
+        let next_rbp = unsafe { *(rbp as *const u64) };
+        let ret_addr = unsafe { *((rbp + 8) as *const u64) };
+        if ret_addr == 0 {
+            break;
+        }
+        frames[depth] = ret_addr;
+        depth += 1;
+        if next_rbp <= rbp || next_rbp - rbp >= MAX_FRAME_SIZE {
+            break;
+        }
+        rbp = next_rbp;
+    }
+    depth
+}
+
+#[test_case]
+fn walk_stack_test() {
+    // Build a fake frame chain: frame[0] -> frame[1] -> frame[2] -> null
+    let mut frames = [[0u64; 2]; 3];
+    let base = frames.as_ptr() as u64;
+    frames[0] = [base + 16, 0x1000];
+    frames[1] = [base + 32, 0x2000];
+    frames[2] = [0, 0x3000];
+    let mut out = [0u64; 8];
+    assert_eq!(walk_stack(base, &mut out), 3);
+    assert_eq!(out[..3], [0x1000, 0x2000, 0x3000]);
+    // Depth is limited by the output buffer
+    assert_eq!(walk_stack(base, &mut out[..2]), 2);
+    assert_eq!(walk_stack(0, &mut out), 0);
+}
+
+#[test_case]
+fn translate_test() {
+    let mut table = PML4::new();
+    let virt = 0x1234_5000_0000u64;
+    table
+        .create_mapping(
+            virt,
+            virt + 0x2000,
+            0x8000_0000,
+            PageAttr::ReadWriteKernel,
+        )
+        .expect("Failed to create mapping");
+    let rw = PageAttr::ReadWriteKernel as u64;
+    assert_eq!(
+        table.translate(virt + 0x123),
+        Ok(TranslationResult::PageMapped4K {
+            phys: 0x8000_0123,
+            attr: rw
+        })
+    );
+    assert_eq!(
+        table.translate(virt + 0x1FFF),
+        Ok(TranslationResult::PageMapped4K {
+            phys: 0x8000_1FFF,
+            attr: rw
+        })
+    );
+    assert!(table.translate(virt + 0x2000).is_err());
+    assert!(table.translate(0).is_err());
+    table
+        .create_mapping(
+            virt + 0x1000,
+            virt + 0x2000,
+            0x8000_1000,
+            PageAttr::ReadOnlyKernel,
+        )
+        .expect("Failed to create mapping");
+    assert!(table.translate(virt).is_ok_and(|e| e.is_writable()));
+    assert!(table
+        .translate(virt + 0x1000)
+        .is_ok_and(|e| !e.is_writable()));
 }
@@ -0,0 +1,349 @@
+//! CPUID based feature detection
+//!
+//! The CPU is queried once at boot by `init_cpu_features()` and the decoded
+//! result is kept in a global so that other subsystems (paging, timers, FPU)
+//! can choose their code paths without issuing CPUID again.
+//!
+//! c.f. SDM Vol.2A: CPUID - CPU Identification
+
+use crate::mutex::RwLock;
+use core::arch::x86_64::__cpuid_count;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::str;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    // SAFETY: CPUID is always available on x86_64.
+    let r = unsafe { __cpuid_count(leaf, subleaf) };
+    CpuidResult {
+        eax: r.eax,
+        ebx: r.ebx,
+        ecx: r.ecx,
+        edx: r.edx,
+    }
+}
+
+fn bit(v: u32, pos: usize) -> bool {
+    (v >> pos) & 1 == 1
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpuVendor {
+    Intel,
+    Amd,
+    Unknown,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct CpuFeatures {
+    vendor_id: [u8; 12],
+    brand: [u8; 48],
+    pub max_leaf: u32,
+    pub max_ext_leaf: u32,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    // Leaf 0x01
+    pub fpu: bool,
+    pub tsc: bool,
+    pub msr: bool,
+    pub apic: bool,
+    pub mtrr: bool,
+    pub pat: bool,
+    pub mca: bool,
+    pub mce: bool,
+    pub fxsr: bool,
+    pub sse: bool,
+    pub sse2: bool,
+    pub sse3: bool,
+    pub ssse3: bool,
+    pub sse4_1: bool,
+    pub sse4_2: bool,
+    pub pdcm: bool,
+    pub x2apic: bool,
+    pub tsc_deadline: bool,
+    pub xsave: bool,
+    pub osxsave: bool,
+    pub avx: bool,
+    pub rdrand: bool,
+    pub hypervisor: bool,
+    // Leaf 0x07
+    pub avx2: bool,
+    pub avx512f: bool,
+    pub rdseed: bool,
+    // Leaf 0x0A
+    pub pmu_version: u8,
+    pub pmu_num_counters: u8,
+    pub pmu_counter_width: u8,
+    // Leaf 0x0D
+    pub xsave_supported_xcr0: u64,
+    pub xsave_max_size: u32,
+    // Leaf 0x80000001
+    pub nx: bool,
+    pub page_1gb: bool,
+    pub rdtscp: bool,
+    // Leaf 0x80000007
+    pub invariant_tsc: bool,
+    // Leaf 0x80000008
+    pub phys_addr_bits: u8,
+    pub linear_addr_bits: u8,
+}
+impl Default for CpuFeatures {
+    fn default() -> Self {
+        // This is safe since all-zero means "no features detected".
+        unsafe { MaybeUninit::zeroed().assume_init() }
+    }
+}
+impl CpuFeatures {
+    pub fn query() -> Self {
+        let mut f = Self::default();
+        let r = cpuid(0, 0);
+        f.max_leaf = r.eax;
+        f.vendor_id[0..4].copy_from_slice(&r.ebx.to_le_bytes());
+        f.vendor_id[4..8].copy_from_slice(&r.edx.to_le_bytes());
+        f.vendor_id[8..12].copy_from_slice(&r.ecx.to_le_bytes());
+        if f.max_leaf >= 0x01 {
+            f.decode_leaf_01(cpuid(0x01, 0));
+        }
+        if f.max_leaf >= 0x07 {
+            f.decode_leaf_07(cpuid(0x07, 0));
+        }
+        if f.max_leaf >= 0x0A {
+            f.decode_leaf_0a(cpuid(0x0A, 0));
+        }
+        if f.max_leaf >= 0x0D && f.xsave {
+            let r = cpuid(0x0D, 0);
+            f.xsave_supported_xcr0 = ((r.edx as u64) << 32) | r.eax as u64;
+            f.xsave_max_size = r.ecx;
+        }
+        f.max_ext_leaf = cpuid(0x8000_0000, 0).eax;
+        if f.max_ext_leaf >= 0x8000_0001 {
+            f.decode_leaf_80000001(cpuid(0x8000_0001, 0));
+        }
+        if f.max_ext_leaf >= 0x8000_0004 {
+            for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let r = cpuid(leaf, 0);
+                for (k, v) in [r.eax, r.ebx, r.ecx, r.edx].iter().enumerate() {
+                    let ofs = i * 16 + k * 4;
+                    f.brand[ofs..ofs + 4].copy_from_slice(&v.to_le_bytes());
+                }
+            }
+        }
+        if f.max_ext_leaf >= 0x8000_0007 {
+            f.invariant_tsc = bit(cpuid(0x8000_0007, 0).edx, 8);
+        }
+        if f.max_ext_leaf >= 0x8000_0008 {
+            let r = cpuid(0x8000_0008, 0);
+            f.phys_addr_bits = r.eax as u8;
+            f.linear_addr_bits = (r.eax >> 8) as u8;
+        }
+        f
+    }
+    fn decode_leaf_01(&mut self, r: CpuidResult) {
+        let stepping = r.eax & 0xF;
+        let model = (r.eax >> 4) & 0xF;
+        let family = (r.eax >> 8) & 0xF;
+        let ext_model = (r.eax >> 16) & 0xF;
+        let ext_family = (r.eax >> 20) & 0xFF;
+        self.stepping = stepping;
+        self.family = if family == 0xF {
+            family + ext_family
+        } else {
+            family
+        };
+        self.model = if family == 0x6 || family == 0xF {
+            (ext_model << 4) | model
+        } else {
+            model
+        };
+        self.fpu = bit(r.edx, 0);
+        self.tsc = bit(r.edx, 4);
+        self.msr = bit(r.edx, 5);
+        self.mce = bit(r.edx, 7);
+        self.apic = bit(r.edx, 9);
+        self.mtrr = bit(r.edx, 12);
+        self.mca = bit(r.edx, 14);
+        self.pat = bit(r.edx, 16);
+        self.fxsr = bit(r.edx, 24);
+        self.sse = bit(r.edx, 25);
+        self.sse2 = bit(r.edx, 26);
+        self.sse3 = bit(r.ecx, 0);
+        self.ssse3 = bit(r.ecx, 9);
+        self.pdcm = bit(r.ecx, 15);
+        self.sse4_1 = bit(r.ecx, 19);
+        self.sse4_2 = bit(r.ecx, 20);
+        self.x2apic = bit(r.ecx, 21);
+        self.tsc_deadline = bit(r.ecx, 24);
+        self.xsave = bit(r.ecx, 26);
+        self.osxsave = bit(r.ecx, 27);
+        self.avx = bit(r.ecx, 28);
+        self.rdrand = bit(r.ecx, 30);
+        self.hypervisor = bit(r.ecx, 31);
+    }
+    fn decode_leaf_07(&mut self, r: CpuidResult) {
+        self.avx2 = bit(r.ebx, 5);
+        self.avx512f = bit(r.ebx, 16);
+        self.rdseed = bit(r.ebx, 18);
+    }
+    fn decode_leaf_0a(&mut self, r: CpuidResult) {
+        self.pmu_version = r.eax as u8;
+        self.pmu_num_counters = (r.eax >> 8) as u8;
+        self.pmu_counter_width = (r.eax >> 16) as u8;
+    }
+    fn decode_leaf_80000001(&mut self, r: CpuidResult) {
+        self.nx = bit(r.edx, 20);
+        self.page_1gb = bit(r.edx, 26);
+        self.rdtscp = bit(r.edx, 27);
+    }
+    pub fn vendor_id(&self) -> &str {
+        str::from_utf8(&self.vendor_id).unwrap_or("?")
+    }
+    pub fn vendor(&self) -> CpuVendor {
+        match &self.vendor_id {
+            b"GenuineIntel" => CpuVendor::Intel,
+            b"AuthenticAMD" => CpuVendor::Amd,
+            _ => CpuVendor::Unknown,
+        }
+    }
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|c| *c == 0).unwrap_or(48);
+        str::from_utf8(&self.brand[..len]).unwrap_or("?").trim()
+    }
+}
+impl fmt::Debug for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "vendor: {} ({:?})", self.vendor_id(), self.vendor())?;
+        writeln!(f, "brand: {}", self.brand())?;
+        writeln!(
+            f,
+            "family: {:#X}, model: {:#X}, stepping: {:#X}",
+            self.family, self.model, self.stepping
+        )?;
+        writeln!(
+            f,
+            "max_leaf: {:#X}, max_ext_leaf: {:#X}",
+            self.max_leaf, self.max_ext_leaf
+        )?;
+        writeln!(
+            f,
+            "phys_addr_bits: {}, linear_addr_bits: {}",
+            self.phys_addr_bits, self.linear_addr_bits
+        )?;
+        let flags = [
+            ("fpu", self.fpu),
+            ("tsc", self.tsc),
+            ("msr", self.msr),
+            ("apic", self.apic),
+            ("x2apic", self.x2apic),
+            ("tsc_deadline", self.tsc_deadline),
+            ("invariant_tsc", self.invariant_tsc),
+            ("rdtscp", self.rdtscp),
+            ("mtrr", self.mtrr),
+            ("pat", self.pat),
+            ("mce", self.mce),
+            ("mca", self.mca),
+            ("fxsr", self.fxsr),
+            ("sse", self.sse),
+            ("sse2", self.sse2),
+            ("sse3", self.sse3),
+            ("ssse3", self.ssse3),
+            ("sse4_1", self.sse4_1),
+            ("sse4_2", self.sse4_2),
+            ("xsave", self.xsave),
+            ("osxsave", self.osxsave),
+            ("avx", self.avx),
+            ("avx2", self.avx2),
+            ("avx512f", self.avx512f),
+            ("nx", self.nx),
+            ("page_1gb", self.page_1gb),
+            ("rdrand", self.rdrand),
+            ("rdseed", self.rdseed),
+            ("pdcm", self.pdcm),
+            ("hypervisor", self.hypervisor),
+        ];
+        write!(f, "flags:")?;
+        for (name, _) in flags.iter().filter(|(_, v)| *v) {
+            write!(f, " {name}")?;
+        }
+        writeln!(f)?;
+        if self.xsave {
+            writeln!(
+                f,
+                "xsave: supported_xcr0: {:#X}, max_size: {}",
+                self.xsave_supported_xcr0, self.xsave_max_size
+            )?;
+        }
+        write!(
+            f,
+            "pmu: version: {}, counters: {}, width: {}",
+            self.pmu_version, self.pmu_num_counters, self.pmu_counter_width
+        )
+    }
+}
+
+static CPU_FEATURES: RwLock<Option<CpuFeatures>> = RwLock::new(None);
+
+pub fn init_cpu_features() -> CpuFeatures {
+    let features = CpuFeatures::query();
+    *CPU_FEATURES.write() = Some(features);
+    features
+}
+/// Returns the features detected by `init_cpu_features()`, querying the CPU
+/// if it has not been called yet.
+pub fn cpu_features() -> CpuFeatures {
+    let features = *CPU_FEATURES.read();
+    features.unwrap_or_else(init_cpu_features)
+}
+
+#[test_case]
+fn decode_family_model_stepping_test() {
+    let mut f = CpuFeatures::default();
+    // Intel Core i7-8700: family 6, model 0x9E, stepping 10
+    f.decode_leaf_01(CpuidResult {
+        eax: 0x000906EA,
+        ..Default::default()
+    });
+    assert_eq!(f.family, 0x6);
+    assert_eq!(f.model, 0x9E);
+    assert_eq!(f.stepping, 0xA);
+    // AMD Ryzen 7 3700X: family 0x17, model 0x71, stepping 0
+    f.decode_leaf_01(CpuidResult {
+        eax: 0x00870F10,
+        ..Default::default()
+    });
+    assert_eq!(f.family, 0x17);
+    assert_eq!(f.model, 0x71);
+    assert_eq!(f.stepping, 0x0);
+}
+
+#[test_case]
+fn decode_feature_bits_test() {
+    let mut f = CpuFeatures::default();
+    f.decode_leaf_01(CpuidResult {
+        ecx: (1 << 21) | (1 << 26) | (1 << 28),
+        edx: (1 << 9) | (1 << 24),
+        ..Default::default()
+    });
+    assert!(f.apic);
+    assert!(f.x2apic);
+    assert!(f.xsave);
+    assert!(f.avx);
+    assert!(f.fxsr);
+    assert!(!f.tsc_deadline);
+    assert!(!f.rdrand);
+    f.decode_leaf_80000001(CpuidResult {
+        edx: (1 << 20) | (1 << 26),
+        ..Default::default()
+    });
+    assert!(f.nx);
+    assert!(f.page_1gb);
+    assert!(!f.rdtscp);
+}
@@ -0,0 +1,206 @@
+//! FPU / SIMD extended state management
+//!
+//! When the CPU supports XSAVE, `init_fpu()` enables it via CR4.OSXSAVE and
+//! sets XCR0 to all the user state components that we know how to handle
+//! (x87, SSE, AVX and AVX-512). The interrupt entry path and
+//! `ExtendedState` then save / restore the full state with XSAVE / XRSTOR,
+//! using the save area size reported by CPUID. Without XSAVE, the legacy
+//! 512-byte FXSAVE area is used instead.
+//!
+//! c.f. SDM Vol.1: 13 Managing State Using the XSAVE Feature Set
+
+extern crate alloc;
+
+use crate::info;
+use crate::result::Result;
+use crate::x86::cpuid::cpu_features;
+use crate::x86::cpuid::cpuid;
+use crate::x86::read_cr0;
+use crate::x86::read_cr4;
+use crate::x86::write_cr0;
+use crate::x86::write_cr4;
+use alloc::alloc::alloc_zeroed;
+use alloc::alloc::dealloc;
+use alloc::alloc::Layout;
+use core::arch::asm;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR0_TS: u64 = 1 << 3;
+const CR0_NE: u64 = 1 << 5;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+pub const XCR0_X87: u64 = 1 << 0;
+pub const XCR0_SSE: u64 = 1 << 1;
+pub const XCR0_AVX: u64 = 1 << 2;
+pub const XCR0_OPMASK: u64 = 1 << 5;
+pub const XCR0_ZMM_HI256: u64 = 1 << 6;
+pub const XCR0_HI16_ZMM: u64 = 1 << 7;
+const XCR0_AVX512: u64 = XCR0_OPMASK | XCR0_ZMM_HI256 | XCR0_HI16_ZMM;
+
+pub const FXSAVE_AREA_SIZE: usize = 512;
+/// XSAVE requires the save area to be aligned on a 64-byte boundary
+pub const XSAVE_AREA_ALIGN: usize = 64;
+
+// These are referenced from the interrupt entry code in x86.rs
+pub(super) static XSAVE_ENABLED: AtomicBool = AtomicBool::new(false);
+pub(super) static XSAVE_AREA_SIZE: AtomicU64 =
+    AtomicU64::new(FXSAVE_AREA_SIZE as u64);
+static XCR0: AtomicU64 = AtomicU64::new(XCR0_X87 | XCR0_SSE);
+
+pub fn read_xcr0() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!("xgetbv",
+            in("ecx") 0,
+            out("eax") lo,
+            out("edx") hi)
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+/// # Safety
+/// Setting unsupported bits in XCR0 will cause #GP.
+pub unsafe fn write_xcr0(xcr0: u64) {
+    asm!("xsetbv",
+        in("ecx") 0,
+        in("eax") xcr0 as u32,
+        in("edx") (xcr0 >> 32) as u32)
+}
+
+/// Returns the XCR0 value that should be enabled, given the components the
+/// CPU supports. Components that depend on each other are enabled together.
+fn calc_xcr0(supported: u64) -> u64 {
+    let mut xcr0 = XCR0_X87 | XCR0_SSE;
+    if supported & XCR0_AVX != 0 {
+        xcr0 |= XCR0_AVX;
+        if supported & XCR0_AVX512 == XCR0_AVX512 {
+            xcr0 |= XCR0_AVX512;
+        }
+    }
+    xcr0
+}
+
+pub fn init_fpu() {
+    let features = cpu_features();
+    unsafe {
+        // Use native FPU exceptions and do not trap on FPU / SIMD
+        // instructions.
+        write_cr0((read_cr0() & !(CR0_EM | CR0_TS)) | CR0_MP | CR0_NE);
+        write_cr4(read_cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT);
+    }
+    if !features.xsave {
+        info!("fpu: XSAVE is not supported. Using FXSAVE.");
+        return;
+    }
+    let xcr0 = calc_xcr0(features.xsave_supported_xcr0);
+    unsafe {
+        write_cr4(read_cr4() | CR4_OSXSAVE);
+        write_xcr0(xcr0);
+    }
+    // CPUID.(EAX=0DH,ECX=0):EBX reports the size required for the
+    // components enabled in XCR0 right now.
+    let size = cpuid(0x0D, 0).ebx as u64;
+    XCR0.store(xcr0, Ordering::SeqCst);
+    XSAVE_AREA_SIZE.store(size, Ordering::SeqCst);
+    XSAVE_ENABLED.store(true, Ordering::SeqCst);
+    info!("fpu: XSAVE enabled. xcr0: {xcr0:#X}, save area: {size} bytes");
+}
+
+pub fn is_xsave_enabled() -> bool {
+    XSAVE_ENABLED.load(Ordering::Relaxed)
+}
+pub fn xsave_area_size() -> usize {
+    XSAVE_AREA_SIZE.load(Ordering::Relaxed) as usize
+}
+pub fn enabled_xcr0() -> u64 {
+    XCR0.load(Ordering::Relaxed)
+}
+/// Returns true if AVX instructions can be used in the kernel, which means
+/// that the CPU supports them and their state is preserved across
+/// interrupts.
+pub fn is_avx_usable() -> bool {
+    is_xsave_enabled() && enabled_xcr0() & XCR0_AVX != 0
+}
+
+/// A buffer to hold the extended (FPU / SIMD) state of a thread of
+/// execution. Its size is determined at runtime so that it can hold all the
+/// components enabled by `init_fpu()`.
+pub struct ExtendedState {
+    area: *mut u8,
+    layout: Layout,
+}
+impl ExtendedState {
+    pub fn new() -> Result<Self> {
+        let layout =
+            Layout::from_size_align(xsave_area_size(), XSAVE_AREA_ALIGN)
+                .or(Err("Invalid layout for the XSAVE area"))?;
+        // The XSAVE header needs to be zero-initialized to be valid for
+        // XRSTOR.
+        let area = unsafe { alloc_zeroed(layout) };
+        if area.is_null() {
+            Err("Failed to allocate XSAVE area")
+        } else {
+            Ok(Self { area, layout })
+        }
+    }
+    /// Saves the current extended state of the CPU into this buffer.
+    pub fn save(&mut self) {
+        unsafe {
+            if is_xsave_enabled() {
+                asm!("xsave64 [{}]",
+                    in(reg) self.area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX)
+            } else {
+                asm!("fxsave64 [{}]", in(reg) self.area)
+            }
+        }
+    }
+    /// Loads the extended state saved in this buffer into the CPU.
+    pub fn restore(&self) {
+        unsafe {
+            if is_xsave_enabled() {
+                asm!("xrstor64 [{}]",
+                    in(reg) self.area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX)
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) self.area)
+            }
+        }
+    }
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+}
+impl Drop for ExtendedState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area, self.layout) }
+    }
+}
+
+#[test_case]
+fn calc_xcr0_test() {
+    assert_eq!(calc_xcr0(0b11), XCR0_X87 | XCR0_SSE);
+    assert_eq!(calc_xcr0(0b111), XCR0_X87 | XCR0_SSE | XCR0_AVX);
+    assert_eq!(
+        calc_xcr0(0b1110_0111),
+        XCR0_X87 | XCR0_SSE | XCR0_AVX | XCR0_AVX512
+    );
+    // AVX-512 components should be enabled only if all of them are there
+    assert_eq!(calc_xcr0(0b0110_0111), XCR0_X87 | XCR0_SSE | XCR0_AVX);
+}
+
+#[test_case]
+fn extended_state_save_restore_test() {
+    let mut state = ExtendedState::new().expect("Failed to alloc");
+    assert!(state.size() >= FXSAVE_AREA_SIZE);
+    state.save();
+    state.restore();
+}
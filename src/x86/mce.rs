@@ -0,0 +1,360 @@
+//! Machine check (#MC) and NMI handling
+//!
+//! Both handlers run on their own IST stacks and write their reports
+//! directly to the serial port, since they can interrupt code that holds
+//! the locks used by `println!` and friends.
+//!
+//! An NMI dumps the CPU state and resumes the interrupted code. It can be
+//! triggered with the `nmi` command of the QEMU monitor (see
+//! scripts/send_nmi.sh).
+//!
+//! c.f. SDM Vol.3B: 16 Machine-Check Architecture
+
+use crate::apic::LocalApic;
+use crate::info;
+use crate::serial::SerialPort;
+use crate::x86::cpuid::cpu_features;
+use crate::x86::read_cr0;
+use crate::x86::read_cr2;
+use crate::x86::read_cr3;
+use crate::x86::read_cr4;
+use crate::x86::read_msr;
+use crate::x86::set_interrupt_handler;
+use crate::x86::write_cr4;
+use crate::x86::write_msr;
+use crate::x86::InterruptInfo;
+use crate::x86::MSR_EFER;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+pub const VECTOR_NMI: u8 = 2;
+pub const VECTOR_MACHINE_CHECK: u8 = 18;
+
+const CR4_MCE: u64 = 1 << 6;
+
+const MSR_IA32_MCG_CAP: u32 = 0x179;
+const MSR_IA32_MCG_STATUS: u32 = 0x17A;
+const MSR_IA32_MCG_CTL: u32 = 0x17B;
+const MSR_IA32_MC0_CTL: u32 = 0x400;
+const MSR_IA32_MC0_STATUS: u32 = 0x401;
+const MSR_IA32_MC0_ADDR: u32 = 0x402;
+const MSR_IA32_MC0_MISC: u32 = 0x403;
+
+const MCG_CAP_COUNT_MASK: u64 = 0xFF;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_EN: u64 = 1 << 60;
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+const MCI_STATUS_PCC: u64 = 1 << 57;
+const MCI_STATUS_S: u64 = 1 << 56;
+const MCI_STATUS_AR: u64 = 1 << 55;
+
+static NUM_MACHINE_CHECKS: AtomicUsize = AtomicUsize::new(0);
+static NUM_NMIS: AtomicUsize = AtomicUsize::new(0);
+
+fn msr_mci_ctl(bank: usize) -> u32 {
+    MSR_IA32_MC0_CTL + 4 * bank as u32
+}
+fn msr_mci_status(bank: usize) -> u32 {
+    MSR_IA32_MC0_STATUS + 4 * bank as u32
+}
+fn msr_mci_addr(bank: usize) -> u32 {
+    MSR_IA32_MC0_ADDR + 4 * bank as u32
+}
+fn msr_mci_misc(bank: usize) -> u32 {
+    MSR_IA32_MC0_MISC + 4 * bank as u32
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MachineCheckSeverity {
+    /// The error was corrected by the hardware
+    Corrected,
+    /// The error was not corrected but the execution can continue
+    Recoverable,
+    /// The execution can not be continued safely
+    Fatal,
+}
+
+/// An error logged in a machine check bank
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MachineCheckRecord {
+    pub bank: usize,
+    pub status: u64,
+    pub addr: Option<u64>,
+    pub misc: Option<u64>,
+}
+impl MachineCheckRecord {
+    fn read(bank: usize) -> Option<Self> {
+        let status = read_msr(msr_mci_status(bank));
+        if status & MCI_STATUS_VAL == 0 {
+            return None;
+        }
+        let addr = (status & MCI_STATUS_ADDRV != 0)
+            .then(|| read_msr(msr_mci_addr(bank)));
+        let misc = (status & MCI_STATUS_MISCV != 0)
+            .then(|| read_msr(msr_mci_misc(bank)));
+        Some(Self {
+            bank,
+            status,
+            addr,
+            misc,
+        })
+    }
+    fn clear(&self) {
+        unsafe { write_msr(msr_mci_status(self.bank), 0) }
+    }
+    fn has(&self, bit: u64) -> bool {
+        self.status & bit != 0
+    }
+    pub fn mca_error_code(&self) -> u16 {
+        self.status as u16
+    }
+    pub fn model_specific_error_code(&self) -> u16 {
+        (self.status >> 16) as u16
+    }
+    pub fn severity(&self) -> MachineCheckSeverity {
+        // Software Recoverable Action Required (SRAR) errors need actions
+        // like offlining the page, which we can't do.
+        let is_srar = self.has(MCI_STATUS_S) && self.has(MCI_STATUS_AR);
+        if !self.has(MCI_STATUS_UC) {
+            MachineCheckSeverity::Corrected
+        } else if self.has(MCI_STATUS_PCC)
+            || self.has(MCI_STATUS_OVER)
+            || is_srar
+        {
+            MachineCheckSeverity::Fatal
+        } else {
+            MachineCheckSeverity::Recoverable
+        }
+    }
+}
+impl fmt::Display for MachineCheckRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[MCE] bank={} status={:#018X} severity={:?} mca_code={:#06X} \
+             model_code={:#06X} val=1 over={} uc={} en={} pcc={} s={} ar={}",
+            self.bank,
+            self.status,
+            self.severity(),
+            self.mca_error_code(),
+            self.model_specific_error_code(),
+            self.has(MCI_STATUS_OVER) as u8,
+            self.has(MCI_STATUS_UC) as u8,
+            self.has(MCI_STATUS_EN) as u8,
+            self.has(MCI_STATUS_PCC) as u8,
+            self.has(MCI_STATUS_S) as u8,
+            self.has(MCI_STATUS_AR) as u8,
+        )?;
+        if let Some(addr) = self.addr {
+            write!(f, " addr={addr:#018X}")?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, " misc={misc:#018X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Decides the severity of the machine check as a whole, given the
+/// IA32_MCG_STATUS value and the records from the banks.
+pub fn classify_machine_check(
+    mcg_status: u64,
+    records: &[MachineCheckRecord],
+) -> MachineCheckSeverity {
+    let worst = records
+        .iter()
+        .map(|r| r.severity())
+        .max()
+        .unwrap_or(MachineCheckSeverity::Corrected);
+    if mcg_status & MCG_STATUS_RIPV == 0 {
+        // The interrupted program can not be restarted reliably.
+        MachineCheckSeverity::Fatal
+    } else {
+        worst
+    }
+}
+
+fn num_banks() -> usize {
+    (read_msr(MSR_IA32_MCG_CAP) & MCG_CAP_COUNT_MASK) as usize
+}
+
+const MAX_BANKS: usize = 32;
+const EMPTY_RECORD: MachineCheckRecord = MachineCheckRecord {
+    bank: 0,
+    status: 0,
+    addr: None,
+    misc: None,
+};
+
+/// Reads the valid records from all the banks into records, without
+/// allocating memory since the allocator may be the one interrupted.
+/// Returns the number of records read.
+fn read_all_records(records: &mut [MachineCheckRecord; MAX_BANKS]) -> usize {
+    let mut len = 0;
+    for bank in 0..num_banks().min(MAX_BANKS) {
+        if let Some(r) = MachineCheckRecord::read(bank) {
+            records[len] = r;
+            len += 1;
+        }
+    }
+    len
+}
+
+fn machine_check_handler(info: &InterruptInfo) {
+    NUM_MACHINE_CHECKS.fetch_add(1, Ordering::SeqCst);
+    let mut w = SerialPort::default();
+    let mcg_status = read_msr(MSR_IA32_MCG_STATUS);
+    let mut records = [EMPTY_RECORD; MAX_BANKS];
+    let len = read_all_records(&mut records);
+    let records = &records[..len];
+    let severity = classify_machine_check(mcg_status, records);
+    let _ = writeln!(
+        w,
+        "[MCE] machine check: rip={:#018X} mcg_status={:#018X} ripv={} \
+         eipv={} severity={:?}",
+        info.rip(),
+        mcg_status,
+        (mcg_status & MCG_STATUS_RIPV != 0) as u8,
+        (mcg_status & MCG_STATUS_EIPV != 0) as u8,
+        severity,
+    );
+    for r in records {
+        let _ = writeln!(w, "{r}");
+    }
+    if severity == MachineCheckSeverity::Fatal {
+        let _ = writeln!(w, "[MCE] {info:?}");
+        panic!("Fatal machine check");
+    }
+    for r in records {
+        r.clear();
+    }
+    // Clear MCIP to allow the next machine check to be delivered instead
+    // of causing a shutdown.
+    unsafe { write_msr(MSR_IA32_MCG_STATUS, mcg_status & !MCG_STATUS_MCIP) }
+}
+
+/// Writes the CPU state to the serial port.
+pub fn dump_cpu_state(w: &mut dyn Write, info: &InterruptInfo) {
+    let lapic_id = LocalApic::current().map(|lapic| lapic.id());
+    let _ = writeln!(w, "[NMI] CPU state (Local APIC id: {lapic_id:?}):");
+    let _ = writeln!(w, "{info:?}");
+    let _ = writeln!(
+        w,
+        "CR0: {:#018X}, CR2: {:#018X}, CR3: {:#018X}, CR4: {:#018X}",
+        read_cr0(),
+        read_cr2(),
+        read_cr3() as u64,
+        read_cr4()
+    );
+    let _ = writeln!(w, "EFER: {:#018X}", read_msr(MSR_EFER));
+}
+
+fn nmi_handler(info: &InterruptInfo) {
+    NUM_NMIS.fetch_add(1, Ordering::SeqCst);
+    dump_cpu_state(&mut SerialPort::default(), info);
+}
+
+pub fn num_machine_checks() -> usize {
+    NUM_MACHINE_CHECKS.load(Ordering::SeqCst)
+}
+pub fn num_nmis() -> usize {
+    NUM_NMIS.load(Ordering::SeqCst)
+}
+
+/// Registers the NMI and #MC handlers, and enables machine checks if the
+/// CPU supports them. Errors left in the banks (e.g. from the previous
+/// boot) are logged and cleared.
+pub fn init_machine_check() {
+    set_interrupt_handler(VECTOR_NMI, nmi_handler);
+    if let Some(lapic) = LocalApic::current() {
+        lapic.enable_lint1_nmi();
+    }
+    let features = cpu_features();
+    if !features.mce || !features.mca {
+        info!("Machine check architecture is not supported");
+        return;
+    }
+    set_interrupt_handler(VECTOR_MACHINE_CHECK, machine_check_handler);
+    let cap = read_msr(MSR_IA32_MCG_CAP);
+    let num_banks = num_banks();
+    if cap & MCG_CAP_CTL_P != 0 {
+        unsafe { write_msr(MSR_IA32_MCG_CTL, u64::MAX) }
+    }
+    let mut records = [EMPTY_RECORD; MAX_BANKS];
+    let len = read_all_records(&mut records);
+    for r in &records[..len] {
+        info!("Found a machine check error logged before boot: {r}");
+    }
+    for bank in 0..num_banks.min(MAX_BANKS) {
+        unsafe {
+            // Bank 0 control is managed by the platform on some CPUs, but
+            // writing all-1s is allowed.
+            write_msr(msr_mci_ctl(bank), u64::MAX);
+            write_msr(msr_mci_status(bank), 0);
+        }
+    }
+    unsafe { write_cr4(read_cr4() | CR4_MCE) }
+    info!("Machine check enabled: {num_banks} banks, MCG_CAP={cap:#X}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(status: u64) -> MachineCheckRecord {
+        MachineCheckRecord {
+            bank: 1,
+            status: status | MCI_STATUS_VAL,
+            addr: None,
+            misc: None,
+        }
+    }
+
+    #[test_case]
+    fn severity_of_records() {
+        use MachineCheckSeverity::*;
+        assert_eq!(record(0).severity(), Corrected);
+        assert_eq!(record(MCI_STATUS_UC).severity(), Recoverable);
+        assert_eq!(
+            record(MCI_STATUS_UC | MCI_STATUS_S).severity(),
+            Recoverable
+        );
+        assert_eq!(
+            record(MCI_STATUS_UC | MCI_STATUS_S | MCI_STATUS_AR).severity(),
+            Fatal
+        );
+        assert_eq!(record(MCI_STATUS_UC | MCI_STATUS_PCC).severity(), Fatal);
+        assert_eq!(record(MCI_STATUS_UC | MCI_STATUS_OVER).severity(), Fatal);
+        // Overflow of corrected errors is still corrected
+        assert_eq!(record(MCI_STATUS_OVER).severity(), Corrected);
+    }
+
+    #[test_case]
+    fn classify_machine_checks() {
+        use MachineCheckSeverity::*;
+        let corrected = record(0);
+        let recoverable = record(MCI_STATUS_UC);
+        let fatal = record(MCI_STATUS_UC | MCI_STATUS_PCC);
+        assert_eq!(classify_machine_check(MCG_STATUS_RIPV, &[]), Corrected);
+        assert_eq!(classify_machine_check(0, &[]), Fatal);
+        assert_eq!(
+            classify_machine_check(MCG_STATUS_RIPV, &[corrected, recoverable]),
+            Recoverable
+        );
+        assert_eq!(
+            classify_machine_check(MCG_STATUS_RIPV, &[corrected, fatal]),
+            Fatal
+        );
+        assert_eq!(classify_machine_check(0, &[corrected]), Fatal);
+    }
+}
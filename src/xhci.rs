@@ -1,7 +1,8 @@
 extern crate alloc;
 
-use crate::allocator::ALLOCATOR;
 use crate::bits::extract_bits;
+use crate::dma::DmaBuffer;
+use crate::dma::DmaConstraints;
 use crate::executor::sleep;
 use crate::executor::spawn_global;
 use crate::executor::yield_execution;
@@ -23,12 +24,10 @@ use crate::usb::UsbDeviceDriver;
 use crate::volatile::Volatile;
 use crate::warn;
 use crate::x86::busy_loop_hint;
-use alloc::boxed::Box;
 use alloc::collections::VecDeque;
 use alloc::rc::Rc;
 use alloc::rc::Weak;
 use alloc::vec::Vec;
-use core::alloc::Layout;
 use core::cmp::max;
 use core::future::Future;
 use core::marker::PhantomPinned;
@@ -110,11 +109,11 @@ impl PciXhciDriver {
     pub fn attach(pci: &Pci, bdf: BusDeviceFunction) -> Result<()> {
         info!("Xhci found at: {bdf:?}");
         pci.disable_interrupt(bdf)?;
-        pci.enable_bus_master(bdf)?;
+        pci.enable_isolated_bus_master(bdf)?;
         let bar0 = pci.try_bar0_mem64(bdf)?;
         bar0.disable_cache();
         let regs = Self::setup_xhc_registers(&bar0)?;
-        let xhc = Controller::new(regs)?;
+        let xhc = Controller::new(regs, bdf)?;
         spawn_global(Self::run(xhc));
         Ok(())
     }
@@ -299,12 +298,17 @@ impl PciXhciDriver {
     ) -> Result<CommandRing> {
         // Setup an input context and send AddressDevice command.
         // 4.3.3 Device Slot Initialization
-        let output_context = Box::pin(OutputContext::default());
+        let output_context =
+            IoBox::new_with_constraints(xhc.dma_constraints())?;
         xhc.set_output_context_for_slot(slot, output_context);
         let mut input_ctrl_ctx = InputControlContext::default();
         input_ctrl_ctx.add_context(0)?;
         input_ctrl_ctx.add_context(1)?;
-        let mut input_context = Box::pin(InputContext::default());
+        let mut input_context_box: IoBox<InputContext> =
+            IoBox::new_with_constraints(xhc.dma_constraints())?;
+        let mut input_context = unsafe {
+            Pin::new_unchecked(input_context_box.get_unchecked_mut())
+        };
         input_context.as_mut().set_input_ctrl_ctx(input_ctrl_ctx)?;
         // 3. Initialize the Input Slot Context data structure (6.2.2)
         input_context.as_mut().set_root_hub_port_number(port)?;
@@ -313,7 +317,7 @@ impl PciXhciDriver {
         // 5. Initialize the Input default control Endpoint 0 Context (6.2.3)
         let portsc = xhc.regs.portsc.get(port).ok_or("PORTSC was invalid")?;
         input_context.as_mut().set_port_speed(portsc.port_speed())?;
-        let ctrl_ep_ring = CommandRing::default();
+        let ctrl_ep_ring = CommandRing::new(xhc.dma_constraints())?;
         input_context.as_mut().set_ep_ctx(
             1,
             EndpointContext::new_control_endpoint(
@@ -322,8 +326,7 @@ impl PciXhciDriver {
             )?,
         )?;
         // 8. Issue an Address Device Command for the Device Slot
-        let cmd =
-            GenericTrbEntry::cmd_address_device(input_context.as_ref(), slot);
+        let cmd = GenericTrbEntry::cmd_address_device(&input_context_box, slot);
         xhc.send_command(cmd).await?.cmd_result_ok()?;
         Ok(ctrl_ep_ring)
     }
@@ -378,11 +381,6 @@ struct RawDeviceContextBaseAddressArray {
     _pinned: PhantomPinned,
 }
 const _: () = assert!(size_of::<RawDeviceContextBaseAddressArray>() == 2048);
-impl RawDeviceContextBaseAddressArray {
-    fn new() -> Self {
-        unsafe { MaybeUninit::zeroed().assume_init() }
-    }
-}
 
 #[repr(C)]
 struct OperationalRegisters {
@@ -438,7 +436,7 @@ impl OperationalRegisters {
         &mut self,
         dcbaa: &mut DeviceContextBaseAddressArray,
     ) -> Result<()> {
-        self.dcbaap.write(dcbaa.inner_mut_ptr());
+        self.dcbaap.write(dcbaa.inner_phys_addr());
         Ok(())
     }
     fn set_num_device_slots(&mut self, num: usize) -> Result<()> {
@@ -490,13 +488,14 @@ impl RuntimeRegisters {
 }
 
 struct ScratchpadBuffers {
-    table: Pin<Box<[*const u8]>>,
-    _bufs: Vec<Pin<Box<[u8]>>>,
+    table: DmaBuffer,
+    _bufs: Vec<DmaBuffer>,
 }
 impl ScratchpadBuffers {
     fn alloc(
         cap_regs: &CapabilityRegisters,
         op_regs: &OperationalRegisters,
+        dma: DmaConstraints,
     ) -> Result<Self> {
         let page_size = op_regs.page_size()?;
         info!("xhci: page_size = {page_size}");
@@ -504,27 +503,21 @@ impl ScratchpadBuffers {
         info!("xhci: original num_scratchpad_bufs = {num_scratchpad_bufs}");
 
         let num_scratchpad_bufs = max(cap_regs.num_scratchpad_bufs(), 1);
-        let table = ALLOCATOR.alloc_with_options(
-            Layout::from_size_align(
-                size_of::<usize>() * num_scratchpad_bufs,
-                page_size,
+        let page_aligned = dma.align(page_size);
+        let mut table = DmaBuffer::new(
+            size_of::<usize>() * num_scratchpad_bufs,
+            page_aligned,
+        )?;
+        let entries = unsafe {
+            slice::from_raw_parts_mut(
+                table.as_mut_ptr() as *mut *const u8,
+                num_scratchpad_bufs,
             )
-            .map_err(|_| "could not allocate scratchpad buffer table")?,
-        );
-        let table = unsafe {
-            slice::from_raw_parts(table as *mut *const u8, num_scratchpad_bufs)
         };
-        let mut table = Pin::new(Box::<[*const u8]>::from(table));
         let mut bufs = Vec::new();
-        for sb in table.iter_mut() {
-            let buf = ALLOCATOR.alloc_with_options(
-                Layout::from_size_align(page_size, page_size)
-                    .map_err(|_| "could not allocated a scratchpad buffer")?,
-            );
-            let buf =
-                unsafe { slice::from_raw_parts(buf as *const u8, page_size) };
-            let buf = Pin::new(Box::<[u8]>::from(buf));
-            *sb = buf.as_ref().as_ptr();
+        for sb in entries.iter_mut() {
+            let buf = DmaBuffer::new(page_size, page_aligned)?;
+            *sb = buf.phys_addr() as *const u8;
             bufs.push(buf);
         }
         Ok(Self { table, _bufs: bufs })
@@ -649,67 +642,69 @@ struct OutputContext {
 const _: () = assert!(size_of::<OutputContext>() <= 4096);
 
 struct DeviceContextBaseAddressArray {
-    inner: Pin<Box<RawDeviceContextBaseAddressArray>>,
+    inner: IoBox<RawDeviceContextBaseAddressArray>,
     // NB: the index of context is [slot - 1], not slot.
-    context: [Option<Pin<Box<OutputContext>>>; 255],
+    context: [Option<IoBox<OutputContext>>; 255],
     _scratchpad_buffers: ScratchpadBuffers,
 }
 impl DeviceContextBaseAddressArray {
-    fn new(scratchpad_buffers: ScratchpadBuffers) -> Self {
-        let mut inner = RawDeviceContextBaseAddressArray::new();
-        inner.scratchpad_table_ptr = scratchpad_buffers.table.as_ref().as_ptr();
-        let inner = Box::pin(inner);
-        Self {
+    fn new(
+        scratchpad_buffers: ScratchpadBuffers,
+        dma: DmaConstraints,
+    ) -> Result<Self> {
+        let mut inner: IoBox<RawDeviceContextBaseAddressArray> =
+            IoBox::new_with_constraints(dma)?;
+        unsafe { inner.get_unchecked_mut() }.scratchpad_table_ptr =
+            scratchpad_buffers.table.phys_addr() as *const *const u8;
+        Ok(Self {
             inner,
-            context: unsafe { MaybeUninit::zeroed().assume_init() },
+            context: core::array::from_fn(|_| None),
             _scratchpad_buffers: scratchpad_buffers,
-        }
+        })
     }
-    fn inner_mut_ptr(&mut self) -> *const RawDeviceContextBaseAddressArray {
-        self.inner.as_ref().get_ref() as *const RawDeviceContextBaseAddressArray
+    fn inner_phys_addr(&self) -> *const RawDeviceContextBaseAddressArray {
+        self.inner.phys_addr() as *const RawDeviceContextBaseAddressArray
     }
     fn set_output_context(
         &mut self,
         slot: u8,
-        output_context: Pin<Box<OutputContext>>,
+        output_context: IoBox<OutputContext>,
     ) {
         let ctx_idx = slot as usize - 1;
-        // Own the output context here
+        // Set it in the actual pointer array...
+        unsafe { self.inner.get_unchecked_mut() }.context[ctx_idx] =
+            output_context.phys_addr();
+        // ...and own the output context here
         self.context[ctx_idx] = Some(output_context);
-        // ...and set it in the actual pointer array
-        unsafe {
-            self.inner.as_mut().get_unchecked_mut().context[ctx_idx] =
-                self.context[ctx_idx]
-                    .as_ref()
-                    .expect("Output Context was None")
-                    .as_ref()
-                    .get_ref() as *const OutputContext as u64;
-        }
     }
 }
 
 pub struct Controller {
     regs: XhcRegisters,
+    bdf: BusDeviceFunction,
     device_context_base_array: Mutex<DeviceContextBaseAddressArray>,
     primary_event_ring: Mutex<EventRing>,
     command_ring: Mutex<CommandRing>,
 }
 impl Controller {
-    fn new(mut regs: XhcRegisters) -> Result<Self> {
+    fn new(mut regs: XhcRegisters, bdf: BusDeviceFunction) -> Result<Self> {
         unsafe {
             regs.op_regs.get_unchecked_mut().reset_xhc();
         }
+        let dma = DmaConstraints::new().device(bdf);
         let scratchpad_buffers = ScratchpadBuffers::alloc(
             regs.cap_regs.as_ref(),
             regs.op_regs.as_ref(),
+            dma,
         )?;
         let device_context_base_array =
-            DeviceContextBaseAddressArray::new(scratchpad_buffers);
+            DeviceContextBaseAddressArray::new(scratchpad_buffers, dma)?;
         let device_context_base_array = Mutex::new(device_context_base_array);
-        let primary_event_ring = Mutex::new(EventRing::new()?);
-        let command_ring = Mutex::new(CommandRing::default());
+        let primary_event_ring = Mutex::new(EventRing::new(dma)?);
+        let command_ring = Mutex::new(CommandRing::new(dma)?);
         let mut xhc = Self {
             regs,
+            bdf,
             device_context_base_array,
             primary_event_ring,
             command_ring,
@@ -722,6 +717,14 @@ impl Controller {
         info!("xHC started running!");
         Ok(xhc)
     }
+    /// Constraints for the memory that the xHC accesses. Only the xHC can
+    /// access such buffers when the IOMMU is enabled.
+    pub fn dma_constraints(&self) -> DmaConstraints {
+        DmaConstraints::new().device(self.bdf)
+    }
+    pub fn alloc_dma_buffer(&self, size: usize) -> Result<DmaBuffer> {
+        DmaBuffer::new(size, self.dma_constraints())
+    }
     fn init_primary_event_ring(&mut self) -> Result<()> {
         let eq = &mut self.primary_event_ring;
         unsafe { self.regs.rt_regs.get_unchecked_mut() }
@@ -762,7 +765,7 @@ impl Controller {
     fn set_output_context_for_slot(
         &self,
         slot: u8,
-        output_context: Pin<Box<OutputContext>>,
+        output_context: IoBox<OutputContext>,
     ) {
         self.device_context_base_array
             .lock()
@@ -775,7 +778,7 @@ impl Controller {
         desc_type: usb::UsbDescriptorType,
         desc_index: u8,
         lang_id: u16,
-        buf: &mut Pin<Box<[u8]>>,
+        buf: &mut DmaBuffer,
     ) -> Result<()> {
         ctrl_ep_ring.push(
             SetupStageTrb::new(
@@ -802,7 +805,7 @@ impl Controller {
         desc_type: usb::UsbDescriptorType,
         desc_index: u8,
         w_index: u16,
-        buf: &mut Pin<Box<[u8]>>,
+        buf: &mut DmaBuffer,
     ) -> Result<()> {
         ctrl_ep_ring.push(
             SetupStageTrb::new(
@@ -827,7 +830,7 @@ impl Controller {
         &self,
         slot: u8,
         ctrl_ep_ring: &mut CommandRing,
-        buf: &mut Pin<Box<[u8]>>,
+        buf: &mut DmaBuffer,
     ) -> Result<()> {
         // [HID] 7.2.1 Get_Report Request
         ctrl_ep_ring.push(
@@ -934,9 +937,9 @@ struct EventRing {
     wait_list: VecDeque<Weak<EventWaitInfo>>,
 }
 impl EventRing {
-    fn new() -> Result<Self> {
-        let ring = TrbRing::new();
-        let erst = EventRingSegmentTableEntry::new(&ring)?;
+    fn new(dma: DmaConstraints) -> Result<Self> {
+        let ring = TrbRing::new(dma)?;
+        let erst = EventRingSegmentTableEntry::new(&ring, dma)?;
         Ok(Self {
             ring,
             erst,
@@ -946,13 +949,13 @@ impl EventRing {
         })
     }
     fn ring_phys_addr(&self) -> u64 {
-        self.ring.as_ref() as *const TrbRing as u64
+        self.ring.phys_addr()
     }
     fn set_erdp(&mut self, erdp: *mut u64) {
         self.erdp = Some(erdp);
     }
     fn erst_phys_addr(&self) -> u64 {
-        self.erst.as_ref() as *const EventRingSegmentTableEntry as u64
+        self.erst.phys_addr()
     }
     /// Non-blocking
     fn pop(&mut self) -> Result<Option<GenericTrbEntry>> {
@@ -1023,12 +1026,11 @@ struct EventRingSegmentTableEntry {
 }
 const _: () = assert!(size_of::<EventRingSegmentTableEntry>() == 4096);
 impl EventRingSegmentTableEntry {
-    fn new(ring: &IoBox<TrbRing>) -> Result<IoBox<Self>> {
-        let mut erst: IoBox<Self> = IoBox::new();
+    fn new(ring: &IoBox<TrbRing>, dma: DmaConstraints) -> Result<IoBox<Self>> {
+        let mut erst: IoBox<Self> = IoBox::new_with_constraints(dma)?;
         {
             let erst = unsafe { erst.get_unchecked_mut() };
-            erst.ring_segment_base_address =
-                ring.as_ref() as *const TrbRing as u64;
+            erst.ring_segment_base_address = ring.phys_addr();
             erst.ring_segment_size = ring
                 .as_ref()
                 .num_trbs()
@@ -1044,13 +1046,13 @@ struct TrbRing {
     current_index: usize,
     _pinned: PhantomPinned,
 }
-// Limiting the size of TrbRing to be equal or less than 4096
-// to avoid crossing 64KiB boundaries. See Table 6-1 of xhci spec.
-const _: () = assert!(size_of::<TrbRing>() <= 4096);
+// TRB rings should not cross 64KiB boundaries. See Table 6-1 of xhci spec.
+const TRB_RING_BOUNDARY: usize = 64 * 1024;
+const _: () = assert!(size_of::<TrbRing>() <= TRB_RING_BOUNDARY);
 impl TrbRing {
     const NUM_TRB: usize = 16;
-    fn new() -> IoBox<Self> {
-        IoBox::new()
+    fn new(dma: DmaConstraints) -> Result<IoBox<Self>> {
+        IoBox::new_with_constraints(dma.boundary(TRB_RING_BOUNDARY))
     }
     const fn num_trbs(&self) -> usize {
         Self::NUM_TRB
@@ -1201,11 +1203,13 @@ impl GenericTrbEntry {
     fn set_slot_id(&mut self, slot: u8) {
         self.control.write_bits(24, 8, slot as u32).unwrap()
     }
-    fn cmd_address_device(input_context: Pin<&InputContext>, slot: u8) -> Self {
+    fn cmd_address_device(
+        input_context: &IoBox<InputContext>,
+        slot: u8,
+    ) -> Self {
         let mut trb = Self::default();
         trb.set_trb_type(TrbType::AddressDeviceCommand);
-        trb.data
-            .write(input_context.get_ref() as *const InputContext as u64);
+        trb.data.write(input_context.phys_addr());
         trb.set_slot_id(slot);
         trb
     }
@@ -1233,8 +1237,18 @@ pub struct CommandRing {
     cycle_state_ours: bool,
 }
 impl CommandRing {
+    fn new(dma: DmaConstraints) -> Result<Self> {
+        let mut this = Self {
+            ring: TrbRing::new(dma)?,
+            cycle_state_ours: false,
+        };
+        let link_trb = GenericTrbEntry::trb_link(this.ring.as_ref());
+        unsafe { this.ring.get_unchecked_mut() }
+            .write(TrbRing::NUM_TRB - 1, link_trb)?;
+        Ok(this)
+    }
     fn ring_phys_addr(&self) -> u64 {
-        self.ring.as_ref() as *const TrbRing as u64
+        self.ring.phys_addr()
     }
     fn push(&mut self, mut src: GenericTrbEntry) -> Result<u64> {
         // Calling get_unchecked_mut() here is safe
@@ -1257,19 +1271,6 @@ impl CommandRing {
         Ok(dst_ptr as u64)
     }
 }
-impl Default for CommandRing {
-    fn default() -> Self {
-        let mut this = Self {
-            ring: TrbRing::new(),
-            cycle_state_ours: false,
-        };
-        let link_trb = GenericTrbEntry::trb_link(this.ring.as_ref());
-        unsafe { this.ring.get_unchecked_mut() }
-            .write(TrbRing::NUM_TRB - 1, link_trb)
-            .expect("failed to write a link trb");
-        this
-    }
-}
 
 #[derive(Debug, Default)]
 struct EventWaitCond {
@@ -1690,9 +1691,9 @@ pub struct DataStageTrb {
 }
 const _: () = assert!(size_of::<DataStageTrb>() == 16);
 impl DataStageTrb {
-    pub fn new_in(buf: &mut Pin<Box<[u8]>>) -> Self {
+    pub fn new_in(buf: &mut DmaBuffer) -> Self {
         Self {
-            buf: buf.as_ptr() as u64,
+            buf: buf.phys_addr(),
             option: buf.len() as u32,
             control: (TrbType::DataStage as u32) << 10
                 | GenericTrbEntry::CTRL_BIT_DATA_DIR_IN